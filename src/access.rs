@@ -0,0 +1,112 @@
+use crate::planner::WritePlanner;
+use chrono::{DateTime, Utc};
+use libfiresale::api::{DatabaseContext, Precondition};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::DocumentPath;
+use reqwest::StatusCode;
+use serde_json::Map;
+use std::time::UNIX_EPOCH;
+
+/// What `can-i` checks the credentials for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Read,
+    Write,
+    Delete,
+}
+
+impl Action {
+    pub fn parse(action: &str) -> Result<Action> {
+        match action {
+            "read" => Ok(Action::Read),
+            "write" => Ok(Action::Write),
+            "delete" => Ok(Action::Delete),
+            _ => Err(Error::InvalidInput {
+                message: format!(
+                    "unknown action {:?}, expected read, write or delete",
+                    action
+                ),
+            }),
+        }
+    }
+
+    // The IAM permission Firestore checks for the action
+    fn permission(self) -> &'static str {
+        match self {
+            Action::Read => "datastore.entities.get",
+            Action::Write => "datastore.entities.update",
+            Action::Delete => "datastore.entities.delete",
+        }
+    }
+}
+
+/// Prints whether the credentials may perform `action` on the document at `path`, and
+/// returns it. Reads get the document. Writes and deletes are probed through the
+/// planner with an update time precondition no document can meet, so permission is
+/// checked before the write is refused and nothing changes either way; a dry run only
+/// prints the probe and returns `None`. Security rules do not apply to service
+/// accounts, so the answer only depends on IAM.
+pub fn can_i(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    action: Action,
+    path: &str,
+) -> Result<Option<bool>> {
+    let path = DocumentPath::parse(path)?.to_string();
+    if action != Action::Read && ctx.is_read_only() {
+        println!("no, --read-only credentials cannot modify documents");
+        return Ok(Some(false));
+    }
+    let database_name = &*planner.database_name;
+    let probe = match action {
+        Action::Read => ctx.get_document(database_name, &*path, None).map(|_| true),
+        Action::Write | Action::Delete => {
+            let write = match action {
+                Action::Delete => ctx.delete_write(database_name, &*path),
+                _ => ctx.update_write(database_name, &*path, &Map::new()),
+            };
+            let never = DateTime::<Utc>::from(UNIX_EPOCH);
+            planner.probe(
+                ctx,
+                write.with_precondition(Precondition::UpdateTime(never)),
+            )
+        }
+    };
+    match probe {
+        Ok(true) => {}
+        Ok(false) => {
+            println!("unknown, --dry-run does not send the probe");
+            return Ok(None);
+        }
+        Err(ref e)
+            if e.status() == Some(StatusCode::UNAUTHORIZED)
+                || e.status() == Some(StatusCode::FORBIDDEN) =>
+        {
+            println!(
+                "no, {} is denied, it needs {} on project {}",
+                path,
+                action.permission(),
+                ctx.project_id
+            );
+            return Ok(Some(false));
+        }
+        // only refusals made past the permission check say anything about it
+        Err(ref e) if past_permission_check(e, &*path) => {}
+        Err(e) => return Err(e),
+    }
+    println!("yes");
+    Ok(Some(true))
+}
+
+// Whether `e` is the failed precondition of a probe, or says the document at `path` is
+// missing. A missing database or a malformed request is not, though it shares the
+// HTTP status.
+fn past_permission_check(e: &Error, path: &str) -> bool {
+    match e.canonical_status() {
+        Some("FAILED_PRECONDITION") => true,
+        Some("NOT_FOUND") => e.api_message().map_or(false, |message| {
+            message.contains(&*format!("/documents/{}", path))
+        }),
+        _ => false,
+    }
+}
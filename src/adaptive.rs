@@ -0,0 +1,78 @@
+use libfiresale::errors::Result;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How much slower than the fastest request seen a request may be while the limit
+/// keeps growing
+const LATENCY_TOLERANCE: u32 = 2;
+
+/// A concurrency limit adjusted AIMD style: it grows by one after a full limit's worth
+/// of requests complete without slowing down, and halves whenever Firestore throttles.
+/// It never goes below one nor above the number of workers sharing it.
+pub struct AdaptiveLimit {
+    max: usize,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    fastest: Option<Duration>,
+}
+
+impl AdaptiveLimit {
+    /// Starts with a single request at a time
+    pub fn new(max: usize) -> AdaptiveLimit {
+        AdaptiveLimit {
+            max: max.max(1),
+            state: Mutex::new(State {
+                limit: 1.0,
+                in_flight: 0,
+                fastest: None,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Runs `request` once fewer requests than the limit are in flight, adjusting
+    /// the limit from how it went
+    pub fn run<T, F>(&self, request: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            while state.in_flight >= state.limit as usize {
+                state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            state.in_flight += 1;
+        }
+        let start = Instant::now();
+        let result = request();
+        let latency = start.elapsed();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight -= 1;
+        match &result {
+            Ok(_) => {
+                let fastest = state
+                    .fastest
+                    .map_or(latency, |fastest| fastest.min(latency));
+                state.fastest = Some(fastest);
+                // a slower response means the database is busier, hold the limit
+                if latency <= fastest * LATENCY_TOLERANCE {
+                    state.limit = (state.limit + 1.0 / state.limit).min(self.max as f64);
+                }
+            }
+            Err(e) if e.is_throttled() => state.limit = (state.limit / 2.0).max(1.0),
+            Err(_) => {}
+        }
+        self.changed.notify_all();
+        result
+    }
+
+    /// Requests currently allowed at the same time
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).limit as usize
+    }
+}
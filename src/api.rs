@@ -1,54 +1,645 @@
+use super::debug::{HttpHook, Interceptor};
+use super::errors::{Error, Result};
 use super::firestore;
+use super::path::{generate_document_id, DocumentPath, DocumentReference, ResourceName};
+use super::policy::{RateLimit, RetryPolicy};
+use chrono::DateTime;
 use chrono::Utc;
-use chrono::{Date, DateTime};
 use goauth::auth::JwtClaims;
 use goauth::scopes::Scope;
-use goauth::scopes::Scope::Firebase;
-use serde::Deserializer;
 use smpl_jwt::Jwt;
-use std::collections::HashMap;
-use std::convert::TryFrom;
+use std::collections::{HashMap, VecDeque};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-const FIRESTORE_BASE_URL: &'static str = "https://firestore.googleapis.com/v1";
-const FIRESTORE_BETA_BASE_URL: &'static str = " https://firestore.googleapis.com/v1beta1";
-
-//// the `fields` attribute for Firestore Documents
-#[derive(Debug, Deserialize)]
+/// the `fields` attribute for Firestore Documents
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FirestoreFields(HashMap<String, FirestoreType>);
 
-#[derive(Debug, Deserialize)]
-struct Map {
+/// The contents of a `mapValue`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapValue {
+    #[serde(default, skip_serializing_if = "FirestoreFields::is_empty")]
     fields: FirestoreFields,
 }
 
-#[derive(Debug, Deserialize)]
-struct Array {
+/// The contents of an `arrayValue`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArrayValue {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     values: Vec<FirestoreType>,
 }
 
-#[derive(Debug)]
+/// An authenticated handle on a project's databases. Clones share the same client and
+/// access token, so a single context can be handed to every worker thread.
+#[derive(Debug, Clone)]
 pub struct DatabaseContext {
     pub project_id: String,
-    auth_token: goauth::auth::Token,
+    authorization: Arc<Authorization>,
     client: reqwest::Client,
+    http_hook: Option<Arc<dyn HttpHook>>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    retry_policy: RetryPolicy,
+    rate_limit: Option<RateLimit>,
+    /// What `client` was built with
+    connection: ConnectionOptions,
+    read_time: Option<DateTime<Utc>>,
+}
+
+/// The OAuth scope a `DatabaseContext` authenticates with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScope {
+    /// `https://www.googleapis.com/auth/datastore`, enough for document access
+    DataStore,
+    /// `https://www.googleapis.com/auth/cloud-platform`, also covers admin operations
+    CloudPlatform,
+    /// `https://www.googleapis.com/auth/cloud-platform.read-only`. The context also
+    /// refuses to send mutating requests, so nothing is written even if the service
+    /// account would be allowed to.
+    ReadOnly,
+}
+
+/// How a `DatabaseContext` obtains its access token
+#[derive(Debug, Clone, Copy)]
+pub struct AuthOptions {
+    pub scope: AuthScope,
+    /// When the token exchange fails because the local clock is off, measure the
+    /// difference with Google's clock and date the token request accordingly
+    pub adjust_clock: bool,
+}
+
+impl Default for AuthOptions {
+    fn default() -> AuthOptions {
+        AuthOptions {
+            scope: AuthScope::DataStore,
+            adjust_clock: false,
+        }
+    }
+}
+
+impl AuthScope {
+    fn scope(self) -> Scope {
+        match self {
+            AuthScope::DataStore => Scope::DataStore,
+            AuthScope::CloudPlatform => Scope::CloudPlatform,
+            AuthScope::ReadOnly => Scope::CloudPlatformReadOnly,
+        }
+    }
+}
+
+impl std::str::FromStr for AuthScope {
+    type Err = Error;
+
+    fn from_str(scope: &str) -> Result<AuthScope> {
+        match scope {
+            "datastore" => Ok(AuthScope::DataStore),
+            "cloud-platform" => Ok(AuthScope::CloudPlatform),
+            "read-only" => Ok(AuthScope::ReadOnly),
+            _ => Err(Error::InvalidInput {
+                message: format!(
+                    "unknown scope {}, expected datastore, cloud-platform or read-only",
+                    scope
+                ),
+            }),
+        }
+    }
+}
+
+/// Which HTTP version a `DatabaseContext` speaks to Firestore
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    /// Offer HTTP/2 and HTTP/1.1 in the TLS handshake and speak whichever the server
+    /// picks. Firestore picks HTTP/2.
+    Negotiate,
+    /// Speak HTTP/2 without negotiating it, for endpoints known to support it
+    Http2,
+    /// Speak HTTP/1.1 over the platform's TLS library, for networks whose proxies
+    /// break HTTP/2
+    Http1,
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = Error;
+
+    fn from_str(version: &str) -> Result<HttpVersion> {
+        match version {
+            "negotiate" => Ok(HttpVersion::Negotiate),
+            "2" => Ok(HttpVersion::Http2),
+            "1.1" => Ok(HttpVersion::Http1),
+            _ => Err(Error::InvalidInput {
+                message: format!(
+                    "unknown HTTP version {}, expected negotiate, 2 or 1.1",
+                    version
+                ),
+            }),
+        }
+    }
+}
+
+/// How the HTTP client behind a `DatabaseContext` manages its connections.
+/// Every clone of a context shares one connection pool, so paginated scans and
+/// concurrent workers reuse open connections.
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub http_version: HttpVersion,
+    /// Idle connections kept open per host, 0 closes each connection after use
+    pub max_idle_per_host: usize,
+    /// Overall timeout for a single request, `None` waits forever
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            http_version: HttpVersion::Negotiate,
+            max_idle_per_host: std::usize::MAX,
+            timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl ConnectionOptions {
+    fn build_client(&self) -> Result<reqwest::Client> {
+        // large listings are dominated by transfer size, so ask for gzip and let
+        // reqwest inflate it. reqwest 0.9 cannot decode deflate, so it is not advertised.
+        let builder = reqwest::Client::builder()
+            .gzip(true)
+            .max_idle_per_host(self.max_idle_per_host)
+            .timeout(self.timeout);
+        // only the rustls backend of reqwest 0.9 offers h2 via ALPN, native-tls
+        // always ends up on HTTP/1.1
+        let builder = match self.http_version {
+            HttpVersion::Negotiate => builder.use_rustls_tls(),
+            HttpVersion::Http2 => builder.use_rustls_tls().h2_prior_knowledge(),
+            HttpVersion::Http1 => builder.use_default_tls(),
+        };
+        builder.build().map_err(Error::from)
+    }
+}
+
+/// Refresh the access token once it is this close to expiring
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// Clock differences below this are not worth blaming for a failed token exchange
+const CLOCK_SKEW_TOLERANCE_SECS: i64 = 30;
+
+/// The access token shared between clones of a `DatabaseContext`
+#[derive(Debug)]
+struct Authorization {
+    service_account_path: PathBuf,
+    scope: AuthScope,
+    adjust_clock: bool,
+    token: RwLock<CachedToken>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    token: goauth::auth::Token,
+    expires_at: Instant,
+    /// Seconds added to the local time when dating the token request
+    clock_offset: i64,
+}
+
+impl Authorization {
+    fn new(service_account_path: PathBuf, options: AuthOptions) -> Result<Authorization, String> {
+        let token = CachedToken::fetch(&service_account_path, options, 0)?;
+        Ok(Authorization {
+            service_account_path,
+            scope: options.scope,
+            adjust_clock: options.adjust_clock,
+            token: RwLock::new(token),
+        })
+    }
+
+    /// The current access token, exchanging the service account credentials for a
+    /// new one first if it is about to expire. Only one caller refreshes at a time,
+    /// the rest wait on the lock and pick up the new token.
+    fn access_token(&self) -> Result<String> {
+        {
+            let cached = self.token.read().map_err(|_| Error::Authentication {
+                message: "token lock poisoned".to_string(),
+            })?;
+            if !cached.is_expiring() {
+                return Ok(cached.token.access_token().to_string());
+            }
+        }
+        let mut cached = self.token.write().map_err(|_| Error::Authentication {
+            message: "token lock poisoned".to_string(),
+        })?;
+        // another thread may have refreshed while we waited for the write lock
+        if cached.is_expiring() {
+            let options = AuthOptions {
+                scope: self.scope,
+                adjust_clock: self.adjust_clock,
+            };
+            *cached = CachedToken::fetch(&self.service_account_path, options, cached.clock_offset)
+                .map_err(|message| Error::Authentication { message })?;
+        }
+        Ok(cached.token.access_token().to_string())
+    }
+
+    /// Exchanges the credentials for a new token even though the current one is good
+    fn renew(&self) -> Result<()> {
+        let mut cached = self.token.write().map_err(|_| Error::Authentication {
+            message: "token lock poisoned".to_string(),
+        })?;
+        let options = AuthOptions {
+            scope: self.scope,
+            adjust_clock: self.adjust_clock,
+        };
+        *cached = CachedToken::fetch(&self.service_account_path, options, cached.clock_offset)
+            .map_err(|message| Error::Authentication { message })?;
+        Ok(())
+    }
+}
+
+impl CachedToken {
+    /// Exchanges the credentials for a token, dating the request `clock_offset` seconds
+    /// from the local time. A failed exchange is checked for clock skew, which Google
+    /// reports as a bare `invalid_grant`.
+    fn fetch(
+        service_account_path: &Path,
+        options: AuthOptions,
+        clock_offset: i64,
+    ) -> Result<CachedToken, String> {
+        // get jwt & credentials from file
+        let credentials = load_credentials(service_account_path)?;
+        let requested_at = Instant::now();
+        let error = match request_token(&credentials, options.scope, clock_offset)? {
+            Ok(token) => return Ok(CachedToken::new(token, requested_at, clock_offset)),
+            Err(error) => error,
+        };
+        let skew = match clock_skew(&*credentials.token_uri()) {
+            Some(skew)
+                if (skew - clock_offset).abs() >= CLOCK_SKEW_TOLERANCE_SECS
+                    || (error.contains("invalid_grant") && skew != clock_offset) =>
+            {
+                skew
+            }
+            _ => return Err(format!("Failed to authenticate: {}", error)),
+        };
+        let direction = if skew > 0 { "behind" } else { "ahead of" };
+        if !options.adjust_clock {
+            return Err(format!(
+                "Failed to authenticate: {}. The local clock is {}s {} Google's, \
+                 fix the system clock or enable clock adjustment",
+                error,
+                skew.abs(),
+                direction
+            ));
+        }
+        eprintln!(
+            "warning: local clock is {}s {} Google's, adjusting the token request",
+            skew.abs(),
+            direction
+        );
+        let requested_at = Instant::now();
+        match request_token(&credentials, options.scope, skew)? {
+            Ok(token) => Ok(CachedToken::new(token, requested_at, skew)),
+            Err(error) => Err(format!(
+                "Failed to authenticate even after adjusting the clock by {}s: {}",
+                skew, error
+            )),
+        }
+    }
+
+    fn new(token: goauth::auth::Token, requested_at: Instant, clock_offset: i64) -> CachedToken {
+        let expires_at = requested_at + Duration::from_secs(u64::from(token.expires_in()));
+        CachedToken {
+            token,
+            expires_at,
+            clock_offset,
+        }
+    }
+
+    fn is_expiring(&self) -> bool {
+        Instant::now() + TOKEN_REFRESH_MARGIN >= self.expires_at
+    }
+}
+
+// Signs and sends one token request. The outer error means the request could not
+// even be built, the inner one that Google refused it.
+fn request_token(
+    credentials: &goauth::credentials::Credentials,
+    scope: AuthScope,
+    clock_offset: i64,
+) -> Result<Result<goauth::auth::Token, String>, String> {
+    let issued_at = Utc::now().timestamp() + clock_offset;
+    let claims = JwtClaims::new(
+        credentials.iss(),
+        &scope.scope(),
+        credentials.token_uri(),
+        Some(issued_at),
+        None,
+    );
+    let jwt = Jwt::new(
+        claims,
+        credentials
+            .rsa_key()
+            .map_err(|_| "Failed to get RSA private key from credentials")?,
+        None,
+    );
+    Ok(goauth::get_token_with_creds(&jwt, credentials).map_err(|e| format!("{:?}", e)))
+}
+
+/// Seconds the local clock is behind Google's, negative when it is ahead, read from
+/// the `Date` header of a request to `url`. `None` if the server could not be reached.
+fn clock_skew(url: &str) -> Option<i64> {
+    let response = reqwest::Client::new().head(url).send().ok()?;
+    let date = response
+        .headers()
+        .get(reqwest::header::DATE)?
+        .to_str()
+        .ok()?;
+    let server_time = DateTime::parse_from_rfc2822(date).ok()?;
+    Some(server_time.timestamp() - Utc::now().timestamp())
+}
+
+/// Resolves a credentials path as given by the user: a leading `~` is the home
+/// directory and a relative path is taken from `base`, or the working directory.
+/// Pass the directory of a config file as `base` for paths written in it.
+pub fn resolve_credentials_path(path: &str, base: Option<&Path>) -> PathBuf {
+    let path = path.trim();
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    let expanded = match (home, path) {
+        (Some(home), "~") => PathBuf::from(home),
+        (Some(home), _) if path.starts_with("~/") || path.starts_with("~\\") => {
+            PathBuf::from(home).join(&path[2..])
+        }
+        _ => PathBuf::from(path),
+    };
+    if expanded.is_absolute() {
+        return expanded;
+    }
+    match base {
+        Some(base) => base.join(expanded),
+        None => std::env::current_dir()
+            .map(|dir| dir.join(&expanded))
+            .unwrap_or(expanded),
+    }
+}
+
+// Checks the file ourselves first, so a missing file, broken JSON and some other
+// kind of key each get their own message instead of goauth's catch-all error
+fn load_credentials(path: &Path) -> Result<goauth::credentials::Credentials, String> {
+    let contents = std::fs::read(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            format!("Credentials file {} not found", path.display())
+        }
+        _ => format!("Failed to read credentials file {}: {}", path.display(), e),
+    })?;
+    let key = serde_json::from_slice::<serde_json::Value>(&*contents).map_err(|e| {
+        format!(
+            "Credentials file {} is not valid JSON: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let key_type = key.get("type").and_then(|t| t.as_str());
+    let complete = ["client_email", "private_key", "token_uri"]
+        .iter()
+        .all(|field| key.get(*field).map_or(false, |value| value.is_string()));
+    if key_type != Some("service_account") || !complete {
+        return Err(format!(
+            "Credentials file {} is not a service account key (type {})",
+            path.display(),
+            key_type.unwrap_or("missing")
+        ));
+    }
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| format!("Credentials path {} is not valid unicode", path.display()))?;
+    goauth::credentials::Credentials::from_file(path_str).map_err(|e| {
+        format!(
+            "Failed to load credentials from {}: {:?}",
+            path.display(),
+            e
+        )
+    })
 }
 
 // Firestore GeoPoint type
-#[derive(Debug, Deserialize, Clone, Copy)]
-struct GeoPoint {
-    latitude: i32,
-    longitude: i32,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// A `doubleValue`. With the `decimal` feature the literal sent by the server is
+/// kept, so values carrying more precision than an f64 survive a round trip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Double {
+    value: f64,
+    literal: Option<String>,
+}
+
+impl Double {
+    pub fn new(value: f64) -> Double {
+        Double {
+            value,
+            literal: None,
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// The exact decimal text received from the server, if it was preserved
+    pub fn literal(&self) -> Option<&str> {
+        self.literal.as_ref().map(|literal| &**literal)
+    }
+
+    pub fn is_nan(&self) -> bool {
+        self.value.is_nan()
+    }
+
+    /// How the API spells NaN and the infinities, which JSON numbers cannot hold
+    fn non_finite_text(&self) -> Option<&'static str> {
+        if self.value.is_nan() {
+            Some(NAN)
+        } else if self.value == std::f64::INFINITY {
+            Some(INFINITY)
+        } else if self.value == std::f64::NEG_INFINITY {
+            Some(NEG_INFINITY)
+        } else {
+            None
+        }
+    }
+}
+
+const NAN: &'static str = "NaN";
+const INFINITY: &'static str = "Infinity";
+const NEG_INFINITY: &'static str = "-Infinity";
+
+/// What to do with NaN and infinite doubles when converting to plain JSON
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NonFinitePolicy {
+    /// Fail the conversion
+    Error,
+    /// Emit `null`
+    Null,
+    /// Emit the API spelling, `"NaN"`, `"Infinity"` or `"-Infinity"`
+    String,
+}
+
+impl Default for NonFinitePolicy {
+    fn default() -> NonFinitePolicy {
+        NonFinitePolicy::String
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Double {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Double, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        // non-finite doubles are sent as strings, everything else as a number
+        let number = match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Number(number) => number,
+            serde_json::Value::String(text) => {
+                return match &*text {
+                    NAN => Ok(Double::new(std::f64::NAN)),
+                    INFINITY => Ok(Double::new(std::f64::INFINITY)),
+                    NEG_INFINITY => Ok(Double::new(std::f64::NEG_INFINITY)),
+                    _ => Err(D::Error::custom(format!("invalid doubleValue {}", text))),
+                };
+            }
+            other => return Err(D::Error::custom(format!("invalid doubleValue {}", other))),
+        };
+        let value = number
+            .as_f64()
+            .ok_or_else(|| D::Error::custom(format!("invalid doubleValue {}", number)))?;
+        let literal = if cfg!(feature = "decimal") {
+            Some(number.to_string())
+        } else {
+            None
+        };
+        Ok(Double { value, literal })
+    }
 }
 
-use serde_aux::field_attributes::deserialize_number_from_string;
+impl serde::Serialize for Double {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if let Some(text) = self.non_finite_text() {
+            return serializer.serialize_str(text);
+        }
+        if let Some(number) = self
+            .literal()
+            .and_then(|literal| literal.parse::<serde_json::Number>().ok())
+        {
+            return number.serialize(serializer);
+        }
+        serializer.serialize_f64(self.value)
+    }
+}
+
+/// A `timestampValue`. The text received from the server is kept next to the parsed
+/// time so writing a document back, or diffing it, never changes its precision.
+#[derive(Debug, Clone)]
+pub struct Timestamp {
+    time: DateTime<Utc>,
+    original: Option<String>,
+}
+
+impl Timestamp {
+    pub fn new(time: DateTime<Utc>) -> Timestamp {
+        Timestamp {
+            time,
+            original: None,
+        }
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    /// The RFC3339 text as received from the server, if this value came from one
+    pub fn original(&self) -> Option<&str> {
+        self.original.as_ref().map(|original| &**original)
+    }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Timestamp) -> bool {
+        self.time == other.time
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.original() {
+            Some(original) => write!(f, "{}", original),
+            None => write!(
+                f,
+                "{}",
+                self.time
+                    .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+            ),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Timestamp {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Timestamp, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let original = String::deserialize(deserializer)?;
+        let time = DateTime::parse_from_rfc3339(&*original)
+            .map_err(|e| D::Error::custom(format!("invalid timestampValue {}: {}", original, e)))?
+            .with_timezone(&Utc);
+        Ok(Timestamp {
+            time,
+            original: Some(original),
+        })
+    }
+}
+
+impl serde::Serialize for Timestamp {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&*self.to_string())
+    }
+}
+
+/// Reads an `integerValue`, which the API sends as a string. Values that do not
+/// fit in an i64 are rejected instead of being truncated. Read through a `Value`, as
+/// with the `decimal` feature serde_json hands numbers over in a form only it knows.
+fn deserialize_integer<'de, D>(deserializer: D) -> std::result::Result<i64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::{Deserialize, Error};
+    let (integer, text) = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(number) => (number.as_i64(), number.to_string()),
+        serde_json::Value::String(text) => (text.parse().ok(), text),
+        other => {
+            return Err(D::Error::custom(format!(
+                "invalid integerValue {}, expected a 64 bit integer or a string containing one",
+                other
+            )))
+        }
+    };
+    integer
+        .ok_or_else(|| D::Error::custom(format!("integerValue {} does not fit in 64 bits", text)))
+}
 
 // Represents a mapping between Firestore data types and Rust types
-#[derive(Debug, Deserialize)]
-enum FirestoreType {
+#[derive(Debug, Clone, Deserialize)]
+pub enum FirestoreType {
     #[serde(rename = "integerValue")]
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    Integer(i32),
+    #[serde(deserialize_with = "deserialize_integer")]
+    Integer(i64),
+    #[serde(rename = "doubleValue")]
+    Double(Double),
     #[serde(rename = "booleanValue")]
     Boolean(bool),
     #[serde(rename = "stringValue")]
@@ -56,18 +647,106 @@ enum FirestoreType {
     #[serde(rename = "geoPointValue")]
     GeoLocation(GeoPoint),
     #[serde(rename = "arrayValue")]
-    Array(Array),
+    Array(ArrayValue),
     #[serde(rename = "mapValue")]
-    Map(Map),
+    Map(MapValue),
     #[serde(rename = "timestampValue")]
-    Timestamp(DateTime<Utc>),
+    Timestamp(Timestamp),
+    #[serde(rename = "bytesValue")]
+    #[serde(deserialize_with = "deserialize_bytes")]
+    Bytes(Vec<u8>),
+    #[serde(rename = "referenceValue")]
+    Reference(DocumentReference),
     #[serde(rename = "nullValue")]
     Null,
 }
 
-#[derive(Debug, Deserialize)]
+/// Reads a `bytesValue`, sent by the API as base64
+fn deserialize_bytes<'de, D>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::{Deserialize, Error};
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(&*encoded).map_err(|e| D::Error::custom(format!("invalid bytesValue: {}", e)))
+}
+
+// Hand written so integers are emitted as strings and null as `{"nullValue": null}`,
+// matching what the REST API sends and expects
+impl serde::Serialize for FirestoreType {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(1))?;
+        match self {
+            FirestoreType::Integer(i) => map.serialize_entry("integerValue", &i.to_string())?,
+            FirestoreType::Double(d) => map.serialize_entry("doubleValue", d)?,
+            FirestoreType::Boolean(b) => map.serialize_entry("booleanValue", b)?,
+            FirestoreType::String(s) => map.serialize_entry("stringValue", s)?,
+            FirestoreType::GeoLocation(point) => map.serialize_entry("geoPointValue", point)?,
+            FirestoreType::Array(array) => map.serialize_entry("arrayValue", array)?,
+            FirestoreType::Map(fields) => map.serialize_entry("mapValue", fields)?,
+            FirestoreType::Timestamp(time) => map.serialize_entry("timestampValue", time)?,
+            FirestoreType::Bytes(bytes) => {
+                map.serialize_entry("bytesValue", &base64::encode(bytes))?
+            }
+            FirestoreType::Reference(reference) => {
+                map.serialize_entry("referenceValue", reference)?
+            }
+            FirestoreType::Null => map.serialize_entry("nullValue", &())?,
+        }
+        map.end()
+    }
+}
+
+impl From<&str> for FirestoreType {
+    fn from(value: &str) -> FirestoreType {
+        FirestoreType::String(value.to_string())
+    }
+}
+
+impl From<String> for FirestoreType {
+    fn from(value: String) -> FirestoreType {
+        FirestoreType::String(value)
+    }
+}
+
+impl From<i64> for FirestoreType {
+    fn from(value: i64) -> FirestoreType {
+        FirestoreType::Integer(value)
+    }
+}
+
+impl From<i32> for FirestoreType {
+    fn from(value: i32) -> FirestoreType {
+        FirestoreType::Integer(i64::from(value))
+    }
+}
+
+impl From<f64> for FirestoreType {
+    fn from(value: f64) -> FirestoreType {
+        FirestoreType::Double(Double::new(value))
+    }
+}
+
+impl From<bool> for FirestoreType {
+    fn from(value: bool) -> FirestoreType {
+        FirestoreType::Boolean(value)
+    }
+}
+
+impl From<DateTime<Utc>> for FirestoreType {
+    fn from(value: DateTime<Utc>) -> FirestoreType {
+        FirestoreType::Timestamp(Timestamp::new(value))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     name: String,
+    #[serde(default, skip_serializing_if = "FirestoreFields::is_empty")]
     fields: FirestoreFields,
     #[serde(rename = "createTime")]
     create_time: DateTime<Utc>,
@@ -75,103 +754,1394 @@ pub struct Document {
     update_time: DateTime<Utc>,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DocumentMask {
     #[serde(rename = "fieldPaths")]
     field_paths: Vec<String>,
 }
 
-#[derive(Serialize)]
-pub enum ConsistencySelector {
-    Transaction(String),
-    #[serde(rename = "readTime")]
-    ReadTime(DateTime<Utc>),
-}
+impl DocumentMask {
+    pub fn new(field_paths: Vec<String>) -> DocumentMask {
+        DocumentMask { field_paths }
+    }
+
+    pub fn field_paths(&self) -> &[String] {
+        &*self.field_paths
+    }
+}
+
+/// Joins field names into a field path such as `address.city`, wrapping names that
+/// are not plain identifiers in backticks
+pub fn field_path(segments: &[&str]) -> String {
+    segments
+        .iter()
+        .map(|segment| {
+            let simple = segment
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if simple {
+                segment.to_string()
+            } else {
+                format!("`{}`", segment.replace('\\', "\\\\").replace('`', "\\`"))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+/// Splits a field path written by `field_path` back into its field names, unquoting
+/// the backticked ones
+pub fn split_field_path(path: &str) -> Result<Vec<String>> {
+    let invalid = || Error::InvalidInput {
+        message: format!("{} is not a valid field path", path),
+    };
+    let mut segments = Vec::new();
+    let mut chars = path.chars().peekable();
+    loop {
+        let mut segment = String::new();
+        if chars.peek() == Some(&'`') {
+            chars.next();
+            loop {
+                match chars.next().ok_or_else(invalid)? {
+                    '`' => break,
+                    '\\' => segment.push(chars.next().ok_or_else(invalid)?),
+                    c => segment.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == '.' {
+                    break;
+                }
+                if c == '`' {
+                    return Err(invalid());
+                }
+                segment.push(c);
+                chars.next();
+            }
+        }
+        if segment.is_empty() {
+            return Err(invalid());
+        }
+        segments.push(segment);
+        match chars.next() {
+            None => return Ok(segments),
+            Some('.') => continue,
+            Some(_) => return Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum ConsistencySelector {
+    #[serde(rename = "transaction")]
+    Transaction(String),
+    #[serde(rename = "readTime")]
+    ReadTime(DateTime<Utc>),
+}
+
+impl ConsistencySelector {
+    /// The query string parameter used to pin a read to this selector
+    pub fn query_pair(&self) -> (&'static str, String) {
+        match self {
+            ConsistencySelector::Transaction(id) => ("transaction", id.clone()),
+            ConsistencySelector::ReadTime(time) => ("readTime", time.to_rfc3339()),
+        }
+    }
+}
+
+/// A single mutation applied as part of a commit
+#[derive(Debug, Clone, Serialize)]
+pub struct Write {
+    #[serde(flatten)]
+    pub operation: WriteOperation,
+    /// Fails the write unless the document on the server matches
+    #[serde(rename = "currentDocument", skip_serializing_if = "Option::is_none")]
+    pub current_document: Option<Precondition>,
+    /// Limits an update to these fields, the others are left untouched. A field in
+    /// the mask but absent from the update is deleted.
+    #[serde(rename = "updateMask", skip_serializing_if = "Option::is_none")]
+    pub update_mask: Option<DocumentMask>,
+    /// Applied by the server after the update, in order
+    #[serde(rename = "updateTransforms", skip_serializing_if = "Vec::is_empty")]
+    pub update_transforms: Vec<FieldTransform>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum WriteOperation {
+    /// Replaces the document with the given fields, creating it if needed
+    #[serde(rename = "update")]
+    Update(DocumentUpdate),
+    /// Deletes the document with the given resource name
+    #[serde(rename = "delete")]
+    Delete(String),
+}
+
+/// The `update` half of a write, holding fields already in wire format
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentUpdate {
+    pub name: String,
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/Write#FieldTransform
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldTransform {
+    #[serde(rename = "fieldPath")]
+    pub field_path: String,
+    #[serde(flatten)]
+    pub transform: Transform,
+}
+
+/// What a `FieldTransform` does, with its operand in wire format
+#[derive(Debug, Clone, Serialize)]
+pub enum Transform {
+    /// Adds to the field, which starts from 0 when absent or not a number
+    #[serde(rename = "increment")]
+    Increment(serde_json::Value),
+}
+
+impl FieldTransform {
+    pub fn increment<S: Into<String>>(field_path: S, by: i64) -> FieldTransform {
+        FieldTransform {
+            field_path: field_path.into(),
+            transform: Transform::Increment(json_to_wire(&serde_json::Value::from(by))),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.transform {
+            Transform::Increment(by) => write!(f, "{} += {}", self.field_path, by),
+        }
+    }
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/Precondition
+#[derive(Debug, Clone, Serialize)]
+pub enum Precondition {
+    #[serde(rename = "exists")]
+    Exists(bool),
+    #[serde(rename = "updateTime")]
+    UpdateTime(DateTime<Utc>),
+}
+
+impl Write {
+    pub fn delete(name: String) -> Write {
+        Write {
+            operation: WriteOperation::Delete(name),
+            current_document: None,
+            update_mask: None,
+            update_transforms: Vec::new(),
+        }
+    }
+
+    /// Builds a write replacing `name` with plain JSON `fields`
+    pub fn update(name: String, fields: &serde_json::Map<String, serde_json::Value>) -> Write {
+        let fields = fields
+            .iter()
+            .map(|(key, value)| (key.clone(), json_to_wire(value)))
+            .collect();
+        Write {
+            operation: WriteOperation::Update(DocumentUpdate { name, fields }),
+            current_document: None,
+            update_mask: None,
+            update_transforms: Vec::new(),
+        }
+    }
+
+    /// Builds a write replacing `name` with `fields` as read back from Firestore
+    pub fn replace(name: String, fields: &FirestoreFields) -> Write {
+        let fields = match serde_json::to_value(fields) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        Write {
+            operation: WriteOperation::Update(DocumentUpdate { name, fields }),
+            current_document: None,
+            update_mask: None,
+            update_transforms: Vec::new(),
+        }
+    }
+
+    /// Builds a write applying only `transforms` to `name`, leaving its other fields
+    /// untouched and creating it if needed
+    pub fn transform(name: String, transforms: Vec<FieldTransform>) -> Write {
+        Write {
+            operation: WriteOperation::Update(DocumentUpdate {
+                name,
+                fields: serde_json::Map::new(),
+            }),
+            current_document: None,
+            update_mask: Some(DocumentMask::new(Vec::new())),
+            update_transforms: transforms,
+        }
+    }
+
+    /// Sets `field` on an update to a typed value, for values plain JSON cannot express
+    pub fn with_field<S: Into<String>>(mut self, field: S, value: &FirestoreType) -> Write {
+        if let WriteOperation::Update(update) = &mut self.operation {
+            if let Ok(value) = serde_json::to_value(value) {
+                update.fields.insert(field.into(), value);
+            }
+        }
+        self
+    }
+
+    /// Turns an update into a patch of `field_paths` only
+    pub fn with_update_mask(mut self, field_paths: Vec<String>) -> Write {
+        self.update_mask = Some(DocumentMask::new(field_paths));
+        self
+    }
+
+    /// Adds a transform the server applies after the update
+    pub fn with_transform(mut self, transform: FieldTransform) -> Write {
+        self.update_transforms.push(transform);
+        self
+    }
+
+    pub fn with_precondition(mut self, precondition: Precondition) -> Write {
+        self.current_document = Some(precondition);
+        self
+    }
+
+    /// Resource name of the document this write targets
+    pub fn path(&self) -> String {
+        match &self.operation {
+            WriteOperation::Update(update) => update.name.clone(),
+            WriteOperation::Delete(name) => name.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Write {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.operation {
+            WriteOperation::Update(update) => {
+                write!(
+                    f,
+                    "update {} {}",
+                    update.name,
+                    serde_json::Value::Object(update.fields.clone())
+                )?;
+                if let Some(mask) = &self.update_mask {
+                    write!(f, " mask [{}]", mask.field_paths.join(", "))?;
+                }
+                if !self.update_transforms.is_empty() {
+                    let transforms = self
+                        .update_transforms
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<String>>();
+                    write!(f, " transform [{}]", transforms.join(", "))?;
+                }
+                Ok(())
+            }
+            WriteOperation::Delete(name) => write!(f, "delete {}", name),
+        }
+    }
+}
+
+/// Converts a plain JSON value into a Firestore `Value` in wire format
+pub fn json_to_wire(value: &serde_json::Value) -> serde_json::Value {
+    use serde_json::{json, Value};
+    match value {
+        Value::Null => json!({ "nullValue": null }),
+        Value::Bool(b) => json!({ "booleanValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "integerValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Array(values) => json!({
+            "arrayValue": { "values": values.iter().map(json_to_wire).collect::<Vec<Value>>() }
+        }),
+        Value::Object(map) => {
+            let fields = map
+                .iter()
+                .map(|(key, value)| (key.clone(), json_to_wire(value)))
+                .collect::<serde_json::Map<String, Value>>();
+            json!({ "mapValue": { "fields": fields } })
+        }
+    }
+}
+
+impl FirestoreType {
+    /// Converts this value into plain JSON, dropping Firestore type information.
+    /// Non-finite doubles are written following `NonFinitePolicy::default()`.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.to_json_with(NonFinitePolicy::default())
+            .unwrap_or(serde_json::Value::Null)
+    }
+
+    /// Converts this value into plain JSON, handling NaN and infinities per `policy`
+    pub fn to_json_with(&self, policy: NonFinitePolicy) -> Result<serde_json::Value> {
+        use serde_json::{json, Value};
+        Ok(match self {
+            FirestoreType::Integer(i) => json!(i),
+            FirestoreType::Double(d) => match (d.non_finite_text(), policy) {
+                (None, _) => json!(d),
+                (Some(text), NonFinitePolicy::String) => json!(text),
+                (Some(_), NonFinitePolicy::Null) => Value::Null,
+                (Some(text), NonFinitePolicy::Error) => {
+                    return Err(Error::InvalidInput {
+                        message: format!("double {} cannot be represented in JSON", text),
+                    });
+                }
+            },
+            FirestoreType::Boolean(b) => json!(b),
+            FirestoreType::String(s) => json!(s),
+            FirestoreType::GeoLocation(point) => json!({
+                "latitude": point.latitude,
+                "longitude": point.longitude,
+            }),
+            FirestoreType::Array(array) => Value::Array(
+                array
+                    .values
+                    .iter()
+                    .map(|value| value.to_json_with(policy))
+                    .collect::<Result<Vec<Value>>>()?,
+            ),
+            FirestoreType::Map(map) => Value::Object(map.fields.to_json_with(policy)?),
+            FirestoreType::Timestamp(time) => json!(time.to_string()),
+            FirestoreType::Bytes(bytes) => json!(base64::encode(bytes)),
+            FirestoreType::Reference(reference) => json!(reference.to_string()),
+            FirestoreType::Null => Value::Null,
+        })
+    }
+}
+
+impl FirestoreFields {
+    pub fn get(&self, field: &str) -> Option<&FirestoreType> {
+        self.0.get(field)
+    }
+
+    /// Looks up a dotted field path such as `address.city`, descending into maps.
+    /// Names quoted with backticks, as `field_path` writes them, may contain dots.
+    pub fn get_path(&self, field_path: &str) -> Option<&FirestoreType> {
+        let segments = split_field_path(field_path).ok()?;
+        let mut segments = segments.iter();
+        let mut value = self.get(segments.next()?)?;
+        for segment in segments {
+            value = match value {
+                FirestoreType::Map(map) => map.fields.get(&**segment)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    /// Only the fields at `field_paths`, where a dotted path such as `address.city`
+    /// keeps just that entry of the map, as a projection on the server would
+    pub fn select(&self, field_paths: &[String]) -> FirestoreFields {
+        let mut selected = FirestoreFields::default();
+        for field_path in field_paths {
+            let segments = match split_field_path(field_path) {
+                Ok(segments) => segments,
+                Err(_) => continue,
+            };
+            let segments = segments.iter().map(|s| &**s).collect::<Vec<&str>>();
+            selected.select_path(self, &*segments);
+        }
+        selected
+    }
+
+    // Copies the value at `segments` from `source`, creating the maps leading to it
+    fn select_path(&mut self, source: &FirestoreFields, segments: &[&str]) {
+        let (first, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        let value = match source.get(first) {
+            Some(value) => value,
+            None => return,
+        };
+        if rest.is_empty() {
+            self.0.insert(first.to_string(), value.clone());
+            return;
+        }
+        let source = match value {
+            FirestoreType::Map(map) => &map.fields,
+            _ => return,
+        };
+        let entry = self
+            .0
+            .entry(first.to_string())
+            .or_insert_with(|| FirestoreType::Map(MapValue::new(FirestoreFields::default())));
+        // when the whole map was selected too, this copies an entry it already has
+        if let FirestoreType::Map(map) = entry {
+            map.fields.select_path(source, rest);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &FirestoreType)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Converts these fields into a plain JSON object
+    pub fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_json()))
+            .collect()
+    }
+
+    /// Converts these fields into a plain JSON object, handling NaN and infinities per `policy`
+    pub fn to_json_with(
+        &self,
+        policy: NonFinitePolicy,
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        self.0
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.to_json_with(policy)?)))
+            .collect()
+    }
+}
+
+impl std::iter::FromIterator<(String, FirestoreType)> for FirestoreFields {
+    fn from_iter<I>(fields: I) -> FirestoreFields
+    where
+        I: IntoIterator<Item = (String, FirestoreType)>,
+    {
+        FirestoreFields(fields.into_iter().collect())
+    }
+}
+
+impl MapValue {
+    pub fn new(fields: FirestoreFields) -> MapValue {
+        MapValue { fields }
+    }
+
+    pub fn fields(&self) -> &FirestoreFields {
+        &self.fields
+    }
+}
+
+impl ArrayValue {
+    pub fn new(values: Vec<FirestoreType>) -> ArrayValue {
+        ArrayValue { values }
+    }
+
+    pub fn values(&self) -> &[FirestoreType] {
+        &*self.values
+    }
+}
+
+impl Document {
+    /// Full resource name of this document
+    pub fn name(&self) -> &str {
+        &*self.name
+    }
+
+    pub fn fields(&self) -> &FirestoreFields {
+        &self.fields
+    }
+
+    pub fn create_time(&self) -> DateTime<Utc> {
+        self.create_time
+    }
+
+    pub fn update_time(&self) -> DateTime<Utc> {
+        self.update_time
+    }
+
+    /// This document with `fields` in place of its own
+    pub fn with_fields(mut self, fields: FirestoreFields) -> Document {
+        self.fields = fields;
+        self
+    }
+}
+
+pub mod list_documents {
+    use super::batch_get::Lookup;
+
+    /// A listed document. With `showMissing`, documents that do not exist but have
+    /// subcollections are listed by name alone, without a create time.
+    #[derive(Debug)]
+    enum Listed {
+        Found(super::Document),
+        Missing(String),
+    }
+
+    impl<'de> serde::Deserialize<'de> for Listed {
+        fn deserialize<D>(deserializer: D) -> std::result::Result<Listed, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            use serde::de::Error;
+            let value = serde_json::Value::deserialize(deserializer)?;
+            if value.get("createTime").is_some() {
+                return serde_json::from_value(value)
+                    .map(Listed::Found)
+                    .map_err(D::Error::custom);
+            }
+            match value.get("name").and_then(serde_json::Value::as_str) {
+                Some(name) => Ok(Listed::Missing(name.to_string())),
+                None => Err(D::Error::custom("listed document has no name")),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Response {
+        #[serde(default)]
+        documents: Vec<Listed>,
+        #[serde(rename = "nextPageToken")]
+        next_page_token: Option<String>,
+    }
+
+    /// One page of a listing
+    #[derive(Debug)]
+    pub struct Page {
+        pub documents: Vec<Lookup>,
+        /// Pass to the next call to continue the listing, `None` on the last page
+        pub next_page_token: Option<String>,
+    }
+
+    impl From<Response> for Page {
+        fn from(response: Response) -> Page {
+            let documents = response
+                .documents
+                .into_iter()
+                .map(|listed| match listed {
+                    Listed::Found(document) => Lookup::Found(document),
+                    Listed::Missing(name) => Lookup::Missing(name),
+                })
+                .collect();
+            Page {
+                documents,
+                next_page_token: response.next_page_token.filter(|token| !token.is_empty()),
+            }
+        }
+    }
+}
+
+pub mod list_collection_ids {
+    use super::DatabaseContext;
+    use crate::errors::Result;
+    use crate::firestore;
+    use chrono::{DateTime, Utc};
+    use std::collections::VecDeque;
+
+    const DEFAULT_PAGE_SIZE: i32 = 300;
+
+    #[derive(Debug, Serialize)]
+    pub struct Request {
+        #[serde(rename = "pageSize")]
+        pub page_size: i32,
+        #[serde(rename = "pageToken", skip_serializing_if = "Option::is_none")]
+        pub page_token: Option<String>,
+        #[serde(rename = "readTime", skip_serializing_if = "Option::is_none")]
+        pub read_time: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Response {
+        #[serde(rename = "collectionIds", default)]
+        pub collection_ids: Vec<String>,
+        #[serde(rename = "nextPageToken")]
+        pub next_page_token: Option<String>,
+    }
+
+    /// Lazily pages through the collection ids beneath a parent, like `QueryStream`
+    /// does through documents. Pinned to a read time with `read_at`, every page sees
+    /// the same set of collections, and `page_token` is where to resume a listing.
+    pub struct CollectionIds<'a> {
+        context: &'a DatabaseContext,
+        /// Resource name of the parent document, or of the documents root
+        parent: String,
+        page_size: i32,
+        page_token: Option<String>,
+        read_time: Option<DateTime<Utc>>,
+        buffer: VecDeque<String>,
+        exhausted: bool,
+    }
+
+    impl<'a> CollectionIds<'a> {
+        pub(crate) fn new(context: &'a DatabaseContext, parent: String) -> CollectionIds<'a> {
+            CollectionIds {
+                context,
+                parent,
+                page_size: DEFAULT_PAGE_SIZE,
+                page_token: None,
+                read_time: context.read_time,
+                buffer: VecDeque::new(),
+                exhausted: false,
+            }
+        }
+
+        /// Number of collection ids requested per call to the API
+        pub fn page_size(mut self, page_size: i32) -> CollectionIds<'a> {
+            self.page_size = page_size.max(1);
+            self
+        }
+
+        /// Lists the collections as they were at `read_time`, within the last hour
+        pub fn read_at(mut self, read_time: DateTime<Utc>) -> CollectionIds<'a> {
+            self.read_time = Some(read_time);
+            self
+        }
+
+        /// Continues a listing from the token of an earlier `page_token()`
+        pub fn resume_from(mut self, page_token: String) -> CollectionIds<'a> {
+            self.page_token = Some(page_token);
+            self
+        }
+
+        /// Token of the page after the ones fetched so far, `None` before the first
+        /// page and after the last one
+        pub fn page_token(&self) -> Option<&str> {
+            self.page_token.as_ref().map(|token| &**token)
+        }
+
+        fn fetch_page(&mut self) -> Result<()> {
+            let query = firestore::documents::ListCollectionIdsQuery {
+                parent: self.parent.clone(),
+                body: Request {
+                    page_size: self.page_size,
+                    page_token: self.page_token.take(),
+                    read_time: self.read_time,
+                },
+            };
+            let response =
+                firestore::documents::list_collection_ids(&self.context.transport()?, query)?;
+            self.buffer.extend(response.collection_ids);
+            self.page_token = response.next_page_token.filter(|token| !token.is_empty());
+            self.exhausted = self.page_token.is_none();
+            Ok(())
+        }
+    }
+
+    impl<'a> Iterator for CollectionIds<'a> {
+        type Item = Result<String>;
+
+        fn next(&mut self) -> Option<Result<String>> {
+            // an empty page may still be followed by more
+            while self.buffer.is_empty() && !self.exhausted {
+                if let Err(e) = self.fetch_page() {
+                    self.exhausted = true;
+                    return Some(Err(e));
+                }
+            }
+            self.buffer.pop_front().map(Ok)
+        }
+    }
+}
+
+pub mod batch_get {
+    use crate::errors::{Error, Result};
+    use chrono::{DateTime, Utc};
+    use std::collections::{HashMap, HashSet};
+
+    /// Maximum number of documents requested in a single batchGet call
+    pub const CHUNK_SIZE: usize = 100;
+    /// Most batchGet calls of one lookup in flight at the same time
+    pub const WORKERS: usize = 8;
+
+    #[derive(Serialize)]
+    pub struct Request {
+        pub documents: Vec<String>,
+        #[serde(rename = "readTime", skip_serializing_if = "Option::is_none")]
+        pub read_time: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Response {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub transaction: Option<String>,
+        #[serde(rename = "readTime")]
+        pub read_time: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub found: Option<super::Document>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub missing: Option<String>,
+    }
+
+    /// The outcome of looking up a single document in a batchGet
+    #[derive(Debug, Clone)]
+    pub enum Lookup {
+        Found(super::Document),
+        /// Holds the resource name of the document that does not exist
+        Missing(String),
+    }
+
+    impl Lookup {
+        fn name(&self) -> &str {
+            match self {
+                Lookup::Found(document) => document.name(),
+                Lookup::Missing(name) => &**name,
+            }
+        }
+    }
+
+    /// `names` without repeats, split into the documents of each batchGet call
+    pub(crate) fn chunks(names: &[String]) -> Vec<Vec<String>> {
+        let mut seen = HashSet::new();
+        let unique = names
+            .iter()
+            .filter(|name| seen.insert(*name))
+            .cloned()
+            .collect::<Vec<String>>();
+        unique
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| chunk.to_vec())
+            .collect()
+    }
+
+    /// The lookup of each of `names`, in order and as often as it is repeated, out of
+    /// those the responses held. A name they left out is an error, not a missing
+    /// document.
+    pub(crate) fn in_order(names: &[String], answered: Vec<Lookup>) -> Result<Vec<Lookup>> {
+        let answered = answered
+            .into_iter()
+            .map(|lookup| (lookup.name().to_string(), lookup))
+            .collect::<HashMap<String, Lookup>>();
+        names
+            .iter()
+            .map(|name| {
+                answered
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| Error::IncompleteResponse {
+                        message: format!("batchGet did not answer for {}", name),
+                    })
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        fn name(id: usize) -> String {
+            format!("projects/p/databases/(default)/documents/users/{}", id)
+        }
+
+        fn found(name: &str) -> Lookup {
+            let document = serde_json::from_value(json!({
+                "name": name,
+                "createTime": "2020-01-01T00:00:00Z",
+                "updateTime": "2020-01-01T00:00:00Z",
+            }))
+            .unwrap();
+            Lookup::Found(document)
+        }
+
+        fn names(lookups: &[Lookup]) -> Vec<(&str, bool)> {
+            lookups
+                .iter()
+                .map(|lookup| match lookup {
+                    Lookup::Found(document) => (document.name(), true),
+                    Lookup::Missing(name) => (&**name, false),
+                })
+                .collect()
+        }
+
+        #[test]
+        fn chunks_split_at_the_chunk_size() {
+            assert!(chunks(&[]).is_empty());
+            let exact = (0..CHUNK_SIZE).map(name).collect::<Vec<String>>();
+            assert_eq!(chunks(&exact), vec![exact.clone()]);
+            let over = (0..CHUNK_SIZE + 1).map(name).collect::<Vec<String>>();
+            let split = chunks(&over);
+            assert_eq!(split.len(), 2);
+            assert_eq!(split[0].len(), CHUNK_SIZE);
+            assert_eq!(split[1], vec![name(CHUNK_SIZE)]);
+        }
+
+        #[test]
+        fn chunks_request_repeated_names_once() {
+            // the repeat would otherwise push the last name into a second call
+            let mut repeated = (0..CHUNK_SIZE).map(name).collect::<Vec<String>>();
+            repeated.insert(1, name(0));
+            let split = chunks(&repeated);
+            assert_eq!(split.len(), 1);
+            assert_eq!(split[0], (0..CHUNK_SIZE).map(name).collect::<Vec<String>>());
+        }
+
+        #[test]
+        fn repeated_names_are_all_answered() {
+            let requested = vec![name(1), name(2), name(1)];
+            let answered = vec![found(&*name(1)), Lookup::Missing(name(2))];
+            let lookups = in_order(&requested, answered).unwrap();
+            assert_eq!(
+                names(&lookups),
+                vec![(&*name(1), true), (&*name(2), false), (&*name(1), true)]
+            );
+        }
+
+        #[test]
+        fn answers_follow_the_requested_order() {
+            let requested = (0..CHUNK_SIZE + 2).map(name).collect::<Vec<String>>();
+            // answers come back per chunk and in any order within one
+            let answered = requested
+                .iter()
+                .rev()
+                .map(|name| found(name))
+                .collect::<Vec<Lookup>>();
+            let lookups = in_order(&requested, answered).unwrap();
+            let expected = requested
+                .iter()
+                .map(|name| (&**name, true))
+                .collect::<Vec<(&str, bool)>>();
+            assert_eq!(names(&lookups), expected);
+        }
+
+        #[test]
+        fn unanswered_names_are_an_error() {
+            let requested = vec![name(1), name(2)];
+            let error = in_order(&requested, vec![found(&*name(1))]).unwrap_err();
+            match error {
+                Error::IncompleteResponse { message } => assert!(message.contains(&*name(2))),
+                e => panic!("unexpected error {}", e),
+            }
+        }
+    }
+}
+
+pub mod filter;
+pub mod geo;
+pub mod query;
+pub mod watch;
+
+/// Attempts of `DatabaseContext::run_transaction` before contention is given up on
+const TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// A transaction begun with `DatabaseContext::transaction`. Reads made through it see
+/// one snapshot and, in a read-write transaction, lock the documents read until the
+/// end. Writes are collected and only sent, all together, by `commit`. A transaction
+/// that is neither committed nor rolled back holds its locks until it expires.
+pub struct Transaction<'a> {
+    context: &'a DatabaseContext,
+    database_name: String,
+    id: String,
+    read_only: bool,
+    writes: Vec<Write>,
+}
+
+impl<'a> Transaction<'a> {
+    /// The identifier Firestore gave the transaction
+    pub fn id(&self) -> &str {
+        &*self.id
+    }
+
+    /// Pins a read made directly on the context to this transaction
+    pub fn consistency(&self) -> ConsistencySelector {
+        ConsistencySelector::Transaction(self.id.clone())
+    }
+
+    /// Reads `document`, a path relative to the database root, within the transaction
+    pub fn get(&self, document: &str) -> Result<Document> {
+        self.context
+            .get_document(&*self.database_name, document, Some(&self.consistency()))
+    }
+
+    /// Runs `query` within the transaction, beneath `parent` if given
+    pub fn query(
+        &self,
+        parent: Option<&DocumentPath>,
+        query: &query::StructuredQuery,
+    ) -> Result<Vec<Document>> {
+        let responses = self.context.run_query(
+            &*self.database_name,
+            parent,
+            query,
+            Some(&self.consistency()),
+        )?;
+        Ok(responses
+            .into_iter()
+            .filter_map(|response| response.document)
+            .collect())
+    }
+
+    /// Adds `write` to those applied on commit
+    pub fn write(&mut self, write: Write) -> &mut Transaction<'a> {
+        self.writes.push(write);
+        self
+    }
+
+    /// The writes collected so far, in order
+    pub fn writes(&self) -> &[Write] {
+        &self.writes
+    }
+
+    /// Applies every collected write atomically and ends the transaction. Fails with
+    /// a conflict when contention aborted it, in which case `retry` starts over.
+    pub fn commit(self) -> Result<commit::Response> {
+        self.context
+            .commit(&*self.database_name, self.writes, Some(self.id))
+    }
+
+    /// Ends the transaction without writing anything, releasing its locks
+    pub fn rollback(self) -> Result<()> {
+        self.context.rollback(&*self.database_name, self.id)
+    }
+
+    /// Begins a transaction replacing this one after contention aborted it, keeping
+    /// its place in line for the locks. Collected writes are dropped, as they were
+    /// based on reads that have to be made again.
+    pub fn retry(self) -> Result<Transaction<'a>> {
+        if self.read_only {
+            return self.context.read_only_transaction(&*self.database_name);
+        }
+        let id = self
+            .context
+            .retry_transaction(&*self.database_name, self.id)?;
+        Ok(Transaction {
+            id,
+            writes: Vec::new(),
+            ..self
+        })
+    }
+}
+
+pub(crate) mod transaction {
+    use chrono::{DateTime, Utc};
+
+    #[derive(Serialize)]
+    pub struct ReadOnly {
+        #[serde(rename = "readTime", skip_serializing_if = "Option::is_none")]
+        pub read_time: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Serialize)]
+    pub struct ReadWrite {
+        #[serde(rename = "retryTransaction", skip_serializing_if = "Option::is_none")]
+        pub retry_transaction: Option<String>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/TransactionOptions
+    #[derive(Serialize)]
+    pub enum Options {
+        #[serde(rename = "readOnly")]
+        ReadOnly(ReadOnly),
+        #[serde(rename = "readWrite")]
+        ReadWrite(ReadWrite),
+    }
+
+    #[derive(Serialize)]
+    pub struct BeginRequest {
+        pub options: Options,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct BeginResponse {
+        pub transaction: String,
+    }
+
+    #[derive(Serialize)]
+    pub struct RollbackRequest {
+        pub transaction: String,
+    }
+}
+
+pub mod commit {
+    use chrono::{DateTime, Utc};
 
-pub mod list_documents {
     #[derive(Serialize)]
     pub struct Request {
-        #[serde(rename = "pageSize")]
-        pub page_size: i32,
-        #[serde(rename = "orderBy")]
-        pub order_by: String,
+        pub writes: Vec<super::Write>,
         #[serde(skip_serializing_if = "Option::is_none")]
-        pub mask: Option<super::DocumentMask>,
-        #[serde(rename = "showMissing")]
-        pub show_missing: bool,
-        pub consistency_selector: super::ConsistencySelector,
+        pub transaction: Option<String>,
     }
 
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct WriteResult {
+        #[serde(rename = "updateTime")]
+        pub update_time: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct Response {
-        documents: Vec<super::Document>,
-        #[serde(rename = "nextPageToken")]
-        next_page_token: String,
+        #[serde(rename = "writeResults", default)]
+        pub write_results: Vec<WriteResult>,
+        #[serde(rename = "commitTime")]
+        pub commit_time: DateTime<Utc>,
     }
 }
 
-pub mod batch_get {
+pub mod batch_write {
+    use super::commit::WriteResult;
+    use super::export::Status;
+
+    /// Most writes Firestore accepts in one `batchWrite`
+    pub const MAX_WRITES: usize = 500;
+
     #[derive(Serialize)]
     pub struct Request {
-        documents: Vec<String>,
+        pub writes: Vec<super::Write>,
     }
 
-    #[derive(Deserialize)]
+    /// One result and one status per write, in the order of the request
+    #[derive(Debug, Deserialize)]
     pub struct Response {
-        transaction: String,
-        read_time: String,
-        found: Option<super::Document>,
-        missing: Option<String>,
+        #[serde(rename = "writeResults", default)]
+        pub write_results: Vec<WriteResult>,
+        #[serde(default)]
+        pub status: Vec<Status>,
+    }
+
+    impl Response {
+        /// The writes that were not applied, by index in the request
+        pub fn failures(&self) -> Vec<(usize, &Status)> {
+            self.status
+                .iter()
+                .enumerate()
+                .filter(|(_, status)| status.code != 0)
+                .collect()
+        }
+    }
+}
+
+pub mod databases {
+    use chrono::{DateTime, Utc};
+
+    #[derive(Debug, Deserialize)]
+    pub struct Database {
+        /// projects/{project_id}/databases/{database_id}
+        pub name: String,
+        /// Oldest time documents can be read at, moving forward as versions expire
+        #[serde(rename = "earliestVersionTime")]
+        pub earliest_version_time: Option<DateTime<Utc>>,
+        /// `POINT_IN_TIME_RECOVERY_ENABLED` when a week of versions is kept
+        #[serde(rename = "pointInTimeRecoveryEnablement")]
+        pub point_in_time_recovery_enablement: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ListResponse {
+        #[serde(default)]
+        pub databases: Vec<Database>,
+    }
+}
+
+pub mod export {
+    /// Work done and estimated, both int64 and so sent as strings
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Progress {
+        #[serde(rename = "completedWork", default)]
+        pub completed_work: Option<String>,
+        #[serde(rename = "estimatedWork", default)]
+        pub estimated_work: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Metadata {
+        /// PROCESSING, SUCCESSFUL, FAILED, CANCELLED and a few transient states
+        #[serde(rename = "operationState", default)]
+        pub operation_state: Option<String>,
+        #[serde(rename = "progressDocuments", default)]
+        pub progress_documents: Option<Progress>,
+        #[serde(rename = "outputUriPrefix", default)]
+        pub output_uri_prefix: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Status {
+        #[serde(default)]
+        pub code: i32,
+        #[serde(default)]
+        pub message: String,
+    }
+
+    /// A long running export, as returned when it starts and each time it is polled
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Operation {
+        /// projects/{project_id}/databases/{database_id}/operations/{operation_id}
+        pub name: String,
+        #[serde(default)]
+        pub metadata: Option<Metadata>,
+        #[serde(default)]
+        pub done: bool,
+        #[serde(default)]
+        pub error: Option<Status>,
+    }
+
+    impl Operation {
+        /// The state reported by Firestore, or what `done` and `error` imply
+        pub fn state(&self) -> String {
+            let reported = self
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.operation_state.clone());
+            match (reported, self.done, &self.error) {
+                (_, true, Some(_)) => "FAILED".to_string(),
+                (Some(state), _, _) => state,
+                (None, true, None) => "SUCCESSFUL".to_string(),
+                (None, false, _) => "PROCESSING".to_string(),
+            }
+        }
+
+        /// Documents exported so far and the estimated total
+        pub fn documents(&self) -> (Option<u64>, Option<u64>) {
+            let progress = self
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.progress_documents.as_ref());
+            let parse = |work: &Option<String>| work.as_ref().and_then(|work| work.parse().ok());
+            match progress {
+                Some(progress) => (
+                    parse(&progress.completed_work),
+                    parse(&progress.estimated_work),
+                ),
+                None => (None, None),
+            }
+        }
     }
 }
 
 impl DatabaseContext {
     /// Creates a header map with proper authorization
-    fn auth_header_map(&self) -> Result<reqwest::header::HeaderMap, String> {
+    fn auth_header_map(&self) -> Result<reqwest::header::HeaderMap> {
         let mut map = reqwest::header::HeaderMap::new();
-        let str = &*self.auth_token.access_token();
-        map.insert(
-            reqwest::header::AUTHORIZATION,
-            str.parse().map_err(|_| "Invalid Header Value")?,
-        );
+        let str = &*self.get_authorization_key()?;
+        map.insert(reqwest::header::AUTHORIZATION, str.parse()?);
         Ok(map)
     }
 
+    /// The client and headers for one request
+    fn transport(&self) -> Result<firestore::Transport> {
+        Ok(firestore::Transport {
+            client: self.client.clone(),
+            headers: self.auth_header_map()?,
+            hook: self.http_hook.clone(),
+            interceptors: self.interceptors.clone(),
+            retry: self.retry_policy,
+            rate_limit: self.rate_limit.clone(),
+        })
+    }
+
+    /// POSTs `body` as JSON to `url`, such as a webhook, with the client, HTTP hook and
+    /// interceptors used for Firestore. The credentials are not sent along, and neither
+    /// the retry policy nor the rate limit apply: a failed post is returned right away.
+    pub fn post_json<B: serde::Serialize>(&self, url: &str, body: &B) -> Result<()> {
+        let transport = firestore::Transport {
+            client: self.client.clone(),
+            headers: reqwest::header::HeaderMap::new(),
+            hook: self.http_hook.clone(),
+            interceptors: self.interceptors.clone(),
+            retry: RetryPolicy::none(),
+            rate_limit: None,
+        };
+        firestore::webhook::post(&transport, url, body)
+    }
+
+    /// Replaces the HTTP client with one configured by `options`. Clones made
+    /// before this call keep using the old connection pool.
+    pub fn with_connection_options(
+        mut self,
+        options: &ConnectionOptions,
+    ) -> Result<DatabaseContext> {
+        self.client = options.build_client()?;
+        self.connection = options.clone();
+        Ok(self)
+    }
+
+    /// A context on `project_id` sharing this one's credentials, client and token.
+    /// The service account needs access to that project too.
+    pub fn for_project<S: Into<String>>(&self, project_id: S) -> DatabaseContext {
+        DatabaseContext {
+            project_id: project_id.into(),
+            ..self.clone()
+        }
+    }
+
+    /// A context sending its requests with `policy`, sharing everything else with this
+    /// one. Use it for a whole job, or for a single call with
+    /// `ctx.with_retry_policy(policy).get_document(..)`.
+    pub fn with_retry_policy(&self, policy: RetryPolicy) -> DatabaseContext {
+        DatabaseContext {
+            retry_policy: policy,
+            ..self.clone()
+        }
+    }
+
+    /// A context whose requests, together with those of its clones, stay within
+    /// `limit`. Contexts given clones of the same limit share its budget.
+    pub fn with_rate_limit(&self, limit: RateLimit) -> DatabaseContext {
+        DatabaseContext {
+            rate_limit: Some(limit),
+            ..self.clone()
+        }
+    }
+
+    /// A context giving each request `timeout` instead of the one of its
+    /// `ConnectionOptions`. reqwest 0.9 only sets timeouts per client, so the context
+    /// gets a client, and connection pool, of its own.
+    pub fn with_timeout(&self, timeout: Duration) -> Result<DatabaseContext> {
+        let options = ConnectionOptions {
+            timeout: Some(timeout),
+            ..self.connection.clone()
+        };
+        self.clone().with_connection_options(&options)
+    }
+
+    /// A context reading documents as they were at `read_time`, unless a read is given
+    /// a consistency of its own. Within the last hour any time can be read, up to seven
+    /// days back whole minutes when point-in-time recovery is enabled. Writes would be
+    /// based on what is no longer there, so the context refuses them.
+    pub fn at_read_time(&self, read_time: DateTime<Utc>) -> DatabaseContext {
+        DatabaseContext {
+            read_time: Some(read_time),
+            ..self.clone()
+        }
+    }
+
+    /// The time this context reads at, from `at_read_time`
+    pub fn read_time(&self) -> Option<DateTime<Utc>> {
+        self.read_time
+    }
+
+    // `consistency` if given, else the read time of the context
+    fn read_consistency(
+        &self,
+        consistency: Option<&ConsistencySelector>,
+    ) -> Option<ConsistencySelector> {
+        consistency
+            .cloned()
+            .or_else(|| self.read_time.map(ConsistencySelector::ReadTime))
+    }
+
+    /// Reports every request sent from this context, and its clones, to `hook`
+    pub fn with_http_hook<H: HttpHook + 'static>(mut self, hook: H) -> DatabaseContext {
+        self.http_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Runs `interceptor` around every request sent from this context and its clones,
+    /// after the interceptors added before it
+    pub fn with_interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> DatabaseContext {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Resource name of the database, e.g. projects/{project_id}/databases/{database_id}
+    fn database_path(&self, database_name: &str) -> String {
+        ResourceName::Database {
+            project_id: self.project_id.clone(),
+            database_id: database_name.to_string(),
+        }
+        .to_string()
+    }
+
+    /// Whether this context was created with `AuthScope::ReadOnly` or reads the past
+    pub fn is_read_only(&self) -> bool {
+        self.authorization.scope == AuthScope::ReadOnly || self.read_time.is_some()
+    }
+
+    /// Exchanges the credentials for a new access token right away, rather than when
+    /// the current one is about to expire
+    pub fn renew_token(&self) -> Result<()> {
+        self.authorization.renew()
+    }
+
+    /// Fails with `Error::ReadOnly` instead of letting `operation` reach the server
+    fn ensure_writable(&self, operation: &str) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly {
+                operation: operation.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// A reference to `path` in this context's project
+    pub fn reference(&self, database_name: &str, path: DocumentPath) -> DocumentReference {
+        DocumentReference::new(&*self.project_id, database_name, path)
+    }
+
+    /// Expands a document path relative to the database root into a full resource name
+    fn document_path(&self, database_name: &str, document: &str) -> String {
+        let documents = ResourceName::Documents {
+            project_id: self.project_id.clone(),
+            database_id: database_name.to_string(),
+        };
+        let root = format!("{}/", documents);
+        if document.starts_with(&*root) {
+            return document.to_string();
+        }
+        format!("{}{}", root, document.trim_start_matches('/'))
+    }
+
+    /// Resource name of the document whose collections are listed or queried, the
+    /// database root when `parent` is `None`
+    fn parent_path(&self, database_name: &str, parent: Option<&DocumentPath>) -> String {
+        match parent {
+            Some(path) => ResourceName::Document(self.reference(database_name, path.clone())),
+            None => ResourceName::Documents {
+                project_id: self.project_id.clone(),
+                database_id: database_name.to_string(),
+            },
+        }
+        .to_string()
+    }
+
     /// Create a new instance that uses project_id as anchoring context
     pub fn new<S>(project_id: S, service_account_path: S) -> Result<DatabaseContext, String>
+    where
+        S: Into<String>,
+    {
+        DatabaseContext::with_scope(project_id, service_account_path, AuthScope::DataStore)
+    }
+
+    /// Like `new`, but authenticates with `scope` instead of the datastore scope
+    pub fn with_scope<S>(
+        project_id: S,
+        service_account_path: S,
+        scope: AuthScope,
+    ) -> Result<DatabaseContext, String>
+    where
+        S: Into<String>,
+    {
+        let options = AuthOptions {
+            scope,
+            ..AuthOptions::default()
+        };
+        DatabaseContext::with_auth_options(project_id, service_account_path, options)
+    }
+
+    /// Like `new`, with full control over how the access token is obtained
+    pub fn with_auth_options<S>(
+        project_id: S,
+        service_account_path: S,
+        options: AuthOptions,
+    ) -> Result<DatabaseContext, String>
     where
         S: Into<String>,
     {
         // ensure String types
         let project_id = project_id.into();
-        let service_account_path = service_account_path.into();
+        let service_account_path = resolve_credentials_path(&*service_account_path.into(), None);
 
-        // get jwt & credentials from file
-        let credentials = goauth::credentials::Credentials::from_file(&*service_account_path)
-            .map_err(|_| "Failed to load credentials from file")?;
-        let claims = JwtClaims::new(
-            credentials.iss(),
-            &Scope::DataStore,
-            credentials.token_uri(),
-            None,
-            None,
-        );
-        let jwt = Jwt::new(
-            claims,
-            credentials
-                .rsa_key()
-                .map_err(|_| "Failed to get RSA private key from credentials")?,
-            None,
-        );
         // cool, we have a token
-        let auth_token = goauth::get_token_with_creds(&jwt, &credentials)
-            .map_err(|_| "Failed to authenticate")?;
-        let client = reqwest::Client::new();
+        let authorization = Arc::new(Authorization::new(service_account_path, options)?);
+        let connection = ConnectionOptions::default();
+        let client = connection
+            .build_client()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
         // return success
         Ok(DatabaseContext {
             client,
             project_id,
-            auth_token,
+            authorization,
+            http_hook: None,
+            interceptors: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            rate_limit: None,
+            connection,
+            read_time: None,
         })
     }
 
@@ -189,14 +2159,6 @@ impl DatabaseContext {
     //        )
     //    }
     //
-    //    fn make_batch_get_url(&self, database_name: String) -> String {
-    //        format!(
-    //            "{}/{{database={}}}/documents:batchGet",
-    //            FIRESTORE_BETA_BASE_URL,
-    //            format!("projects/{}/databases/{}", self.project_id, database_name)
-    //        )
-    //    }
-    //
     //    // Deletes a document from said collection
     //
     //    pub fn delete_document<S>(&self, collection_name: S, document_id: S) -> Result<Document, String>
@@ -253,89 +2215,1058 @@ impl DatabaseContext {
     //            .map_err(errors::json_decode_error)?;
     //        Ok(document)
     //    }
-    //
-    //    // Internal for batch_get_documents
-    //    // https://firebase.google.com/docs/firestore/reference/rest/v1beta1/projects.databases.documents/batchGet#google.firestore.v1beta1.Firestore.BatchGetDocuments
-    //    fn batch_get(&self, documents: Vec<String>, path: &str) -> Result<batch_get::Response, String> {
-    //        let mut response = self
-    //            .client
-    //            .post(path)
-    //            .headers(self.auth_header_map()?)
-    //            .send()
-    //            .map_err(errors::http_error)?;
-    //        response
-    //            .json::<batch_get::Response>()
-    //            .map_err(errors::json_decode_error)
-    //    }
-    //
-    //    pub fn batch_get_documents<S>(
-    //        &self,
-    //        documents: Vec<S>,
-    //        database_name: S,
-    //    ) -> Result<batch_get::Response, String>
-    //    where
-    //        S: Into<String>,
-    //    {
-    //        let documents = documents
-    //            .into_iter()
-    //            .map(|s| s.into())
-    //            .collect::<Vec<String>>();
-    //        let database_name: String = database_name.into();
-    //        self.batch_get(documents, &*self.make_batch_get_url(database_name))
-    //    }
-    //
-    //    // want https://firestore.googleapis.com/v1beta1/{parent=projects/*/databases/*/documents/*/**}/{collectionId}
-    //    // ours https://firestore.googleapis.com/v1beta1/{parent=projects/hazes-test-project/databases/default/documents/*/**}/cars
-    //    fn make_list_documents_url(&self, database_name: &str, collection_name: &str) -> String {
-    //        let parent = format!("projects/{}/databases/{}", self.project_id, database_name);
-    //        format!(
-    //            "{}/{{parent={}}}/{}",
-    //            FIRESTORE_BETA_BASE_URL, parent, collection_name
-    //        )
-    //    }
-    //
-    //    pub fn list_documents(
-    //        &self,
-    //        page_size: i32,
-    //        order_by: String,
-    //        mask: Option<DocumentMask>,
-    //        show_missing: bool,
-    //        consistency_selector: ConsistencySelector,
-    //        database_name: &str,
-    //        collection_name: &str,
-    //    ) -> Result<list_documents::Response, String> {
-    //        let request = list_documents::Request {
-    //            page_size,
-    //            order_by,
-    //            mask,
-    //            show_missing,
-    //            consistency_selector,
-    //        };
-    //        let request_json = serde_json::to_string(&request).map_err(errors::json_encode_error)?;
-    //        println!(
-    //            "{}",
-    //            &*self.make_list_documents_url(database_name, collection_name)
-    //        );
-    //        let mut response = self
-    //            .client
-    //            .get(&*self.make_list_documents_url(database_name, collection_name))
-    //            .headers(self.auth_header_map()?)
-    //            .body(request_json)
-    //            .send()
-    //            .map_err(errors::http_error)?;
-    //        response
-    //            .json::<list_documents::Response>()
-    //            .map_err(errors::json_decode_error)
-    //    }
 
-    pub fn export_database(&self, query: firestore::databases::ExportDocumentQuery) {
-        firestore::databases::export_documents(self.client, self.auth_header_map())
+    /// Retrieves every document in `documents` (paths relative to the database root or
+    /// full resource names), splitting the lookup into batchGet calls of at most
+    /// `batch_get::CHUNK_SIZE` documents, up to `batch_get::WORKERS` of them at the same
+    /// time. Results are returned in input order, once for every time a document is
+    /// asked for; a document Firestore does not answer for fails the lookup.
+    pub fn batch_get_documents<S>(
+        &self,
+        documents: Vec<S>,
+        database_name: &str,
+    ) -> Result<Vec<batch_get::Lookup>>
+    where
+        S: Into<String>,
+    {
+        let documents = documents
+            .into_iter()
+            .map(|s| self.document_path(database_name, &*s.into()))
+            .collect::<Vec<String>>();
+        let chunks = batch_get::chunks(&documents);
+        let workers = batch_get::WORKERS.min(chunks.len());
+        let queue = Arc::new(Mutex::new(chunks.into_iter().collect::<VecDeque<_>>()));
+        let transport = self.transport()?;
+        let handles = (0..workers)
+            .map(|_| {
+                let queue = queue.clone();
+                let transport = transport.clone();
+                let database_path = self.database_path(database_name);
+                let read_time = self.read_time;
+                std::thread::spawn(move || -> Result<Vec<batch_get::Lookup>> {
+                    let mut answered = Vec::new();
+                    loop {
+                        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+                        let chunk = match next {
+                            Some(chunk) => chunk,
+                            None => return Ok(answered),
+                        };
+                        let query = firestore::documents::BatchGetQuery {
+                            database_name: database_path.clone(),
+                            documents: chunk,
+                            read_time,
+                        };
+                        let responses = match firestore::documents::batch_get(&transport, query) {
+                            Ok(responses) => responses,
+                            Err(e) => {
+                                // the lookup fails anyway, let the other workers stop
+                                queue.lock().unwrap_or_else(|e| e.into_inner()).clear();
+                                return Err(e);
+                            }
+                        };
+                        for response in responses {
+                            if let Some(document) = response.found {
+                                answered.push(batch_get::Lookup::Found(document));
+                            } else if let Some(missing) = response.missing {
+                                answered.push(batch_get::Lookup::Missing(missing));
+                            }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut answered = Vec::new();
+        let mut first_error = None;
+        for handle in handles {
+            let result = handle.join().map_err(|_| Error::WorkerPanic {
+                task: "batch getting documents".to_string(),
+            });
+            match result.and_then(|result| result) {
+                Ok(lookups) => answered.extend(lookups),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+        batch_get::in_order(&documents, answered)
+    }
+
+    /// Retrieves a single document, optionally pinned to a transaction or read time
+    pub fn get_document(
+        &self,
+        database_name: &str,
+        document: &str,
+        consistency: Option<&ConsistencySelector>,
+    ) -> Result<Document> {
+        self.get_document_with_mask(database_name, document, consistency, None)
+    }
+
+    /// Like `get_document`, returning only the fields in `mask`
+    pub fn get_document_with_mask(
+        &self,
+        database_name: &str,
+        document: &str,
+        consistency: Option<&ConsistencySelector>,
+        mask: Option<&DocumentMask>,
+    ) -> Result<Document> {
+        let query = firestore::documents::GetDocumentQuery {
+            name: self.document_path(database_name, document),
+            consistency: self.read_consistency(consistency),
+            mask: mask.cloned(),
+        };
+        firestore::documents::get(&self.transport()?, query)
+    }
+
+    /// Read-modify-write of `document` with optimistic concurrency. Its fields are
+    /// handed to `modify` as plain JSON and the top level fields it changes written
+    /// back, on condition that the document was not updated in between; the others
+    /// keep their Firestore types. When another writer got there first the cycle
+    /// starts over from a fresh read, up to `attempts` times in all. A missing document
+    /// starts from no fields. Returns `None` when `modify` changed nothing.
+    pub fn modify<F>(
+        &self,
+        database_name: &str,
+        document: &str,
+        attempts: u32,
+        mut modify: F,
+    ) -> Result<Option<commit::Response>>
+    where
+        F: FnMut(
+            serde_json::Map<String, serde_json::Value>,
+        ) -> Result<serde_json::Map<String, serde_json::Value>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let base = self.find_document(database_name, document)?;
+            let fields = base
+                .as_ref()
+                .map_or_else(serde_json::Map::new, |base| base.fields().to_json());
+            let write =
+                match self.modify_write(database_name, document, base.as_ref(), &modify(fields)?) {
+                    Some(write) => write,
+                    None => return Ok(None),
+                };
+            let error = match self.commit(database_name, vec![write], None) {
+                Ok(response) => return Ok(Some(response)),
+                Err(error) => error,
+            };
+            // a failed precondition is reported as 400 or 409, tell it apart from a bad
+            // request by whether the document really changed
+            let precondition_status = error.status() == Some(reqwest::StatusCode::BAD_REQUEST)
+                || error.status() == Some(reqwest::StatusCode::CONFLICT);
+            if attempt >= attempts || !precondition_status {
+                return Err(error);
+            }
+            let current = self.find_document(database_name, document)?;
+            if current.map(|current| current.update_time())
+                == base.as_ref().map(Document::update_time)
+            {
+                return Err(error);
+            }
+            attempt += 1;
+        }
+    }
+
+    /// The write storing `modified`, the plain JSON fields of `base` after a change,
+    /// as `modify` does: only changed top level fields, on condition that `base` is
+    /// still current. `None` when nothing changed.
+    pub fn modify_write(
+        &self,
+        database_name: &str,
+        document: &str,
+        base: Option<&Document>,
+        modified: &serde_json::Map<String, serde_json::Value>,
+    ) -> Option<Write> {
+        let original = base.map_or_else(serde_json::Map::new, |base| base.fields().to_json());
+        let mut changed = original
+            .keys()
+            .chain(modified.keys())
+            .filter(|field| original.get(*field) != modified.get(*field))
+            .map(|field| field_path(&[&**field]))
+            .collect::<Vec<String>>();
+        if changed.is_empty() {
+            return None;
+        }
+        changed.sort();
+        changed.dedup();
+        let precondition = match base {
+            Some(base) => Precondition::UpdateTime(base.update_time()),
+            None => Precondition::Exists(false),
+        };
+        Some(
+            self.update_write(database_name, document, modified)
+                .with_update_mask(changed)
+                .with_precondition(precondition),
+        )
+    }
+
+    /// Retrieves a single document, `None` when it does not exist
+    pub fn find_document(&self, database_name: &str, document: &str) -> Result<Option<Document>> {
+        match self.get_document(database_name, document, None) {
+            Ok(document) => Ok(Some(document)),
+            Err(ref e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `fields` to `document` immediately, outside of any transaction, and returns
+    /// the document as stored. With `mask` only the field paths it lists are replaced,
+    /// and those missing from `fields` are removed; without it the whole document is.
+    /// A missing document is created either way.
+    pub fn update_document(
+        &self,
+        database_name: &str,
+        document: &str,
+        fields: &serde_json::Map<String, serde_json::Value>,
+        mask: Option<&DocumentMask>,
+    ) -> Result<Document> {
+        self.ensure_writable("update a document")?;
+        let query = firestore::documents::PatchDocumentQuery {
+            name: self.document_path(database_name, document),
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.clone(), json_to_wire(value)))
+                .collect(),
+            update_mask: mask.cloned(),
+        };
+        firestore::documents::patch(&self.transport()?, query)
+    }
+
+    /// Deletes a single document immediately, outside of any transaction
+    pub fn delete_document(&self, database_name: &str, document: &str) -> Result<()> {
+        self.ensure_writable("delete a document")?;
+        let name = self.document_path(database_name, document);
+        firestore::documents::delete(&self.transport()?, &*name)
+    }
+
+    /// Builds a `Write` that deletes `document` when committed
+    pub fn delete_write(&self, database_name: &str, document: &str) -> Write {
+        Write::delete(self.document_path(database_name, document))
+    }
+
+    /// Builds a `Write` that replaces `document` with plain JSON `fields` when committed
+    pub fn update_write(
+        &self,
+        database_name: &str,
+        document: &str,
+        fields: &serde_json::Map<String, serde_json::Value>,
+    ) -> Write {
+        Write::update(self.document_path(database_name, document), fields)
+    }
+
+    /// Builds a `Write` that replaces `document` with typed `fields` when committed
+    pub fn replace_write(
+        &self,
+        database_name: &str,
+        document: &str,
+        fields: &FirestoreFields,
+    ) -> Write {
+        Write::replace(self.document_path(database_name, document), fields)
+    }
+
+    /// Lists one page of the collection `collection_id`, beneath `parent` if given.
+    /// With `show_missing`, documents that do not exist but have subcollections are
+    /// included as `Lookup::Missing`.
+    pub fn list_documents(
+        &self,
+        database_name: &str,
+        parent: Option<&DocumentPath>,
+        collection_id: &str,
+        page_size: i32,
+        page_token: Option<String>,
+        show_missing: bool,
+    ) -> Result<list_documents::Page> {
+        let parent = self.parent_path(database_name, parent);
+        let query = firestore::documents::ListDocumentsQuery {
+            parent,
+            collection_id: collection_id.to_string(),
+            page_size,
+            page_token,
+            show_missing,
+            read_time: self.read_time,
+        };
+        firestore::documents::list(&self.transport()?, query).map(list_documents::Page::from)
+    }
+
+    /// Every collection id directly beneath `parent`, or the root collections when
+    /// not given, reading all pages
+    pub fn list_collection_ids(
+        &self,
+        database_name: &str,
+        parent: Option<&DocumentPath>,
+    ) -> Result<Vec<String>> {
+        self.collection_ids(database_name, parent).collect()
+    }
+
+    /// Iterates over the collection ids beneath `parent`, or the root collections when
+    /// not given, fetching pages lazily
+    pub fn collection_ids(
+        &self,
+        database_name: &str,
+        parent: Option<&DocumentPath>,
+    ) -> list_collection_ids::CollectionIds<'_> {
+        list_collection_ids::CollectionIds::new(self, self.parent_path(database_name, parent))
+    }
+
+    /// Runs `query` once, returning the raw responses, one per matching document.
+    /// `parent` selects the document whose subcollections are queried.
+    pub fn run_query(
+        &self,
+        database_name: &str,
+        parent: Option<&DocumentPath>,
+        query: &query::StructuredQuery,
+        consistency: Option<&ConsistencySelector>,
+    ) -> Result<Vec<query::RunQueryResponse>> {
+        let parent = self.parent_path(database_name, parent);
+        let request = firestore::documents::RunQueryQuery {
+            parent,
+            body: query::RunQueryRequest {
+                structured_query: query.clone(),
+                consistency: self.read_consistency(consistency),
+            },
+        };
+        firestore::documents::run_query(&self.transport()?, request)
+    }
+
+    /// Computes `aggregations` over the results of `query` on the server, returning
+    /// each result by its alias
+    pub fn run_aggregation_query(
+        &self,
+        database_name: &str,
+        parent: Option<&DocumentPath>,
+        query: &query::StructuredQuery,
+        aggregations: Vec<query::Aggregation>,
+    ) -> Result<FirestoreFields> {
+        let parent = self.parent_path(database_name, parent);
+        let request = firestore::documents::RunAggregationQueryQuery {
+            parent,
+            body: query::RunAggregationQueryRequest {
+                structured_aggregation_query: query::StructuredAggregationQuery {
+                    structured_query: query.clone(),
+                    aggregations,
+                },
+                consistency: self.read_consistency(None),
+            },
+        };
+        let responses = firestore::documents::run_aggregation_query(&self.transport()?, request)?;
+        Ok(responses
+            .into_iter()
+            .filter_map(|response| response.result)
+            .flat_map(|result| result.aggregate_fields.0)
+            .collect())
+    }
+
+    /// Splits the results of the collection group `query`, which must be unfiltered and
+    /// ordered by name alone, into at most `partition_count` ranges of similar size. The
+    /// returned cursors are the split points, in order, to be used as `start_at` and
+    /// `end_at` of queries run in parallel.
+    pub fn partition_query(
+        &self,
+        database_name: &str,
+        parent: Option<&DocumentPath>,
+        query: &query::StructuredQuery,
+        partition_count: i64,
+    ) -> Result<Vec<query::Cursor>> {
+        let parent = self.parent_path(database_name, parent);
+        let transport = self.transport()?;
+        let mut partitions = Vec::new();
+        let mut page_token = None;
+        loop {
+            let request = firestore::documents::PartitionQueryQuery {
+                parent: parent.clone(),
+                body: query::PartitionQueryRequest {
+                    structured_query: query.clone(),
+                    partition_count,
+                    page_token,
+                },
+            };
+            let response = firestore::documents::partition_query(&transport, request)?;
+            partitions.extend(response.partitions);
+            page_token = match response.next_page_token {
+                Some(token) if !token.is_empty() => Some(token),
+                _ => break,
+            };
+        }
+        // each page is sorted on its own, the split points are ordered by name
+        partitions.sort_by_key(|cursor| {
+            cursor
+                .values
+                .iter()
+                .map(|value| match value {
+                    FirestoreType::Reference(reference) => {
+                        reference.to_string().split('/').map(String::from).collect()
+                    }
+                    _ => Vec::new(),
+                })
+                .collect::<Vec<Vec<String>>>()
+        });
+        Ok(partitions)
+    }
+
+    /// Starts a query on `collection`, a collection path relative to the root of the
+    /// `(default)` database, to be narrowed and run with the `QueryBuilder` methods
+    pub fn collection<S: Into<String>>(&self, collection: S) -> query::QueryBuilder<'_> {
+        query::QueryBuilder::new(self, collection.into())
+    }
+
+    /// Iterates over every result of `query`, fetching pages lazily with cursors
+    pub fn query_stream(
+        &self,
+        database_name: &str,
+        parent: Option<DocumentPath>,
+        query: query::StructuredQuery,
+    ) -> query::QueryStream<'_> {
+        query::QueryStream::new(self, database_name, parent, query)
+    }
+
+    /// Starts watching `database_name` for changes, to targets added with
+    /// `ChangeStream::target`
+    pub fn changes(&self, database_name: &str) -> watch::ChangeStream<'_> {
+        watch::ChangeStream::new(self, database_name)
+    }
+
+    /// Creates a document in `collection` (a collection path relative to the database
+    /// root) with `fields`, failing with a conflict when `document_id` is taken. Without
+    /// it Firestore assigns an id, found in the name of the returned document; as that
+    /// request cannot be told apart from a retry, prefer `add_document` where a lost
+    /// response must not create a second document.
+    pub fn create_document(
+        &self,
+        database_name: &str,
+        collection: &str,
+        document_id: Option<&str>,
+        fields: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Document> {
+        self.ensure_writable("create a document")?;
+        if let Some(document_id) = document_id.filter(|id| id.contains('/')) {
+            return Err(Error::InvalidInput {
+                message: format!("document id {} contains a /", document_id),
+            });
+        }
+        // a placeholder id still validates the collection path
+        let path = DocumentPath::parse(&*format!(
+            "{}/{}",
+            collection.trim_matches('/'),
+            document_id.unwrap_or("_")
+        ))?;
+        let query = firestore::documents::CreateDocumentQuery {
+            parent: self.parent_path(database_name, path.parent().as_ref()),
+            collection_id: path.collection_id().to_string(),
+            document_id: document_id.map(String::from),
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.clone(), json_to_wire(value)))
+                .collect(),
+        };
+        firestore::documents::create(&self.transport()?, query)
+    }
+
+    /// Creates a document with a client generated id in `collection` (a collection path
+    /// relative to the database root) and returns its path. The write carries an
+    /// `exists: false` precondition, so when a retry after a lost response finds the
+    /// document already there it must be our own earlier attempt and counts as success.
+    pub fn add_document(
+        &self,
+        database_name: &str,
+        collection: &str,
+        fields: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<String> {
+        const ATTEMPTS: usize = 3;
+        self.ensure_writable("create a document")?;
+        let path = format!(
+            "{}/{}",
+            collection.trim_matches('/'),
+            generate_document_id()
+        );
+        let write = self
+            .update_write(database_name, &*path, fields)
+            .with_precondition(Precondition::Exists(false));
+        let mut attempt = 1;
+        loop {
+            match self.commit(database_name, vec![write.clone()], None) {
+                Ok(_) => return Ok(path),
+                Err(ref e) if attempt > 1 && e.status() == Some(reqwest::StatusCode::CONFLICT) => {
+                    return Ok(path);
+                }
+                Err(ref e) if attempt < ATTEMPTS && e.is_retryable() => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Runs `body` in a read-write transaction and commits its writes, starting over
+    /// when contention aborts the transaction, up to five attempts in all as the
+    /// official SDKs do. `body` may thus run more than once and should only act
+    /// through the transaction it is given. When it fails the transaction is rolled
+    /// back and its error returned, unless it was the abort of a read.
+    pub fn run_transaction<T, F>(&self, database_name: &str, mut body: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction) -> Result<T>,
+    {
+        let policy = RetryPolicy::attempts(TRANSACTION_ATTEMPTS);
+        let mut transaction = self.transaction(database_name)?;
+        let mut attempt = 1;
+        loop {
+            let id = transaction.id().to_string();
+            let error = match body(&mut transaction) {
+                Ok(value) => match transaction.commit() {
+                    Ok(_) => return Ok(value),
+                    Err(e) => e,
+                },
+                Err(e) => {
+                    // best effort, the transaction expires on its own anyway
+                    transaction.rollback().ok();
+                    e
+                }
+            };
+            // Firestore aborts a transaction that lost a race for its documents
+            if attempt >= policy.max_attempts
+                || error.status() != Some(reqwest::StatusCode::CONFLICT)
+            {
+                return Err(error);
+            }
+            std::thread::sleep(policy.backoff(attempt));
+            attempt += 1;
+            transaction = Transaction {
+                context: self,
+                database_name: database_name.to_string(),
+                id: self.retry_transaction(database_name, id)?,
+                read_only: false,
+                writes: Vec::new(),
+            };
+        }
+    }
+
+    /// Begins a read-write transaction, a handle collecting reads and writes
+    pub fn transaction(&self, database_name: &str) -> Result<Transaction<'_>> {
+        self.begin(database_name, false)
+    }
+
+    /// Begins a read-only transaction, reading one consistent snapshot. Within a context
+    /// created `at_read_time` the snapshot is the one at that time.
+    pub fn read_only_transaction(&self, database_name: &str) -> Result<Transaction<'_>> {
+        self.begin(database_name, true)
+    }
+
+    fn begin(&self, database_name: &str, read_only: bool) -> Result<Transaction<'_>> {
+        Ok(Transaction {
+            context: self,
+            database_name: database_name.to_string(),
+            id: self.begin_transaction(database_name, read_only)?,
+            read_only,
+            writes: Vec::new(),
+        })
+    }
+
+    /// Starts a new transaction, returning its identifier
+    pub fn begin_transaction(&self, database_name: &str, read_only: bool) -> Result<String> {
+        if !read_only {
+            self.ensure_writable("begin a read-write transaction")?;
+        }
+        let options = if read_only {
+            transaction::Options::ReadOnly(transaction::ReadOnly {
+                read_time: self.read_time,
+            })
+        } else {
+            transaction::Options::ReadWrite(transaction::ReadWrite {
+                retry_transaction: None,
+            })
+        };
+        let query = firestore::documents::BeginTransactionQuery {
+            database_name: self.database_path(database_name),
+            options,
+        };
+        firestore::documents::begin_transaction(&self.transport()?, query)
+            .map(|response| response.transaction)
+    }
+
+    /// Starts a read-write transaction in place of `transaction`, which was aborted by
+    /// contention, so the retry keeps its priority for the documents it locks
+    pub fn retry_transaction(&self, database_name: &str, transaction: String) -> Result<String> {
+        self.ensure_writable("begin a read-write transaction")?;
+        let query = firestore::documents::BeginTransactionQuery {
+            database_name: self.database_path(database_name),
+            options: transaction::Options::ReadWrite(transaction::ReadWrite {
+                retry_transaction: Some(transaction),
+            }),
+        };
+        firestore::documents::begin_transaction(&self.transport()?, query)
+            .map(|response| response.transaction)
+    }
+
+    /// Applies `writes` atomically, committing `transaction` if one is given
+    pub fn commit(
+        &self,
+        database_name: &str,
+        writes: Vec<Write>,
+        transaction: Option<String>,
+    ) -> Result<commit::Response> {
+        self.ensure_writable("commit writes")?;
+        let query = firestore::documents::CommitQuery {
+            database_name: self.database_path(database_name),
+            writes,
+            transaction,
+        };
+        firestore::documents::commit(&self.transport()?, query)
+    }
+
+    /// Applies up to 500 `writes` independently of each other, in no particular order.
+    /// Unlike `commit` a failing write does not stop the others, so the response has to
+    /// be checked for failures. A document may only be written once per call.
+    pub fn batch_write(
+        &self,
+        database_name: &str,
+        writes: Vec<Write>,
+    ) -> Result<batch_write::Response> {
+        self.ensure_writable("batch write")?;
+        if writes.len() > batch_write::MAX_WRITES {
+            return Err(Error::InvalidInput {
+                message: format!(
+                    "{} writes in one batch write, at most {} are allowed",
+                    writes.len(),
+                    batch_write::MAX_WRITES
+                ),
+            });
+        }
+        let query = firestore::documents::BatchWriteQuery {
+            database_name: self.database_path(database_name),
+            writes,
+        };
+        firestore::documents::batch_write(&self.transport()?, query)
+    }
+
+    /// Abandons `transaction` without applying any of its writes
+    pub fn rollback(&self, database_name: &str, transaction: String) -> Result<()> {
+        let query = firestore::documents::RollbackQuery {
+            database_name: self.database_path(database_name),
+            transaction,
+        };
+        firestore::documents::rollback(&self.transport()?, query)
+    }
+
+    /// Starts a managed export, returning the operation to follow it with `export_status`
+    pub fn export_database(
+        &self,
+        query: firestore::databases::ExportDocumentQuery,
+    ) -> Result<export::Operation> {
+        self.ensure_writable("export documents")?;
+        firestore::databases::export_documents(&self.transport()?, query)
+    }
+
+    /// The current state of the export started as `operation`
+    pub fn export_status(&self, operation: &str) -> Result<export::Operation> {
+        firestore::databases::get_export(&self.transport()?, operation)
+    }
+
+    /// The ids of every database of the project, `(default)` included
+    pub fn list_databases(&self) -> Result<Vec<String>> {
+        let response = firestore::databases::list(&self.transport()?, &*self.project_id)?;
+        response
+            .databases
+            .iter()
+            .map(|database| {
+                ResourceName::parse(&*database.name).map(|name| name.database_id().to_string())
+            })
+            .collect()
+    }
+
+    /// The settings of database `database_name`, among them how far back it can be read
+    pub fn database_info(&self, database_name: &str) -> Result<databases::Database> {
+        firestore::databases::get(&self.transport()?, &*self.database_path(database_name))
+    }
+
+    /// Uploads `contents` to `gs://<bucket>/<object>`. Needs the cloud-platform scope.
+    pub fn upload_object(
+        &self,
+        bucket: &str,
+        object: &str,
+        content_type: &str,
+        contents: Vec<u8>,
+    ) -> Result<()> {
+        self.ensure_writable("upload to Cloud Storage")?;
+        firestore::storage::upload_object(
+            &self.transport()?,
+            bucket.trim_start_matches("gs://").trim_end_matches('/'),
+            object,
+            content_type,
+            contents,
+        )
     }
 
     // Used to give us the key for our Authorization Header
     // Authorization: Bearer <token>
     // ------------------^
-    fn get_authorization_key(&self) -> String {
-        format!("Bearer {}", self.auth_token.access_token())
+    fn get_authorization_key(&self) -> Result<String> {
+        Ok(format!("Bearer {}", self.authorization.access_token()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::{json, Value};
+
+    // Serializes `value`, checks it against the wire format and decodes it again
+    fn round_trip(value: FirestoreType, wire: Value) -> FirestoreType {
+        assert_eq!(serde_json::to_value(&value).unwrap(), wire);
+        let decoded = serde_json::from_value::<FirestoreType>(wire.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), wire);
+        decoded
+    }
+
+    #[test]
+    fn integers_travel_as_strings() {
+        for &i in &[0, 42, -7, i64::max_value(), i64::min_value()] {
+            match round_trip(
+                FirestoreType::Integer(i),
+                json!({ "integerValue": i.to_string() }),
+            ) {
+                FirestoreType::Integer(decoded) => assert_eq!(decoded, i),
+                other => panic!("decoded {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn integers_are_read_as_numbers_too() {
+        let decoded = serde_json::from_value::<FirestoreType>(json!({ "integerValue": 7 }));
+        match decoded.unwrap() {
+            FirestoreType::Integer(7) => {}
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn integers_past_64_bits_are_rejected() {
+        let wire = json!({ "integerValue": "9223372036854775808" });
+        assert!(serde_json::from_value::<FirestoreType>(wire).is_err());
+    }
+
+    #[test]
+    fn doubles() {
+        match round_trip(
+            FirestoreType::Double(Double::new(1.5)),
+            json!({ "doubleValue": 1.5 }),
+        ) {
+            FirestoreType::Double(d) => assert_eq!(d.value(), 1.5),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn non_finite_doubles_travel_as_strings() {
+        let cases = [
+            (std::f64::INFINITY, "Infinity"),
+            (std::f64::NEG_INFINITY, "-Infinity"),
+            (std::f64::NAN, "NaN"),
+        ];
+        for &(value, text) in &cases {
+            match round_trip(
+                FirestoreType::Double(Double::new(value)),
+                json!({ "doubleValue": text }),
+            ) {
+                FirestoreType::Double(d) if value.is_nan() => assert!(d.is_nan()),
+                FirestoreType::Double(d) => assert_eq!(d.value(), value),
+                other => panic!("decoded {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn booleans_and_strings() {
+        match round_trip(
+            FirestoreType::Boolean(true),
+            json!({ "booleanValue": true }),
+        ) {
+            FirestoreType::Boolean(true) => {}
+            other => panic!("decoded {:?}", other),
+        }
+        match round_trip(
+            FirestoreType::from("zoë \"quoted\""),
+            json!({ "stringValue": "zoë \"quoted\"" }),
+        ) {
+            FirestoreType::String(s) => assert_eq!(s, "zoë \"quoted\""),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn geo_points() {
+        let point = GeoPoint {
+            latitude: 51.5,
+            longitude: -0.125,
+        };
+        match round_trip(
+            FirestoreType::GeoLocation(point),
+            json!({ "geoPointValue": { "latitude": 51.5, "longitude": -0.125 } }),
+        ) {
+            FirestoreType::GeoLocation(decoded) => {
+                assert_eq!(decoded.latitude, 51.5);
+                assert_eq!(decoded.longitude, -0.125);
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arrays() {
+        let array = ArrayValue::new(vec![
+            FirestoreType::Integer(1),
+            FirestoreType::from("two"),
+            FirestoreType::Null,
+        ]);
+        round_trip(
+            FirestoreType::Array(array),
+            json!({ "arrayValue": { "values": [
+                { "integerValue": "1" },
+                { "stringValue": "two" },
+                { "nullValue": null },
+            ] } }),
+        );
+        round_trip(
+            FirestoreType::Array(ArrayValue::new(Vec::new())),
+            json!({ "arrayValue": {} }),
+        );
+    }
+
+    #[test]
+    fn maps() {
+        let inner = vec![("count".to_string(), FirestoreType::Integer(3))]
+            .into_iter()
+            .collect::<FirestoreFields>();
+        let fields = vec![
+            ("name".to_string(), FirestoreType::from("alice")),
+            (
+                "nested".to_string(),
+                FirestoreType::Map(MapValue::new(inner)),
+            ),
+        ]
+        .into_iter()
+        .collect::<FirestoreFields>();
+        round_trip(
+            FirestoreType::Map(MapValue::new(fields)),
+            json!({ "mapValue": { "fields": {
+                "name": { "stringValue": "alice" },
+                "nested": { "mapValue": { "fields": { "count": { "integerValue": "3" } } } },
+            } } }),
+        );
+        round_trip(
+            FirestoreType::Map(MapValue::new(FirestoreFields::default())),
+            json!({ "mapValue": {} }),
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let time = Utc.ymd(2020, 1, 2).and_hms(3, 4, 5);
+        round_trip(
+            FirestoreType::from(time),
+            json!({ "timestampValue": "2020-01-02T03:04:05Z" }),
+        );
+    }
+
+    #[test]
+    fn timestamps_keep_the_text_they_were_read_as() {
+        for &text in &[
+            "2020-01-02T03:04:05.123456789Z",
+            "2020-01-02T04:04:05.120+01:00",
+        ] {
+            let wire = json!({ "timestampValue": text });
+            let decoded = serde_json::from_value::<FirestoreType>(wire.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), wire);
+        }
+    }
+
+    #[test]
+    fn bytes_travel_as_base64() {
+        match round_trip(
+            FirestoreType::Bytes(vec![0, 1, 2, 255]),
+            json!({ "bytesValue": "AAEC/w==" }),
+        ) {
+            FirestoreType::Bytes(bytes) => assert_eq!(bytes, vec![0, 1, 2, 255]),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn references() {
+        let name = "projects/p/databases/(default)/documents/users/alice";
+        let reference = DocumentReference::parse(name).unwrap();
+        match round_trip(
+            FirestoreType::Reference(reference.clone()),
+            json!({ "referenceValue": name }),
+        ) {
+            FirestoreType::Reference(decoded) => assert_eq!(decoded, reference),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    // Decodes a value the way responses are, from their text
+    #[cfg(feature = "decimal")]
+    fn decode(text: &str) -> FirestoreType {
+        serde_json::from_str::<FirestoreType>(text).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "decimal")]
+    fn decimal_integers() {
+        match decode(r#"{"integerValue": "9007199254740993"}"#) {
+            FirestoreType::Integer(9_007_199_254_740_993) => {}
+            other => panic!("decoded {:?}", other),
+        }
+        match decode(r#"{"integerValue": 9007199254740993}"#) {
+            FirestoreType::Integer(9_007_199_254_740_993) => {}
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "decimal")]
+    fn decimal_doubles_keep_their_literal() {
+        match decode(r#"{"doubleValue": 0.1}"#) {
+            FirestoreType::Double(d) => {
+                assert_eq!(d.value(), 0.1);
+                assert_eq!(d.literal(), Some("0.1"));
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "decimal")]
+    fn big_decimals_survive_a_round_trip() {
+        let text = r#"{"doubleValue":123456789012345678901234567890.123456789012345678901}"#;
+        let decoded = decode(text);
+        match &decoded {
+            FirestoreType::Double(d) => {
+                assert_eq!(d.value(), 1.2345678901234568e29);
+                assert_eq!(
+                    d.literal(),
+                    Some("123456789012345678901234567890.123456789012345678901")
+                );
+            }
+            other => panic!("decoded {:?}", other),
+        }
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), text);
+    }
+
+    #[test]
+    fn null_travels_as_an_explicit_null() {
+        match round_trip(FirestoreType::Null, json!({ "nullValue": null })) {
+            FirestoreType::Null => {}
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn field_paths_round_trip() {
+        for segments in &[
+            vec!["address", "city"],
+            vec!["a.b", "c"],
+            vec!["with space", "back`tick", "back\\slash"],
+            vec!["1st", "_ok"],
+        ] {
+            let path = field_path(&*segments);
+            assert_eq!(split_field_path(&*path).unwrap(), *segments, "{}", path);
+        }
+        assert_eq!(field_path(&["a.b", "c"]), "`a.b`.c");
+    }
+
+    #[test]
+    fn malformed_field_paths_are_rejected() {
+        for path in &["", "a.", ".a", "a..b", "`a", "`a`b", "a`b`", "`a\\"] {
+            let error = split_field_path(path).unwrap_err().to_string();
+            assert!(error.contains("is not a valid field path"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn get_path_honours_quoted_names() {
+        let fields: FirestoreFields = serde_json::from_value(json!({
+            "a.b": { "integerValue": "1" },
+            "a": { "mapValue": { "fields": {
+                "b": { "integerValue": "2" },
+                "c.d": { "integerValue": "3" },
+            } } },
+        }))
+        .unwrap();
+        let get = |path: &str| fields.get_path(path).map(|value| value.to_json());
+        assert_eq!(get("`a.b`"), Some(json!(1)));
+        assert_eq!(get("a.b"), Some(json!(2)));
+        assert_eq!(get("a.`c.d`"), Some(json!(3)));
+        assert_eq!(get("a.c.d"), None);
+        assert_eq!(get("`a.b"), None);
+        let selected = fields.select(&["`a.b`".to_string(), "a.`c.d`".to_string()]);
+        let mut names = selected
+            .iter()
+            .map(|(name, _)| &**name)
+            .collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["a", "a.b"]);
+        assert_eq!(
+            selected.get_path("a.`c.d`").map(|value| value.to_json()),
+            Some(json!(3))
+        );
+        assert!(selected.get_path("a.b").is_none());
+    }
+
+    #[test]
+    fn gzip_responses_are_decoded() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::{BufRead, BufReader, Write as _};
+        use std::net::TcpListener;
+
+        let document = json!({
+            "name": "projects/p/databases/(default)/documents/users/ada",
+            "fields": {"name": {"stringValue": "Ada"}},
+            "createTime": "2019-06-01T00:00:00Z",
+            "updateTime": "2019-06-01T00:00:00Z",
+        });
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(document.to_string().as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_lowercase());
+            }
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+            headers
+        });
+
+        let client = ConnectionOptions::default().build_client().unwrap();
+        let decoded: Document = client.get(&*url).send().unwrap().json().unwrap();
+        assert_eq!(
+            decoded.name(),
+            "projects/p/databases/(default)/documents/users/ada"
+        );
+        assert_eq!(
+            serde_json::to_value(decoded.fields()).unwrap(),
+            document["fields"]
+        );
+        let headers = server.join().unwrap();
+        assert!(headers.iter().any(|h| h == "accept-encoding: gzip"));
     }
 }
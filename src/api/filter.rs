@@ -0,0 +1,453 @@
+//! `--where` conditions, split between what Firestore can evaluate and what has to be
+//! checked locally on the streamed documents
+
+use super::query::{Direction, UnaryFilter, UnaryOperator, DOCUMENT_NAME_FIELD};
+use super::query::{FieldFilter, FieldOperator, FieldReference, Filter, Order, StructuredQuery};
+use super::{json_to_wire, Document, FirestoreFields, FirestoreType, Timestamp};
+use crate::errors::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A comparison in a `--where` condition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    /// Any operator Firestore evaluates natively
+    Field(FieldOperator),
+    /// Substring match on string fields, only available client side
+    Contains,
+    /// The field is present, whatever its value, only available client side
+    Exists,
+    /// The field is absent, only available client side
+    Missing,
+}
+
+// longest spellings first so `<=` is not read as `<`
+const OPERATORS: &[(&str, Operator)] = &[
+    (
+        "array-contains",
+        Operator::Field(FieldOperator::ArrayContains),
+    ),
+    ("==", Operator::Field(FieldOperator::Equal)),
+    ("!=", Operator::Field(FieldOperator::NotEqual)),
+    ("<=", Operator::Field(FieldOperator::LessThanOrEqual)),
+    (">=", Operator::Field(FieldOperator::GreaterThanOrEqual)),
+    ("<", Operator::Field(FieldOperator::LessThan)),
+    (">", Operator::Field(FieldOperator::GreaterThan)),
+    ("~", Operator::Contains),
+    ("exists", Operator::Exists),
+    ("missing", Operator::Missing),
+];
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let spelling = OPERATORS
+            .iter()
+            .find(|(_, op)| op == self)
+            .map_or("?", |(spelling, _)| *spelling);
+        write!(f, "{}", spelling)
+    }
+}
+
+/// A single `field op value` condition, e.g. `age >= 21` or `name ~ "smith"`, or a
+/// `field exists` / `field missing` check. Values are read as JSON when possible
+/// and as a plain string otherwise, `Null` for the checks without one. An unquoted
+/// `now`, or an offset from it such as `-7d` or `now+1h`, is a timestamp resolved
+/// when the condition is parsed, e.g. `createdAt > -7d`. Quote it as a JSON string,
+/// `tag == "-7d"`, to compare with the text instead. Any amount of whitespace may
+/// separate the field, operator and value.
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub field: String,
+    pub op: Operator,
+    pub value: FirestoreType,
+}
+
+impl Condition {
+    /// The equivalent server side filter, if Firestore supports the operator
+    pub fn to_filter(&self) -> Option<Filter> {
+        // comparisons with null have to be sent as unary filters
+        let unary = match (self.op, &self.value) {
+            (Operator::Field(FieldOperator::Equal), FirestoreType::Null) => {
+                Some(UnaryOperator::IsNull)
+            }
+            (Operator::Field(FieldOperator::NotEqual), FirestoreType::Null) => {
+                Some(UnaryOperator::IsNotNull)
+            }
+            _ => None,
+        };
+        if let Some(op) = unary {
+            return Some(Filter::Unary(UnaryFilter::new(op, &*self.field)));
+        }
+        match self.op {
+            Operator::Field(op) => Some(Filter::Field(FieldFilter {
+                field: FieldReference {
+                    field_path: self.field.clone(),
+                },
+                op,
+                value: self.value.clone(),
+            })),
+            Operator::Contains | Operator::Exists | Operator::Missing => None,
+        }
+    }
+
+    fn is_inequality(&self) -> bool {
+        match self.op {
+            Operator::Field(op) => op.is_inequality(),
+            _ => false,
+        }
+    }
+
+    /// Whether the operator takes no value, like `exists`
+    fn is_check(&self) -> bool {
+        self.op == Operator::Exists || self.op == Operator::Missing
+    }
+
+    /// Evaluates the condition locally. Like the server, a missing field never
+    /// matches anything but `missing`.
+    pub fn matches(&self, fields: &FirestoreFields) -> bool {
+        match (self.op, fields.get_path(&*self.field)) {
+            (Operator::Exists, value) => value.is_some(),
+            (Operator::Missing, value) => value.is_none(),
+            (_, None) => false,
+            (Operator::Contains, Some(value)) => match (value, &self.value) {
+                (FirestoreType::String(haystack), FirestoreType::String(needle)) => {
+                    haystack.contains(&**needle)
+                }
+                _ => false,
+            },
+            (Operator::Field(FieldOperator::ArrayContains), Some(value)) => match value {
+                FirestoreType::Array(array) => array
+                    .values()
+                    .iter()
+                    .any(|element| compare(element, &self.value) == Some(Ordering::Equal)),
+                _ => false,
+            },
+            (Operator::Field(FieldOperator::NotEqual), Some(value)) => {
+                compare(value, &self.value) != Some(Ordering::Equal)
+            }
+            (Operator::Field(op), Some(value)) => match compare(value, &self.value) {
+                Some(ordering) => match op {
+                    FieldOperator::LessThan => ordering == Ordering::Less,
+                    FieldOperator::LessThanOrEqual => ordering != Ordering::Greater,
+                    FieldOperator::GreaterThan => ordering == Ordering::Greater,
+                    FieldOperator::GreaterThanOrEqual => ordering != Ordering::Less,
+                    _ => ordering == Ordering::Equal,
+                },
+                None => false,
+            },
+        }
+    }
+}
+
+/// Orders two values of the same kind, integers and doubles compare as numbers.
+/// `None` when the values cannot be compared, which the server also treats as no match.
+fn compare(a: &FirestoreType, b: &FirestoreType) -> Option<Ordering> {
+    use FirestoreType::*;
+    match (a, b) {
+        (Integer(a), Integer(b)) => Some(a.cmp(b)),
+        (Integer(a), Double(b)) => (*a as f64).partial_cmp(&b.value()),
+        (Double(a), Integer(b)) => a.value().partial_cmp(&(*b as f64)),
+        (Double(a), Double(b)) => a.value().partial_cmp(&b.value()),
+        (Boolean(a), Boolean(b)) => Some(a.cmp(b)),
+        (String(a), String(b)) => Some(a.cmp(b)),
+        (Timestamp(a), Timestamp(b)) => Some(a.time().cmp(&b.time())),
+        (Bytes(a), Bytes(b)) => Some(a.cmp(b)),
+        (Reference(a), Reference(b)) => Some(a.to_string().cmp(&b.to_string())),
+        (Null, Null) => Some(Ordering::Equal),
+        (Array(_), Array(_)) | (Map(_), Map(_)) | (GeoLocation(_), GeoLocation(_)) => {
+            if a.to_json() == b.to_json() {
+                Some(Ordering::Equal)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    fn from_str(condition: &str) -> Result<Condition> {
+        Condition::parse(condition, Utc::now())
+    }
+}
+
+impl Condition {
+    /// Parses `condition`, resolving relative times against `now`
+    pub fn parse(condition: &str, now: DateTime<Utc>) -> Result<Condition> {
+        let invalid = |reason: &str| Error::InvalidInput {
+            message: format!("invalid condition {:?}: {}", condition, reason),
+        };
+        let (field, rest) = split_word(condition.trim());
+        let (op, value) = split_word(rest);
+        let value = if value.is_empty() { None } else { Some(value) };
+        if field.is_empty() || op.is_empty() {
+            return Err(invalid("expected `field operator value`"));
+        }
+        let op = OPERATORS
+            .iter()
+            .find(|(spelling, _)| *spelling == op)
+            .map(|(_, op)| *op)
+            .ok_or_else(|| invalid("unknown operator"))?;
+        let value = match value {
+            None if op == Operator::Exists || op == Operator::Missing => {
+                return Ok(Condition {
+                    field: field.to_string(),
+                    op,
+                    value: FirestoreType::Null,
+                });
+            }
+            Some(_) if op == Operator::Exists || op == Operator::Missing => {
+                return Err(invalid("exists and missing take no value"));
+            }
+            Some(value) => value,
+            None => return Err(invalid("expected `field operator value`")),
+        };
+        if let Some(time) = relative_time(value, now) {
+            return Ok(Condition {
+                field: field.to_string(),
+                op,
+                value: FirestoreType::Timestamp(Timestamp::new(time)),
+            });
+        }
+        let json = serde_json::from_str::<serde_json::Value>(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        let value =
+            serde_json::from_value(json_to_wire(&json)).map_err(|e| invalid(&*e.to_string()))?;
+        Ok(Condition {
+            field: field.to_string(),
+            op,
+            value,
+        })
+    }
+}
+
+// Splits off the first word, and the rest without the whitespace before it
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// Reads `now`, `now-7d`, `+30m` or `-2w` as a time relative to `now`, in seconds,
+/// minutes, hours, days or weeks. `None` for anything else, quoted strings included.
+pub fn relative_time(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let offset = if value.starts_with("now") {
+        &value["now".len()..]
+    } else {
+        value
+    };
+    if offset.is_empty() {
+        // only `now` itself leaves nothing after it
+        return if value.is_empty() { None } else { Some(now) };
+    }
+    let (sign, offset) = match offset.chars().next() {
+        Some('-') => (-1, &offset[1..]),
+        Some('+') => (1, &offset[1..]),
+        _ => return None,
+    };
+    let split = offset.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = offset.split_at(split);
+    let amount = sign * amount.parse::<i64>().ok()?;
+    let offset = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        "w" => Duration::weeks(amount),
+        _ => return None,
+    };
+    now.checked_add_signed(offset)
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_check() {
+            return write!(f, "{} {}", self.field, self.op);
+        }
+        write!(f, "{} {} {}", self.field, self.op, self.value.to_json())
+    }
+}
+
+/// Conditions split by where they run. Firestore evaluates everything it can,
+/// the rest is checked against each streamed document without altering it.
+#[derive(Debug, Clone, Default)]
+pub struct FilterPlan {
+    pub server: Vec<Condition>,
+    pub client: Vec<Condition>,
+}
+
+impl FilterPlan {
+    pub fn new(conditions: Vec<Condition>) -> FilterPlan {
+        let mut plan = FilterPlan::default();
+        // Firestore allows inequalities on one field only, the first one wins
+        let mut inequality_field: Option<String> = None;
+        for condition in conditions {
+            let server_side = match condition.op {
+                Operator::Field(_) if condition.is_inequality() => {
+                    let field = inequality_field.get_or_insert_with(|| condition.field.clone());
+                    *field == condition.field
+                }
+                Operator::Field(_) => true,
+                Operator::Contains | Operator::Exists | Operator::Missing => false,
+            };
+            if server_side {
+                plan.server.push(condition);
+            } else {
+                plan.client.push(condition);
+            }
+        }
+        plan
+    }
+
+    /// Evaluates every condition locally, for listings that cannot carry a filter
+    pub fn client_only(conditions: Vec<Condition>) -> FilterPlan {
+        FilterPlan {
+            server: Vec::new(),
+            client: conditions,
+        }
+    }
+
+    /// Adds the server side conditions to `query`. An inequality also has to be the
+    /// first ordering, so it is put in front of any existing order.
+    pub fn apply(&self, query: &mut StructuredQuery) {
+        let mut filters = self
+            .server
+            .iter()
+            .filter_map(Condition::to_filter)
+            .collect::<Vec<Filter>>();
+        if let Some(filter) = query.filter.take() {
+            filters.insert(0, filter);
+        }
+        query.filter = Filter::all(filters);
+        if let Some(condition) = self.server.iter().find(|c| c.is_inequality()) {
+            let ordered_first = query
+                .order_by
+                .first()
+                .map_or(false, |order| order.field.field_path == condition.field);
+            if !ordered_first && condition.field != DOCUMENT_NAME_FIELD {
+                query.order_by.insert(
+                    0,
+                    Order {
+                        field: FieldReference {
+                            field_path: condition.field.clone(),
+                        },
+                        direction: Direction::Ascending,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Whether `document` passes every client side condition
+    pub fn matches(&self, document: &Document) -> bool {
+        self.matches_fields(document.fields())
+    }
+
+    /// Like `matches`, for the fields of a document. Missing documents have none.
+    pub fn matches_fields(&self, fields: &FirestoreFields) -> bool {
+        self.client
+            .iter()
+            .all(|condition| condition.matches(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2019, 6, 15).and_hms(12, 0, 0)
+    }
+
+    fn parse(condition: &str) -> Condition {
+        Condition::parse(condition, now()).unwrap()
+    }
+
+    #[test]
+    fn any_whitespace_separates_the_parts() {
+        for text in &["age >= 21", "age  >=   21", "  age\t>=\n21  "] {
+            let condition = parse(text);
+            assert_eq!(condition.field, "age");
+            assert_eq!(
+                condition.op,
+                Operator::Field(FieldOperator::GreaterThanOrEqual)
+            );
+            assert_eq!(condition.value.to_json(), serde_json::json!(21));
+        }
+    }
+
+    #[test]
+    fn values_keep_their_inner_whitespace() {
+        let condition = parse("name ==  \"Ada  Lovelace\"");
+        assert_eq!(
+            condition.value.to_json(),
+            serde_json::json!("Ada  Lovelace")
+        );
+        let condition = parse("name == Ada  Lovelace");
+        assert_eq!(
+            condition.value.to_json(),
+            serde_json::json!("Ada  Lovelace")
+        );
+    }
+
+    #[test]
+    fn checks_take_no_value() {
+        assert_eq!(parse("email  exists ").op, Operator::Exists);
+        assert_eq!(parse("email missing").op, Operator::Missing);
+        assert!(Condition::parse("email exists yes", now()).is_err());
+    }
+
+    #[test]
+    fn incomplete_conditions_are_rejected() {
+        for text in &["", "age", "age >=", "age  >=  ", "age => 21"] {
+            assert!(Condition::parse(text, now()).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn relative_times_resolve_against_now() {
+        let time = |condition: &Condition| match &condition.value {
+            FirestoreType::Timestamp(timestamp) => timestamp.time(),
+            other => panic!("expected a timestamp, got {:?}", other),
+        };
+        assert_eq!(time(&parse("updatedAt < now")), now());
+        assert_eq!(
+            time(&parse("createdAt > -7d")),
+            Utc.ymd(2019, 6, 8).and_hms(12, 0, 0)
+        );
+        assert_eq!(
+            time(&parse("expiresAt <= now+90m")),
+            Utc.ymd(2019, 6, 15).and_hms(13, 30, 0)
+        );
+        assert_eq!(
+            time(&parse("seenAt >= +2w")),
+            Utc.ymd(2019, 6, 29).and_hms(12, 0, 0)
+        );
+    }
+
+    #[test]
+    fn quoted_relative_times_stay_strings() {
+        assert_eq!(
+            parse("tag == \"-7d\"").value.to_json(),
+            serde_json::json!("-7d")
+        );
+        assert_eq!(
+            parse("status == \"now\"").value.to_json(),
+            serde_json::json!("now")
+        );
+    }
+
+    #[test]
+    fn numbers_are_not_relative_times() {
+        assert_eq!(parse("delta < -7").value.to_json(), serde_json::json!(-7));
+        assert_eq!(relative_time("-7x", now()), None);
+        assert_eq!(relative_time("nowhere", now()), None);
+        assert_eq!(relative_time("", now()), None);
+    }
+}
@@ -0,0 +1,491 @@
+//! `--near` searches. Firestore has no radius query, so a circle is turned into range
+//! filters Firestore can run, on a GeoPoint field or on a geohash field, and the
+//! documents they return are then checked for their exact distance.
+
+use super::filter::{Condition, Operator};
+use super::query::FieldOperator;
+use super::{FirestoreFields, FirestoreType, GeoPoint};
+use crate::errors::{Error, Result};
+
+/// Mean radius of the Earth, in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+/// Longest geohash used for prefix scans, cells of a few meters
+const MAX_PRECISION: usize = 9;
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+/// Sorts after every geohash character, closing the range of a prefix
+const PREFIX_END: char = '~';
+
+/// Documents whose GeoPoint `field` lies within `radius` meters of `center`
+#[derive(Debug, Clone)]
+pub struct GeoFilter {
+    pub field: String,
+    pub center: GeoPoint,
+    pub radius: f64,
+    /// Field holding the geohash of `field`, scanned by prefix instead of by range
+    pub geohash_field: Option<String>,
+}
+
+impl GeoFilter {
+    pub fn new<S: Into<String>>(field: S, center: GeoPoint, radius: f64) -> GeoFilter {
+        GeoFilter {
+            field: field.into(),
+            center,
+            radius,
+            geohash_field: None,
+        }
+    }
+
+    pub fn with_geohash_field<S: Into<String>>(mut self, field: S) -> GeoFilter {
+        self.geohash_field = Some(field.into());
+        self
+    }
+
+    /// Reads `latitude,longitude` in degrees, e.g. `52.5,13.4`
+    pub fn parse_point(point: &str) -> Result<GeoPoint> {
+        let invalid = || Error::InvalidInput {
+            message: format!("invalid point {:?}, expected e.g. 52.5,13.4", point),
+        };
+        let mut parts = point.splitn(2, ',');
+        let (latitude, longitude) = match (parts.next(), parts.next()) {
+            (Some(latitude), Some(longitude)) => (latitude.trim(), longitude.trim()),
+            _ => return Err(invalid()),
+        };
+        let latitude = latitude.parse::<f64>().map_err(|_| invalid())?;
+        let longitude = longitude.parse::<f64>().map_err(|_| invalid())?;
+        if !(latitude.abs() <= 90.0 && longitude.abs() <= 180.0) {
+            return Err(invalid());
+        }
+        Ok(GeoPoint {
+            latitude,
+            longitude,
+        })
+    }
+
+    /// Reads a distance such as `500m`, `5km` or `3mi` into meters, meters when unitless
+    pub fn parse_distance(distance: &str) -> Result<f64> {
+        let distance = distance.trim();
+        let invalid = || Error::InvalidInput {
+            message: format!("invalid distance {:?}, expected e.g. 500m or 5km", distance),
+        };
+        let split = distance
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(distance.len());
+        let (amount, unit) = distance.split_at(split);
+        let amount = amount.parse::<f64>().map_err(|_| invalid())?;
+        let meters = match unit.trim() {
+            "" | "m" => amount,
+            "km" => amount * 1000.0,
+            "mi" => amount * 1609.344,
+            _ => return Err(invalid()),
+        };
+        if !meters.is_finite() || meters <= 0.0 {
+            return Err(invalid());
+        }
+        Ok(meters)
+    }
+
+    /// South-west and north-east corners of a box holding the circle. Near a pole or
+    /// across the antimeridian the box spans every longitude.
+    pub fn bounding_box(&self) -> (GeoPoint, GeoPoint) {
+        let angle = self.radius / EARTH_RADIUS;
+        let latitude = self.center.latitude.to_radians();
+        let (south, north) = (latitude - angle, latitude + angle);
+        let half_width = (angle.sin() / latitude.cos()).asin();
+        let longitude = self.center.longitude.to_radians();
+        let (west, east) = if south <= -std::f64::consts::FRAC_PI_2
+            || north >= std::f64::consts::FRAC_PI_2
+            || half_width.is_nan()
+            || longitude - half_width < -std::f64::consts::PI
+            || longitude + half_width > std::f64::consts::PI
+        {
+            (-180.0, 180.0)
+        } else {
+            (
+                (longitude - half_width).to_degrees(),
+                (longitude + half_width).to_degrees(),
+            )
+        };
+        (
+            GeoPoint {
+                latitude: south.to_degrees().max(-90.0),
+                longitude: west,
+            },
+            GeoPoint {
+                latitude: north.to_degrees().min(90.0),
+                longitude: east,
+            },
+        )
+    }
+
+    /// Server side conditions, one set per query to run, between them returning every
+    /// document within the radius and others nearby. A GeoPoint range only narrows the
+    /// latitude, as points are ordered by latitude first; geohash prefixes narrow both.
+    pub fn conditions(&self) -> Vec<Vec<Condition>> {
+        let (south_west, north_east) = self.bounding_box();
+        let geohash_field = match &self.geohash_field {
+            Some(field) => field,
+            None => {
+                return vec![vec![
+                    condition(
+                        &*self.field,
+                        FieldOperator::GreaterThanOrEqual,
+                        FirestoreType::GeoLocation(south_west),
+                    ),
+                    condition(
+                        &*self.field,
+                        FieldOperator::LessThanOrEqual,
+                        FirestoreType::GeoLocation(north_east),
+                    ),
+                ]];
+            }
+        };
+        prefixes(south_west, north_east)
+            .into_iter()
+            .map(|prefix| {
+                if prefix.is_empty() {
+                    // a box wider than any cell is scanned whole
+                    return Vec::new();
+                }
+                vec![
+                    condition(
+                        &**geohash_field,
+                        FieldOperator::GreaterThanOrEqual,
+                        FirestoreType::String(prefix.clone()),
+                    ),
+                    condition(
+                        &**geohash_field,
+                        FieldOperator::LessThan,
+                        FirestoreType::String(format!("{}{}", prefix, PREFIX_END)),
+                    ),
+                ]
+            })
+            .collect()
+    }
+
+    /// Great-circle distance from the center to `point`, in meters
+    pub fn distance_to(&self, point: &GeoPoint) -> f64 {
+        let (a, b) = (&self.center, point);
+        let d_latitude = (b.latitude - a.latitude).to_radians();
+        let d_longitude = (b.longitude - a.longitude).to_radians();
+        let h = (d_latitude / 2.0).sin().powi(2)
+            + a.latitude.to_radians().cos()
+                * b.latitude.to_radians().cos()
+                * (d_longitude / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+    }
+
+    /// Whether `fields` hold a GeoPoint within the radius
+    pub fn matches(&self, fields: &FirestoreFields) -> bool {
+        match fields.get_path(&*self.field) {
+            Some(FirestoreType::GeoLocation(point)) => self.distance_to(point) <= self.radius,
+            _ => false,
+        }
+    }
+}
+
+fn condition(field: &str, op: FieldOperator, value: FirestoreType) -> Condition {
+    Condition {
+        field: field.to_string(),
+        op: Operator::Field(op),
+        value,
+    }
+}
+
+/// The geohash of `point` with `precision` characters
+pub fn geohash(point: &GeoPoint, precision: usize) -> String {
+    let (mut latitude, mut longitude) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let (mut bits, mut index, mut even) = (0, 0, true);
+    while hash.len() < precision {
+        // bits alternate between longitude and latitude, longitude first
+        let (range, value) = if even {
+            (&mut longitude, point.longitude)
+        } else {
+            (&mut latitude, point.latitude)
+        };
+        let middle = (range.0 + range.1) / 2.0;
+        index <<= 1;
+        if value >= middle {
+            index |= 1;
+            range.0 = middle;
+        } else {
+            range.1 = middle;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+    hash
+}
+
+/// Geohash prefixes whose cells cover the box, at the longest precision where a cell
+/// is at least as large as the box. The box then meets at most four cells, each of
+/// which holds one of its corners. A single empty prefix when no cell is that large.
+fn prefixes(south_west: GeoPoint, north_east: GeoPoint) -> Vec<String> {
+    let height = north_east.latitude - south_west.latitude;
+    let width = north_east.longitude - south_west.longitude;
+    let precision = (1..=MAX_PRECISION)
+        .take_while(|precision| {
+            let bits = 5 * precision;
+            let cell_width = 360.0 / 2f64.powi(((bits + 1) / 2) as i32);
+            let cell_height = 180.0 / 2f64.powi((bits / 2) as i32);
+            cell_width >= width && cell_height >= height
+        })
+        .last()
+        .unwrap_or(0);
+    let corners = [
+        south_west,
+        north_east,
+        GeoPoint {
+            latitude: south_west.latitude,
+            longitude: north_east.longitude,
+        },
+        GeoPoint {
+            latitude: north_east.latitude,
+            longitude: south_west.longitude,
+        },
+    ];
+    let mut prefixes = corners
+        .iter()
+        .map(|corner| geohash(corner, precision))
+        .collect::<Vec<String>>();
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(latitude: f64, longitude: f64) -> GeoPoint {
+        GeoPoint {
+            latitude,
+            longitude,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn points() {
+        let parsed = GeoFilter::parse_point("52.5,13.4").unwrap();
+        assert_eq!((parsed.latitude, parsed.longitude), (52.5, 13.4));
+        let parsed = GeoFilter::parse_point(" -33.9 , 151.2 ").unwrap();
+        assert_eq!((parsed.latitude, parsed.longitude), (-33.9, 151.2));
+        let parsed = GeoFilter::parse_point("90,-180").unwrap();
+        assert_eq!((parsed.latitude, parsed.longitude), (90.0, -180.0));
+        for invalid in &["52.5", "52.5;13.4", "a,b", "91,0", "0,180.5", "NaN,0", ""] {
+            assert!(GeoFilter::parse_point(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(GeoFilter::parse_distance("500m").unwrap(), 500.0);
+        assert_eq!(GeoFilter::parse_distance("250").unwrap(), 250.0);
+        assert_eq!(GeoFilter::parse_distance("5km").unwrap(), 5000.0);
+        assert_eq!(GeoFilter::parse_distance(" 1.5 km ").unwrap(), 1500.0);
+        assert_eq!(GeoFilter::parse_distance("3mi").unwrap(), 4828.032);
+        for invalid in &["", "km", "0m", "-5km", "5ft", "1e3", "1.2.3m"] {
+            assert!(GeoFilter::parse_distance(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn known_geohashes() {
+        assert_eq!(geohash(&point(57.64911, 10.40744), 11), "u4pruydqqvj");
+        assert_eq!(geohash(&point(42.6, -5.6), 5), "ezs42");
+        assert_eq!(geohash(&point(-25.382708, -49.265506), 8), "6gkzwgjz");
+        assert_eq!(geohash(&point(0.0, 0.0), 4), "s000");
+        assert_eq!(geohash(&point(90.0, 180.0), 4), "zzzz");
+        assert_eq!(geohash(&point(-90.0, -180.0), 4), "0000");
+        assert_eq!(geohash(&point(52.5, 13.4), 0), "");
+    }
+
+    #[test]
+    fn known_distances() {
+        let paris = GeoFilter::new("location", point(48.8566, 2.3522), 0.0);
+        assert_close(
+            paris.distance_to(&point(51.5074, -0.1278)),
+            343_560.0,
+            500.0,
+        );
+        assert_close(paris.distance_to(&point(48.8566, 2.3522)), 0.0, 1e-6);
+        // a degree of latitude is the same length everywhere on a sphere
+        let equator = GeoFilter::new("location", point(0.0, 0.0), 0.0);
+        assert_close(equator.distance_to(&point(1.0, 0.0)), 111_195.1, 1.0);
+        // the short way round crosses the antimeridian
+        let east = GeoFilter::new("location", point(0.0, 179.5), 0.0);
+        assert_close(east.distance_to(&point(0.0, -179.5)), 111_195.1, 1.0);
+        let pole = GeoFilter::new("location", point(90.0, 0.0), 0.0);
+        assert_close(
+            pole.distance_to(&point(-90.0, 0.0)),
+            EARTH_RADIUS * std::f64::consts::PI,
+            1.0,
+        );
+    }
+
+    #[test]
+    fn bounding_boxes_hold_the_circle() {
+        let filter = GeoFilter::new("location", point(52.5, 13.4), 10_000.0);
+        let (south_west, north_east) = filter.bounding_box();
+        assert!(south_west.latitude < 52.5 && 52.5 < north_east.latitude);
+        assert!(south_west.longitude < 13.4 && 13.4 < north_east.longitude);
+        // the edges of the box are at least the radius away from the center
+        for edge in &[
+            point(south_west.latitude, 13.4),
+            point(north_east.latitude, 13.4),
+            point(52.5, south_west.longitude),
+            point(52.5, north_east.longitude),
+        ] {
+            assert!(filter.distance_to(edge) >= 10_000.0 - 1.0, "{:?}", edge);
+        }
+        assert_close(north_east.latitude - south_west.latitude, 0.18, 0.001);
+    }
+
+    #[test]
+    fn bounding_boxes_near_a_pole_span_every_longitude() {
+        let filter = GeoFilter::new("location", point(89.99, 45.0), 10_000.0);
+        let (south_west, north_east) = filter.bounding_box();
+        assert_eq!(north_east.latitude, 90.0);
+        assert_eq!(
+            (south_west.longitude, north_east.longitude),
+            (-180.0, 180.0)
+        );
+        let filter = GeoFilter::new("location", point(-89.99, 45.0), 10_000.0);
+        let (south_west, north_east) = filter.bounding_box();
+        assert_eq!(south_west.latitude, -90.0);
+        assert_eq!(
+            (south_west.longitude, north_east.longitude),
+            (-180.0, 180.0)
+        );
+    }
+
+    #[test]
+    fn bounding_boxes_across_the_antimeridian_span_every_longitude() {
+        for longitude in &[179.99, -179.99] {
+            let filter = GeoFilter::new("location", point(10.0, *longitude), 10_000.0);
+            let (south_west, north_east) = filter.bounding_box();
+            assert_eq!(
+                (south_west.longitude, north_east.longitude),
+                (-180.0, 180.0)
+            );
+            assert!(south_west.latitude < 10.0 && 10.0 < north_east.latitude);
+        }
+    }
+
+    #[test]
+    fn prefixes_cover_the_circle() {
+        let center = point(52.5, 13.4);
+        let filter = GeoFilter::new("location", center, 2_000.0);
+        let (south_west, north_east) = filter.bounding_box();
+        let prefixes = prefixes(south_west, north_east);
+        assert!(!prefixes.is_empty() && prefixes.len() <= 4);
+        assert!(!prefixes[0].is_empty());
+        assert!(prefixes
+            .iter()
+            .all(|prefix| prefix.len() == prefixes[0].len()));
+        // sample points on rings around the center, up to the radius
+        for step in 0..=8 {
+            for bearing in 0..36 {
+                let distance = 2_000.0 * f64::from(step) / 8.0;
+                let bearing = f64::from(bearing * 10).to_radians();
+                let sample = point(
+                    center.latitude + (distance * bearing.cos() / EARTH_RADIUS).to_degrees(),
+                    center.longitude
+                        + (distance * bearing.sin()
+                            / (EARTH_RADIUS * center.latitude.to_radians().cos()))
+                        .to_degrees(),
+                );
+                let hash = geohash(&sample, MAX_PRECISION);
+                assert!(
+                    prefixes.iter().any(|prefix| hash.starts_with(&**prefix)),
+                    "{:?} ({}) is outside {:?}",
+                    sample,
+                    hash,
+                    prefixes
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prefixes_stop_at_the_longest_precision() {
+        let filter = GeoFilter::new("location", point(52.5, 13.4), 0.5);
+        let (south_west, north_east) = filter.bounding_box();
+        assert!(prefixes(south_west, north_east)
+            .iter()
+            .all(|prefix| prefix.len() == MAX_PRECISION));
+    }
+
+    #[test]
+    fn boxes_wider_than_any_cell_are_scanned_whole() {
+        let filter =
+            GeoFilter::new("location", point(0.0, 0.0), 10_000_000.0).with_geohash_field("geohash");
+        let (south_west, north_east) = filter.bounding_box();
+        assert_eq!(prefixes(south_west, north_east), vec![String::new()]);
+        let conditions = filter.conditions();
+        assert_eq!(conditions.len(), 1);
+        assert!(conditions[0].is_empty());
+    }
+
+    #[test]
+    fn geohash_conditions_scan_each_prefix() {
+        let filter =
+            GeoFilter::new("location", point(52.5, 13.4), 2_000.0).with_geohash_field("geohash");
+        let (south_west, north_east) = filter.bounding_box();
+        let prefixes = prefixes(south_west, north_east);
+        let conditions = filter.conditions();
+        assert_eq!(conditions.len(), prefixes.len());
+        for (conditions, prefix) in conditions.iter().zip(&prefixes) {
+            let shown = conditions
+                .iter()
+                .map(|condition| condition.to_string())
+                .collect::<Vec<String>>();
+            assert_eq!(
+                shown,
+                vec![
+                    format!("geohash >= \"{}\"", prefix),
+                    format!("geohash < \"{}~\"", prefix),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn geopoint_conditions_narrow_the_latitude() {
+        let filter = GeoFilter::new("location", point(52.5, 13.4), 2_000.0);
+        let conditions = filter.conditions();
+        assert_eq!(conditions.len(), 1);
+        let ops = conditions[0]
+            .iter()
+            .map(|condition| (condition.field.clone(), condition.op))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                (
+                    "location".to_string(),
+                    Operator::Field(FieldOperator::GreaterThanOrEqual)
+                ),
+                (
+                    "location".to_string(),
+                    Operator::Field(FieldOperator::LessThanOrEqual)
+                ),
+            ]
+        );
+    }
+}
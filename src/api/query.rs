@@ -0,0 +1,678 @@
+use super::filter::{Condition, Operator};
+use super::{ConsistencySelector, DatabaseContext, Document, FirestoreFields, FirestoreType};
+use crate::cancel::CancellationToken;
+use crate::errors::Result;
+use crate::path::{CollectionPath, DocumentPath, DocumentReference};
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+
+/// Field path Firestore uses for a document's name in orderings and cursors
+pub const DOCUMENT_NAME_FIELD: &'static str = "__name__";
+/// The database every project has, queried unless the builder is told otherwise
+pub const DEFAULT_DATABASE: &'static str = "(default)";
+const DEFAULT_PAGE_SIZE: i32 = 300;
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#FieldReference
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldReference {
+    #[serde(rename = "fieldPath")]
+    pub field_path: String,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Operator_2
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum UnaryOperator {
+    #[serde(rename = "IS_NAN")]
+    IsNan,
+    #[serde(rename = "IS_NULL")]
+    IsNull,
+    #[serde(rename = "IS_NOT_NAN")]
+    IsNotNan,
+    #[serde(rename = "IS_NOT_NULL")]
+    IsNotNull,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#UnaryFilter
+#[derive(Debug, Clone, Serialize)]
+pub struct UnaryFilter {
+    pub op: UnaryOperator,
+    pub field: FieldReference,
+}
+
+impl UnaryFilter {
+    pub fn new<S: Into<String>>(op: UnaryOperator, field_path: S) -> UnaryFilter {
+        UnaryFilter {
+            op,
+            field: FieldReference {
+                field_path: field_path.into(),
+            },
+        }
+    }
+
+    /// Evaluates the filter client side against a field's value, `None` if absent.
+    /// Like the server, NaN checks only match doubles.
+    pub fn matches(&self, value: Option<&FirestoreType>) -> bool {
+        let is_nan = match value {
+            Some(FirestoreType::Double(d)) => Some(d.is_nan()),
+            _ => None,
+        };
+        let is_null = match value {
+            Some(FirestoreType::Null) => true,
+            _ => false,
+        };
+        match self.op {
+            UnaryOperator::IsNan => is_nan == Some(true),
+            UnaryOperator::IsNotNan => is_nan == Some(false),
+            UnaryOperator::IsNull => is_null,
+            UnaryOperator::IsNotNull => value.is_some() && !is_null,
+        }
+    }
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Operator_1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum FieldOperator {
+    #[serde(rename = "LESS_THAN")]
+    LessThan,
+    #[serde(rename = "LESS_THAN_OR_EQUAL")]
+    LessThanOrEqual,
+    #[serde(rename = "GREATER_THAN")]
+    GreaterThan,
+    #[serde(rename = "GREATER_THAN_OR_EQUAL")]
+    GreaterThanOrEqual,
+    #[serde(rename = "EQUAL")]
+    Equal,
+    #[serde(rename = "NOT_EQUAL")]
+    NotEqual,
+    #[serde(rename = "ARRAY_CONTAINS")]
+    ArrayContains,
+}
+
+impl FieldOperator {
+    /// Firestore only allows these on a single field per query
+    pub fn is_inequality(self) -> bool {
+        match self {
+            FieldOperator::Equal | FieldOperator::ArrayContains => false,
+            _ => true,
+        }
+    }
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#FieldFilter
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldFilter {
+    pub field: FieldReference,
+    pub op: FieldOperator,
+    pub value: FirestoreType,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Operator
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum CompositeOperator {
+    #[serde(rename = "AND")]
+    And,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#CompositeFilter
+#[derive(Debug, Clone, Serialize)]
+pub struct CompositeFilter {
+    pub op: CompositeOperator,
+    pub filters: Vec<Filter>,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Filter
+#[derive(Debug, Clone, Serialize)]
+pub enum Filter {
+    #[serde(rename = "unaryFilter")]
+    Unary(UnaryFilter),
+    #[serde(rename = "fieldFilter")]
+    Field(FieldFilter),
+    #[serde(rename = "compositeFilter")]
+    Composite(CompositeFilter),
+}
+
+impl Filter {
+    /// Combines `filters` so all of them must match, `None` if there are none
+    pub fn all(mut filters: Vec<Filter>) -> Option<Filter> {
+        match filters.len() {
+            0 => None,
+            1 => filters.pop(),
+            _ => Some(Filter::Composite(CompositeFilter {
+                op: CompositeOperator::And,
+                filters,
+            })),
+        }
+    }
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#CollectionSelector
+#[derive(Debug, Clone, Serialize)]
+pub struct CollectionSelector {
+    #[serde(rename = "collectionId")]
+    pub collection_id: String,
+    #[serde(rename = "allDescendants")]
+    pub all_descendants: bool,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Direction
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Direction {
+    #[serde(rename = "ASCENDING")]
+    Ascending,
+    #[serde(rename = "DESCENDING")]
+    Descending,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Order
+#[derive(Debug, Clone, Serialize)]
+pub struct Order {
+    pub field: FieldReference,
+    pub direction: Direction,
+}
+
+/// A position in a query's result set. Serializable so long scans can be checkpointed.
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/Cursor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cursor {
+    pub values: Vec<FirestoreType>,
+    /// Whether the position is just before, rather than just after, the given values
+    #[serde(default)]
+    pub before: bool,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery#Projection
+#[derive(Debug, Clone, Serialize)]
+pub struct Projection {
+    pub fields: Vec<FieldReference>,
+}
+
+impl Projection {
+    /// Returns only the fields at `field_paths`, and the document name
+    pub fn new(field_paths: &[String]) -> Projection {
+        Projection {
+            fields: field_paths
+                .iter()
+                .map(|field_path| FieldReference {
+                    field_path: field_path.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredQuery
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StructuredQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub select: Option<Projection>,
+    pub from: Vec<CollectionSelector>,
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    #[serde(rename = "orderBy", skip_serializing_if = "Vec::is_empty")]
+    pub order_by: Vec<Order>,
+    #[serde(rename = "startAt", skip_serializing_if = "Option::is_none")]
+    pub start_at: Option<Cursor>,
+    #[serde(rename = "endAt", skip_serializing_if = "Option::is_none")]
+    pub end_at: Option<Cursor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i32>,
+}
+
+impl StructuredQuery {
+    /// Queries every document of the collection `collection_id`
+    pub fn collection<S: Into<String>>(collection_id: S) -> StructuredQuery {
+        StructuredQuery {
+            from: vec![CollectionSelector {
+                collection_id: collection_id.into(),
+                all_descendants: false,
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+/// A query on one collection put together call by call, e.g.
+/// `ctx.collection("cars").filter("make", FieldOperator::Equal, "Honda")
+/// .order_by("year", Direction::Descending).limit(10).fetch()`. Filters are combined
+/// with AND. Nothing is sent until `fetch` or `stream`.
+pub struct QueryBuilder<'a> {
+    context: &'a DatabaseContext,
+    database_name: String,
+    collection: String,
+    filters: Vec<Filter>,
+    query: StructuredQuery,
+}
+
+impl<'a> QueryBuilder<'a> {
+    pub(crate) fn new(context: &'a DatabaseContext, collection: String) -> QueryBuilder<'a> {
+        QueryBuilder {
+            context,
+            database_name: DEFAULT_DATABASE.to_string(),
+            collection,
+            filters: Vec::new(),
+            query: StructuredQuery::default(),
+        }
+    }
+
+    /// Queries a named database instead of `(default)`
+    pub fn in_database<S: Into<String>>(mut self, database_name: S) -> QueryBuilder<'a> {
+        self.database_name = database_name.into();
+        self
+    }
+
+    /// Keeps the documents whose `field_path` compares to `value` with `op`. Equality
+    /// with null is sent as the unary filter Firestore requires.
+    pub fn filter<S, V>(mut self, field_path: S, op: FieldOperator, value: V) -> QueryBuilder<'a>
+    where
+        S: Into<String>,
+        V: Into<FirestoreType>,
+    {
+        let condition = Condition {
+            field: field_path.into(),
+            op: Operator::Field(op),
+            value: value.into(),
+        };
+        // every field operator has a server side filter
+        self.filters.extend(condition.to_filter());
+        self
+    }
+
+    /// Sorts by `field_path`, after any earlier orderings
+    pub fn order_by<S: Into<String>>(
+        mut self,
+        field_path: S,
+        direction: Direction,
+    ) -> QueryBuilder<'a> {
+        self.query.order_by.push(Order {
+            field: FieldReference {
+                field_path: field_path.into(),
+            },
+            direction,
+        });
+        self
+    }
+
+    /// Starts the results at `cursor`, whose values follow the orderings
+    pub fn start_at(mut self, cursor: Cursor) -> QueryBuilder<'a> {
+        self.query.start_at = Some(cursor);
+        self
+    }
+
+    /// Skips the first `offset` results
+    pub fn offset(mut self, offset: i32) -> QueryBuilder<'a> {
+        self.query.offset = Some(offset);
+        self
+    }
+
+    pub fn limit(mut self, limit: i32) -> QueryBuilder<'a> {
+        self.query.limit = Some(limit);
+        self
+    }
+
+    /// Returns only the fields at `field_paths`
+    pub fn select(mut self, field_paths: &[String]) -> QueryBuilder<'a> {
+        self.query.select = Some(Projection::new(field_paths));
+        self
+    }
+
+    /// The query as sent, and the document whose subcollection it reads, if any
+    pub fn build(&self) -> Result<(Option<DocumentPath>, StructuredQuery)> {
+        let path = CollectionPath::parse(&*self.collection)?;
+        let mut query = self.query.clone();
+        query.from = vec![CollectionSelector {
+            collection_id: path.collection_id().to_string(),
+            all_descendants: false,
+        }];
+        query.filter = Filter::all(self.filters.clone());
+        Ok((path.parent(), query))
+    }
+
+    /// Runs the query, fetching pages lazily
+    pub fn stream(&self) -> Result<QueryStream<'a>> {
+        let (parent, query) = self.build()?;
+        Ok(QueryStream::new(
+            self.context,
+            &*self.database_name,
+            parent,
+            query,
+        ))
+    }
+
+    /// Runs the query and returns every result
+    pub fn fetch(&self) -> Result<Vec<Document>> {
+        self.stream()?.collect()
+    }
+
+    /// Computes `aggregations` over the results on the server, returning each by alias
+    pub fn aggregate(&self, aggregations: Vec<Aggregation>) -> Result<FirestoreFields> {
+        let (parent, query) = self.build()?;
+        self.context.run_aggregation_query(
+            &*self.database_name,
+            parent.as_ref(),
+            &query,
+            aggregations,
+        )
+    }
+
+    /// Counts the results on the server, without reading them
+    pub fn count(&self) -> Result<i64> {
+        const COUNT_ALIAS: &'static str = "count";
+        let fields = self.aggregate(vec![Aggregation::count(COUNT_ALIAS)])?;
+        match fields.get(COUNT_ALIAS) {
+            Some(FirestoreType::Integer(count)) => Ok(*count),
+            // a response without the aggregate counted nothing
+            _ => Ok(0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RunQueryRequest {
+    #[serde(rename = "structuredQuery")]
+    pub structured_query: StructuredQuery,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub consistency: Option<ConsistencySelector>,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/StructuredAggregationQuery#Aggregation
+#[derive(Debug, Clone, Serialize)]
+pub struct Aggregation {
+    /// Name of the result in the response
+    pub alias: String,
+    #[serde(flatten)]
+    pub operator: AggregationOperator,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AggregationOperator {
+    #[serde(rename = "count")]
+    Count {},
+    #[serde(rename = "sum")]
+    Sum { field: FieldReference },
+    #[serde(rename = "avg")]
+    Avg { field: FieldReference },
+}
+
+impl Aggregation {
+    /// Counts the results
+    pub fn count<S: Into<String>>(alias: S) -> Aggregation {
+        Aggregation {
+            alias: alias.into(),
+            operator: AggregationOperator::Count {},
+        }
+    }
+
+    /// Sums the numbers at `field_path`, ignoring other values
+    pub fn sum<S: Into<String>>(alias: S, field_path: S) -> Aggregation {
+        Aggregation {
+            alias: alias.into(),
+            operator: AggregationOperator::Sum {
+                field: FieldReference {
+                    field_path: field_path.into(),
+                },
+            },
+        }
+    }
+
+    /// Averages the numbers at `field_path`, null when there are none
+    pub fn avg<S: Into<String>>(alias: S, field_path: S) -> Aggregation {
+        Aggregation {
+            alias: alias.into(),
+            operator: AggregationOperator::Avg {
+                field: FieldReference {
+                    field_path: field_path.into(),
+                },
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StructuredAggregationQuery {
+    #[serde(rename = "structuredQuery")]
+    pub structured_query: StructuredQuery,
+    pub aggregations: Vec<Aggregation>,
+}
+
+#[derive(Serialize)]
+pub struct RunAggregationQueryRequest {
+    #[serde(rename = "structuredAggregationQuery")]
+    pub structured_aggregation_query: StructuredAggregationQuery,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub consistency: Option<ConsistencySelector>,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/runAggregationQuery#response-body
+#[derive(Debug, Deserialize)]
+pub struct RunAggregationQueryResponse {
+    pub result: Option<AggregationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AggregationResult {
+    #[serde(rename = "aggregateFields", default)]
+    pub aggregate_fields: FirestoreFields,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/partitionQuery#request-body
+#[derive(Serialize)]
+pub struct PartitionQueryRequest {
+    #[serde(rename = "structuredQuery")]
+    pub structured_query: StructuredQuery,
+    /// Upper bound on the number of partitions, the server may return fewer
+    #[serde(rename = "partitionCount")]
+    pub partition_count: i64,
+    #[serde(rename = "pageToken", skip_serializing_if = "Option::is_none")]
+    pub page_token: Option<String>,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/partitionQuery#response-body
+#[derive(Debug, Deserialize)]
+pub struct PartitionQueryResponse {
+    /// Split points between partitions, one fewer than the partitions
+    #[serde(default)]
+    pub partitions: Vec<Cursor>,
+    #[serde(rename = "nextPageToken")]
+    pub next_page_token: Option<String>,
+}
+
+/// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/runQuery#response-body
+#[derive(Debug, Deserialize)]
+pub struct RunQueryResponse {
+    pub transaction: Option<String>,
+    pub document: Option<Document>,
+    #[serde(rename = "readTime")]
+    pub read_time: Option<DateTime<Utc>>,
+    #[serde(rename = "skippedResults", default)]
+    pub skipped_results: i32,
+}
+
+/// Lazily pages through a query's results. Every page after the first starts from
+/// a cursor on the last document seen and is pinned to the first page's read time,
+/// so the scan is consistent and can be resumed later with `resume_from`.
+///
+/// A stream given a `CancellationToken` ends with `Error::Cancelled` once it is
+/// cancelled, and its `cursor()` is then the checkpoint to resume from.
+pub struct QueryStream<'a> {
+    context: &'a DatabaseContext,
+    database_name: String,
+    parent: Option<DocumentPath>,
+    query: StructuredQuery,
+    page_size: i32,
+    remaining: Option<i32>,
+    buffer: VecDeque<Document>,
+    cursor: Option<Cursor>,
+    read_time: Option<DateTime<Utc>>,
+    skipped_results: i32,
+    exhausted: bool,
+    cancellation: Option<CancellationToken>,
+}
+
+impl<'a> QueryStream<'a> {
+    pub(crate) fn new(
+        context: &'a DatabaseContext,
+        database_name: &str,
+        parent: Option<DocumentPath>,
+        mut query: StructuredQuery,
+    ) -> QueryStream<'a> {
+        // cursors need a total order, which the document name provides
+        let ordered_by_name = query
+            .order_by
+            .iter()
+            .any(|order| order.field.field_path == DOCUMENT_NAME_FIELD);
+        if !ordered_by_name {
+            let direction = query
+                .order_by
+                .last()
+                .map_or(Direction::Ascending, |order| order.direction);
+            query.order_by.push(Order {
+                field: FieldReference {
+                    field_path: DOCUMENT_NAME_FIELD.to_string(),
+                },
+                direction,
+            });
+        }
+        QueryStream {
+            context,
+            database_name: database_name.to_string(),
+            parent,
+            remaining: query.limit,
+            cursor: query.start_at.clone(),
+            query,
+            page_size: DEFAULT_PAGE_SIZE,
+            buffer: VecDeque::new(),
+            read_time: None,
+            skipped_results: 0,
+            exhausted: false,
+            cancellation: None,
+        }
+    }
+
+    /// Number of documents requested per call to the API
+    pub fn page_size(mut self, page_size: i32) -> QueryStream<'a> {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Continues a scan just after the position returned by an earlier `cursor()`
+    pub fn resume_from(mut self, cursor: Cursor) -> QueryStream<'a> {
+        self.cursor = Some(cursor);
+        self.query.offset = None;
+        self
+    }
+
+    /// Pins every page to `read_time` instead of the time of the first page
+    pub fn read_at(mut self, read_time: DateTime<Utc>) -> QueryStream<'a> {
+        self.read_time = Some(read_time);
+        self
+    }
+
+    /// Stops the scan before its next document once `token` is cancelled
+    pub fn cancel_on(mut self, token: CancellationToken) -> QueryStream<'a> {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Position just after the last document returned by the iterator
+    pub fn cursor(&self) -> Option<&Cursor> {
+        self.cursor.as_ref()
+    }
+
+    /// The read time every page of this scan is pinned to
+    pub fn read_time(&self) -> Option<DateTime<Utc>> {
+        self.read_time
+    }
+
+    /// Results skipped by the query's offset so far
+    pub fn skipped_results(&self) -> i32 {
+        self.skipped_results
+    }
+
+    /// The cursor positioned just after `document` in this query's ordering
+    fn cursor_after(&self, document: &Document) -> Cursor {
+        let values = self
+            .query
+            .order_by
+            .iter()
+            .map(|order| {
+                if order.field.field_path == DOCUMENT_NAME_FIELD {
+                    return DocumentReference::parse(document.name())
+                        .map(FirestoreType::Reference)
+                        .unwrap_or(FirestoreType::Null);
+                }
+                document
+                    .fields()
+                    .get_path(&*order.field.field_path)
+                    .cloned()
+                    .unwrap_or(FirestoreType::Null)
+            })
+            .collect();
+        Cursor {
+            values,
+            before: false,
+        }
+    }
+
+    fn fetch_page(&mut self) -> Result<()> {
+        let mut query = self.query.clone();
+        query.start_at = self.cursor.clone();
+        query.limit = Some(match self.remaining {
+            Some(remaining) => remaining.min(self.page_size),
+            None => self.page_size,
+        });
+        let consistency = self.read_time.map(ConsistencySelector::ReadTime);
+        let responses = self.context.run_query(
+            &*self.database_name,
+            self.parent.as_ref(),
+            &query,
+            consistency.as_ref(),
+        )?;
+        // the offset only applies to the first page
+        self.query.offset = None;
+        let mut fetched = 0;
+        for response in responses {
+            self.skipped_results += response.skipped_results;
+            if self.read_time.is_none() {
+                self.read_time = response.read_time;
+            }
+            if let Some(document) = response.document {
+                fetched += 1;
+                self.buffer.push_back(document);
+            }
+        }
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= fetched;
+        }
+        if fetched < query.limit.unwrap_or(0) || self.remaining == Some(0) {
+            self.exhausted = true;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for QueryStream<'a> {
+    type Item = Result<Document>;
+
+    fn next(&mut self) -> Option<Result<Document>> {
+        if let Some(token) = &self.cancellation {
+            if let Err(e) = token.check("query") {
+                // reported once, the cursor stays on the last document returned
+                self.cancellation = None;
+                self.buffer.clear();
+                self.exhausted = true;
+                return Some(Err(e));
+            }
+        }
+        if self.buffer.is_empty() && !self.exhausted {
+            if let Err(e) = self.fetch_page() {
+                self.exhausted = true;
+                return Some(Err(e));
+            }
+        }
+        let document = self.buffer.pop_front()?;
+        self.cursor = Some(self.cursor_after(&document));
+        Some(Ok(document))
+    }
+}
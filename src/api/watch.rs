@@ -0,0 +1,814 @@
+//! Watching queries for changes. Firestore only pushes changes over the gRPC Listen
+//! stream, so a `ChangeStream` polls its targets with requests the REST API serves.
+//! A target with an updated field is read incrementally, only the documents written
+//! since the previous poll, and a COUNT aggregation tells whether any left it; only
+//! then, or for targets without that field, are the names and update times of all of
+//! its documents listed to find what was removed, and just the changed ones read.
+
+use super::batch_get::Lookup;
+use super::filter::{Condition, FilterPlan, Operator};
+use super::query::{Aggregation, FieldOperator, Projection, StructuredQuery, DOCUMENT_NAME_FIELD};
+use super::{DatabaseContext, Document, FirestoreType, Timestamp};
+use crate::cancel::CancellationToken;
+use crate::errors::Result;
+use crate::path::{CollectionPath, DocumentPath};
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const COUNT_ALIAS: &'static str = "count";
+/// Time between polls unless told otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest a cancelled stream sleeps before it notices
+const CANCEL_CHECK: Duration = Duration::from_secs(1);
+/// Documents of each target whose last state is kept, unless told otherwise
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+/// A query whose results are watched
+#[derive(Debug, Clone)]
+pub struct WatchTarget {
+    /// Names the target in the changes reported for it
+    pub label: String,
+    /// Document the queried collection is under, the database root when `None`
+    pub parent: Option<DocumentPath>,
+    pub query: StructuredQuery,
+    /// Only its client side conditions are used, checked against every document read
+    pub filter: FilterPlan,
+    /// Timestamp field every write to the target's documents sets, ideally to the
+    /// server time. Firestore cannot query documents by their update time, so this is
+    /// what polls filter on to read only what was written since.
+    pub updated_field: Option<String>,
+}
+
+impl WatchTarget {
+    /// The documents of `collection`, a collection path relative to the database root,
+    /// matching `filter`
+    pub fn collection(collection: &str, filter: FilterPlan) -> Result<WatchTarget> {
+        let collection = collection.trim_matches('/');
+        let path = CollectionPath::parse(collection)?;
+        let mut query = StructuredQuery::collection(path.collection_id());
+        filter.apply(&mut query);
+        Ok(WatchTarget {
+            label: collection.to_string(),
+            parent: path.parent(),
+            query,
+            filter,
+            updated_field: None,
+        })
+    }
+
+    pub fn with_updated_field<S: Into<String>>(mut self, field: S) -> WatchTarget {
+        self.updated_field = Some(field.into());
+        self
+    }
+}
+
+/// A change to the documents of a target. The state of a document before the change
+/// is known when the stream read it earlier and still has it cached.
+#[derive(Debug, Clone)]
+pub enum DocumentChange {
+    /// A document entered the target, created or newly matching
+    Added(Document),
+    /// A document of the target was written
+    Modified {
+        before: Option<Document>,
+        after: Document,
+    },
+    /// A document left the target, deleted or no longer matching
+    Removed {
+        /// Full name of the document
+        name: String,
+        before: Option<Document>,
+    },
+}
+
+impl DocumentChange {
+    /// Full name of the changed document
+    pub fn name(&self) -> &str {
+        match self {
+            DocumentChange::Added(after) | DocumentChange::Modified { after, .. } => after.name(),
+            DocumentChange::Removed { name, .. } => &**name,
+        }
+    }
+
+    /// The document before the change, if it was there and is known
+    pub fn before(&self) -> Option<&Document> {
+        match self {
+            DocumentChange::Added(_) => None,
+            DocumentChange::Modified { before, .. } | DocumentChange::Removed { before, .. } => {
+                before.as_ref()
+            }
+        }
+    }
+
+    /// The document after the change, unless it left the target
+    pub fn after(&self) -> Option<&Document> {
+        match self {
+            DocumentChange::Added(after) | DocumentChange::Modified { after, .. } => Some(after),
+            DocumentChange::Removed { .. } => None,
+        }
+    }
+}
+
+/// The last state read of up to `capacity` documents, those written longest ago
+/// forgotten first
+struct StateCache {
+    capacity: usize,
+    states: HashMap<String, (u64, Document)>,
+    /// Documents in the order they were written, with the write they were written by;
+    /// entries for documents written again since are skipped on eviction
+    order: VecDeque<(String, u64)>,
+    writes: u64,
+}
+
+impl StateCache {
+    fn new(capacity: usize) -> StateCache {
+        StateCache {
+            capacity,
+            states: HashMap::new(),
+            order: VecDeque::new(),
+            writes: 0,
+        }
+    }
+
+    /// Keeps `document` and returns the state it replaces
+    fn insert(&mut self, document: Document) -> Option<Document> {
+        self.writes += 1;
+        let name = document.name().to_string();
+        let before = self
+            .states
+            .insert(name.clone(), (self.writes, document))
+            .map(|(_, before)| before);
+        self.order.push_back((name, self.writes));
+        while self.order.len() > self.capacity {
+            if let Some((name, write)) = self.order.pop_front() {
+                let latest = self
+                    .states
+                    .get(&name)
+                    .map_or(false, |(last, _)| *last == write);
+                if latest {
+                    self.states.remove(&name);
+                }
+            }
+        }
+        before
+    }
+
+    fn remove(&mut self, name: &str) -> Option<Document> {
+        self.states.remove(name).map(|(_, before)| before)
+    }
+}
+
+/// What a stream knows of one of its targets
+struct TargetState {
+    target: WatchTarget,
+    /// Update time of every document the query returns, by full name, whether or not
+    /// it matches the client side conditions
+    present: HashMap<String, DateTime<Utc>>,
+    /// The documents of `present` matching the client side conditions too
+    members: HashSet<String>,
+    /// Last state of the members
+    states: StateCache,
+    /// Latest value of the updated field read
+    updated_up_to: Option<DateTime<Utc>>,
+    /// Whether `present` was filled by the caller
+    seeded: bool,
+    /// Whether the target was read in full once
+    listed: bool,
+}
+
+impl TargetState {
+    fn new(target: WatchTarget, cache_size: usize) -> TargetState {
+        TargetState {
+            target,
+            present: HashMap::new(),
+            members: HashSet::new(),
+            states: StateCache::new(cache_size),
+            updated_up_to: None,
+            seeded: false,
+            listed: false,
+        }
+    }
+
+    fn poll(
+        &mut self,
+        ctx: &DatabaseContext,
+        database_name: &str,
+        changes: &mut Vec<DocumentChange>,
+    ) -> Result<()> {
+        if !self.listed {
+            self.list(ctx, database_name, changes)?;
+            self.listed = true;
+            return Ok(());
+        }
+        match (self.target.updated_field.clone(), self.updated_up_to) {
+            (Some(field), Some(since)) => {
+                self.read_since(ctx, database_name, &*field, since, changes)?;
+                // documents leave without a write we could read, so they are counted
+                if self.count(ctx, database_name)? != Some(self.present.len()) {
+                    self.reconcile(ctx, database_name, changes)?;
+                }
+                Ok(())
+            }
+            _ => self.reconcile(ctx, database_name, changes),
+        }
+    }
+
+    // Reads every document of the target
+    fn list(
+        &mut self,
+        ctx: &DatabaseContext,
+        database_name: &str,
+        changes: &mut Vec<DocumentChange>,
+    ) -> Result<()> {
+        let mut listed = HashSet::new();
+        let query = self.target.query.clone();
+        for document in ctx.query_stream(database_name, self.target.parent.clone(), query) {
+            let document = document?;
+            listed.insert(document.name().to_string());
+            self.written(document, changes);
+        }
+        self.remove_unlisted(&listed, changes);
+        Ok(())
+    }
+
+    // Reads the documents whose updated field is at least `since`. Those written at
+    // `since` exactly are read again, as another write may share the time, and are
+    // then told apart by their update time.
+    fn read_since(
+        &mut self,
+        ctx: &DatabaseContext,
+        database_name: &str,
+        field: &str,
+        since: DateTime<Utc>,
+        changes: &mut Vec<DocumentChange>,
+    ) -> Result<()> {
+        let mut query = self.target.query.clone();
+        let since = FilterPlan {
+            server: vec![Condition {
+                field: field.to_string(),
+                op: Operator::Field(FieldOperator::GreaterThanOrEqual),
+                value: FirestoreType::Timestamp(Timestamp::new(since)),
+            }],
+            client: Vec::new(),
+        };
+        since.apply(&mut query);
+        for document in ctx.query_stream(database_name, self.target.parent.clone(), query) {
+            self.written(document?, changes);
+        }
+        Ok(())
+    }
+
+    // How many documents the query returns, `None` when the database cannot tell
+    fn count(&self, ctx: &DatabaseContext, database_name: &str) -> Result<Option<usize>> {
+        let counted = ctx.run_aggregation_query(
+            database_name,
+            self.target.parent.as_ref(),
+            &self.target.query,
+            vec![Aggregation::count(COUNT_ALIAS)],
+        );
+        match counted {
+            Ok(fields) => match fields.get(COUNT_ALIAS) {
+                Some(FirestoreType::Integer(count)) => Ok(Some(*count as usize)),
+                // a response without the aggregate counted nothing
+                _ => Ok(Some(0)),
+            },
+            // emulators without aggregations reconcile on every poll instead
+            Err(ref e)
+                if e.status() == Some(StatusCode::NOT_FOUND)
+                    || e.status() == Some(StatusCode::NOT_IMPLEMENTED) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Lists the names and update times of the target's documents, then reads those
+    // that are new or changed and drops those that are gone
+    fn reconcile(
+        &mut self,
+        ctx: &DatabaseContext,
+        database_name: &str,
+        changes: &mut Vec<DocumentChange>,
+    ) -> Result<()> {
+        let mut query = self.target.query.clone();
+        // the cursors of the listing need the fields it is ordered by
+        let mut fields = vec![DOCUMENT_NAME_FIELD.to_string()];
+        fields.extend(
+            query
+                .order_by
+                .iter()
+                .map(|order| order.field.field_path.clone()),
+        );
+        query.select = Some(Projection::new(&fields));
+        let mut listed = HashSet::new();
+        let mut stale = Vec::new();
+        for document in ctx.query_stream(database_name, self.target.parent.clone(), query) {
+            let document = document?;
+            let name = document.name().to_string();
+            if self.present.get(&name) != Some(&document.update_time()) {
+                stale.push(name.clone());
+            }
+            listed.insert(name);
+        }
+        self.remove_unlisted(&listed, changes);
+        if stale.is_empty() {
+            return Ok(());
+        }
+        for lookup in ctx.batch_get_documents(stale, database_name)? {
+            match lookup {
+                Lookup::Found(document) => self.written(document, changes),
+                // deleted since it was listed
+                Lookup::Missing(name) => self.removed(&*name, changes),
+            }
+        }
+        Ok(())
+    }
+
+    fn written(&mut self, document: Document, changes: &mut Vec<DocumentChange>) {
+        if let Some(field) = &self.target.updated_field {
+            if let Some(FirestoreType::Timestamp(time)) = document.fields().get_path(&**field) {
+                let time = time.time();
+                if self.updated_up_to.map_or(true, |up_to| time > up_to) {
+                    self.updated_up_to = Some(time);
+                }
+            }
+        }
+        let name = document.name().to_string();
+        let update_time = document.update_time();
+        if self.present.insert(name.clone(), update_time) == Some(update_time) {
+            return;
+        }
+        if self.target.filter.matches(&document) {
+            let before = self.states.insert(document.clone());
+            if self.members.insert(name) {
+                changes.push(DocumentChange::Added(document));
+            } else {
+                changes.push(DocumentChange::Modified {
+                    before,
+                    after: document,
+                });
+            }
+        } else if self.members.remove(&name) {
+            let before = self.states.remove(&name);
+            changes.push(DocumentChange::Removed { name, before });
+        }
+    }
+
+    fn removed(&mut self, name: &str, changes: &mut Vec<DocumentChange>) {
+        self.present.remove(name);
+        if self.members.remove(name) {
+            changes.push(DocumentChange::Removed {
+                name: name.to_string(),
+                before: self.states.remove(name),
+            });
+        }
+    }
+
+    fn remove_unlisted(&mut self, listed: &HashSet<String>, changes: &mut Vec<DocumentChange>) {
+        let gone = self
+            .present
+            .keys()
+            .filter(|name| !listed.contains(*name))
+            .cloned()
+            .collect::<Vec<String>>();
+        for name in gone {
+            self.removed(&*name, changes);
+        }
+    }
+}
+
+/// Yields the changes to the documents of several targets, each with the label of its
+/// target, polling them all in turn every interval. The first poll of a target reads
+/// it in full and reports an `Added` for each document, unless told to only take
+/// stock or seeded with the documents the caller already holds, when only the
+/// differences with those are reported. A document written more than once between
+/// two polls is reported once, in its latest state, and with the state it had when
+/// last read for as many documents of each target as the cache holds.
+///
+/// The stream only ends with an error. A `CancellationToken` ends it with
+/// `Error::Cancelled` between polls.
+pub struct ChangeStream<'a> {
+    context: &'a DatabaseContext,
+    database_name: String,
+    targets: Vec<TargetState>,
+    cache_size: usize,
+    interval: Duration,
+    report_existing: bool,
+    polled: bool,
+    buffer: VecDeque<(String, DocumentChange)>,
+    cancellation: Option<CancellationToken>,
+    finished: bool,
+}
+
+impl<'a> ChangeStream<'a> {
+    pub(crate) fn new(context: &'a DatabaseContext, database_name: &str) -> ChangeStream<'a> {
+        ChangeStream {
+            context,
+            database_name: database_name.to_string(),
+            targets: Vec::new(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            interval: DEFAULT_INTERVAL,
+            report_existing: true,
+            polled: false,
+            buffer: VecDeque::new(),
+            cancellation: None,
+            finished: false,
+        }
+    }
+
+    /// Watches `target` as well
+    pub fn target(mut self, target: WatchTarget) -> ChangeStream<'a> {
+        self.targets.push(TargetState::new(target, self.cache_size));
+        self
+    }
+
+    /// Keeps the last state of up to `documents` documents of each target for the
+    /// `before` of their changes, 10,000 unless set
+    pub fn cache_size(mut self, documents: usize) -> ChangeStream<'a> {
+        self.cache_size = documents;
+        for state in self.targets.iter_mut() {
+            state.states = StateCache::new(documents);
+        }
+        self
+    }
+
+    /// Starts the target labelled `label` from `documents` rather than from nothing,
+    /// so its first poll reports what changed since the caller read them
+    pub fn seed<I: IntoIterator<Item = Document>>(
+        mut self,
+        label: &str,
+        documents: I,
+    ) -> ChangeStream<'a> {
+        if let Some(state) = self
+            .targets
+            .iter_mut()
+            .find(|state| state.target.label == label)
+        {
+            for document in documents {
+                let name = document.name().to_string();
+                state.present.insert(name.clone(), document.update_time());
+                if state.target.filter.matches(&document) {
+                    state.members.insert(name);
+                    state.states.insert(document);
+                }
+            }
+            state.seeded = true;
+        }
+        self
+    }
+
+    /// Time between polls, 30 seconds unless set
+    pub fn interval(mut self, interval: Duration) -> ChangeStream<'a> {
+        self.interval = interval;
+        self
+    }
+
+    /// Whether the first poll of a target reports the documents it already holds,
+    /// as it does unless set
+    pub fn report_existing(mut self, report_existing: bool) -> ChangeStream<'a> {
+        self.report_existing = report_existing;
+        self
+    }
+
+    /// Ends the stream once `token` is cancelled
+    pub fn cancel_on(mut self, token: CancellationToken) -> ChangeStream<'a> {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Polls every target once, right away, and returns their changes. For callers
+    /// that act once per poll rather than per change; iterating polls on its own.
+    pub fn poll(&mut self) -> Result<Vec<(String, DocumentChange)>> {
+        let mut polled = Vec::new();
+        for state in self.targets.iter_mut() {
+            let mut changes = Vec::new();
+            let first = !state.listed;
+            state.poll(self.context, &*self.database_name, &mut changes)?;
+            if first && !state.seeded && !self.report_existing {
+                continue;
+            }
+            let label = &state.target.label;
+            polled.extend(changes.into_iter().map(|change| (label.clone(), change)));
+        }
+        self.polled = true;
+        Ok(polled)
+    }
+
+    fn sleep(&self) -> Result<()> {
+        let started = Instant::now();
+        loop {
+            if let Some(token) = &self.cancellation {
+                token.check("watch")?;
+            }
+            let left = self
+                .interval
+                .checked_sub(started.elapsed())
+                .unwrap_or_default();
+            if left == Duration::default() {
+                return Ok(());
+            }
+            thread::sleep(left.min(CANCEL_CHECK));
+        }
+    }
+
+    fn step(&mut self) -> Result<()> {
+        if self.polled {
+            self.sleep()?;
+        } else if let Some(token) = &self.cancellation {
+            token.check("watch")?;
+        }
+        let changes = self.poll()?;
+        self.buffer.extend(changes);
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ChangeStream<'a> {
+    type Item = Result<(String, DocumentChange)>;
+
+    fn next(&mut self) -> Option<Result<(String, DocumentChange)>> {
+        if self.finished {
+            return None;
+        }
+        while self.buffer.is_empty() {
+            if let Err(e) = self.step() {
+                // reported once
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PREFIX: &'static str = "projects/p/databases/(default)/documents/users/";
+
+    // A user document as the stream reads it, `version` standing in for its update time
+    fn user(id: &str, version: u32, status: &str) -> Document {
+        serde_json::from_value(json!({
+            "name": format!("{}{}", PREFIX, id),
+            "fields": { "status": { "stringValue": status } },
+            "createTime": "2019-06-01T00:00:00Z",
+            "updateTime": format!("2019-06-01T00:00:{:02}Z", version),
+        }))
+        .unwrap()
+    }
+
+    fn target(conditions: &[&str]) -> TargetState {
+        let conditions = conditions
+            .iter()
+            .map(|condition| condition.parse().unwrap())
+            .collect();
+        let target = WatchTarget::collection("users", FilterPlan::client_only(conditions));
+        TargetState::new(target.unwrap(), DEFAULT_CACHE_SIZE)
+    }
+
+    // A poll listing `documents` as the whole target, as the first one does
+    fn poll(state: &mut TargetState, documents: Vec<Document>) -> Vec<DocumentChange> {
+        let mut changes = Vec::new();
+        let listed = documents
+            .iter()
+            .map(|document| document.name().to_string())
+            .collect::<HashSet<String>>();
+        for document in documents {
+            state.written(document, &mut changes);
+        }
+        state.remove_unlisted(&listed, &mut changes);
+        changes
+    }
+
+    // The kind, document id and update seconds before and after of each change
+    fn summary(
+        changes: &[DocumentChange],
+    ) -> Vec<(&'static str, String, Option<u32>, Option<u32>)> {
+        let seconds = |document: Option<&Document>| {
+            document.map(|document| document.update_time().timestamp() as u32 % 60)
+        };
+        let mut summary = changes
+            .iter()
+            .map(|change| {
+                let kind = match change {
+                    DocumentChange::Added(_) => "added",
+                    DocumentChange::Modified { .. } => "modified",
+                    DocumentChange::Removed { .. } => "removed",
+                };
+                let id = change.name().trim_start_matches(PREFIX).to_string();
+                (kind, id, seconds(change.before()), seconds(change.after()))
+            })
+            .collect::<Vec<_>>();
+        summary.sort_by(|a, b| a.1.cmp(&b.1));
+        summary
+    }
+
+    #[test]
+    fn polls_report_what_changed_since_the_previous_one() {
+        let mut state = target(&[]);
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![user("a", 1, "new"), user("b", 1, "new")]
+            )),
+            vec![
+                ("added", "a".to_string(), None, Some(1)),
+                ("added", "b".to_string(), None, Some(1)),
+            ]
+        );
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![
+                    user("a", 2, "old"),
+                    user("b", 1, "new"),
+                    user("c", 1, "new")
+                ]
+            )),
+            vec![
+                ("modified", "a".to_string(), Some(1), Some(2)),
+                ("added", "c".to_string(), None, Some(1)),
+            ]
+        );
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![user("a", 2, "old"), user("c", 1, "new")]
+            )),
+            vec![("removed", "b".to_string(), Some(1), None)]
+        );
+        // nothing written, nothing reported
+        assert!(poll(&mut state, vec![user("a", 2, "old"), user("c", 1, "new")]).is_empty());
+    }
+
+    #[test]
+    fn modified_documents_carry_both_states() {
+        let mut state = target(&[]);
+        poll(&mut state, vec![user("a", 1, "new")]);
+        let changes = poll(&mut state, vec![user("a", 2, "old")]);
+        match &changes[..] {
+            [DocumentChange::Modified { before, after }] => {
+                let status = |document: &Document| document.fields().get("status").cloned();
+                assert_eq!(
+                    status(before.as_ref().unwrap()).map(|s| s.to_json()),
+                    Some(json!("new"))
+                );
+                assert_eq!(status(after).map(|s| s.to_json()), Some(json!("old")));
+            }
+            other => panic!("expected one modification, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn removed_then_re_added_documents_are_added_again() {
+        let mut state = target(&[]);
+        poll(&mut state, vec![user("a", 1, "new")]);
+        assert_eq!(
+            summary(&poll(&mut state, Vec::new())),
+            vec![("removed", "a".to_string(), Some(1), None)]
+        );
+        // the earlier state went with the document, it is not the new one's before
+        assert_eq!(
+            summary(&poll(&mut state, vec![user("a", 3, "new")])),
+            vec![("added", "a".to_string(), None, Some(3))]
+        );
+    }
+
+    #[test]
+    fn documents_move_in_and_out_of_the_filter() {
+        let mut state = target(&["status == \"open\""]);
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![user("a", 1, "open"), user("b", 1, "closed")]
+            )),
+            vec![("added", "a".to_string(), None, Some(1))]
+        );
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![user("a", 2, "closed"), user("b", 2, "open")]
+            )),
+            vec![
+                ("removed", "a".to_string(), Some(1), None),
+                ("added", "b".to_string(), None, Some(2)),
+            ]
+        );
+        // deleting a document that never matched is no change to the target
+        assert!(poll(&mut state, vec![user("b", 2, "open")]).is_empty());
+    }
+
+    #[test]
+    fn deletes_of_unseen_documents_are_ignored() {
+        let mut state = target(&[]);
+        poll(&mut state, vec![user("a", 1, "new")]);
+        let mut changes = Vec::new();
+        state.removed(&*format!("{}never", PREFIX), &mut changes);
+        assert!(changes.is_empty());
+        state.removed(&*format!("{}a", PREFIX), &mut changes);
+        assert_eq!(
+            summary(&changes),
+            vec![("removed", "a".to_string(), Some(1), None)]
+        );
+        // a second delete of the same document is not reported twice
+        changes.clear();
+        state.removed(&*format!("{}a", PREFIX), &mut changes);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn the_updated_field_tracks_the_latest_write() {
+        let mut state = target(&[]);
+        state.target.updated_field = Some("updatedAt".to_string());
+        let written = |id: &str, second: u32| -> Document {
+            serde_json::from_value(json!({
+                "name": format!("{}{}", PREFIX, id),
+                "fields": {
+                    "updatedAt": { "timestampValue": format!("2019-06-02T00:00:{:02}Z", second) }
+                },
+                "createTime": "2019-06-01T00:00:00Z",
+                "updateTime": format!("2019-06-01T00:00:{:02}Z", second),
+            }))
+            .unwrap()
+        };
+        poll(
+            &mut state,
+            vec![written("a", 5), written("b", 9), written("c", 7)],
+        );
+        assert_eq!(
+            state.updated_up_to.map(|time| time.to_rfc3339()),
+            Some("2019-06-02T00:00:09+00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn the_cache_returns_the_state_it_replaces() {
+        let mut cache = StateCache::new(10);
+        assert!(cache.insert(user("a", 1, "new")).is_none());
+        let before = cache.insert(user("a", 2, "old")).unwrap();
+        assert_eq!(before.update_time().timestamp() % 60, 1);
+        assert_eq!(
+            cache
+                .remove(&*format!("{}a", PREFIX))
+                .map(|d| d.update_time().timestamp() % 60),
+            Some(2)
+        );
+        assert!(cache.remove(&*format!("{}a", PREFIX)).is_none());
+    }
+
+    #[test]
+    fn the_cache_forgets_the_documents_written_longest_ago() {
+        let mut cache = StateCache::new(2);
+        cache.insert(user("a", 1, "new"));
+        cache.insert(user("b", 1, "new"));
+        cache.insert(user("c", 1, "new"));
+        assert_eq!(cache.states.len(), 2);
+        assert!(cache.remove(&*format!("{}a", PREFIX)).is_none());
+        assert!(cache.remove(&*format!("{}b", PREFIX)).is_some());
+        assert!(cache.remove(&*format!("{}c", PREFIX)).is_some());
+    }
+
+    #[test]
+    fn the_cache_evicts_by_latest_write() {
+        let mut cache = StateCache::new(2);
+        cache.insert(user("a", 1, "new"));
+        cache.insert(user("b", 1, "new"));
+        // writing a again makes b the one written longest ago
+        cache.insert(user("a", 2, "old"));
+        cache.insert(user("c", 1, "new"));
+        assert_eq!(cache.states.len(), 2);
+        assert!(cache.remove(&*format!("{}b", PREFIX)).is_none());
+        assert!(cache.remove(&*format!("{}a", PREFIX)).is_some());
+        assert!(cache.remove(&*format!("{}c", PREFIX)).is_some());
+        // stale entries of the order do not pile up past the bound
+        assert!(cache.order.len() <= 2);
+    }
+
+    #[test]
+    fn evicted_documents_are_modified_without_a_before() {
+        let mut state = target(&[]);
+        state.states = StateCache::new(1);
+        // the cache holds only b once both are read
+        poll(&mut state, vec![user("a", 1, "new"), user("b", 1, "new")]);
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![user("a", 1, "new"), user("b", 2, "old")]
+            )),
+            vec![("modified", "b".to_string(), Some(1), Some(2))]
+        );
+        assert_eq!(
+            summary(&poll(
+                &mut state,
+                vec![user("a", 2, "old"), user("b", 2, "old")]
+            )),
+            vec![("modified", "a".to_string(), None, Some(2))]
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+/// Overrides the location of the audit log
+const AUDIT_LOG_KEY: &'static str = "FIRESALE_AUDIT_LOG";
+const DEFAULT_AUDIT_LOG: &'static str = ".firesale_audit.jsonl";
+
+/// One destructive operation performed by the CLI, stored as a single JSON line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub user: String,
+    pub timestamp: DateTime<Utc>,
+    pub project_id: String,
+    pub database: String,
+    pub operation: String,
+    pub paths: Vec<String>,
+    pub count: usize,
+}
+
+impl Entry {
+    pub fn new<S>(project_id: S, database: S, operation: S, paths: Vec<String>) -> Entry
+    where
+        S: Into<String>,
+    {
+        Entry {
+            user: current_user(),
+            timestamp: Utc::now(),
+            project_id: project_id.into(),
+            database: database.into(),
+            operation: operation.into(),
+            count: paths.len(),
+            paths,
+        }
+    }
+}
+
+/// Best effort name of whoever is running the CLI
+pub fn current_user() -> String {
+    use std::env;
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// Location of the audit log, `$FIRESALE_AUDIT_LOG` or a file in the home directory
+pub fn log_path() -> PathBuf {
+    use std::env;
+    if let Ok(path) = env::var(AUDIT_LOG_KEY) {
+        return PathBuf::from(path);
+    }
+    let home = env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .unwrap_or_else(|_| String::from("."));
+    PathBuf::from(home).join(DEFAULT_AUDIT_LOG)
+}
+
+/// Appends `entry` to the audit log. Failing to audit never fails the operation
+/// itself, the error is reported on stderr instead.
+pub fn record(entry: &Entry) {
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())
+        .and_then(|mut file| {
+            let line = serde_json::to_string(entry)?;
+            writeln!(file, "{}", line)
+        });
+    if let Err(e) = result {
+        eprintln!("warning: failed to write audit log: {}", e);
+    }
+}
+
+/// Prints the most recent `limit` entries of the audit log, oldest first
+pub fn show(limit: Option<usize>) -> io::Result<()> {
+    let file = match OpenOptions::new().read(true).open(log_path()) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("audit log is empty");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let entries = BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<Entry>(&*line).ok())
+        .collect::<Vec<Entry>>();
+    let skip = limit.map_or(0, |limit| entries.len().saturating_sub(limit));
+    for entry in entries.iter().skip(skip) {
+        println!(
+            "{} {} {}/{} {} ({} document(s))",
+            entry.timestamp.to_rfc3339(),
+            entry.user,
+            entry.project_id,
+            entry.database,
+            entry.operation,
+            entry.count
+        );
+        for path in &entry.paths {
+            println!("    {}", path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use crate::errors::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Asks a long operation to stop at its next safe point. Clones share the same
+/// flag, so an embedding application keeps one and hands the others to the
+/// operations it may want to abort, from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `Error::Cancelled` once the token is cancelled, for operations to
+    /// call between steps
+    pub fn check(&self, operation: &str) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled {
+                operation: operation.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
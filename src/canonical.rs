@@ -0,0 +1,161 @@
+//! Deterministic JSON for comparing and hashing documents. Values are kept in
+//! Firestore wire format so integers and doubles stay distinct, object keys are
+//! sorted and timestamps are rewritten at a single precision.
+
+use crate::api::{json_to_wire, FirestoreFields};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+const TIMESTAMP_VALUE: &'static str = "timestampValue";
+const INTEGER_VALUE: &'static str = "integerValue";
+const DOUBLE_VALUE: &'static str = "doubleValue";
+
+/// Canonical form of a document's fields as returned by the API
+pub fn canonical_fields(fields: &FirestoreFields) -> Value {
+    canonicalize(serde_json::to_value(fields).unwrap_or(Value::Null))
+}
+
+/// Canonical form of plain JSON fields, as they would be written by the CLI
+pub fn canonical_json(fields: &Map<String, Value>) -> Value {
+    let wire = fields
+        .iter()
+        .map(|(key, value)| (key.clone(), json_to_wire(value)))
+        .collect::<Map<String, Value>>();
+    canonicalize(Value::Object(wire))
+}
+
+/// Normalizes a wire format value: sorts keys, rewrites timestamps with nanosecond
+/// precision in UTC and integers without leading zeros or signs
+pub fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries = map.into_iter().collect::<Vec<(String, Value)>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = normalize_scalar(&*key, value);
+                        (key, canonicalize(value))
+                    })
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonicalize).collect()),
+        scalar => scalar,
+    }
+}
+
+fn normalize_scalar(key: &str, value: Value) -> Value {
+    match (key, value) {
+        (TIMESTAMP_VALUE, Value::String(time)) => match DateTime::parse_from_rfc3339(&*time) {
+            Ok(time) => Value::String(
+                time.with_timezone(&Utc)
+                    .to_rfc3339_opts(SecondsFormat::Nanos, true),
+            ),
+            Err(_) => Value::String(time),
+        },
+        (INTEGER_VALUE, Value::String(integer)) => match integer.parse::<i64>() {
+            Ok(integer) => Value::String(integer.to_string()),
+            Err(_) => Value::String(integer),
+        },
+        (INTEGER_VALUE, Value::Number(integer)) => Value::String(integer.to_string()),
+        (DOUBLE_VALUE, Value::Number(double)) => match double.as_f64() {
+            Some(double) => Value::from(double),
+            None => Value::Number(double),
+        },
+        (_, value) => value,
+    }
+}
+
+/// Compact serialization of an already canonical value
+pub fn to_canonical_string(value: &Value) -> String {
+    // serde_json keeps object keys ordered, so compact output is stable
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// Stable 64 bit FNV-1a checksum of a canonical value, as 16 hex digits. Unlike
+/// `std::hash` this is identical across machines and compiler versions.
+pub fn checksum(value: &Value) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let hash = to_canonical_string(value)
+        .bytes()
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn canonical(text: &str) -> String {
+        to_canonical_string(&canonicalize(serde_json::from_str(text).unwrap()))
+    }
+
+    #[test]
+    fn keys_are_sorted_at_every_level() {
+        let text = r#"{"b":{"mapValue":{"fields":{"z":{"nullValue":null},"a":{"booleanValue":true}}}},"a":{"stringValue":"x"}}"#;
+        assert_eq!(
+            canonical(text),
+            r#"{"a":{"stringValue":"x"},"b":{"mapValue":{"fields":{"a":{"booleanValue":true},"z":{"nullValue":null}}}}}"#
+        );
+        let reordered = r#"{"a":{"stringValue":"x"},"b":{"mapValue":{"fields":{"a":{"booleanValue":true},"z":{"nullValue":null}}}}}"#;
+        assert_eq!(canonical(text), canonical(reordered));
+    }
+
+    #[test]
+    fn timestamps_share_one_precision_in_utc() {
+        let timestamp = |time: &str| canonicalize(json!({ "t": { "timestampValue": time } }));
+        let expected = json!({ "t": { "timestampValue": "2019-06-01T00:00:00.500000000Z" } });
+        assert_eq!(timestamp("2019-06-01T00:00:00.5Z"), expected);
+        assert_eq!(timestamp("2019-06-01T00:00:00.500000Z"), expected);
+        assert_eq!(timestamp("2019-06-01T02:00:00.5+02:00"), expected);
+        assert_eq!(
+            timestamp("2019-06-01T00:00:00Z"),
+            json!({ "t": { "timestampValue": "2019-06-01T00:00:00.000000000Z" } })
+        );
+        // text that is not a timestamp is left alone rather than dropped
+        assert_eq!(
+            timestamp("yesterday"),
+            json!({ "t": { "timestampValue": "yesterday" } })
+        );
+    }
+
+    #[test]
+    fn integers_and_doubles_stay_distinct() {
+        let integer = canonical_json(json!({ "n": 1 }).as_object().unwrap());
+        let double = canonical_json(json!({ "n": 1.0 }).as_object().unwrap());
+        assert_eq!(integer, json!({ "n": { "integerValue": "1" } }));
+        assert_eq!(double, json!({ "n": { "doubleValue": 1.0 } }));
+        assert_ne!(to_canonical_string(&integer), to_canonical_string(&double));
+        assert_ne!(checksum(&integer), checksum(&double));
+    }
+
+    #[test]
+    fn integers_are_written_one_way() {
+        let integer = |value: Value| canonicalize(json!({ "n": { "integerValue": value } }));
+        let expected = json!({ "n": { "integerValue": "7" } });
+        assert_eq!(integer(json!("7")), expected);
+        assert_eq!(integer(json!("007")), expected);
+        assert_eq!(integer(json!("+7")), expected);
+        assert_eq!(integer(json!(7)), expected);
+    }
+
+    #[test]
+    fn checksums_ignore_key_order() {
+        let a = canonical_json(json!({ "x": 1, "y": [true, "s"] }).as_object().unwrap());
+        let b = canonical_json(json!({ "y": [true, "s"], "x": 1 }).as_object().unwrap());
+        assert_eq!(checksum(&a), checksum(&b));
+        assert_eq!(checksum(&a).len(), 16);
+        assert_ne!(
+            checksum(&a),
+            checksum(&canonical_json(
+                json!({ "x": 2, "y": [true, "s"] }).as_object().unwrap()
+            ))
+        );
+    }
+}
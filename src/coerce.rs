@@ -0,0 +1,367 @@
+use crate::dump::relative_path;
+use crate::groupby::display;
+use crate::output::{OutputFormat, Reporter};
+use crate::planner::WritePlanner;
+use chrono::{DateTime, TimeZone, Utc};
+use libfiresale::api::{
+    field_path, DatabaseContext, Double, FirestoreType, Precondition, Timestamp, Write,
+};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use libfiresale::report::{Finding, Report};
+use serde_json::{json, Map};
+
+/// Writes per commit, the most Firestore accepts in one
+const BATCH_SIZE: usize = 500;
+
+/// The type `fix-types` converts a field to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Integer,
+    Double,
+    String,
+    Boolean,
+    Timestamp,
+}
+
+impl Target {
+    pub fn parse(target: &str) -> Result<Target> {
+        match target {
+            "integer" => Ok(Target::Integer),
+            "double" => Ok(Target::Double),
+            "string" => Ok(Target::String),
+            "boolean" => Ok(Target::Boolean),
+            "timestamp" => Ok(Target::Timestamp),
+            _ => Err(Error::InvalidInput {
+                message: format!(
+                    "unknown type {:?}, expected integer, double, string, boolean or timestamp",
+                    target
+                ),
+            }),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Target::Integer => "integer",
+            Target::Double => "double",
+            Target::String => "string",
+            Target::Boolean => "boolean",
+            Target::Timestamp => "timestamp",
+        }
+    }
+
+    fn holds(self, value: &FirestoreType) -> bool {
+        match (self, value) {
+            (Target::Integer, FirestoreType::Integer(_))
+            | (Target::Double, FirestoreType::Double(_))
+            | (Target::String, FirestoreType::String(_))
+            | (Target::Boolean, FirestoreType::Boolean(_))
+            | (Target::Timestamp, FirestoreType::Timestamp(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// `value` as `target`, `None` when it cannot be converted. Only exact conversions are
+/// made unless `lossy`, which also rounds doubles to integers, reads 0 and 1, yes and
+/// no as booleans and integers as seconds since the epoch.
+fn convert(value: &FirestoreType, target: Target, lossy: bool) -> Option<FirestoreType> {
+    match (target, value) {
+        (Target::Integer, FirestoreType::Double(d)) if d.value().is_finite() => {
+            let rounded = d.value().round();
+            if rounded == d.value() || lossy {
+                Some(FirestoreType::Integer(rounded as i64))
+            } else {
+                None
+            }
+        }
+        (Target::Integer, FirestoreType::String(text)) => match text.trim().parse::<i64>() {
+            Ok(integer) => Some(FirestoreType::Integer(integer)),
+            Err(_) if lossy => text
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|double| double.is_finite())
+                .map(|double| FirestoreType::Integer(double.round() as i64)),
+            Err(_) => None,
+        },
+        (Target::Integer, FirestoreType::Boolean(b)) if lossy => {
+            Some(FirestoreType::Integer(if *b { 1 } else { 0 }))
+        }
+        (Target::Double, FirestoreType::Integer(integer)) => {
+            Some(FirestoreType::Double(Double::new(*integer as f64)))
+        }
+        (Target::Double, FirestoreType::String(text)) => text
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|double| double.is_finite())
+            .map(|double| FirestoreType::Double(Double::new(double))),
+        (Target::String, FirestoreType::Integer(_))
+        | (Target::String, FirestoreType::Double(_))
+        | (Target::String, FirestoreType::Boolean(_)) => {
+            Some(FirestoreType::String(display(value)))
+        }
+        (Target::String, FirestoreType::Timestamp(time)) => {
+            Some(FirestoreType::String(time.time().to_rfc3339()))
+        }
+        (Target::Boolean, FirestoreType::String(text)) => {
+            match (&*text.trim().to_lowercase(), lossy) {
+                ("true", _) | ("yes", true) | ("1", true) => Some(FirestoreType::Boolean(true)),
+                ("false", _) | ("no", true) | ("0", true) => Some(FirestoreType::Boolean(false)),
+                _ => None,
+            }
+        }
+        (Target::Boolean, FirestoreType::Integer(integer)) if lossy && *integer == 0 => {
+            Some(FirestoreType::Boolean(false))
+        }
+        (Target::Boolean, FirestoreType::Integer(integer)) if lossy && *integer == 1 => {
+            Some(FirestoreType::Boolean(true))
+        }
+        (Target::Timestamp, FirestoreType::String(text)) => {
+            DateTime::parse_from_rfc3339(text.trim())
+                .ok()
+                .map(|time| FirestoreType::Timestamp(Timestamp::new(time.with_timezone(&Utc))))
+        }
+        (Target::Timestamp, FirestoreType::Integer(seconds)) if lossy => Utc
+            .timestamp_opt(*seconds, 0)
+            .single()
+            .map(|time| FirestoreType::Timestamp(Timestamp::new(time))),
+        _ => None,
+    }
+}
+
+/// Converts the top level `field` of every document of `collection` to `target`, patching
+/// only that field and only if the document was not written since it was read. Values
+/// that cannot be converted are reported as `not_coercible` findings and left alone, as
+/// are null and missing ones. With `--dry-run` the patches are printed instead, which
+/// previews both the conversions and what would be left behind.
+pub fn fix_types(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    collection: &str,
+    field: &str,
+    target: Target,
+    lossy: bool,
+    format: &OutputFormat,
+) -> Result<Report> {
+    let collection = collection.trim_matches('/');
+    let database_name = &*planner.database_name;
+    let path = CollectionPath::parse(collection)?;
+    let query = StructuredQuery::collection(path.collection_id());
+    let mut reporter = Reporter::new("fix-types", format);
+    let (mut converted, mut typed, mut empty, mut failed) = (0, 0, 0, 0);
+    let mut batch = Vec::new();
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        let document = document?;
+        let value = match document.fields().get(field) {
+            None | Some(FirestoreType::Null) => {
+                empty += 1;
+                continue;
+            }
+            Some(value) if target.holds(value) => {
+                typed += 1;
+                continue;
+            }
+            Some(value) => value,
+        };
+        let subject = relative_path(document.name());
+        match convert(value, target, lossy) {
+            Some(value) => {
+                let write = ctx
+                    .update_write(database_name, &*subject, &Map::new())
+                    .with_field(field, &value)
+                    .with_update_mask(vec![field_path(&[field])])
+                    .with_precondition(Precondition::UpdateTime(document.update_time()));
+                batch.push(write);
+                converted += 1;
+                if batch.len() == BATCH_SIZE {
+                    commit(ctx, planner, &mut batch)?;
+                }
+            }
+            None => {
+                failed += 1;
+                let message = format!(
+                    "{}: {} {} is not {}",
+                    subject,
+                    field,
+                    display(value),
+                    if lossy {
+                        format!("convertible to {}", target.name())
+                    } else {
+                        format!("exactly convertible to {}, try --coerce", target.name())
+                    }
+                );
+                let finding = Finding::new("not_coercible", &*subject, &*message)
+                    .with_field(field)
+                    .with_details(json!({ "value": value, "to": target.name() }));
+                reporter.found(finding, message);
+            }
+        }
+    }
+    commit(ctx, planner, &mut batch)?;
+    let prefix = if planner.dry_run { "[dry-run] " } else { "" };
+    reporter.say(format!(
+        "{}{}.{}: {} converted to {}, {} already {}, {} null or missing, {} not convertible",
+        prefix,
+        collection,
+        field,
+        converted,
+        target.name(),
+        typed,
+        target.name(),
+        empty,
+        failed
+    ));
+    reporter.count("converted", converted);
+    reporter.count("already_typed", typed);
+    reporter.count("empty", empty);
+    reporter.count("not_coercible", failed);
+    reporter.finish()
+}
+
+fn commit(ctx: &DatabaseContext, planner: &WritePlanner, batch: &mut Vec<Write>) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let writes = batch.drain(..).collect::<Vec<Write>>();
+    planner.apply(ctx, "fix-types", writes, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn integer(i: i64) -> FirestoreType {
+        FirestoreType::Integer(i)
+    }
+
+    fn double(d: f64) -> FirestoreType {
+        FirestoreType::Double(Double::new(d))
+    }
+
+    fn string(text: &str) -> FirestoreType {
+        FirestoreType::String(text.to_string())
+    }
+
+    // The converted value as plain JSON, after checking it has the type asked for
+    fn exact(value: FirestoreType, target: Target) -> Option<Value> {
+        converted(value, target, false)
+    }
+
+    fn lossy(value: FirestoreType, target: Target) -> Option<Value> {
+        converted(value, target, true)
+    }
+
+    fn converted(value: FirestoreType, target: Target, lossy: bool) -> Option<Value> {
+        convert(&value, target, lossy).map(|value| {
+            assert!(target.holds(&value), "{:?} is not {}", value, target.name());
+            value.to_json()
+        })
+    }
+
+    #[test]
+    fn targets() {
+        for name in &["integer", "double", "string", "boolean", "timestamp"] {
+            assert_eq!(Target::parse(name).unwrap().name(), *name);
+        }
+        let error = Target::parse("number").unwrap_err().to_string();
+        assert!(error.contains("unknown type \"number\""), "{}", error);
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(exact(double(3.0), Target::Integer), Some(json!(3)));
+        assert_eq!(exact(double(2.5), Target::Integer), None);
+        assert_eq!(lossy(double(2.6), Target::Integer), Some(json!(3)));
+        assert_eq!(lossy(double(std::f64::NAN), Target::Integer), None);
+        assert_eq!(exact(string(" 42 "), Target::Integer), Some(json!(42)));
+        assert_eq!(exact(string("4.2"), Target::Integer), None);
+        assert_eq!(lossy(string("4.2"), Target::Integer), Some(json!(4)));
+        assert_eq!(lossy(string("lots"), Target::Integer), None);
+        assert_eq!(exact(FirestoreType::Boolean(true), Target::Integer), None);
+        assert_eq!(
+            lossy(FirestoreType::Boolean(true), Target::Integer),
+            Some(json!(1))
+        );
+    }
+
+    #[test]
+    fn doubles() {
+        assert_eq!(exact(integer(7), Target::Double), Some(json!(7.0)));
+        assert_eq!(exact(string("1.5"), Target::Double), Some(json!(1.5)));
+        assert_eq!(exact(string("inf"), Target::Double), None);
+        assert_eq!(lossy(string("one"), Target::Double), None);
+        assert_eq!(lossy(FirestoreType::Boolean(true), Target::Double), None);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(exact(integer(7), Target::String), Some(json!("7")));
+        assert_eq!(exact(double(1.5), Target::String), Some(json!("1.5")));
+        assert_eq!(
+            exact(FirestoreType::Boolean(false), Target::String),
+            Some(json!("false"))
+        );
+        let time = Utc.ymd(2019, 6, 1).and_hms(12, 0, 0);
+        assert_eq!(
+            exact(
+                FirestoreType::Timestamp(Timestamp::new(time)),
+                Target::String
+            ),
+            Some(json!("2019-06-01T12:00:00+00:00"))
+        );
+        assert_eq!(exact(FirestoreType::Null, Target::String), None);
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(exact(string("True"), Target::Boolean), Some(json!(true)));
+        assert_eq!(
+            exact(string(" false "), Target::Boolean),
+            Some(json!(false))
+        );
+        for text in &["yes", "no", "1", "0"] {
+            assert_eq!(exact(string(text), Target::Boolean), None);
+        }
+        assert_eq!(lossy(string("yes"), Target::Boolean), Some(json!(true)));
+        assert_eq!(lossy(string("0"), Target::Boolean), Some(json!(false)));
+        assert_eq!(lossy(string("maybe"), Target::Boolean), None);
+        assert_eq!(exact(integer(1), Target::Boolean), None);
+        assert_eq!(lossy(integer(1), Target::Boolean), Some(json!(true)));
+        assert_eq!(lossy(integer(0), Target::Boolean), Some(json!(false)));
+        assert_eq!(lossy(integer(2), Target::Boolean), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        let seconds =
+            |text: FirestoreType, lossy: bool| match convert(&text, Target::Timestamp, lossy) {
+                Some(FirestoreType::Timestamp(time)) => Some(time.time().timestamp()),
+                _ => None,
+            };
+        assert_eq!(
+            seconds(string("2019-06-01T12:00:00Z"), false),
+            Some(1_559_390_400)
+        );
+        assert_eq!(
+            seconds(string("2019-06-01T14:00:00+02:00"), false),
+            Some(1_559_390_400)
+        );
+        assert_eq!(seconds(string("June 1st"), true), None);
+        assert_eq!(seconds(integer(1_559_390_400), false), None);
+        assert_eq!(seconds(integer(1_559_390_400), true), Some(1_559_390_400));
+    }
+
+    #[test]
+    fn values_of_the_target_type_hold_it() {
+        assert!(Target::Integer.holds(&integer(1)));
+        assert!(!Target::Integer.holds(&double(1.0)));
+        assert!(Target::Double.holds(&double(1.0)));
+        assert!(!Target::String.holds(&FirestoreType::Null));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::dump::collect_schema;
+use chrono::{DateTime, Utc};
+use libfiresale::api::DatabaseContext;
+use libfiresale::canonical::canonical_fields;
+use libfiresale::errors::Result;
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use libfiresale::sink;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Overrides the location of the completion cache
+const CACHE_KEY: &'static str = "FIRESALE_COMPLETION_CACHE";
+const DEFAULT_CACHE: &'static str = ".firesale_completion.json";
+/// Time between background refreshes of an open shell's cache
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Documents read per collection to learn its field names
+const FIELD_SAMPLE: i32 = 20;
+
+/// Shell commands, completed at the start of a line
+pub const COMMANDS: &[&str] = &[
+    "begin", "commit", "delete", "exit", "get", "help", "query", "rollback",
+];
+
+/// What is known about one database: its root collections and the field paths seen
+/// in a sample of each
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Snapshot {
+    refreshed_at: Option<DateTime<Utc>>,
+    collections: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Collection ids and field names for completion, kept on disk per project and
+/// database so a new session completes instantly from what the last one learnt
+#[derive(Clone)]
+pub struct CompletionCache {
+    /// `<project>/<database>`, the entry of the cache file this session uses
+    key: String,
+    snapshot: Arc<RwLock<Snapshot>>,
+}
+
+impl CompletionCache {
+    /// The cached entry for `database_name`, empty when there is none yet
+    pub fn load(project_id: &str, database_name: &str) -> CompletionCache {
+        let key = format!("{}/{}", project_id, database_name);
+        let snapshot = read_cache().remove(&key).unwrap_or_default();
+        CompletionCache {
+            key,
+            snapshot: Arc::new(RwLock::new(snapshot)),
+        }
+    }
+
+    /// Refreshes the cache from the database on a background thread, right away and
+    /// then every `REFRESH_INTERVAL`. Completion keeps using the previous entries
+    /// meanwhile, and a failed refresh leaves them in place.
+    pub fn refresh_in_background(&self, context: DatabaseContext, database_name: String) {
+        let cache = self.clone();
+        thread::spawn(move || loop {
+            if let Ok(snapshot) = scan(&context, &*database_name) {
+                *cache.snapshot.write().unwrap_or_else(|e| e.into_inner()) = snapshot.clone();
+                cache.save(snapshot);
+            }
+            thread::sleep(REFRESH_INTERVAL);
+        });
+    }
+
+    // Best effort, a cache that cannot be written is simply rebuilt next time
+    fn save(&self, snapshot: Snapshot) {
+        let mut entries = read_cache();
+        entries.insert(self.key.clone(), snapshot);
+        if let Ok(contents) = serde_json::to_string_pretty(&entries) {
+            sink::write(cache_path(), contents).ok();
+        }
+    }
+
+    /// Candidates for the word ending `line` and the byte offset where that word starts
+    pub fn complete(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line.rfind(char::is_whitespace).map_or(0, |space| space + 1);
+        let partial = &line[start..];
+        let words = line[..start].split_whitespace().collect::<Vec<&str>>();
+        let snapshot = self.snapshot.read().unwrap_or_else(|e| e.into_inner());
+        let collections = || snapshot.collections.keys().cloned();
+        let candidates: Vec<String> = match &*words {
+            [] => COMMANDS.iter().map(|command| command.to_string()).collect(),
+            // document ids are not cached, only the collection part of a path
+            ["get"] | ["delete"] if !partial.contains('/') => {
+                collections().map(|id| format!("{}/", id)).collect()
+            }
+            ["query"] => collections().collect(),
+            ["query", collection, .., last] if *last == "where" || *last == "and" => snapshot
+                .collections
+                .get(*collection)
+                .map_or(Vec::new(), |fields| fields.iter().cloned().collect()),
+            _ => Vec::new(),
+        };
+        let candidates = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(partial))
+            .collect();
+        (start, candidates)
+    }
+}
+
+// Lists the root collections and samples each one's fields
+fn scan(context: &DatabaseContext, database_name: &str) -> Result<Snapshot> {
+    let mut collections = BTreeMap::new();
+    for id in context.list_collection_ids(database_name, None)? {
+        let path = CollectionPath::parse(&*id)?;
+        let mut query = StructuredQuery::collection(path.collection_id());
+        query.limit = Some(FIELD_SAMPLE);
+        let mut schema = BTreeMap::new();
+        for document in context.query_stream(database_name, path.parent(), query) {
+            collect_schema(&canonical_fields(document?.fields()), "", &mut schema);
+        }
+        collections.insert(id, schema.into_iter().map(|(field, _)| field).collect());
+    }
+    Ok(Snapshot {
+        refreshed_at: Some(Utc::now()),
+        collections,
+    })
+}
+
+/// Location of the cache, `$FIRESALE_COMPLETION_CACHE` or a file in the home directory
+fn cache_path() -> PathBuf {
+    use std::env;
+    if let Ok(path) = env::var(CACHE_KEY) {
+        return PathBuf::from(path);
+    }
+    let home = env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .unwrap_or_else(|_| String::from("."));
+    PathBuf::from(home).join(DEFAULT_CACHE)
+}
+
+// Every cached database, nothing when the file is missing or unreadable
+fn read_cache() -> BTreeMap<String, Snapshot> {
+    fs::read_to_string(cache_path())
+        .ok()
+        .and_then(|contents| serde_json::from_str(&*contents).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,125 @@
+use serde_derive::Deserialize;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+/// Overrides the location of the config file
+const CONFIG_KEY: &'static str = "FIRESALE_CONFIG";
+const DEFAULT_CONFIG: &'static str = ".firesale.toml";
+const DEFAULT_TEMPLATE_DIR: &'static str = ".firesale/templates";
+
+/// Settings read from `~/.firesale.toml`, every key is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Project ids, `*` and `?` wildcards allowed, that destructive commands only
+    /// touch after the user types the project id back
+    #[serde(default)]
+    pub protected_projects: Vec<String>,
+    /// Directory holding the document templates used by `new`,
+    /// `~/.firesale/templates` by default. Relative to the config file.
+    pub templates: Option<String>,
+}
+
+/// Location of the config file, `$FIRESALE_CONFIG` or a file in the home directory
+pub fn config_path() -> PathBuf {
+    if let Ok(path) = std::env::var(CONFIG_KEY) {
+        return PathBuf::from(path);
+    }
+    home_dir().join(DEFAULT_CONFIG)
+}
+
+fn home_dir() -> PathBuf {
+    use std::env;
+    let home = env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .unwrap_or_else(|_| String::from("."));
+    PathBuf::from(home)
+}
+
+impl Config {
+    /// Reads the config file, an absent file being an empty config
+    pub fn load() -> Result<Config, String> {
+        let path = config_path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        toml::from_str(&*contents).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn template_dir(&self) -> PathBuf {
+        match &self.templates {
+            Some(dir) => {
+                let base = config_path()
+                    .parent()
+                    .map(|dir| dir.to_path_buf())
+                    .unwrap_or_default();
+                base.join(dir)
+            }
+            None => home_dir().join(DEFAULT_TEMPLATE_DIR),
+        }
+    }
+
+    pub fn is_protected(&self, project_id: &str) -> bool {
+        self.protected_projects
+            .iter()
+            .any(|pattern| wildcard_match(pattern.as_bytes(), project_id.as_bytes()))
+    }
+
+    /// Lets `operation` go ahead on an unprotected project. A protected one needs its
+    /// id typed on the terminal, or passed as `confirmed` when there is no terminal.
+    pub fn confirm(
+        &self,
+        project_id: &str,
+        operation: &str,
+        confirmed: Option<&str>,
+    ) -> Result<(), String> {
+        if !self.is_protected(project_id) {
+            return Ok(());
+        }
+        if let Some(confirmed) = confirmed {
+            if confirmed == project_id {
+                return Ok(());
+            }
+            return Err(format!(
+                "--confirm-project {} does not match protected project {}",
+                confirmed, project_id
+            ));
+        }
+        if !atty::is(atty::Stream::Stdin) {
+            return Err(format!(
+                "{} is a protected project, pass --confirm-project {} to {} without a terminal",
+                project_id, project_id, operation
+            ));
+        }
+        eprint!(
+            "{} is a protected project. Type the project id to {}: ",
+            project_id, operation
+        );
+        io::stderr().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        if line.trim() != project_id {
+            return Err(String::from("Confirmation did not match, nothing was done"));
+        }
+        Ok(())
+    }
+}
+
+// Matches `*` against any run of characters and `?` against exactly one
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], text)
+                || (!text.is_empty() && wildcard_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => wildcard_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
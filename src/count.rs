@@ -0,0 +1,167 @@
+use crate::dump::relative_path;
+use libfiresale::api::query::{
+    Aggregation, CollectionSelector, Direction, FieldReference, Order, Projection, StructuredQuery,
+    DOCUMENT_NAME_FIELD,
+};
+use libfiresale::api::{DatabaseContext, FirestoreType};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::{CollectionPath, DocumentPath};
+use reqwest::StatusCode;
+use std::thread;
+
+const COUNT_ALIAS: &'static str = "count";
+/// Index entries a single billed read of a COUNT aggregation covers
+const ENTRIES_PER_READ: i64 = 1000;
+
+/// Prints the number of documents in `collection`. A COUNT aggregation is tried first,
+/// and when the database refuses it, as older emulators and restricted credentials
+/// do, the documents are read keys-only instead: in up to `workers` ranges at the same
+/// time when the database can partition the collection group, in a single scan when
+/// not. The method used and the reads it cost are printed with the count.
+pub fn count(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    workers: usize,
+) -> Result<()> {
+    let collection = collection.trim_matches('/');
+    let path = CollectionPath::parse(collection)?;
+    let query = StructuredQuery::collection(path.collection_id());
+    let aggregation = ctx.run_aggregation_query(
+        database_name,
+        path.parent().as_ref(),
+        &query,
+        vec![Aggregation::count(COUNT_ALIAS)],
+    );
+    match aggregation {
+        Ok(fields) => {
+            let count = match fields.get(COUNT_ALIAS) {
+                Some(FirestoreType::Integer(count)) => *count,
+                _ => 0,
+            };
+            // even an empty result is billed one read
+            let reads = ((count + ENTRIES_PER_READ - 1) / ENTRIES_PER_READ).max(1);
+            println!(
+                "{}: {} document(s), counted by aggregation, {} read(s)",
+                collection, count, reads
+            );
+            Ok(())
+        }
+        Err(ref e) if unavailable(e) => {
+            eprintln!(
+                "warning: COUNT aggregation unavailable ({}), counting keys instead",
+                e
+            );
+            scan(ctx, database_name, collection, &path, workers)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// What an emulator without the endpoint or credentials without access answer
+fn unavailable(e: &Error) -> bool {
+    match e.status() {
+        Some(status) => {
+            status == StatusCode::BAD_REQUEST
+                || status == StatusCode::FORBIDDEN
+                || status == StatusCode::NOT_FOUND
+                || status == StatusCode::NOT_IMPLEMENTED
+        }
+        None => false,
+    }
+}
+
+/// Counts `collection` by reading the name of each of its documents. Partitioning only
+/// works on collection groups, so the ranges cover every collection of that id under
+/// the same parent and documents of the others are read and left out of the count.
+fn scan(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    path: &CollectionPath,
+    workers: usize,
+) -> Result<()> {
+    let parent = path.parent();
+    let mut query = StructuredQuery {
+        from: vec![CollectionSelector {
+            collection_id: path.collection_id().to_string(),
+            all_descendants: true,
+        }],
+        order_by: vec![Order {
+            field: FieldReference {
+                field_path: DOCUMENT_NAME_FIELD.to_string(),
+            },
+            direction: Direction::Ascending,
+        }],
+        ..Default::default()
+    };
+    let split_points = if workers > 1 {
+        match ctx.partition_query(database_name, parent.as_ref(), &query, workers as i64) {
+            Ok(split_points) => split_points,
+            Err(ref e) if unavailable(e) => {
+                eprintln!("warning: partitioning unavailable ({}), scanning once", e);
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        Vec::new()
+    };
+    query.select = Some(Projection::new(&[DOCUMENT_NAME_FIELD.to_string()]));
+    // the ranges run from one split point to the next, open at both ends
+    let mut bounds = vec![None];
+    bounds.extend(split_points.into_iter().map(Some));
+    bounds.push(None);
+    let handles = bounds
+        .windows(2)
+        .map(|range| {
+            let ctx = ctx.clone();
+            let database_name = database_name.to_string();
+            let collection = collection.to_string();
+            let parent = parent.clone();
+            let mut query = query.clone();
+            query.start_at = range[0].clone();
+            query.end_at = range[1].clone();
+            thread::spawn(move || -> Result<(u64, u64)> {
+                count_range(&ctx, &*database_name, &*collection, parent, query)
+            })
+        })
+        .collect::<Vec<_>>();
+    let partitions = handles.len();
+    let (mut count, mut reads) = (0, 0);
+    for handle in handles {
+        let (counted, read) = handle.join().map_err(|_| Error::WorkerPanic {
+            task: "counting documents".to_string(),
+        })??;
+        count += counted;
+        reads += read;
+    }
+    println!(
+        "{}: {} document(s), counted by keys-only scan in {} partition(s), {} document read(s)",
+        collection, count, partitions, reads
+    );
+    Ok(())
+}
+
+// The documents of `collection` in the range of `query`, and the documents read
+fn count_range(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    parent: Option<DocumentPath>,
+    query: StructuredQuery,
+) -> Result<(u64, u64)> {
+    let (mut count, mut reads) = (0, 0);
+    for document in ctx.query_stream(database_name, parent, query) {
+        let document = document?;
+        reads += 1;
+        let path = relative_path(document.name());
+        let in_collection = path
+            .rfind('/')
+            .map_or(false, |index| &path[..index] == collection);
+        if in_collection {
+            count += 1;
+        }
+    }
+    Ok((count, reads))
+}
@@ -0,0 +1,101 @@
+use crate::planner::WritePlanner;
+use libfiresale::api::query::Aggregation;
+use libfiresale::api::{DatabaseContext, FieldTransform, FirestoreType, Precondition, Write};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::DocumentPath;
+use libfiresale::prelude::StructuredQuery;
+use rand::Rng;
+use serde_json::{json, Map, Value};
+
+/// Subcollection of the counter document holding its shards
+const SHARDS_COLLECTION: &'static str = "shards";
+/// Field of the counter document recording how many shards it has
+const SHARDS_FIELD: &'static str = "shards";
+/// Field of each shard holding its part of the count
+const COUNT_FIELD: &'static str = "count";
+const TOTAL_ALIAS: &'static str = "total";
+
+/// A counter spread over `shards` documents, each taking a share of the increments,
+/// since a single document only sustains about one write per second
+///
+/// `<path>` records the number of shards and `<path>/shards/0` to `<path>/shards/<n-1>`
+/// each hold a `count`. The counter's value is their sum.
+pub fn init(ctx: &DatabaseContext, planner: &WritePlanner, path: &str, shards: u32) -> Result<()> {
+    if shards == 0 {
+        return Err(Error::InvalidInput {
+            message: "a counter needs at least one shard".to_string(),
+        });
+    }
+    let path = DocumentPath::parse(path)?.to_string();
+    let database_name = &*planner.database_name;
+    let mut fields = Map::new();
+    fields.insert(SHARDS_FIELD.to_string(), json!(shards));
+    // an existing counter is not reset
+    let mut writes = vec![ctx
+        .update_write(database_name, &*path, &fields)
+        .with_precondition(Precondition::Exists(false))];
+    let mut shard = Map::new();
+    shard.insert(COUNT_FIELD.to_string(), json!(0));
+    for index in 0..shards {
+        writes.push(ctx.update_write(database_name, &*shard_path(&*path, index), &shard));
+    }
+    if planner.apply(ctx, "counter init", writes, None)?.is_some() {
+        println!("initialized {} with {} shard(s)", path, shards);
+    }
+    Ok(())
+}
+
+/// Adds `by` to a shard picked at random, with a server side increment so concurrent
+/// increments of the same shard all count
+pub fn incr(ctx: &DatabaseContext, planner: &WritePlanner, path: &str, by: i64) -> Result<()> {
+    let database_name = &*planner.database_name;
+    let (name, shards) = counter(ctx, database_name, path)?;
+    let index = rand::thread_rng().gen_range(0, shards);
+    let write = Write::transform(
+        shard_path(&*name, index),
+        vec![FieldTransform::increment(COUNT_FIELD, by)],
+    );
+    if planner
+        .apply(ctx, "counter incr", vec![write], None)?
+        .is_some()
+    {
+        println!("incremented shard {} of {} by {}", index, path, by);
+    }
+    Ok(())
+}
+
+/// Prints the counter's value, summed over its shards by the server
+pub fn read(ctx: &DatabaseContext, database_name: &str, path: &str) -> Result<()> {
+    counter(ctx, database_name, path)?;
+    let parent = DocumentPath::parse(path)?;
+    let query = StructuredQuery::collection(SHARDS_COLLECTION);
+    let aggregation = Aggregation::sum(TOTAL_ALIAS, COUNT_FIELD);
+    let result =
+        ctx.run_aggregation_query(database_name, Some(&parent), &query, vec![aggregation])?;
+    match result.get(TOTAL_ALIAS).map(FirestoreType::to_json) {
+        Some(Value::Number(total)) => println!("{}", total),
+        _ => println!("0"),
+    }
+    Ok(())
+}
+
+/// The resource name of the counter at `path` and its number of shards
+fn counter(ctx: &DatabaseContext, database_name: &str, path: &str) -> Result<(String, u32)> {
+    let not_a_counter = || Error::InvalidInput {
+        message: format!("{} is not a counter, run counter init first", path),
+    };
+    let counter = ctx
+        .find_document(database_name, &*DocumentPath::parse(path)?.to_string())?
+        .ok_or_else(not_a_counter)?;
+    match counter.fields().get(SHARDS_FIELD) {
+        Some(FirestoreType::Integer(shards)) if *shards > 0 => {
+            Ok((counter.name().to_string(), *shards as u32))
+        }
+        _ => Err(not_a_counter()),
+    }
+}
+
+// Shard `index` of the counter at `path`, relative or a full resource name
+fn shard_path(path: &str, index: u32) -> String {
+    format!("{}/{}/{}", path, SHARDS_COLLECTION, index)
+}
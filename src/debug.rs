@@ -0,0 +1,136 @@
+//! Hooks for observing, and intercepting, the HTTP traffic a `DatabaseContext` produces
+
+use crate::errors::Result;
+use crate::sink;
+use reqwest::header::HeaderMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Headers whose values are credentials and never leave the process
+const REDACTED_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+/// A request and the response Firestore sent back for it
+#[derive(Debug)]
+pub struct Exchange<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub request_headers: &'a HeaderMap,
+    pub request_body: Option<&'a str>,
+    pub status: u16,
+    pub response_headers: &'a HeaderMap,
+    pub response_body: &'a str,
+}
+
+/// Called with every exchange once the response body has been read,
+/// see `DatabaseContext::with_http_hook`
+pub trait HttpHook: fmt::Debug + Send + Sync {
+    fn exchange(&self, exchange: &Exchange);
+}
+
+/// A request about to be sent, whose headers an interceptor may change
+#[derive(Debug)]
+pub struct OutgoingRequest<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub headers: &'a mut HeaderMap,
+    pub body: Option<&'a [u8]>,
+}
+
+/// A response as received, before its status is checked
+#[derive(Debug)]
+pub struct IncomingResponse<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub status: u16,
+    pub headers: &'a HeaderMap,
+    pub body: &'a str,
+    /// Time from sending the request to reading the whole body
+    pub elapsed: Duration,
+}
+
+/// Runs around every attempt at every request, see `DatabaseContext::with_interceptor`.
+/// Returning an error from either method fails the attempt with it, which is how
+/// chaos tests inject faults. Interceptors run in the order they were added.
+pub trait Interceptor: fmt::Debug + Send + Sync {
+    /// Called before sending, e.g. to add headers or replace the authorization
+    fn on_request(&self, _request: &mut OutgoingRequest) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the response body has been read, e.g. to record metrics
+    fn on_response(&self, _response: &IncomingResponse) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes each exchange to numbered files in a directory, 0001-request.txt and
+/// 0001-response.txt and so on, with credentials redacted and JSON bodies pretty printed
+#[derive(Debug)]
+pub struct HttpDump {
+    dir: PathBuf,
+    counter: AtomicUsize,
+}
+
+impl HttpDump {
+    /// Creates `dir` if needed
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<HttpDump> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(HttpDump {
+            dir,
+            counter: AtomicUsize::new(0),
+        })
+    }
+
+    fn write(&self, name: String, contents: String) {
+        // failing to dump never fails the request
+        if let Err(e) = sink::write(self.dir.join(&*name), contents) {
+            eprintln!("warning: failed to write {}: {}", name, e);
+        }
+    }
+}
+
+impl HttpHook for HttpDump {
+    fn exchange(&self, exchange: &Exchange) {
+        let number = self.counter.fetch_add(1, Ordering::SeqCst) + 1;
+        let request = format!(
+            "{} {}\n{}\n{}",
+            exchange.method,
+            exchange.url,
+            format_headers(exchange.request_headers),
+            exchange.request_body.map(pretty_body).unwrap_or_default()
+        );
+        let response = format!(
+            "{}\n{}\n{}",
+            exchange.status,
+            format_headers(exchange.response_headers),
+            pretty_body(exchange.response_body)
+        );
+        self.write(format!("{:04}-request.txt", number), request);
+        self.write(format!("{:04}-response.txt", number), response);
+    }
+}
+
+/// One `name: value` line per header, with credential values replaced
+fn format_headers(headers: &HeaderMap) -> String {
+    let mut lines = String::new();
+    for (name, value) in headers {
+        let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+            "<redacted>"
+        } else {
+            value.to_str().unwrap_or("<binary>")
+        };
+        lines.push_str(&*format!("{}: {}\n", name, value));
+    }
+    lines
+}
+
+// Bodies that are not JSON are written as they are
+fn pretty_body(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| body.to_string())
+}
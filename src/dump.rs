@@ -0,0 +1,869 @@
+use crate::output::{OutputFormat, Reporter};
+use crate::progress::{Phase, ProgressEvents};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use libfiresale::api::{ConsistencySelector, DatabaseContext, Document, FirestoreType};
+use libfiresale::canonical::{canonical_fields, checksum};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::{CollectionPath, DocumentReference};
+use libfiresale::prelude::{FilterPlan, StructuredQuery};
+use libfiresale::report::Finding;
+use libfiresale::sink::{self, AtomicFile};
+use rand::seq::IteratorRandom;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const MANIFEST_FILE: &'static str = "manifest.json";
+const DUMP_EXTENSION: &'static str = "ndjson";
+const GZIP_EXTENSION: &'static str = "gz";
+const ZSTD_EXTENSION: &'static str = "zst";
+/// Documents per collection re-read from the live database by `verify-backup --deep`
+const DEEP_SAMPLE_SIZE: usize = 25;
+
+/// Describes one `dump` run, written next to the collection files so the
+/// backup can later be checked for completeness and corruption
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub project_id: String,
+    pub database: String,
+    pub created_at: DateTime<Utc>,
+    /// Every collection was read at this time, so the dump is a consistent snapshot
+    pub read_time: Option<DateTime<Utc>>,
+    pub collections: Vec<CollectionManifest>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectionManifest {
+    /// Collection path relative to the database root, e.g. `cars` or `cars/abc/parts`
+    pub collection: String,
+    /// Name of the dump file within the snapshot directory, unless the dump is sharded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// The dump files in order, when split with `--shard-size`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shards: Vec<Shard>,
+    /// With `--partition-by`, the time partition these documents fall in. Each
+    /// partition of a collection has its own entry and directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partition: Option<String>,
+    pub documents: usize,
+    /// Checksum over every document's path and canonical content
+    pub content_hash: String,
+    /// Checksum of `schema`, cheap to compare between snapshots
+    pub schema_fingerprint: String,
+    /// Every field path seen in the collection, with the value types it held
+    pub schema: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// One file of a sharded collection dump. Shards can be read independently.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Shard {
+    pub file: String,
+    pub documents: usize,
+    /// Uncompressed size of the shard
+    pub bytes: u64,
+    /// Path of the first document in the shard
+    pub first: String,
+}
+
+impl CollectionManifest {
+    /// The collection, with its partition if any, as shown in reports
+    pub fn label(&self) -> String {
+        match &self.partition {
+            Some(partition) => format!("{} [{}]", self.collection, partition),
+            None => self.collection.clone(),
+        }
+    }
+
+    /// Every dump file of the collection, in order
+    pub fn files(&self) -> Vec<&str> {
+        match &self.file {
+            Some(file) => vec![&**file],
+            None => self.shards.iter().map(|shard| &*shard.file).collect(),
+        }
+    }
+}
+
+/// How `dump` lays out and selects what it writes
+#[derive(Debug, Clone, Default)]
+pub struct DumpOptions {
+    pub compression: Compression,
+    /// Split each collection into files of at most this many uncompressed bytes
+    pub max_shard_bytes: Option<u64>,
+    /// Only dump the documents matching this filter
+    pub filter: Option<FilterPlan>,
+    pub partition: Option<Partition>,
+    /// Collections dumped at the same time, one at a time when 0 or 1
+    pub jobs: usize,
+    pub progress: ProgressEvents,
+}
+
+/// Puts documents into directories by the value of a timestamp field, e.g.
+/// `createdAt:month` writes `2024-05/users.ndjson`. Documents where the field is
+/// not a timestamp go to `undated/`.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    field: String,
+    format: &'static str,
+}
+
+const UNDATED_PARTITION: &'static str = "undated";
+
+impl Partition {
+    /// Parses `field:granularity`, the granularity being year, month, day or hour
+    pub fn parse(spec: &str) -> Result<Partition> {
+        let mut parts = spec.rsplitn(2, ':');
+        let (granularity, field) = match (parts.next(), parts.next()) {
+            (Some(granularity), Some(field)) if !field.is_empty() => (granularity, field),
+            _ => ("", ""),
+        };
+        let format = match granularity {
+            "year" => "%Y",
+            "month" => "%Y-%m",
+            "day" => "%Y-%m-%d",
+            "hour" => "%Y-%m-%dT%H",
+            _ => {
+                return Err(Error::InvalidInput {
+                    message: format!(
+                        "invalid partition {:?}, expected field:year|month|day|hour",
+                        spec
+                    ),
+                })
+            }
+        };
+        Ok(Partition {
+            field: field.to_string(),
+            format,
+        })
+    }
+
+    fn key(&self, document: &Document) -> String {
+        match document.fields().get_path(&*self.field) {
+            Some(FirestoreType::Timestamp(time)) => time.time().format(self.format).to_string(),
+            _ => UNDATED_PARTITION.to_string(),
+        }
+    }
+}
+
+/// How dump files are compressed, chosen with `dump --compress` and recognised
+/// from the file extension when reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Result<Compression> {
+        match name {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(Error::InvalidInput {
+                message: format!("unknown compression {}, expected gzip or zstd", name),
+            }),
+        }
+    }
+
+    fn from_file_name(file: &str) -> Compression {
+        match Path::new(file)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some(GZIP_EXTENSION) => Compression::Gzip,
+            Some(ZSTD_EXTENSION) => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// File name of a dump holding `stem`, e.g. `users.ndjson.gz`
+    fn file_name(self, stem: &str) -> String {
+        match self {
+            Compression::None => format!("{}.{}", stem, DUMP_EXTENSION),
+            Compression::Gzip => format!("{}.{}.{}", stem, DUMP_EXTENSION, GZIP_EXTENSION),
+            Compression::Zstd => format!("{}.{}.{}", stem, DUMP_EXTENSION, ZSTD_EXTENSION),
+        }
+    }
+
+    fn writer(self, file: AtomicFile) -> Result<DumpWriter> {
+        let file = BufWriter::new(file);
+        Ok(match self {
+            Compression::None => DumpWriter::Plain(file),
+            Compression::Gzip => {
+                DumpWriter::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            Compression::Zstd => DumpWriter::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    fn reader(self, file: File) -> Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Compression::None => Box::new(BufReader::new(file)),
+            Compression::Gzip => Box::new(BufReader::new(GzDecoder::new(file))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+        })
+    }
+}
+
+/// Streams documents into a dump file, compressing as it goes
+enum DumpWriter {
+    Plain(BufWriter<AtomicFile>),
+    Gzip(GzEncoder<BufWriter<AtomicFile>>),
+    Zstd(zstd::Encoder<BufWriter<AtomicFile>>),
+}
+
+impl DumpWriter {
+    /// Writes the compression trailer and moves the file into place, dropping a writer
+    /// removes the unfinished file
+    fn finish(self) -> Result<()> {
+        let file = match self {
+            DumpWriter::Plain(file) => file,
+            DumpWriter::Gzip(encoder) => encoder.finish()?,
+            DumpWriter::Zstd(encoder) => encoder.finish()?,
+        };
+        file.into_inner().map_err(io::Error::from)?.commit()?;
+        Ok(())
+    }
+}
+
+impl Write for DumpWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DumpWriter::Plain(file) => file.write(buf),
+            DumpWriter::Gzip(encoder) => encoder.write(buf),
+            DumpWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DumpWriter::Plain(file) => file.flush(),
+            DumpWriter::Gzip(encoder) => encoder.flush(),
+            DumpWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Running totals for one collection, built the same way when dumping and verifying
+#[derive(Debug, Default)]
+pub struct Summary {
+    documents: usize,
+    /// Path and content checksum of every document, in order
+    entries: Vec<(String, String)>,
+    schema: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Summary {
+    pub fn add(&mut self, document: &Document) {
+        let fields = canonical_fields(document.fields());
+        self.documents += 1;
+        self.entries
+            .push((relative_path(document.name()), checksum(&fields)));
+        collect_schema(&fields, "", &mut self.schema);
+    }
+
+    /// The collection's manifest, without any files
+    pub fn into_manifest(self, collection: &str) -> CollectionManifest {
+        let schema_value = serde_json::to_value(&self.schema).unwrap_or(Value::Null);
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|(path, sum)| Value::Array(vec![Value::String(path), Value::String(sum)]))
+            .collect();
+        CollectionManifest {
+            collection: collection.to_string(),
+            file: None,
+            shards: Vec::new(),
+            partition: None,
+            documents: self.documents,
+            content_hash: checksum(&Value::Array(entries)),
+            schema_fingerprint: checksum(&schema_value),
+            schema: self.schema,
+        }
+    }
+}
+
+/// Documents are identified by their path so a snapshot hashes the same in any project
+pub fn relative_path(name: &str) -> String {
+    DocumentReference::parse(name)
+        .map(|reference| reference.path.to_string())
+        .unwrap_or_else(|_| name.to_string())
+}
+
+/// Records the wire type of every field in canonical `fields`, descending into maps
+pub fn collect_schema(
+    fields: &Value,
+    prefix: &str,
+    schema: &mut BTreeMap<String, BTreeSet<String>>,
+) {
+    let fields = match fields.as_object() {
+        Some(fields) => fields,
+        None => return,
+    };
+    for (field, value) in fields {
+        let path = format!("{}{}", prefix, field);
+        if let Some((kind, inner)) = value.as_object().and_then(|value| value.iter().next()) {
+            schema.entry(path.clone()).or_default().insert(kind.clone());
+            if kind == "mapValue" {
+                if let Some(nested) = inner.get("fields") {
+                    collect_schema(nested, &*format!("{}.", path), schema);
+                }
+            }
+        }
+    }
+}
+
+impl Manifest {
+    pub fn read(dir: &Path) -> Result<Manifest> {
+        let file = File::open(dir.join(MANIFEST_FILE))?;
+        serde_json::from_reader(BufReader::new(file)).map_err(Error::from)
+    }
+}
+
+/// Writes a collection's documents to a single file or, given a maximum size,
+/// to numbered shards such as `users-00001.ndjson`. The stem may start with a
+/// partition directory. The limit applies before
+/// compression and a shard always holds at least one document.
+struct ShardWriter<'a> {
+    dir: &'a Path,
+    stem: String,
+    compression: Compression,
+    max_bytes: Option<u64>,
+    current: Option<(DumpWriter, Shard)>,
+    shards: Vec<Shard>,
+}
+
+impl<'a> ShardWriter<'a> {
+    fn new(
+        dir: &'a Path,
+        stem: String,
+        compression: Compression,
+        max_bytes: Option<u64>,
+    ) -> ShardWriter<'a> {
+        ShardWriter {
+            dir,
+            stem,
+            compression,
+            max_bytes,
+            current: None,
+            shards: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, document: &Document) -> Result<()> {
+        let mut line = serde_json::to_vec(document)?;
+        line.push(b'\n');
+        let full = match (&self.current, self.max_bytes) {
+            (Some((_, shard)), Some(max_bytes)) => shard.bytes + line.len() as u64 > max_bytes,
+            _ => false,
+        };
+        if full {
+            self.close_current()?;
+        }
+        if self.current.is_none() {
+            self.open(relative_path(document.name()))?;
+        }
+        if let Some((out, shard)) = self.current.as_mut() {
+            out.write_all(&*line)?;
+            shard.documents += 1;
+            shard.bytes += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, first: String) -> Result<()> {
+        let file = match self.max_bytes {
+            Some(_) => {
+                self.compression
+                    .file_name(&*format!("{}-{:05}", self.stem, self.shards.len() + 1))
+            }
+            None => self.compression.file_name(&*self.stem),
+        };
+        let path = self.dir.join(&*file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let out = self.compression.writer(AtomicFile::create(path)?)?;
+        let shard = Shard {
+            file,
+            documents: 0,
+            bytes: 0,
+            first,
+        };
+        self.current = Some((out, shard));
+        Ok(())
+    }
+
+    fn close_current(&mut self) -> Result<()> {
+        if let Some((out, shard)) = self.current.take() {
+            out.finish()?;
+            self.shards.push(shard);
+        }
+        Ok(())
+    }
+
+    /// Closes the last file and records the files in `manifest`
+    fn finish(mut self, manifest: &mut CollectionManifest) -> Result<()> {
+        if self.max_bytes.is_none() && self.current.is_none() {
+            // an empty collection still gets its (empty) file
+            self.open(String::new())?;
+        }
+        self.close_current()?;
+        if self.max_bytes.is_some() {
+            manifest.shards = self.shards;
+        } else {
+            manifest.file = self.shards.pop().map(|shard| shard.file);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the documents of a dump file, one JSON document per line,
+/// decompressing according to the file's extension
+pub fn read_documents(path: &Path) -> Result<impl Iterator<Item = Result<Document>>> {
+    let compression = Compression::from_file_name(&*path.to_string_lossy());
+    let reader = compression.reader(File::open(path)?)?;
+    Ok(reader
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&*line?)?)))
+}
+
+/// Checks every dump file in `dir` against the manifest. With `ctx`, a random sample of
+/// each collection is also re-read from the live database at the manifest's read time.
+/// Each problem is reported as a `problem` finding about its collection.
+pub fn verify(dir: &str, ctx: Option<&DatabaseContext>, format: &OutputFormat) -> Result<()> {
+    let dir = Path::new(dir);
+    let manifest = Manifest::read(dir)?;
+    if let Some(ctx) = ctx {
+        if ctx.project_id != manifest.project_id {
+            return Err(Error::Conflict {
+                message: format!(
+                    "snapshot was taken from {} but running against {}",
+                    manifest.project_id, ctx.project_id
+                ),
+            });
+        }
+    }
+    let mut reporter = Reporter::new("verify-backup", format);
+    let mut problems = 0;
+    for expected in &manifest.collections {
+        let mut summary = Summary::default();
+        let mut collection_problems = Vec::new();
+        for file in expected.files() {
+            let mut documents = 0;
+            for document in read_documents(&dir.join(file))? {
+                summary.add(&document?);
+                documents += 1;
+            }
+            let shard = expected.shards.iter().find(|shard| shard.file == file);
+            if let Some(shard) = shard.filter(|shard| shard.documents != documents) {
+                collection_problems.push(format!(
+                    "{} has {} document(s), manifest lists {}",
+                    file, documents, shard.documents
+                ));
+            }
+        }
+        let sample = match ctx {
+            Some(_) => summary
+                .entries
+                .iter()
+                .cloned()
+                .choose_multiple(&mut rand::thread_rng(), DEEP_SAMPLE_SIZE),
+            None => Vec::new(),
+        };
+        let actual = summary.into_manifest(&*expected.collection);
+        if actual.documents != expected.documents {
+            collection_problems.push(format!(
+                "{} document(s), manifest lists {}",
+                actual.documents, expected.documents
+            ));
+        }
+        if actual.content_hash != expected.content_hash {
+            collection_problems.push("content hash differs from the manifest".to_string());
+        }
+        if actual.schema_fingerprint != expected.schema_fingerprint {
+            collection_problems.push("schema differs from the manifest".to_string());
+        }
+        if let Some(ctx) = ctx {
+            collection_problems.extend(compare_live(ctx, &manifest, sample)?);
+        }
+        if collection_problems.is_empty() {
+            reporter.say(format!(
+                "ok {}: {} document(s)",
+                expected.label(),
+                actual.documents
+            ));
+        }
+        reporter.count("collections", 1);
+        reporter.count("documents", actual.documents as u64);
+        for problem in collection_problems {
+            let line = format!("FAILED {}: {}", expected.label(), problem);
+            reporter.found(Finding::new("problem", expected.label(), problem), line);
+            problems += 1;
+        }
+    }
+    reporter.finish()?;
+    if problems > 0 {
+        return Err(Error::Conflict {
+            message: format!("{} problem(s) found in the snapshot", problems),
+        });
+    }
+    Ok(())
+}
+
+// Re-reads sampled documents as of the snapshot and reports those whose content differs
+fn compare_live(
+    ctx: &DatabaseContext,
+    manifest: &Manifest,
+    sample: Vec<(String, String)>,
+) -> Result<Vec<String>> {
+    let read_time = match manifest.read_time {
+        Some(read_time) => read_time,
+        None => return Ok(vec!["manifest has no read time to compare at".to_string()]),
+    };
+    let consistency = ConsistencySelector::ReadTime(read_time);
+    let mut problems = Vec::new();
+    for (path, dumped) in sample {
+        match ctx.get_document(&*manifest.database, &*path, Some(&consistency)) {
+            Ok(document) => {
+                if checksum(&canonical_fields(document.fields())) != dumped {
+                    problems.push(format!("{} differs from the live database", path));
+                }
+            }
+            Err(ref e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                problems.push(format!("{} is missing from the live database", path));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(problems)
+}
+
+// The writer for one collection, or one partition of it
+fn writer<'a>(
+    dir: &'a Path,
+    stem: &str,
+    partition: Option<&str>,
+    options: &DumpOptions,
+) -> ShardWriter<'a> {
+    let stem = match partition {
+        Some(partition) => format!("{}/{}", partition, stem),
+        None => stem.to_string(),
+    };
+    ShardWriter::new(dir, stem, options.compression, options.max_shard_bytes)
+}
+
+/// Writes every document of `collections` to `<dir>/<collection>.ndjson`, compressed,
+/// split into shards or partitioned if asked, all read at the same time, followed by a
+/// manifest describing the snapshot. Up to `options.jobs` collections are dumped at once.
+pub fn dump(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    dir: &str,
+    collections: &[String],
+    options: &DumpOptions,
+) -> Result<()> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+    let collections = collections
+        .iter()
+        .map(|collection| collection.trim_matches('/').to_string())
+        .collect::<Vec<String>>();
+    // every collection is read at the first one's read time, found before any starts
+    let read_time = if collections.len() > 1 {
+        first_read_time(ctx, database_name, &*collections[0], options)?
+    } else {
+        None
+    };
+    let workers = options.jobs.max(1).min(collections.len().max(1));
+    let queue = Arc::new(Mutex::new(
+        collections
+            .iter()
+            .cloned()
+            .enumerate()
+            .collect::<VecDeque<(usize, String)>>(),
+    ));
+    let job = Arc::new(DumpJob {
+        context: ctx.clone(),
+        database_name: database_name.to_string(),
+        dir: dir.to_path_buf(),
+        options: options.clone(),
+        read_time,
+        progress: DumpProgress {
+            collections: collections.len(),
+            done: Mutex::new((0, 0)),
+            // documents are only counted as their collection is read
+            phase: options.progress.phase("dump", None),
+        },
+    });
+    let handles = (0..workers)
+        .map(|_| {
+            let queue = queue.clone();
+            let job = job.clone();
+            thread::spawn(move || -> Result<Vec<DumpedCollection>> {
+                let mut dumped = Vec::new();
+                loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+                    let (index, collection) = match next {
+                        Some(next) => next,
+                        None => return Ok(dumped),
+                    };
+                    match job.dump_collection(&*collection) {
+                        Ok((manifests, read_time)) => {
+                            job.progress.finished(&*collection, &manifests);
+                            dumped.push((index, manifests, read_time));
+                        }
+                        Err(e) => {
+                            job.progress.phase.error();
+                            // the snapshot is unusable anyway, let the other workers stop
+                            queue.lock().unwrap_or_else(|e| e.into_inner()).clear();
+                            return Err(e);
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut dumped = Vec::new();
+    let mut first_error = None;
+    for handle in handles {
+        let result = handle.join().map_err(|_| Error::WorkerPanic {
+            task: "dumping collections".to_string(),
+        });
+        match result.and_then(|result| result) {
+            Ok(collections) => dumped.extend(collections),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error {
+        return Err(e);
+    }
+    // the manifest lists collections in the order they were asked for
+    dumped.sort_by_key(|(index, _, _)| *index);
+    let read_time = read_time.or_else(|| dumped.iter().find_map(|(_, _, read_time)| *read_time));
+    let manifest = Manifest {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        project_id: ctx.project_id.clone(),
+        database: database_name.to_string(),
+        created_at: Utc::now(),
+        read_time,
+        collections: dumped
+            .into_iter()
+            .flat_map(|(_, manifests, _)| manifests)
+            .collect(),
+    };
+    // written last, so a dump without its manifest is known to be incomplete
+    sink::write_json(dir.join(MANIFEST_FILE), &manifest)?;
+    job.progress.phase.finish();
+    let (collections, documents) = *job.progress.done.lock().unwrap_or_else(|e| e.into_inner());
+    println!(
+        "{} collection(s), {} document(s), manifest written to {}",
+        collections,
+        documents,
+        dir.join(MANIFEST_FILE).display()
+    );
+    Ok(())
+}
+
+/// A collection's position in the request, its manifests and the time it was read at
+type DumpedCollection = (usize, Vec<CollectionManifest>, Option<DateTime<Utc>>);
+
+// The read time Firestore picks for a scan of `collection`, read from its first page
+fn first_read_time(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    options: &DumpOptions,
+) -> Result<Option<DateTime<Utc>>> {
+    let path = CollectionPath::parse(collection)?;
+    let mut query = StructuredQuery::collection(path.collection_id());
+    if let Some(filter) = &options.filter {
+        filter.apply(&mut query);
+    }
+    query.limit = Some(1);
+    let mut stream = ctx.query_stream(database_name, path.parent(), query);
+    if let Some(document) = stream.next() {
+        document?;
+    }
+    Ok(stream.read_time())
+}
+
+/// Everything the workers of one `dump` share
+struct DumpJob {
+    context: DatabaseContext,
+    database_name: String,
+    dir: PathBuf,
+    options: DumpOptions,
+    /// Pins every collection's scan when several are dumped
+    read_time: Option<DateTime<Utc>>,
+    progress: DumpProgress,
+}
+
+/// Collections and documents dumped so far, across every worker
+struct DumpProgress {
+    collections: usize,
+    done: Mutex<(usize, usize)>,
+    phase: Phase,
+}
+
+impl DumpProgress {
+    // Prints one line per dumped collection or partition, counting it as done
+    fn finished(&self, collection: &str, manifests: &[CollectionManifest]) {
+        let mut done = self.done.lock().unwrap_or_else(|e| e.into_inner());
+        done.0 += 1;
+        if manifests.is_empty() {
+            println!(
+                "[{}/{}] {}: 0 document(s)",
+                done.0, self.collections, collection
+            );
+        }
+        for manifest in manifests {
+            done.1 += manifest.documents;
+            self.phase.advance(manifest.documents as u64);
+            println!(
+                "[{}/{}] {}: {} document(s)",
+                done.0,
+                self.collections,
+                manifest.label(),
+                manifest.documents
+            );
+        }
+    }
+}
+
+impl DumpJob {
+    /// Writes one collection's dump files, returning a manifest per partition and the
+    /// read time of its scan
+    fn dump_collection(
+        &self,
+        collection: &str,
+    ) -> Result<(Vec<CollectionManifest>, Option<DateTime<Utc>>)> {
+        let (dir, options) = (&*self.dir, &self.options);
+        let path = CollectionPath::parse(collection)?;
+        let stem = collection.replace('/', ".");
+        let mut query = StructuredQuery::collection(path.collection_id());
+        if let Some(filter) = &options.filter {
+            filter.apply(&mut query);
+        }
+        let mut stream = self
+            .context
+            .query_stream(&*self.database_name, path.parent(), query);
+        if let Some(read_time) = self.read_time {
+            stream = stream.read_at(read_time);
+        }
+        // one writer per partition, or a single one keyed by None
+        let mut partitions = BTreeMap::new();
+        if options.partition.is_none() {
+            // so an empty collection still gets its (empty) file
+            partitions.insert(
+                None,
+                (writer(dir, &*stem, None, options), Summary::default()),
+            );
+        }
+        for document in stream.by_ref() {
+            let document = document?;
+            if !options
+                .filter
+                .as_ref()
+                .map_or(true, |filter| filter.matches(&document))
+            {
+                continue;
+            }
+            let key = options.partition.as_ref().map(|p| p.key(&document));
+            let (out, summary) = partitions.entry(key.clone()).or_insert_with(|| {
+                (
+                    writer(dir, &*stem, key.as_ref().map(|key| &**key), options),
+                    Summary::default(),
+                )
+            });
+            out.write(&document)?;
+            summary.add(&document);
+        }
+        let mut manifests = Vec::new();
+        for (partition, (out, summary)) in partitions {
+            let mut manifest = summary.into_manifest(collection);
+            manifest.partition = partition;
+            out.finish(&mut manifest)?;
+            manifests.push(manifest);
+        }
+        Ok((manifests, stream.read_time()))
+    }
+}
+
+/// Exports the documents of `collection` matching `filter` to `gs://<bucket>/<prefix>`.
+/// The managed export cannot filter, so this writes a gzip compressed dump locally and
+/// uploads it: the result is read back with `load`, not `gcloud firestore import`.
+pub fn export_filtered(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    bucket: &str,
+    collection: &str,
+    filter: &FilterPlan,
+) -> Result<()> {
+    let prefix = format!("firesale-export-{}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let staging = std::env::temp_dir().join(&*prefix);
+    let result = dump(
+        ctx,
+        database_name,
+        &*staging.to_string_lossy(),
+        &[collection.to_string()],
+        &DumpOptions {
+            compression: Compression::Gzip,
+            filter: Some(filter.clone()),
+            ..DumpOptions::default()
+        },
+    )
+    .and_then(|_| upload_dir(ctx, &*staging, bucket, &*prefix));
+    // the staging copy is only a means to the upload
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        eprintln!("warning: failed to remove {}: {}", staging.display(), e);
+    }
+    result?;
+    println!(
+        "exported to gs://{}/{} as a firesale dump, restore it with load",
+        bucket.trim_start_matches("gs://").trim_end_matches('/'),
+        prefix
+    );
+    Ok(())
+}
+
+// Uploads every file of a dump directory under `prefix`
+fn upload_dir(ctx: &DatabaseContext, dir: &Path, bucket: &str, prefix: &str) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let content_type = if name.ends_with(GZIP_EXTENSION) {
+            "application/gzip"
+        } else {
+            "application/json"
+        };
+        ctx.upload_object(
+            bucket,
+            &*format!("{}/{}", prefix, name),
+            content_type,
+            fs::read(&path)?,
+        )?;
+    }
+    Ok(())
+}
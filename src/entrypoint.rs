@@ -1,13 +1,454 @@
-use libfiresale::errors::Result;
-use libfiresale::firestore;
+use crate::dump;
+use crate::export;
+use crate::mirror::MirrorSource;
+use crate::output::OutputFormat;
+use crate::planner::WritePlanner;
+use crate::poll;
+use chrono::{DateTime, Utc};
+use libfiresale::prelude::{
+    CollectionPath, Condition, Document, DocumentMask, DocumentPath, Error, ExportDocumentQuery,
+    FilterPlan, FirestoreFields, FirestoreType, GeoFilter, Lookup, Projection, ResourceName,
+    Result, StructuredQuery,
+};
+use libfiresale::sink;
+
+/// Documents requested, and rendered, per page of a listing
+const LIST_PAGE_SIZE: i32 = 300;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Prints a document, returning `false` without printing anything when
+/// `--if-changed-since` was given and the document has not been updated since.
+/// With `mirror` the document is read from there first, unless polling.
+pub fn handle_document_get(
+    query: crate::DocumentQuery,
+    ctx: crate::DatabaseContext,
+    database_name: &str,
+    output: &OutputFormat,
+    mirror: Option<&MirrorSource>,
+) -> Result<bool> {
+    let since = match &query.if_changed_since {
+        Some(since) => Some(parse_since(&*since)?),
+        None => None,
+    };
+    let path = format!("{}/{}", query.collection_name, query.document_name);
+    let mask = if query.select.is_empty() {
+        None
+    } else {
+        Some(DocumentMask::new(query.select.clone()))
+    };
+    if let Some(interval) = &query.poll {
+        // a deleted document drops out of the results rather than ending the poll
+        poll::poll(
+            poll::parse_interval(&*interval)?,
+            query.diff,
+            output,
+            || match ctx.get_document_with_mask(database_name, &*path, None, mask.as_ref()) {
+                Ok(document) => Ok(vec![document]),
+                Err(ref e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => Ok(Vec::new()),
+                Err(e) => Err(e),
+            },
+        )?;
+        return Ok(true);
+    }
+    let mirrored = match mirror {
+        Some(mirror) => mirror.document(&*path)?,
+        None => None,
+    };
+    let document = match mirrored {
+        Some(document) => project(document, &query.select),
+        None => ctx.get_document_with_mask(database_name, &*path, None, mask.as_ref())?,
+    };
+    if since.map_or(false, |since| document.update_time() <= since) {
+        return Ok(false);
+    }
+    if let Some(field) = &query.raw_field {
+        write_raw_field(&document, &*field)?;
+        return Ok(true);
+    }
+    output.document(&document)?;
+    if let Some(dir) = query.save_bytes {
+        fs::create_dir_all(&*dir)?;
+        save_bytes_fields(
+            Path::new(&*dir),
+            &*query.document_name,
+            "",
+            document.fields(),
+        )?;
+    }
+    Ok(true)
+}
+
+/// Writes the decoded bytes or the text of a field to stdout, with nothing around it
+fn write_raw_field(document: &Document, field: &str) -> Result<()> {
+    let raw = match document.fields().get_path(field) {
+        Some(FirestoreType::Bytes(bytes)) => &**bytes,
+        Some(FirestoreType::String(text)) => text.as_bytes(),
+        Some(_) => {
+            return Err(Error::InvalidInput {
+                message: format!("{} is neither a string nor bytes", field),
+            })
+        }
+        None => {
+            return Err(Error::InvalidInput {
+                message: format!("{} has no field {}", document.name(), field),
+            })
+        }
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(raw)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads `--if-changed-since`: an RFC 3339 time, or a file containing one. A file
+/// holding anything else counts by its modification time.
+fn parse_since(since: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since.trim()) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let contents = fs::read_to_string(since).map_err(|_| Error::InvalidInput {
+        message: format!("{} is neither an RFC 3339 time nor a readable file", since),
+    })?;
+    match DateTime::parse_from_rfc3339(contents.trim()) {
+        Ok(time) => Ok(time.with_timezone(&Utc)),
+        Err(_) => Ok(DateTime::<Utc>::from(fs::metadata(since)?.modified()?)),
+    }
+}
+
+/// Lists a collection. With `mirror` the listing comes from there first, unless
+/// polling or showing missing documents, which the mirror does not know about.
+/// With `--near` the documents are narrowed on the server by one query per range
+/// the circle needs, then kept only if within the radius.
+pub fn handle_collection_list(
+    query: crate::CollectionQuery,
+    ctx: crate::DatabaseContext,
+    database_name: &str,
+    output: &OutputFormat,
+    mirror: Option<&MirrorSource>,
+) -> Result<()> {
+    let conditions = query
+        .filters
+        .iter()
+        .map(|condition| condition.parse())
+        .collect::<Result<Vec<Condition>>>()?;
+    let geo = match &query.near {
+        Some((point, radius)) => {
+            let geo = GeoFilter::new(
+                &*query.geo_field,
+                GeoFilter::parse_point(&*point)?,
+                GeoFilter::parse_distance(&*radius)?,
+            );
+            Some(match &query.geohash_field {
+                Some(field) => geo.with_geohash_field(&**field),
+                None => geo,
+            })
+        }
+        None => None,
+    };
+    let near = |document: &Document| {
+        geo.as_ref()
+            .map_or(true, |geo| geo.matches(document.fields()))
+    };
+    if let (Some(mirror), None, false) = (mirror, &query.poll, query.show_missing) {
+        if let Some(documents) = mirror.collection(&*query.collection_name)? {
+            // the mirror is a plain copy, every condition is checked here
+            let plan = FilterPlan::client_only(conditions);
+            let page = documents
+                .into_iter()
+                .filter(|document| plan.matches(document) && near(document))
+                .map(|document| project(document, &query.select))
+                .collect::<Vec<Document>>();
+            return output.page(&page);
+        }
+    }
+    // listings showing missing documents cannot carry a filter
+    let plans = match &geo {
+        _ if query.show_missing => vec![FilterPlan::client_only(conditions)],
+        // the geo ranges go first so theirs is the inequality sent to the server
+        Some(geo) => geo
+            .conditions()
+            .into_iter()
+            .map(|mut bounds| {
+                bounds.extend(conditions.iter().cloned());
+                FilterPlan::new(bounds)
+            })
+            .collect(),
+        None => vec![FilterPlan::new(conditions)],
+    };
+    // say where each condition runs, so nothing is silently dropped
+    for plan in &plans {
+        for (place, conditions) in &[("server", &plan.server), ("client", &plan.client)] {
+            if !conditions.is_empty() {
+                let conditions = conditions.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+                eprintln!("{}-side filter: {}", place, conditions.join(" AND "));
+            }
+        }
+    }
+    if let Some(geo) = &geo {
+        eprintln!(
+            "client-side filter: {} within {}m of {},{}",
+            geo.field, geo.radius, geo.center.latitude, geo.center.longitude
+        );
+    }
+    let path = CollectionPath::parse(&*query.collection_name)?;
+    if query.show_missing {
+        return list_with_missing(&ctx, database_name, &path, &plans[0], &query.select, output);
+    }
+    let queries = plans
+        .iter()
+        .map(|plan| {
+            let mut structured = StructuredQuery::collection(path.collection_id());
+            plan.apply(&mut structured);
+            // client-side conditions need the fields they test, so those documents are
+            // fetched whole and only cut down to the selection once they matched
+            if !query.select.is_empty() && plan.client.is_empty() && geo.is_none() {
+                structured.select = Some(Projection::new(&query.select));
+            }
+            (plan, structured)
+        })
+        .collect::<Vec<(&FilterPlan, StructuredQuery)>>();
+    let select = &query.select;
+    if let Some(interval) = &query.poll {
+        return poll::poll(
+            poll::parse_interval(&*interval)?,
+            query.diff,
+            output,
+            || {
+                let mut documents = Vec::new();
+                for (plan, structured) in &queries {
+                    for document in
+                        ctx.query_stream(database_name, path.parent(), structured.clone())
+                    {
+                        let document = document?;
+                        if plan.matches(&document) && near(&document) {
+                            documents.push(project(document, select));
+                        }
+                    }
+                }
+                Ok(documents)
+            },
+        );
+    }
+    let mut page = Vec::new();
+    // the ranges of a geo search do not overlap, no document is listed twice
+    for (plan, structured) in queries {
+        for document in ctx.query_stream(database_name, path.parent(), structured) {
+            let document = document?;
+            if plan.matches(&document) && near(&document) {
+                page.push(project(document, select));
+            }
+            if page.len() == LIST_PAGE_SIZE as usize {
+                output.page(&page)?;
+                page.clear();
+            }
+        }
+    }
+    if !page.is_empty() {
+        output.page(&page)?;
+    }
+    Ok(())
+}
+
+// Pages through listDocuments, which unlike runQuery reports missing documents
+fn list_with_missing(
+    ctx: &crate::DatabaseContext,
+    database_name: &str,
+    path: &CollectionPath,
+    plan: &FilterPlan,
+    select: &[String],
+    output: &OutputFormat,
+) -> Result<()> {
+    let parent = path.parent();
+    let mut page_token = None;
+    loop {
+        let page = ctx.list_documents(
+            database_name,
+            parent.as_ref(),
+            path.collection_id(),
+            LIST_PAGE_SIZE,
+            page_token,
+            true,
+        )?;
+        let mut found = Vec::new();
+        for lookup in page.documents {
+            match lookup {
+                Lookup::Found(document) => {
+                    if plan.matches(&document) {
+                        found.push(project(document, select));
+                    }
+                }
+                Lookup::Missing(name) => {
+                    if !plan.matches_fields(&FirestoreFields::default()) {
+                        continue;
+                    }
+                    // in order among the documents as text, other formats have no
+                    // place for them so stdout stays parseable
+                    if output.is_text() {
+                        output.page(&found)?;
+                        found.clear();
+                        println!("{} (missing)", name);
+                    } else {
+                        eprintln!("{} (missing)", name);
+                    }
+                }
+            }
+        }
+        output.page(&found)?;
+        page_token = page.next_page_token;
+        if page_token.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+// Keeps only the `--select` fields of a document, all of them when there are none
+fn project(document: Document, select: &[String]) -> Document {
+    if select.is_empty() {
+        return document;
+    }
+    let fields = document.fields().select(select);
+    document.with_fields(fields)
+}
+
+// Writes every bytes field, including those nested in maps, to `<dir>/<document>.<field path>`
+fn save_bytes_fields(
+    dir: &Path,
+    document_name: &str,
+    prefix: &str,
+    fields: &FirestoreFields,
+) -> Result<()> {
+    for (field, value) in fields.iter() {
+        let field_path = format!("{}{}", prefix, field);
+        match value {
+            FirestoreType::Bytes(bytes) => {
+                let file = dir.join(format!("{}.{}", document_name, field_path));
+                sink::write(&file, bytes)?;
+                println!("saved {} ({} bytes)", file.display(), bytes.len());
+            }
+            FirestoreType::Map(map) => save_bytes_fields(
+                dir,
+                document_name,
+                &*format!("{}.", field_path),
+                map.fields(),
+            )?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Prints the path of every collection beneath the document at `path`, or of every
+/// root collection, one per line so they can be fed to other commands
+pub fn handle_collection_ids(
+    ctx: &crate::DatabaseContext,
+    database_name: &str,
+    path: Option<&str>,
+) -> Result<()> {
+    let parent = match path {
+        Some(path) => Some(DocumentPath::parse(path)?),
+        None => None,
+    };
+    for id in ctx.collection_ids(database_name, parent.as_ref()) {
+        let id = id?;
+        match &parent {
+            Some(parent) => println!("{}/{}", parent, id),
+            None => println!("{}", id),
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_document_add(
+    collection: &str,
+    fields: &str,
+    ctx: crate::DatabaseContext,
+    planner: &WritePlanner,
+) -> Result<()> {
+    let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(fields)?;
+    if let Some(path) = planner.add(&ctx, collection, &fields)? {
+        println!("{}", path);
+    }
+    Ok(())
+}
+
+pub fn handle_document_set(
+    query: crate::SetDocumentQuery,
+    ctx: crate::DatabaseContext,
+    planner: &WritePlanner,
+) -> Result<()> {
+    let path = format!("{}/{}", query.collection_name, query.document_name);
+    let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&*query.fields)?;
+    let mut write = ctx.update_write(&*planner.database_name, &*path, &fields);
+    for spec in &query.bytes_fields {
+        let mut parts = spec.splitn(2, "=@");
+        match (parts.next(), parts.next()) {
+            (Some(field), Some(file)) if !field.is_empty() => {
+                let bytes = fs::read(file)?;
+                write = write.with_field(field, &FirestoreType::Bytes(bytes));
+            }
+            _ => {
+                return Err(Error::InvalidInput {
+                    message: format!("expected field=@path, got {}", spec),
+                })
+            }
+        }
+    }
+    planner.apply(&ctx, "set", vec![write], None)?;
+    Ok(())
+}
+
+pub fn handle_document_delete(
+    query: crate::DocumentQuery,
+    ctx: crate::DatabaseContext,
+    planner: &WritePlanner,
+) -> Result<()> {
+    let path = format!("{}/{}", query.collection_name, query.document_name);
+    let write = ctx.delete_write(&*planner.database_name, &*path);
+    planner.apply(&ctx, "delete", vec![write], None)?;
+    Ok(())
+}
 
 pub fn handle_database_export(
     query: crate::ExportCollectionQuery,
     ctx: crate::DatabaseContext,
+    database_name: &str,
 ) -> Result<()> {
-    ctx.export_database(firestore::databases::ExportDocumentQuery {
-        database_name: "".to_string(),
-        collection_ids: None,
-        output_uri_prefix: "".to_string(),
-    })
+    if let Some(collection) = &query.collection {
+        let conditions = query
+            .filters
+            .iter()
+            .map(|condition| condition.parse())
+            .collect::<Result<Vec<Condition>>>()?;
+        let plan = FilterPlan::new(conditions);
+        return dump::export_filtered(
+            &ctx,
+            database_name,
+            &*query.bucket_name,
+            collection.trim_matches('/'),
+            &plan,
+        );
+    }
+    if query.all_databases {
+        return export::export_all_databases(&ctx, &*query.bucket_name, &*query.collections);
+    }
+    let operation = ctx.export_database(ExportDocumentQuery {
+        database_name: ResourceName::database(&*ctx.project_id, database_name)?.to_string(),
+        collection_ids: if query.collections.is_empty() {
+            None
+        } else {
+            Some(query.collections)
+        },
+        output_uri_prefix: format!(
+            "gs://{}",
+            query
+                .bucket_name
+                .trim_start_matches("gs://")
+                .trim_end_matches('/')
+        ),
+    })?;
+    println!("started export {}", operation.name);
+    Ok(())
 }
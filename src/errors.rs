@@ -1,3 +1,4 @@
+use reqwest::header::InvalidHeaderValue;
 use reqwest::Error as ReqwestError;
 use serde_json::Error as SerdeError;
 
@@ -8,11 +9,120 @@ pub enum Error {
     #[snafu(display("Network Error: {}", source))]
     Network { source: ReqwestError },
 
+    /// A response Firestore explained, with the canonical status it failed with,
+    /// e.g. `FAILED_PRECONDITION`
+    #[snafu(display("Network Error: {} ({}: {})", source, status, message))]
+    Api {
+        source: ReqwestError,
+        status: String,
+        message: String,
+    },
+
     #[snafu(display("JSON Encode/Decode Error: {}", source))]
     JSON { source: ReqwestError },
 
     #[snafu(display("Unknown Error from reqwest: {}", source))]
     UnknownReqwest { source: ReqwestError },
+
+    #[snafu(display("Invalid Authorization Header: {}", source))]
+    InvalidHeader { source: InvalidHeaderValue },
+
+    #[snafu(display("Authentication Error: {}", message))]
+    Authentication { message: String },
+
+    #[snafu(display("Worker thread panicked while {}", task))]
+    WorkerPanic { task: String },
+
+    #[snafu(display("JSON Encode/Decode Error: {}", source))]
+    Serde { source: SerdeError },
+
+    #[snafu(display("I/O Error: {}", source))]
+    Io { source: std::io::Error },
+
+    #[snafu(display("Invalid Input: {}", message))]
+    InvalidInput { message: String },
+
+    #[snafu(display("Conflict: {}", message))]
+    Conflict { message: String },
+
+    #[snafu(display("Refusing to {} in read-only mode", operation))]
+    ReadOnly { operation: String },
+
+    #[snafu(display("{} was cancelled", operation))]
+    Cancelled { operation: String },
+
+    #[snafu(display("Incomplete Response: {}", message))]
+    IncompleteResponse { message: String },
+}
+
+impl Error {
+    /// HTTP status returned by Firestore, if the error came from a response
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            Error::Network { source }
+            | Error::UnknownReqwest { source }
+            | Error::Api { source, .. } => source.status(),
+            _ => None,
+        }
+    }
+
+    /// Canonical status Firestore named in the response, as several share one HTTP
+    /// status: `FAILED_PRECONDITION` and `INVALID_ARGUMENT` are both a 400
+    pub fn canonical_status(&self) -> Option<&str> {
+        match self {
+            Error::Api { status, .. } => Some(&**status),
+            _ => None,
+        }
+    }
+
+    /// The explanation Firestore gave along with the status
+    pub fn api_message(&self) -> Option<&str> {
+        match self {
+            Error::Api { message, .. } => Some(&**message),
+            _ => None,
+        }
+    }
+
+    /// Whether sending the same request again may succeed: timeouts, throttling,
+    /// server errors and failures that never produced a response
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Network { source }
+            | Error::UnknownReqwest { source }
+            | Error::Api { source, .. } => {
+                source.is_timeout()
+                    || match source.status() {
+                        Some(status) => {
+                            status.is_server_error()
+                                || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        }
+                        None => true,
+                    }
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether Firestore is asking clients to slow down: resource exhausted (429),
+    /// unavailable (503), deadline exceeded (504) or a request that timed out
+    pub fn is_throttled(&self) -> bool {
+        match self {
+            Error::Network { source }
+            | Error::UnknownReqwest { source }
+            | Error::Api { source, .. } => {
+                source.is_timeout()
+                    || match source.status() {
+                        Some(status) => {
+                            status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                                || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+                                || status == reqwest::StatusCode::GATEWAY_TIMEOUT
+                        }
+                        None => false,
+                    }
+            }
+            _ => false,
+        }
+    }
 }
 
 impl From<ReqwestError> for Error {
@@ -30,4 +140,22 @@ impl From<ReqwestError> for Error {
     }
 }
 
+impl From<InvalidHeaderValue> for Error {
+    fn from(source: InvalidHeaderValue) -> Self {
+        Error::InvalidHeader { source }
+    }
+}
+
+impl From<SerdeError> for Error {
+    fn from(source: SerdeError) -> Self {
+        Error::Serde { source }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Error::Io { source }
+    }
+}
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
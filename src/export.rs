@@ -0,0 +1,168 @@
+use libfiresale::api::export::Operation;
+use libfiresale::api::DatabaseContext;
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::ResourceName;
+use libfiresale::prelude::ExportDocumentQuery;
+use std::thread;
+use std::time::Duration;
+
+/// Between two rounds of polling the running exports
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One database being exported, with what was last heard of it
+struct Export {
+    database_id: String,
+    output_uri_prefix: String,
+    operation: Option<Operation>,
+    /// Why the export could not be started
+    error: Option<String>,
+}
+
+impl Export {
+    fn is_done(&self) -> bool {
+        self.error.is_some()
+            || self
+                .operation
+                .as_ref()
+                .map_or(false, |operation| operation.done)
+    }
+
+    fn state(&self) -> String {
+        match (&self.error, &self.operation) {
+            (Some(_), _) => "FAILED".to_string(),
+            (None, Some(operation)) => operation.state(),
+            (None, None) => "PENDING".to_string(),
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.is_done() && self.state() != "SUCCESSFUL"
+    }
+
+    fn documents(&self) -> String {
+        let documents = self.operation.as_ref().map(Operation::documents);
+        match documents {
+            Some((Some(completed), Some(estimated))) => format!("{}/{}", completed, estimated),
+            Some((Some(completed), None)) => completed.to_string(),
+            _ => "-".to_string(),
+        }
+    }
+
+    fn detail(&self) -> String {
+        let error = self
+            .operation
+            .as_ref()
+            .and_then(|operation| operation.error.as_ref())
+            .map(|status| status.message.clone());
+        self.error
+            .clone()
+            .or(error)
+            .unwrap_or_else(|| self.output_uri_prefix.clone())
+    }
+}
+
+/// Starts a managed export of every database of the project, each to
+/// `gs://<bucket>/<prefix>/<database id>`, then polls them until all are over, printing a
+/// table of their states whenever one changes. A database whose export cannot be started
+/// does not stop the others; the command fails once they are over if any did.
+pub fn export_all_databases(
+    ctx: &DatabaseContext,
+    destination: &str,
+    collections: &[String],
+) -> Result<()> {
+    let destination = format!(
+        "gs://{}",
+        destination
+            .trim_start_matches("gs://")
+            .trim_end_matches('/')
+    );
+    let database_ids = ctx.list_databases()?;
+    if database_ids.is_empty() {
+        return Err(Error::InvalidInput {
+            message: format!("project {} has no databases", ctx.project_id),
+        });
+    }
+    let mut exports = database_ids
+        .into_iter()
+        .map(|database_id| Export {
+            output_uri_prefix: format!("{}/{}", destination, database_id),
+            database_id,
+            operation: None,
+            error: None,
+        })
+        .collect::<Vec<Export>>();
+    for export in exports.iter_mut() {
+        let query = ResourceName::database(&*ctx.project_id, &*export.database_id).map(|name| {
+            ExportDocumentQuery {
+                database_name: name.to_string(),
+                collection_ids: if collections.is_empty() {
+                    None
+                } else {
+                    Some(collections.to_vec())
+                },
+                output_uri_prefix: export.output_uri_prefix.clone(),
+            }
+        });
+        match query.and_then(|query| ctx.export_database(query)) {
+            Ok(operation) => export.operation = Some(operation),
+            Err(e) => export.error = Some(e.to_string()),
+        }
+    }
+    let mut printed = Vec::new();
+    loop {
+        let states = exports.iter().map(Export::state).collect::<Vec<String>>();
+        if states != printed {
+            print_table(&exports);
+            printed = states;
+        }
+        if exports.iter().all(Export::is_done) {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+        for export in exports.iter_mut().filter(|export| !export.is_done()) {
+            let name = match &export.operation {
+                Some(operation) => operation.name.clone(),
+                None => continue,
+            };
+            // the export goes on regardless, so a failed poll is only reported
+            match ctx.export_status(&*name) {
+                Ok(operation) => export.operation = Some(operation),
+                Err(e) => eprintln!("warning: failed to poll {}: {}", name, e),
+            }
+        }
+    }
+    let failed = exports.iter().filter(|export| export.failed()).count();
+    if failed > 0 {
+        return Err(Error::InvalidInput {
+            message: format!("{} of {} export(s) did not succeed", failed, exports.len()),
+        });
+    }
+    Ok(())
+}
+
+fn print_table(exports: &[Export]) {
+    let width = exports
+        .iter()
+        .map(|export| export.database_id.len())
+        .chain(std::iter::once("DATABASE".len()))
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<width$}  {:<12}  {:>13}  OUTPUT",
+        "DATABASE",
+        "STATE",
+        "DOCUMENTS",
+        width = width
+    );
+    for export in exports {
+        println!(
+            "{:<width$}  {:<12}  {:>13}  {}",
+            export.database_id,
+            export.state(),
+            export.documents(),
+            export.detail(),
+            width = width
+        );
+    }
+    println!();
+}
@@ -1,11 +1,192 @@
 // This file contains 1:1 representations of the REST APIs firestore provides
 
+use super::debug::{Exchange, HttpHook, IncomingResponse, Interceptor, OutgoingRequest};
 use super::errors::{Error, Result};
+use super::policy::{RateLimit, RetryPolicy};
 use reqwest::header::HeaderMap;
+use reqwest::{Client, Method};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Instant;
 
+#[allow(dead_code)]
 const FIRESTORE_BASE_1BETA2: &'static str = "https://firestore.googleapis.com/v1beta2";
+const FIRESTORE_BASE_1: &'static str = "https://firestore.googleapis.com/v1";
 
-/// Contains 1:1 representations of gRPC firestore types
+/// An authorized client, plus the hook observing every request it sends and the
+/// policies it sends them with
+#[derive(Clone)]
+pub struct Transport {
+    pub client: Client,
+    pub headers: HeaderMap,
+    pub hook: Option<Arc<dyn HttpHook>>,
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+    pub retry: RetryPolicy,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Transport {
+    /// Sends a request with an optional JSON body and returns the response text,
+    /// reporting the exchange to the hook before checking the status
+    fn send<B: Serialize>(
+        &self,
+        method: Method,
+        url: &str,
+        query: &[(&str, String)],
+        body: Option<&B>,
+    ) -> Result<String> {
+        let body = match body {
+            Some(body) => Some(("application/json", serde_json::to_vec(body)?)),
+            None => None,
+        };
+        self.send_raw(method, url, query, body)
+    }
+
+    /// Like `send`, with a body of any content type
+    fn send_raw(
+        &self,
+        method: Method,
+        url: &str,
+        query: &[(&str, String)],
+        body: Option<(&str, Vec<u8>)>,
+    ) -> Result<String> {
+        self.retry.run(|| {
+            if let Some(rate_limit) = &self.rate_limit {
+                rate_limit.acquire(1);
+            }
+            let body = body
+                .as_ref()
+                .map(|(content_type, bytes)| (*content_type, bytes.clone()));
+            self.send_once(method.clone(), url, query, body)
+        })
+    }
+
+    fn send_once(
+        &self,
+        method: Method,
+        url: &str,
+        query: &[(&str, String)],
+        body: Option<(&str, Vec<u8>)>,
+    ) -> Result<String> {
+        let mut headers = self.headers.clone();
+        for interceptor in &self.interceptors {
+            interceptor.on_request(&mut OutgoingRequest {
+                method: method.as_str(),
+                url,
+                headers: &mut headers,
+                body: body.as_ref().map(|(_, bytes)| &**bytes),
+            })?;
+        }
+        let mut request = self
+            .client
+            .request(method.clone(), url)
+            .headers(headers.clone())
+            .query(query);
+        // the hook sees text bodies as they are and only the size of binary ones
+        let logged_body = body
+            .as_ref()
+            .map(|(_, bytes)| match std::str::from_utf8(bytes) {
+                Ok(text) => text.to_string(),
+                Err(_) => format!("<{} bytes>", bytes.len()),
+            });
+        if let Some((content_type, bytes)) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(bytes);
+        }
+        let sent_at = Instant::now();
+        let mut response = request.send()?;
+        let text = response.text()?;
+        for interceptor in &self.interceptors {
+            interceptor.on_response(&IncomingResponse {
+                method: method.as_str(),
+                url: response.url().as_str(),
+                status: response.status().as_u16(),
+                headers: response.headers(),
+                body: &*text,
+                elapsed: sent_at.elapsed(),
+            })?;
+        }
+        if let Some(hook) = &self.hook {
+            hook.exchange(&Exchange {
+                method: method.as_str(),
+                url: response.url().as_str(),
+                request_headers: &headers,
+                request_body: logged_body.as_ref().map(|body| &**body),
+                status: response.status().as_u16(),
+                response_headers: response.headers(),
+                response_body: &*text,
+            });
+        }
+        if let Err(source) = response.error_for_status() {
+            // the body says which of the canonical statuses sharing the code it was
+            return Err(match serde_json::from_str::<types::ErrorResponse>(&*text) {
+                Ok(body) => Error::Api {
+                    source,
+                    status: body.error.status,
+                    message: body.error.message,
+                },
+                Err(_) => source.into(),
+            });
+        }
+        Ok(text)
+    }
+
+    /// Like `send`, decoding the response body as JSON
+    fn send_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        url: &str,
+        query: &[(&str, String)],
+        body: Option<&B>,
+    ) -> Result<T> {
+        let text = self.send(method, url, query, body)?;
+        serde_json::from_str(&*text).map_err(Error::from)
+    }
+}
+
+/// Cloud Storage, used to stage files firesale produces itself
+pub mod storage {
+    use super::{Method, Result, Transport};
+
+    const STORAGE_UPLOAD_BASE: &'static str = "https://storage.googleapis.com/upload/storage/v1";
+
+    /// https://cloud.google.com/storage/docs/json_api/v1/objects/insert
+    pub fn upload_object(
+        transport: &Transport,
+        bucket: &str,
+        object: &str,
+        content_type: &str,
+        contents: Vec<u8>,
+    ) -> Result<()> {
+        let url = format!("{}/b/{}/o", STORAGE_UPLOAD_BASE, bucket);
+        let query = [
+            ("uploadType", "media".to_string()),
+            ("name", object.to_string()),
+        ];
+        transport
+            .send_raw(Method::POST, &*url, &query, Some((content_type, contents)))
+            .map(|_| ())
+    }
+}
+
+/// Endpoints outside Google that firesale reports to, such as webhooks
+pub mod webhook {
+    use super::{Method, Result, Transport};
+    use serde::Serialize;
+
+    /// POSTs `body` as JSON to `url`
+    pub fn post<B: Serialize>(transport: &Transport, url: &str, body: &B) -> Result<()> {
+        transport
+            .send(Method::POST, url, &[], Some(body))
+            .map(|_| ())
+    }
+}
+
+/// Contains 1:1 representations of gRPC firestore types. Those of long running
+/// operations are only used by `import_documents`, which no command calls yet.
+#[allow(dead_code)]
 mod types {
     use serde::Deserialize;
     use std::collections::HashMap;
@@ -46,21 +227,34 @@ mod types {
     /// Represents `google.protobuf.Empty`
     #[derive(Deserialize)]
     pub struct EmptyResponse;
+
+    /// Body of a failed response, https://cloud.google.com/apis/design/errors#http_mapping
+    #[derive(Deserialize)]
+    pub struct ErrorResponse {
+        pub error: ErrorStatus,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ErrorStatus {
+        #[serde(default)]
+        pub status: String,
+        #[serde(default)]
+        pub message: String,
+    }
 }
 
 pub mod databases {
     use super::types::{EmptyResponse, Operation};
-    use super::{Error, HeaderMap, Result};
-    use reqwest::Client;
-    use snafu::ResultExt;
+    use super::{Method, Result, Transport};
+    use crate::api::{databases, export};
 
     /// Represents the input parameters for `export_documents`
     pub struct ExportDocumentQuery {
         /// Database to export. Should be of the form:
         /// projects/{project_id}/databases/{database_id}.
-        database_name: String,
-        collection_ids: Option<Vec<String>>,
-        output_uri_prefix: String,
+        pub database_name: String,
+        pub collection_ids: Option<Vec<String>>,
+        pub output_uri_prefix: String,
     }
 
     #[derive(Serialize)]
@@ -82,36 +276,51 @@ pub mod databases {
         }
     }
 
-    /// https://firebase.google.com/docs/firestore/reference/rest/v1beta2/projects.databases/exportDocuments
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases/exportDocuments
     pub fn export_documents(
-        client: Client,
-        headers: HeaderMap,
+        transport: &Transport,
         params: ExportDocumentQuery,
-    ) -> Result<Operation<EmptyResponse>> {
-        fn make_url(name: &str) -> String {
-            format!(
-                "{}/{{name={}}}:exportDocuments",
-                super::FIRESTORE_BASE_1BETA2,
-                name
-            )
-        }
+    ) -> Result<export::Operation> {
         // setup parameters
-        let database_name = &*params.database_name;
-        let url = &*make_url(database_name);
+        let url = &*format!(
+            "{}/{}:exportDocuments",
+            super::FIRESTORE_BASE_1,
+            params.database_name
+        );
         let request_body = params.into_body();
         // send request
-        let mut response = client.post(url).headers(headers).send()?;
-        response
-            .json::<Operation<EmptyResponse>>()
-            .map_err(Error::from)
+        transport.send_json(Method::POST, url, &[], Some(&request_body))
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.operations/get
+    pub fn get_export(transport: &Transport, name: &str) -> Result<export::Operation> {
+        let url = &*format!("{}/{}", super::FIRESTORE_BASE_1, name);
+        transport.send_json(Method::GET, url, &[], None::<&()>)
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases/list
+    pub fn list(transport: &Transport, project_id: &str) -> Result<databases::ListResponse> {
+        let url = &*format!(
+            "{}/projects/{}/databases",
+            super::FIRESTORE_BASE_1,
+            project_id
+        );
+        transport.send_json(Method::GET, url, &[], None::<&()>)
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases/get
+    pub fn get(transport: &Transport, name: &str) -> Result<databases::Database> {
+        let url = &*format!("{}/{}", super::FIRESTORE_BASE_1, name);
+        transport.send_json(Method::GET, url, &[], None::<&()>)
     }
 
     pub struct ImportDocumentQuery {
-        database_name: String,
-        collection_ids: Vec<String>,
-        input_uri_prefix: String,
+        pub database_name: String,
+        pub collection_ids: Vec<String>,
+        pub input_uri_prefix: String,
     }
 
+    #[allow(dead_code)]
     impl ImportDocumentQuery {
         fn into_body(self) -> ImportDocumentBody {
             let collection_ids = self.collection_ids;
@@ -124,6 +333,7 @@ pub mod databases {
     }
 
     /// Input body for `import_documents`
+    #[allow(dead_code)]
     #[derive(Serialize)]
     struct ImportDocumentBody {
         /// Which collection ids to import. Unspecified means all collections included in the import.
@@ -137,9 +347,9 @@ pub mod databases {
     }
 
     /// https://firebase.google.com/docs/firestore/reference/rest/v1beta2/projects.databases/importDocuments
+    #[allow(dead_code)]
     pub fn import_documents(
-        client: Client,
-        headers: HeaderMap,
+        transport: &Transport,
         params: ImportDocumentQuery,
     ) -> Result<Operation<EmptyResponse>> {
         fn make_url(name: &str) -> String {
@@ -154,10 +364,371 @@ pub mod databases {
         let url = &*make_url(database_name);
         let request_body = params.into_body();
         // send request
-        let mut response = client.post(url).headers(headers).send()?;
-        response
-            .json::<Operation<EmptyResponse>>()
-            .map_err(Error::from)
+        transport.send_json(Method::POST, url, &[], Some(&request_body))
     }
+}
 
+pub mod documents {
+    use super::{Method, Result, Transport};
+    use crate::api::{
+        batch_get, batch_write, commit, list_collection_ids, list_documents, query, transaction,
+        ConsistencySelector, Document, DocumentMask, Write,
+    };
+    use chrono::{DateTime, Utc};
+
+    /// Represents the input parameters for `get`
+    pub struct GetDocumentQuery {
+        /// Resource name of the document. Should be of the form:
+        /// projects/{project_id}/databases/{database_id}/documents/{document_path}.
+        pub name: String,
+        /// Reads the document within a transaction or at a given time
+        pub consistency: Option<ConsistencySelector>,
+        /// Returns only these fields
+        pub mask: Option<DocumentMask>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/get
+    pub fn get(transport: &Transport, params: GetDocumentQuery) -> Result<Document> {
+        let url = &*format!("{}/{}", super::FIRESTORE_BASE_1, params.name);
+        let mut query = params
+            .consistency
+            .iter()
+            .map(ConsistencySelector::query_pair)
+            .collect::<Vec<_>>();
+        if let Some(mask) = &params.mask {
+            for field_path in mask.field_paths() {
+                query.push(("mask.fieldPaths", field_path.clone()));
+            }
+        }
+        // send request
+        transport.send_json(Method::GET, url, &*query, None::<&()>)
+    }
+
+    /// Represents the input parameters for `create`
+    pub struct CreateDocumentQuery {
+        /// Either projects/{project_id}/databases/{database_id}/documents
+        /// or a document beneath it when creating in a subcollection
+        pub parent: String,
+        pub collection_id: String,
+        /// Assigned by the server when not given
+        pub document_id: Option<String>,
+        /// Field values in wire format
+        pub fields: serde_json::Map<String, serde_json::Value>,
+    }
+
+    #[derive(Serialize)]
+    struct CreateDocumentBody {
+        fields: serde_json::Map<String, serde_json::Value>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/createDocument
+    pub fn create(transport: &Transport, params: CreateDocumentQuery) -> Result<Document> {
+        let url = &*format!(
+            "{}/{}/{}",
+            super::FIRESTORE_BASE_1,
+            params.parent,
+            params.collection_id
+        );
+        let query = params
+            .document_id
+            .into_iter()
+            .map(|document_id| ("documentId", document_id))
+            .collect::<Vec<_>>();
+        let request_body = CreateDocumentBody {
+            fields: params.fields,
+        };
+        // send request
+        transport.send_json(Method::POST, url, &*query, Some(&request_body))
+    }
+
+    /// Represents the input parameters for `patch`
+    pub struct PatchDocumentQuery {
+        /// Resource name of the document. Should be of the form:
+        /// projects/{project_id}/databases/{database_id}/documents/{document_path}.
+        pub name: String,
+        /// Field values in wire format
+        pub fields: serde_json::Map<String, serde_json::Value>,
+        /// Replaces only these fields, the whole document when not given
+        pub update_mask: Option<DocumentMask>,
+    }
+
+    #[derive(Serialize)]
+    struct PatchDocumentBody {
+        fields: serde_json::Map<String, serde_json::Value>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/patch
+    pub fn patch(transport: &Transport, params: PatchDocumentQuery) -> Result<Document> {
+        let url = &*format!("{}/{}", super::FIRESTORE_BASE_1, params.name);
+        let mut query = Vec::new();
+        if let Some(mask) = &params.update_mask {
+            for field_path in mask.field_paths() {
+                query.push(("updateMask.fieldPaths", field_path.clone()));
+            }
+        }
+        let request_body = PatchDocumentBody {
+            fields: params.fields,
+        };
+        // send request
+        transport.send_json(Method::PATCH, url, &*query, Some(&request_body))
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/delete
+    pub fn delete(transport: &Transport, name: &str) -> Result<()> {
+        let url = &*format!("{}/{}", super::FIRESTORE_BASE_1, name);
+        transport.send(Method::DELETE, url, &[], None::<&()>)?;
+        Ok(())
+    }
+
+    /// Represents the input parameters for `list`
+    pub struct ListDocumentsQuery {
+        /// Either projects/{project_id}/databases/{database_id}/documents
+        /// or a document beneath it when listing subcollections
+        pub parent: String,
+        pub collection_id: String,
+        pub page_size: i32,
+        pub page_token: Option<String>,
+        /// Include documents that do not exist but have subcollections
+        pub show_missing: bool,
+        /// Lists the documents as they were at that time
+        pub read_time: Option<DateTime<Utc>>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/list
+    pub fn list(
+        transport: &Transport,
+        params: ListDocumentsQuery,
+    ) -> Result<list_documents::Response> {
+        let url = &*format!(
+            "{}/{}/{}",
+            super::FIRESTORE_BASE_1,
+            params.parent,
+            params.collection_id
+        );
+        let mut query = vec![("pageSize", params.page_size.to_string())];
+        if let Some(page_token) = params.page_token {
+            query.push(("pageToken", page_token));
+        }
+        if params.show_missing {
+            query.push(("showMissing", "true".to_string()));
+        }
+        if let Some(read_time) = params.read_time {
+            query.push(("readTime", read_time.to_rfc3339()));
+        }
+        // send request
+        transport.send_json(Method::GET, url, &*query, None::<&()>)
+    }
+
+    /// Represents the input parameters for `list_collection_ids`
+    pub struct ListCollectionIdsQuery {
+        /// Either projects/{project_id}/databases/{database_id}/documents for root
+        /// collections or a document beneath it for its subcollections
+        pub parent: String,
+        pub body: list_collection_ids::Request,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/listCollectionIds
+    pub fn list_collection_ids(
+        transport: &Transport,
+        params: ListCollectionIdsQuery,
+    ) -> Result<list_collection_ids::Response> {
+        let url = &*format!(
+            "{}/{}:listCollectionIds",
+            super::FIRESTORE_BASE_1,
+            params.parent
+        );
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&params.body))
+    }
+
+    /// Represents the input parameters for `run_query`
+    pub struct RunQueryQuery {
+        /// Parent resource, either projects/{project_id}/databases/{database_id}/documents
+        /// or a document beneath it when querying subcollections
+        pub parent: String,
+        pub body: query::RunQueryRequest,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/runQuery
+    /// N.B. the REST endpoint streams one response per result
+    pub fn run_query(
+        transport: &Transport,
+        params: RunQueryQuery,
+    ) -> Result<Vec<query::RunQueryResponse>> {
+        let url = &*format!("{}/{}:runQuery", super::FIRESTORE_BASE_1, params.parent);
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&params.body))
+    }
+
+    /// Represents the input parameters for `run_aggregation_query`
+    pub struct RunAggregationQueryQuery {
+        /// Parent resource, as for `run_query`
+        pub parent: String,
+        pub body: query::RunAggregationQueryRequest,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/runAggregationQuery
+    /// N.B. like `run_query`, the REST endpoint streams its responses
+    pub fn run_aggregation_query(
+        transport: &Transport,
+        params: RunAggregationQueryQuery,
+    ) -> Result<Vec<query::RunAggregationQueryResponse>> {
+        let url = &*format!(
+            "{}/{}:runAggregationQuery",
+            super::FIRESTORE_BASE_1,
+            params.parent
+        );
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&params.body))
+    }
+
+    /// Represents the input parameters for `partition_query`
+    pub struct PartitionQueryQuery {
+        /// Parent resource, as for `run_query`
+        pub parent: String,
+        pub body: query::PartitionQueryRequest,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/partitionQuery
+    pub fn partition_query(
+        transport: &Transport,
+        params: PartitionQueryQuery,
+    ) -> Result<query::PartitionQueryResponse> {
+        let url = &*format!(
+            "{}/{}:partitionQuery",
+            super::FIRESTORE_BASE_1,
+            params.parent
+        );
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&params.body))
+    }
+
+    /// Represents the input parameters for `begin_transaction`
+    pub struct BeginTransactionQuery {
+        pub database_name: String,
+        pub options: transaction::Options,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/beginTransaction
+    pub fn begin_transaction(
+        transport: &Transport,
+        params: BeginTransactionQuery,
+    ) -> Result<transaction::BeginResponse> {
+        let url = &*format!(
+            "{}/{}/documents:beginTransaction",
+            super::FIRESTORE_BASE_1,
+            params.database_name
+        );
+        let request_body = transaction::BeginRequest {
+            options: params.options,
+        };
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&request_body))
+    }
+
+    /// Represents the input parameters for `commit`
+    pub struct CommitQuery {
+        pub database_name: String,
+        pub writes: Vec<Write>,
+        /// If set, applies all writes in this transaction and commits it
+        pub transaction: Option<String>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/commit
+    pub fn commit(transport: &Transport, params: CommitQuery) -> Result<commit::Response> {
+        let url = &*format!(
+            "{}/{}/documents:commit",
+            super::FIRESTORE_BASE_1,
+            params.database_name
+        );
+        let request_body = commit::Request {
+            writes: params.writes,
+            transaction: params.transaction,
+        };
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&request_body))
+    }
+
+    /// Represents the input parameters for `batch_write`
+    pub struct BatchWriteQuery {
+        pub database_name: String,
+        pub writes: Vec<Write>,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/batchWrite
+    pub fn batch_write(
+        transport: &Transport,
+        params: BatchWriteQuery,
+    ) -> Result<batch_write::Response> {
+        let url = &*format!(
+            "{}/{}/documents:batchWrite",
+            super::FIRESTORE_BASE_1,
+            params.database_name
+        );
+        let request_body = batch_write::Request {
+            writes: params.writes,
+        };
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&request_body))
+    }
+
+    /// Represents the input parameters for `rollback`
+    pub struct RollbackQuery {
+        pub database_name: String,
+        pub transaction: String,
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/rollback
+    pub fn rollback(transport: &Transport, params: RollbackQuery) -> Result<()> {
+        let url = &*format!(
+            "{}/{}/documents:rollback",
+            super::FIRESTORE_BASE_1,
+            params.database_name
+        );
+        let request_body = transaction::RollbackRequest {
+            transaction: params.transaction,
+        };
+        transport.send(Method::POST, url, &[], Some(&request_body))?;
+        Ok(())
+    }
+
+    /// Represents the input parameters for `batch_get`
+    pub struct BatchGetQuery {
+        /// Database to read from. Should be of the form:
+        /// projects/{project_id}/databases/{database_id}.
+        pub database_name: String,
+        /// Full resource names of the documents to retrieve
+        pub documents: Vec<String>,
+        /// Reads the documents as they were at that time
+        pub read_time: Option<DateTime<Utc>>,
+    }
+
+    impl BatchGetQuery {
+        fn into_body(self) -> batch_get::Request {
+            batch_get::Request {
+                documents: self.documents,
+                read_time: self.read_time,
+            }
+        }
+    }
+
+    /// https://firebase.google.com/docs/firestore/reference/rest/v1/projects.databases.documents/batchGet
+    /// N.B. the REST endpoint streams one response per requested document
+    pub fn batch_get(
+        transport: &Transport,
+        params: BatchGetQuery,
+    ) -> Result<Vec<batch_get::Response>> {
+        fn make_url(database: &str) -> String {
+            format!(
+                "{}/{}/documents:batchGet",
+                super::FIRESTORE_BASE_1,
+                database
+            )
+        }
+        // setup parameters
+        let url = &*make_url(&*params.database_name);
+        let request_body = params.into_body();
+        // send request
+        transport.send_json(Method::POST, url, &[], Some(&request_body))
+    }
 }
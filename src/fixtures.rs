@@ -0,0 +1,167 @@
+use crate::dump::relative_path;
+use libfiresale::api::{DatabaseContext, Document};
+use libfiresale::canonical::checksum;
+use libfiresale::errors::Result;
+use libfiresale::path::{CollectionPath, ResourceName};
+use libfiresale::prelude::StructuredQuery;
+use libfiresale::sink;
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+
+/// Decimal places kept of anonymized geopoints, about 11km
+const GEOPOINT_PRECISION: f64 = 10.0;
+
+/// Writes a random sample of `collection` to `<out>/<collection>.json`, anonymized, as
+/// the body of a Firestore `commit` request creating the documents in `project_id`.
+/// POSTing it to the emulator's `projects/<project_id>/databases/(default)/documents:commit`
+/// seeds it for rules tests.
+///
+/// Strings become pseudonyms, the same text getting the same pseudonym within a run so
+/// equal values stay equal, bytes are zeroed and geopoints rounded. Numbers, booleans,
+/// timestamps, references and document ids are kept, as are the fields in `keep`.
+pub fn fixtures(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    sample: usize,
+    out: &str,
+    project_id: &str,
+    keep: &[String],
+) -> Result<()> {
+    let collection = collection.trim_matches('/');
+    let path = CollectionPath::parse(collection)?;
+    let query = StructuredQuery::collection(path.collection_id());
+    let mut rng = rand::thread_rng();
+    // reservoir sampling, so the collection is read once without holding all of it
+    let mut chosen: Vec<Document> = Vec::with_capacity(sample);
+    for (seen, document) in ctx
+        .query_stream(database_name, path.parent(), query)
+        .enumerate()
+    {
+        let document = document?;
+        if chosen.len() < sample {
+            chosen.push(document);
+        } else {
+            let slot = rng.gen_range(0, seen + 1);
+            if slot < sample {
+                chosen[slot] = document;
+            }
+        }
+    }
+    let anonymizer = Anonymizer {
+        salt: rng.gen(),
+        keep,
+    };
+    let root = ResourceName::documents(project_id, "(default)")?;
+    let writes = chosen
+        .iter()
+        .map(|document| {
+            let fields = serde_json::to_value(document.fields())?;
+            Ok(json!({
+                "update": {
+                    "name": format!("{}/{}", root, relative_path(document.name())),
+                    "fields": anonymizer.fields(fields, ""),
+                }
+            }))
+        })
+        .collect::<Result<Vec<Value>>>()?;
+    fs::create_dir_all(out)?;
+    let file = Path::new(out).join(format!("{}.json", collection.replace('/', ".")));
+    sink::write_json(&file, &json!({ "writes": writes }))?;
+    println!(
+        "{}: {} document(s) written to {}",
+        collection,
+        writes.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+struct Anonymizer<'a> {
+    /// Random per run, so pseudonyms cannot be matched against hashed guesses
+    salt: u64,
+    keep: &'a [String],
+}
+
+impl<'a> Anonymizer<'a> {
+    // Anonymizes the wire format fields of a map, `prefix` being the map's field path
+    fn fields(&self, fields: Value, prefix: &str) -> Value {
+        let fields = match fields {
+            Value::Object(fields) => fields,
+            other => return other,
+        };
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(field, value)| {
+                    let path = format!("{}{}", prefix, field);
+                    let value = if self.keep.contains(&path) {
+                        value
+                    } else {
+                        self.value(value, &*path)
+                    };
+                    (field, value)
+                })
+                .collect::<Map<String, Value>>(),
+        )
+    }
+
+    fn value(&self, value: Value, path: &str) -> Value {
+        let (kind, inner) = match value {
+            Value::Object(mut value) if value.len() == 1 => {
+                let kind = value.keys().next().cloned().unwrap_or_default();
+                let inner = value.remove(&*kind).unwrap_or(Value::Null);
+                (kind, inner)
+            }
+            other => return other,
+        };
+        let inner = match (&*kind, inner) {
+            ("stringValue", Value::String(text)) => Value::String(self.pseudonym(&*text)),
+            ("bytesValue", Value::String(encoded)) => {
+                let length = base64::decode(&*encoded).map_or(0, |bytes| bytes.len());
+                Value::String(base64::encode(&vec![0u8; length]))
+            }
+            ("geoPointValue", Value::Object(point)) => Value::Object(
+                point
+                    .into_iter()
+                    .map(|(axis, degrees)| {
+                        let rounded = degrees
+                            .as_f64()
+                            .map(|d| (d * GEOPOINT_PRECISION).round() / GEOPOINT_PRECISION)
+                            .map_or(degrees, |d| json!(d));
+                        (axis, rounded)
+                    })
+                    .collect(),
+            ),
+            ("mapValue", mut map) => {
+                if let Some(fields) = map.get_mut("fields") {
+                    *fields = self.fields(fields.take(), &*format!("{}.", path));
+                }
+                map
+            }
+            ("arrayValue", mut array) => {
+                if let Some(Value::Array(values)) = array.get_mut("values") {
+                    for element in values.iter_mut() {
+                        *element = self.value(element.take(), path);
+                    }
+                }
+                array
+            }
+            (_, inner) => inner,
+        };
+        let mut value = Map::new();
+        value.insert(kind, inner);
+        Value::Object(value)
+    }
+
+    // Email addresses stay valid addresses so format checks in rules still pass
+    fn pseudonym(&self, text: &str) -> String {
+        let hash = checksum(&json!([self.salt, text]));
+        if text.contains('@') {
+            return format!("user-{}@example.com", &hash[..8]);
+        }
+        hash[..8].to_string()
+    }
+}
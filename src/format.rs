@@ -0,0 +1,540 @@
+//! Rendering of documents, collection listings and reports. Each `Formatter` is
+//! registered by name in `Formatters`, which is how the CLI's `--output` picks one;
+//! library users can register their own next to the built-in `text`, `json`,
+//! `ndjson`, `yaml` and `table`.
+//!
+//! Only the table is rendered for people to read, so it is the one following a
+//! `Locale`; the JSON based formats always write numbers and times the same way.
+
+use crate::api::{ArrayValue, Document, Double, FirestoreFields, FirestoreType, MapValue};
+use crate::errors::{Error, Result};
+use crate::path::DocumentReference;
+use crate::report::Report;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+pub trait Formatter: Send + Sync {
+    fn document(&self, out: &mut dyn Write, document: &Document) -> io::Result<()>;
+
+    /// Renders one page of a listing. Long listings are rendered a page at a time, so
+    /// a format wrapping documents in a container gets one per page.
+    fn page(&self, out: &mut dyn Write, documents: &[Document]) -> io::Result<()> {
+        for document in documents {
+            self.document(out, document)?;
+        }
+        Ok(())
+    }
+
+    fn report(&self, out: &mut dyn Write, report: &Report) -> io::Result<()>;
+}
+
+/// Formatters by name, starting with the built-in ones
+pub struct Formatters {
+    formatters: BTreeMap<String, Arc<dyn Formatter>>,
+}
+
+impl Formatters {
+    pub fn new() -> Formatters {
+        let mut formatters = Formatters {
+            formatters: BTreeMap::new(),
+        };
+        formatters.register("text", Text);
+        formatters.register("json", Json);
+        formatters.register("ndjson", Ndjson);
+        formatters.register("yaml", Yaml);
+        formatters.register("table", Table::default());
+        formatters
+    }
+
+    /// Adds `formatter` under `name`, replacing any formatter already using it
+    pub fn register<S, F>(&mut self, name: S, formatter: F)
+    where
+        S: Into<String>,
+        F: Formatter + 'static,
+    {
+        self.formatters.insert(name.into(), Arc::new(formatter));
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn Formatter>> {
+        self.formatters
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::InvalidInput {
+                message: format!(
+                    "unknown output format {}, expected one of {}",
+                    name,
+                    self.names().join(", ")
+                ),
+            })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.formatters.keys().map(|name| &**name).collect()
+    }
+}
+
+impl Default for Formatters {
+    fn default() -> Formatters {
+        Formatters::new()
+    }
+}
+
+/// Limits on how much of a large value is rendered, `None` meaning no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Truncation {
+    /// Longest string or bytes value shown, in bytes
+    pub max_field_bytes: Option<usize>,
+    pub max_array_items: Option<usize>,
+}
+
+impl Truncation {
+    /// `document` with long strings and bytes cut short and long arrays shortened at
+    /// any depth, each ending with a note of what was left out
+    pub fn document(&self, document: &Document) -> Document {
+        if *self == Truncation::default() {
+            return document.clone();
+        }
+        document.clone().with_fields(self.fields(document.fields()))
+    }
+
+    fn fields(&self, fields: &FirestoreFields) -> FirestoreFields {
+        fields
+            .iter()
+            .map(|(field, value)| (field.clone(), self.value(value)))
+            .collect()
+    }
+
+    fn value(&self, value: &FirestoreType) -> FirestoreType {
+        match value {
+            FirestoreType::String(text) => match self.max_field_bytes {
+                Some(max) if text.len() > max => {
+                    let mut end = max;
+                    while !text.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    let left_out = text.len() - end;
+                    FirestoreType::String(format!("{}… ({} more bytes)", &text[..end], left_out))
+                }
+                _ => value.clone(),
+            },
+            // what is left of a bytes value is shown as base64 text, with the note
+            FirestoreType::Bytes(bytes) => match self.max_field_bytes {
+                Some(max) if bytes.len() > max => FirestoreType::String(format!(
+                    "{}… ({} more bytes)",
+                    base64::encode(&bytes[..max]),
+                    bytes.len() - max
+                )),
+                _ => value.clone(),
+            },
+            FirestoreType::Array(array) => {
+                let values = array.values();
+                let shown = self
+                    .max_array_items
+                    .unwrap_or(values.len())
+                    .min(values.len());
+                let mut truncated = values[..shown]
+                    .iter()
+                    .map(|value| self.value(value))
+                    .collect::<Vec<FirestoreType>>();
+                if shown < values.len() {
+                    let note = format!("… ({} more items)", values.len() - shown);
+                    truncated.push(FirestoreType::String(note));
+                }
+                FirestoreType::Array(ArrayValue::new(truncated))
+            }
+            FirestoreType::Map(map) => FirestoreType::Map(MapValue::new(self.fields(map.fields()))),
+            value => value.clone(),
+        }
+    }
+}
+
+/// A document as plain JSON: its path relative to the database root, its create and
+/// update times and its fields
+pub fn document_json(document: &Document) -> Value {
+    json!({
+        "path": relative_path(document.name()),
+        "createTime": document.create_time().to_rfc3339(),
+        "updateTime": document.update_time().to_rfc3339(),
+        "fields": document.fields().to_json(),
+    })
+}
+
+fn relative_path(name: &str) -> String {
+    DocumentReference::parse(name)
+        .map(|reference| reference.path.to_string())
+        .unwrap_or_else(|_| name.to_string())
+}
+
+fn report_json(report: &Report) -> io::Result<Value> {
+    serde_json::to_value(report).map_err(io::Error::from)
+}
+
+/// Debug output of documents, and each finding's message for reports
+pub struct Text;
+
+impl Formatter for Text {
+    fn document(&self, out: &mut dyn Write, document: &Document) -> io::Result<()> {
+        writeln!(out, "{:#?}", document)
+    }
+
+    fn report(&self, out: &mut dyn Write, report: &Report) -> io::Result<()> {
+        for finding in &report.findings {
+            match &finding.field {
+                Some(field) => {
+                    writeln!(out, "{}: {}: {}", finding.subject, field, finding.message)?
+                }
+                None => writeln!(out, "{}: {}", finding.subject, finding.message)?,
+            }
+        }
+        for (key, count) in &report.summary {
+            writeln!(out, "{}: {}", key, count)?;
+        }
+        writeln!(out, "{}", if report.ok { "ok" } else { "failed" })
+    }
+}
+
+/// Pretty printed JSON, pages as arrays
+pub struct Json;
+
+impl Formatter for Json {
+    fn document(&self, out: &mut dyn Write, document: &Document) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, &document_json(document))?;
+        writeln!(out)
+    }
+
+    fn page(&self, out: &mut dyn Write, documents: &[Document]) -> io::Result<()> {
+        let page = documents.iter().map(document_json).collect::<Vec<Value>>();
+        serde_json::to_writer_pretty(&mut *out, &page)?;
+        writeln!(out)
+    }
+
+    fn report(&self, out: &mut dyn Write, report: &Report) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, report)?;
+        writeln!(out)
+    }
+}
+
+/// One compact JSON value per line
+pub struct Ndjson;
+
+impl Formatter for Ndjson {
+    fn document(&self, out: &mut dyn Write, document: &Document) -> io::Result<()> {
+        writeln!(out, "{}", document_json(document))
+    }
+
+    fn report(&self, out: &mut dyn Write, report: &Report) -> io::Result<()> {
+        writeln!(out, "{}", report_json(report)?)
+    }
+}
+
+/// YAML, one YAML document per rendered document
+pub struct Yaml;
+
+impl Formatter for Yaml {
+    fn document(&self, out: &mut dyn Write, document: &Document) -> io::Result<()> {
+        writeln!(out, "---")?;
+        for line in yaml_lines(&document_json(document)) {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn report(&self, out: &mut dyn Write, report: &Report) -> io::Result<()> {
+        writeln!(out, "---")?;
+        for line in yaml_lines(&report_json(report)?) {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+fn yaml_lines(value: &Value) -> Vec<String> {
+    match value {
+        Value::Object(map) if !map.is_empty() => map
+            .iter()
+            .flat_map(|(key, value)| yaml_entry(&*yaml_scalar(&Value::String(key.clone())), value))
+            .collect(),
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .flat_map(|item| {
+                yaml_lines(item)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, line)| format!("{}{}", if i == 0 { "- " } else { "  " }, line))
+                    .collect::<Vec<String>>()
+            })
+            .collect(),
+        scalar => vec![yaml_scalar(scalar)],
+    }
+}
+
+fn yaml_entry(key: &str, value: &Value) -> Vec<String> {
+    match value {
+        Value::Object(map) if !map.is_empty() => nested(key, value),
+        Value::Array(items) if !items.is_empty() => nested(key, value),
+        scalar => vec![format!("{}: {}", key, yaml_scalar(scalar))],
+    }
+}
+
+fn nested(key: &str, value: &Value) -> Vec<String> {
+    let mut lines = vec![format!("{}:", key)];
+    lines.extend(
+        yaml_lines(value)
+            .into_iter()
+            .map(|line| format!("  {}", line)),
+    );
+    lines
+}
+
+// Strings are left plain when YAML would not read them as anything else
+fn yaml_scalar(value: &Value) -> String {
+    match value {
+        Value::String(text) => {
+            let plain = text
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+                && text
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-./ ".contains(c))
+                && !text.ends_with(' ')
+                && !["true", "false", "null", "yes", "no", "on", "off", "y", "n"]
+                    .contains(&&*text.to_lowercase());
+            if plain {
+                text.clone()
+            } else {
+                Value::String(text.clone()).to_string()
+            }
+        }
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// How numbers and times read in a given locale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locale {
+    /// Between groups of three integer digits, if any
+    pub thousands: Option<char>,
+    pub decimal: char,
+    /// `chrono` format of a date and time, always shown in UTC
+    pub time_format: &'static str,
+}
+
+impl Default for Locale {
+    /// The `C` locale: no grouping and ISO 8601 times
+    fn default() -> Locale {
+        Locale {
+            thousands: None,
+            decimal: '.',
+            time_format: "%Y-%m-%d %H:%M:%S",
+        }
+    }
+}
+
+impl std::str::FromStr for Locale {
+    type Err = Error;
+
+    /// Reads a language tag such as `de`, `en-GB` or `fr_CA.UTF-8`, falling back
+    /// from the region to the language alone
+    fn from_str(tag: &str) -> Result<Locale> {
+        let tag = tag
+            .split('.')
+            .next()
+            .unwrap_or(tag)
+            .replace('_', "-")
+            .to_lowercase();
+        let language = tag.split('-').next().unwrap_or("");
+        let locale = |thousands, decimal, time_format| Locale {
+            thousands,
+            decimal,
+            time_format,
+        };
+        Ok(match (&*tag, language) {
+            ("c", _) | ("posix", _) => Locale::default(),
+            ("en-us", _) | ("en", _) => locale(Some(','), '.', "%m/%d/%Y %I:%M:%S %p"),
+            ("de-ch", _) => locale(Some('\''), '.', "%d.%m.%Y %H:%M:%S"),
+            (_, "en") => locale(Some(','), '.', "%d/%m/%Y %H:%M:%S"),
+            (_, "de") => locale(Some('.'), ',', "%d.%m.%Y %H:%M:%S"),
+            // a narrow no-break space, so a number never wraps
+            (_, "fr") => locale(Some('\u{202f}'), ',', "%d/%m/%Y %H:%M:%S"),
+            (_, "es") | (_, "it") | (_, "pt") => locale(Some('.'), ',', "%d/%m/%Y %H:%M:%S"),
+            (_, "nl") => locale(Some('.'), ',', "%d-%m-%Y %H:%M:%S"),
+            (_, "ja") | (_, "zh") | (_, "ko") => locale(Some(','), '.', "%Y/%m/%d %H:%M:%S"),
+            _ => {
+                return Err(Error::InvalidInput {
+                    message: format!("unsupported locale {}", tag),
+                })
+            }
+        })
+    }
+}
+
+impl Locale {
+    pub fn integer(&self, integer: i64) -> String {
+        self.number(&*integer.to_string())
+    }
+
+    /// Keeps the exact text received from the server when there is one
+    pub fn double(&self, double: &Double) -> String {
+        let text = match double.literal() {
+            Some(literal) => literal.to_string(),
+            None => double.value().to_string(),
+        };
+        // exponents and NaN or the infinities are left as they are
+        if !double.value().is_finite() || text.contains(|c| c == 'e' || c == 'E') {
+            return text;
+        }
+        self.number(&*text)
+    }
+
+    pub fn time(&self, time: &DateTime<Utc>) -> String {
+        format!("{} UTC", time.format(self.time_format))
+    }
+
+    // Groups the integer digits of a plain decimal and swaps its decimal point
+    fn number(&self, text: &str) -> String {
+        let mut number = String::new();
+        if text.starts_with('-') {
+            number.push('-');
+        }
+        let mut parts = text.trim_start_matches('-').splitn(2, '.');
+        let digits = parts.next().unwrap_or("");
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                number.extend(self.thousands);
+            }
+            number.push(digit);
+        }
+        if let Some(fraction) = parts.next() {
+            number.push(self.decimal);
+            number.push_str(fraction);
+        }
+        number
+    }
+}
+
+/// Aligned columns: a page gets a row per document and a column per top level field.
+/// Top level numbers and times, and report counts, follow `locale`.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    pub locale: Locale,
+}
+
+impl Table {
+    pub fn new(locale: Locale) -> Table {
+        Table { locale }
+    }
+
+    // Strings as they are, numbers and times per locale and anything else as compact JSON
+    fn cell(&self, value: &FirestoreType) -> String {
+        match value {
+            FirestoreType::Integer(integer) => self.locale.integer(*integer),
+            FirestoreType::Double(double) => self.locale.double(double),
+            FirestoreType::Timestamp(time) => self.locale.time(&time.time()),
+            value => match value.to_json() {
+                Value::String(text) => text,
+                value => value.to_string(),
+            },
+        }
+    }
+}
+
+impl Formatter for Table {
+    fn document(&self, out: &mut dyn Write, document: &Document) -> io::Result<()> {
+        writeln!(out, "{}", relative_path(document.name()))?;
+        let mut rows = document
+            .fields()
+            .iter()
+            .map(|(field, value)| vec![field.clone(), self.cell(value)])
+            .collect::<Vec<Vec<String>>>();
+        rows.sort();
+        write_table(out, &["field".to_string(), "value".to_string()], &rows)
+    }
+
+    fn page(&self, out: &mut dyn Write, documents: &[Document]) -> io::Result<()> {
+        let fields = documents
+            .iter()
+            .flat_map(|document| document.fields().iter().map(|(field, _)| field.clone()))
+            .collect::<BTreeSet<String>>();
+        let header = Some("path".to_string())
+            .into_iter()
+            .chain(fields.iter().cloned())
+            .collect::<Vec<String>>();
+        let rows = documents
+            .iter()
+            .map(|document| {
+                Some(relative_path(document.name()))
+                    .into_iter()
+                    .chain(fields.iter().map(|field| {
+                        document
+                            .fields()
+                            .get(field)
+                            .map_or(String::new(), |value| self.cell(value))
+                    }))
+                    .collect()
+            })
+            .collect::<Vec<Vec<String>>>();
+        write_table(out, &header, &rows)
+    }
+
+    fn report(&self, out: &mut dyn Write, report: &Report) -> io::Result<()> {
+        let status = if report.ok { "ok" } else { "failed" };
+        writeln!(out, "{}: {}", report.command, status)?;
+        for (key, count) in &report.summary {
+            writeln!(out, "{}: {}", key, self.locale.number(&*count.to_string()))?;
+        }
+        if report.findings.is_empty() {
+            return Ok(());
+        }
+        writeln!(out)?;
+        let header = ["severity", "kind", "subject", "field", "message"]
+            .iter()
+            .map(|column| column.to_string())
+            .collect::<Vec<String>>();
+        let rows = report
+            .findings
+            .iter()
+            .map(|finding| {
+                let severity = serde_json::to_value(finding.severity).unwrap_or(Value::Null);
+                vec![
+                    severity.as_str().unwrap_or("").to_string(),
+                    finding.kind.clone(),
+                    finding.subject.clone(),
+                    finding.field.clone().unwrap_or_default(),
+                    finding.message.clone(),
+                ]
+            })
+            .collect::<Vec<Vec<String>>>();
+        write_table(out, &header, &rows)
+    }
+}
+
+// Left aligned columns separated by two spaces
+fn write_table(out: &mut dyn Write, header: &[String], rows: &[Vec<String>]) -> io::Result<()> {
+    let mut widths = header
+        .iter()
+        .map(|cell| cell.chars().count())
+        .collect::<Vec<usize>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in Some(header)
+        .into_iter()
+        .chain(rows.iter().map(|row| &**row))
+    {
+        let padded = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<String>>();
+        writeln!(out, "{}", padded.join("  ").trim_end())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use libfiresale::api::{DatabaseContext, FirestoreType};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::{Condition, FilterPlan, StructuredQuery};
+use std::collections::BTreeMap;
+
+/// Shown in a group column for documents without the field
+const MISSING: &'static str = "(missing)";
+
+/// An aggregate computed for each group, written `count` or `<function>:<field>`
+#[derive(Debug, Clone)]
+enum Aggregate {
+    Count,
+    Sum(String),
+    Avg(String),
+    Min(String),
+    Max(String),
+}
+
+impl Aggregate {
+    fn parse(spec: &str) -> Result<Aggregate> {
+        let spec = spec.trim();
+        if spec == "count" {
+            return Ok(Aggregate::Count);
+        }
+        let mut parts = spec.splitn(2, ':');
+        let aggregate = match (parts.next(), parts.next()) {
+            (Some("sum"), Some(field)) if !field.is_empty() => Aggregate::Sum(field.to_string()),
+            (Some("avg"), Some(field)) if !field.is_empty() => Aggregate::Avg(field.to_string()),
+            (Some("min"), Some(field)) if !field.is_empty() => Aggregate::Min(field.to_string()),
+            (Some("max"), Some(field)) if !field.is_empty() => Aggregate::Max(field.to_string()),
+            _ => {
+                return Err(Error::InvalidInput {
+                    message: format!(
+                        "invalid aggregate {:?}, expected count, sum:<field>, avg:<field>, \
+                         min:<field> or max:<field>",
+                        spec
+                    ),
+                })
+            }
+        };
+        Ok(aggregate)
+    }
+
+    fn field(&self) -> Option<&str> {
+        match self {
+            Aggregate::Count => None,
+            Aggregate::Sum(field)
+            | Aggregate::Avg(field)
+            | Aggregate::Min(field)
+            | Aggregate::Max(field) => Some(&**field),
+        }
+    }
+}
+
+/// Running state of one aggregate in one group. Only numbers are summed and compared,
+/// documents where the field is absent or not a number are skipped like SQL nulls.
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    documents: u64,
+    values: u64,
+    sum: f64,
+    /// Every value so far was an integer, so the sum prints as one
+    integral: bool,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    fn new() -> Accumulator {
+        Accumulator {
+            integral: true,
+            ..Accumulator::default()
+        }
+    }
+
+    fn add(&mut self, value: Option<&FirestoreType>) {
+        self.documents += 1;
+        let number = match value {
+            Some(FirestoreType::Integer(i)) => *i as f64,
+            Some(FirestoreType::Double(d)) => {
+                self.integral = false;
+                d.value()
+            }
+            _ => return,
+        };
+        self.values += 1;
+        self.sum += number;
+        self.min = Some(self.min.map_or(number, |min| min.min(number)));
+        self.max = Some(self.max.map_or(number, |max| max.max(number)));
+    }
+
+    fn result(&self, aggregate: &Aggregate) -> String {
+        let number = |n: f64| {
+            if self.integral {
+                format!("{}", n as i64)
+            } else {
+                format!("{}", n)
+            }
+        };
+        let none = String::new;
+        match aggregate {
+            Aggregate::Count => self.documents.to_string(),
+            Aggregate::Sum(_) => number(self.sum),
+            Aggregate::Avg(_) if self.values == 0 => none(),
+            Aggregate::Avg(_) => format!("{}", self.sum / self.values as f64),
+            Aggregate::Min(_) => self.min.map_or_else(none, number),
+            Aggregate::Max(_) => self.max.map_or_else(none, number),
+        }
+    }
+}
+
+/// Streams `collection`, keeping the documents matching `filters`, and prints a table
+/// with one row per distinct combination of the `by` fields and a column per aggregate
+pub fn group_by(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    by: &[String],
+    aggregates: &[String],
+    filters: &[String],
+) -> Result<()> {
+    let aggregates = aggregates
+        .iter()
+        .map(|spec| Aggregate::parse(spec))
+        .collect::<Result<Vec<Aggregate>>>()?;
+    let conditions = filters
+        .iter()
+        .map(|condition| condition.parse())
+        .collect::<Result<Vec<Condition>>>()?;
+    let plan = FilterPlan::new(conditions);
+    let path = CollectionPath::parse(collection)?;
+    let mut query = StructuredQuery::collection(path.collection_id());
+    plan.apply(&mut query);
+    let mut groups: BTreeMap<Vec<String>, Vec<Accumulator>> = BTreeMap::new();
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        let document = document?;
+        if !plan.matches(&document) {
+            continue;
+        }
+        let fields = document.fields();
+        let key = by
+            .iter()
+            .map(|field| fields.get_path(field).map_or(MISSING.to_string(), display))
+            .collect::<Vec<String>>();
+        let accumulators = groups
+            .entry(key)
+            .or_insert_with(|| vec![Accumulator::new(); aggregates.len()]);
+        for (accumulator, aggregate) in accumulators.iter_mut().zip(&aggregates) {
+            accumulator.add(aggregate.field().and_then(|field| fields.get_path(field)));
+        }
+    }
+    let header = by
+        .iter()
+        .cloned()
+        .chain(aggregates.iter().map(label))
+        .collect::<Vec<String>>();
+    let rows = groups
+        .iter()
+        .map(|(key, accumulators)| {
+            key.iter()
+                .cloned()
+                .chain(
+                    accumulators
+                        .iter()
+                        .zip(&aggregates)
+                        .map(|(accumulator, aggregate)| accumulator.result(aggregate)),
+                )
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+    print_table(&header, &rows);
+    Ok(())
+}
+
+fn label(aggregate: &Aggregate) -> String {
+    match aggregate {
+        Aggregate::Count => "count".to_string(),
+        Aggregate::Sum(field) => format!("sum:{}", field),
+        Aggregate::Avg(field) => format!("avg:{}", field),
+        Aggregate::Min(field) => format!("min:{}", field),
+        Aggregate::Max(field) => format!("max:{}", field),
+    }
+}
+
+/// A value as shown in reports: strings as they are, anything else as JSON
+pub fn display(value: &FirestoreType) -> String {
+    match value {
+        FirestoreType::String(text) => text.clone(),
+        value => value.to_json().to_string(),
+    }
+}
+
+// Left aligned columns separated by two spaces
+fn print_table(header: &[String], rows: &[Vec<String>]) {
+    let mut widths = header
+        .iter()
+        .map(|cell| cell.chars().count())
+        .collect::<Vec<usize>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: &[String]| {
+        let padded = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<String>>();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(header);
+    for row in rows {
+        line(row);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::join::split_field;
+use crate::output::{OutputFormat, Terminal};
+use libfiresale::api::{DatabaseContext, FirestoreType};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+
+/// Width of the longest histogram bar
+const BAR_WIDTH: usize = 40;
+const PERCENTILES: &[f64] = &[50.0, 90.0, 95.0, 99.0];
+
+/// Prints statistics and a histogram of the numeric `field` of a collection,
+/// written `users.age`. With `sample` only that many documents are read, the first ones
+/// by id, which for generated ids is close to a random sample.
+pub fn histogram(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    target: &str,
+    buckets: usize,
+    sample: Option<usize>,
+    output: &OutputFormat,
+) -> Result<()> {
+    if buckets == 0 {
+        return Err(Error::InvalidInput {
+            message: "--buckets must be at least 1".to_string(),
+        });
+    }
+    let (collection, field) = split_field(target)?;
+    let path = CollectionPath::parse(collection)?;
+    let mut query = StructuredQuery::collection(path.collection_id());
+    query.limit = sample.map(|sample| sample as i32);
+    let (mut values, mut documents) = (Vec::new(), 0);
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        documents += 1;
+        match document?.fields().get_path(field) {
+            Some(FirestoreType::Integer(i)) => values.push(*i as f64),
+            Some(FirestoreType::Double(d)) if d.value().is_finite() => values.push(d.value()),
+            _ => {}
+        }
+    }
+    println!(
+        "{}: {} document(s), {} with a numeric {}",
+        collection,
+        documents,
+        values.len(),
+        field
+    );
+    if values.is_empty() {
+        return Ok(());
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let (min, max) = (values[0], values[values.len() - 1]);
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance =
+        values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64;
+    println!("min     {}", min);
+    println!("max     {}", max);
+    println!("mean    {}", mean);
+    println!("stddev  {}", variance.sqrt());
+    for percentile in PERCENTILES {
+        println!(
+            "{:<8}{}",
+            format!("p{}", percentile),
+            nearest_rank(&values, *percentile)
+        );
+    }
+    println!();
+    print_histogram(&values, min, max, buckets, output.terminal());
+    Ok(())
+}
+
+// The smallest value with at least `percentile` percent of the values at or below it
+pub fn nearest_rank(sorted: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+fn print_histogram(values: &[f64], min: f64, max: f64, buckets: usize, terminal: Terminal) {
+    // a single distinct value gets a single bucket
+    let buckets = if max > min { buckets } else { 1 };
+    let width = (max - min) / buckets as f64;
+    let mut counts = vec![0usize; buckets];
+    for value in values {
+        let bucket = if buckets == 1 {
+            0
+        } else {
+            (((value - min) / width) as usize).min(buckets - 1)
+        };
+        counts[bucket] += 1;
+    }
+    let largest = counts.iter().cloned().max().unwrap_or(0).max(1);
+    let labels = (0..buckets)
+        .map(|bucket| {
+            let low = min + width * bucket as f64;
+            let high = if bucket + 1 == buckets {
+                max
+            } else {
+                min + width * (bucket + 1) as f64
+            };
+            // the last bucket includes the maximum
+            let close = if bucket + 1 == buckets { "]" } else { ")" };
+            format!("[{}, {}{}", round(low), round(high), close)
+        })
+        .collect::<Vec<String>>();
+    let label_width = labels.iter().map(|label| label.len()).max().unwrap_or(0);
+    for (label, count) in labels.iter().zip(&counts) {
+        let bar = terminal.bar(count * BAR_WIDTH / largest);
+        println!(
+            "{:<label_width$}  {:<bar_width$}  {}",
+            label,
+            bar,
+            count,
+            label_width = label_width,
+            bar_width = BAR_WIDTH
+        );
+    }
+}
+
+// Bucket bounds with at most four decimals
+fn round(value: f64) -> String {
+    let rounded = (value * 10_000.0).round() / 10_000.0;
+    format!("{}", rounded)
+}
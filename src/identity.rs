@@ -0,0 +1,172 @@
+use crate::{credentials_key, project_id_key, Environment};
+use libfiresale::api::{resolve_credentials_path, AuthScope};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// Where a setting came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    CommandLine,
+    Environment(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::Environment(variable) => write!(f, "${}", variable),
+        }
+    }
+}
+
+/// The project and service account a command runs as
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub project_id: String,
+    pub project_source: Source,
+    pub service_account_path: String,
+    pub credentials_source: Source,
+}
+
+impl Identity {
+    fn same_as(&self, other: &Identity) -> bool {
+        self.project_id == other.project_id
+            && resolve_credentials_path(&*self.service_account_path, None)
+                == resolve_credentials_path(&*other.service_account_path, None)
+    }
+
+    /// The `client_email` of the service account key, if it can be read
+    fn service_account(&self) -> Option<String> {
+        let path = resolve_credentials_path(&*self.service_account_path, None);
+        let key = std::fs::read(path).ok()?;
+        let key = serde_json::from_slice::<serde_json::Value>(&*key).ok()?;
+        key.get("client_email")?.as_str().map(String::from)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "project {} ({}) as {} ({})",
+            self.project_id,
+            self.project_source,
+            self.service_account()
+                .unwrap_or_else(|| self.service_account_path.clone()),
+            self.credentials_source
+        )
+    }
+}
+
+/// Every identity the arguments and environment could describe. Command line values
+/// come first and fill in for the environment, so the first one is the default.
+fn candidates(flags: &Environment, environment: &Environment) -> Vec<Identity> {
+    let mut candidates = Vec::new();
+    let project = flags
+        .project_id
+        .clone()
+        .map(|id| (id, Source::CommandLine))
+        .or_else(|| {
+            let id = environment.project_id.clone()?;
+            Some((id, Source::Environment(project_id_key())))
+        });
+    let credentials = flags
+        .service_account_path
+        .clone()
+        .map(|path| (path, Source::CommandLine))
+        .or_else(|| {
+            let path = environment.service_account_path.clone()?;
+            Some((path, Source::Environment(credentials_key())))
+        });
+    if let (Some((project_id, project_source)), Some((path, credentials_source))) =
+        (project, credentials)
+    {
+        candidates.push(Identity {
+            project_id,
+            project_source,
+            service_account_path: path,
+            credentials_source,
+        });
+    }
+    if let (Some(project_id), Some(path)) = (
+        environment.project_id.clone(),
+        environment.service_account_path.clone(),
+    ) {
+        let from_environment = Identity {
+            project_id,
+            project_source: Source::Environment(project_id_key()),
+            service_account_path: path,
+            credentials_source: Source::Environment(credentials_key()),
+        };
+        if !candidates.iter().any(|c| c.same_as(&from_environment)) {
+            candidates.push(from_environment);
+        }
+    }
+    candidates
+}
+
+/// Picks the identity to run as. When the command line and the environment disagree,
+/// the user is asked on a terminal; otherwise the command line wins as it always has.
+pub fn select(flags: &Environment, environment: &Environment) -> Result<Identity, String> {
+    let mut candidates = candidates(flags, environment);
+    if candidates.is_empty() {
+        return Err(String::from(
+            "Failed to create database context, not provided in environment variables or cli args",
+        ));
+    }
+    if candidates.len() > 1 && atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stderr) {
+        let choice = prompt(&*candidates)?;
+        return Ok(candidates.swap_remove(choice));
+    }
+    Ok(candidates.swap_remove(0))
+}
+
+// Lists the candidates on stderr and reads a choice, the first one on an empty line
+fn prompt(candidates: &[Identity]) -> Result<usize, String> {
+    eprintln!("Several identities are available:");
+    for (number, candidate) in candidates.iter().enumerate() {
+        eprintln!("  {}) {}", number + 1, candidate);
+    }
+    let stdin = io::stdin();
+    loop {
+        eprint!("Run as [1-{}, default 1]: ", candidates.len());
+        io::stderr().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Err(String::from("No identity selected"));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(0);
+        }
+        match line.parse::<usize>() {
+            Ok(number) if number >= 1 && number <= candidates.len() => return Ok(number - 1),
+            _ => eprintln!("expected a number between 1 and {}", candidates.len()),
+        }
+    }
+}
+
+/// Prints who a command would run as, without contacting Google
+pub fn whoami(identity: &Identity, scope: AuthScope) {
+    println!(
+        "project:         {} ({})",
+        identity.project_id, identity.project_source
+    );
+    println!(
+        "credentials:     {} ({})",
+        resolve_credentials_path(&*identity.service_account_path, None).display(),
+        identity.credentials_source
+    );
+    println!(
+        "service account: {}",
+        identity
+            .service_account()
+            .unwrap_or_else(|| String::from("<unreadable>"))
+    );
+    println!("scope:           {:?}", scope);
+}
@@ -0,0 +1,81 @@
+use clap::{App, ArgSettings};
+use libfiresale::errors::Result;
+use libfiresale::format::Formatters;
+use serde_json::{json, Map, Value};
+
+/// Prints what this build can do as JSON: every command with its arguments, the
+/// output formats and the optional features compiled in, so wrappers can discover
+/// them instead of scraping help text
+pub fn introspect(app: &App, version: &str) -> Result<()> {
+    let description = json!({
+        "version": version,
+        "outputFormats": Formatters::new().names(),
+        "features": {
+            "decimal": cfg!(feature = "decimal"),
+        },
+        "command": command(app),
+    });
+    println!("{}", serde_json::to_string_pretty(&description)?);
+    Ok(())
+}
+
+// clap 2 has no accessors for a built `App`, its parser is the only view of the tree.
+// Help and version flags are only added when parsing, so they are not listed.
+fn command(app: &App) -> Value {
+    let parser = &app.p;
+    let mut args = Vec::new();
+    for flag in &parser.flags {
+        let mut described = arg(flag.b.name, "flag", flag.b.help, &|s| flag.b.is_set(s));
+        described.insert("long".to_string(), json!(flag.s.long));
+        described.insert("short".to_string(), json!(flag.s.short));
+        args.push(described);
+    }
+    for option in &parser.opts {
+        let mut described = arg(option.b.name, "option", option.b.help, &|s| {
+            option.b.is_set(s)
+        });
+        described.insert("long".to_string(), json!(option.s.long));
+        described.insert("short".to_string(), json!(option.s.short));
+        described.insert("possibleValues".to_string(), json!(option.v.possible_vals));
+        let default = option.v.default_val.map(|v| v.to_string_lossy());
+        described.insert("default".to_string(), json!(default));
+        let env = option.v.env.as_ref().map(|(key, _)| key.to_string_lossy());
+        described.insert("env".to_string(), json!(env));
+        args.push(described);
+    }
+    for positional in parser.positionals.values() {
+        let is_set = |s| positional.b.is_set(s);
+        let mut described = arg(positional.b.name, "positional", positional.b.help, &is_set);
+        described.insert("index".to_string(), json!(positional.index));
+        described.insert(
+            "possibleValues".to_string(),
+            json!(positional.v.possible_vals),
+        );
+        let default = positional.v.default_val.map(|v| v.to_string_lossy());
+        described.insert("default".to_string(), json!(default));
+        args.push(described);
+    }
+    json!({
+        "name": parser.meta.name,
+        "about": parser.meta.about,
+        "args": args.into_iter().map(Value::Object).collect::<Vec<Value>>(),
+        "subcommands": parser.subcommands.iter().map(command).collect::<Vec<Value>>(),
+    })
+}
+
+// The description every kind of argument shares
+fn arg(
+    name: &str,
+    kind: &str,
+    help: Option<&str>,
+    is_set: &dyn Fn(ArgSettings) -> bool,
+) -> Map<String, Value> {
+    let mut described = Map::new();
+    described.insert("name".to_string(), json!(name));
+    described.insert("kind".to_string(), json!(kind));
+    described.insert("help".to_string(), json!(help));
+    described.insert("required".to_string(), json!(is_set(ArgSettings::Required)));
+    described.insert("global".to_string(), json!(is_set(ArgSettings::Global)));
+    described.insert("multiple".to_string(), json!(is_set(ArgSettings::Multiple)));
+    described
+}
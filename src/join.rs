@@ -0,0 +1,271 @@
+use crate::dump::relative_path;
+use libfiresale::api::batch_get::{Lookup, CHUNK_SIZE};
+use libfiresale::api::{DatabaseContext, Document, FirestoreType};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Left documents buffered before their references are fetched together
+const BATCH_DOCUMENTS: usize = CHUNK_SIZE * 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format> {
+        match name {
+            "ndjson" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            _ => Err(Error::InvalidInput {
+                message: format!("unknown format {}, expected ndjson or csv", name),
+            }),
+        }
+    }
+}
+
+/// One side of the join: a collection path and its id, which prefixes selected fields
+struct Side {
+    collection: String,
+    path: CollectionPath,
+}
+
+impl Side {
+    fn new(collection: &str) -> Result<Side> {
+        let collection = collection.trim_matches('/').to_string();
+        let path = CollectionPath::parse(&*collection)?;
+        Ok(Side { collection, path })
+    }
+
+    fn id(&self) -> &str {
+        self.path.collection_id()
+    }
+}
+
+/// What `join` was asked for, shared by every batch
+struct Join<'a> {
+    left: Side,
+    right: Side,
+    /// Field of the left documents referring to the right ones
+    key: &'a str,
+    columns: Vec<Column>,
+    format: Format,
+}
+
+/// A `--select` entry, `<collection id>.<field path>`
+struct Column {
+    name: String,
+    right: bool,
+    field: String,
+}
+
+/// Streams the `left` collection, written `orders.userId`, and looks up the document of
+/// `right` each one refers to, writing one row per left document to stdout. The field
+/// may hold a reference, a document path or a plain id in `right`. A missing or absent
+/// reference leaves the right side empty, like a left join.
+pub fn join(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    left: &str,
+    right: &str,
+    select: &[String],
+    format: Format,
+) -> Result<()> {
+    let (left, key) = split_field(left)?;
+    let (left, right) = (Side::new(left)?, Side::new(right)?);
+    let columns = select
+        .iter()
+        .map(|column| parse_column(column, &left, &right))
+        .collect::<Result<Vec<Column>>>()?;
+    let join = Join {
+        left,
+        right,
+        key,
+        columns,
+        format,
+    };
+    if format == Format::Csv && join.columns.is_empty() {
+        return Err(Error::InvalidInput {
+            message: "csv output needs --select to pick its columns".to_string(),
+        });
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if format == Format::Csv {
+        let header = join.columns.iter().map(|column| csv_field(&*column.name));
+        writeln!(out, "{}", header.collect::<Vec<String>>().join(","))?;
+    }
+    let query = StructuredQuery::collection(join.left.id());
+    let mut batch = Vec::new();
+    for document in ctx.query_stream(database_name, join.left.path.parent(), query) {
+        batch.push(document?);
+        if batch.len() == BATCH_DOCUMENTS {
+            let rows = join.lookup(ctx, database_name, &batch)?;
+            join.write_rows(&mut out, &batch, &rows)?;
+            batch.clear();
+        }
+    }
+    let rows = join.lookup(ctx, database_name, &batch)?;
+    join.write_rows(&mut out, &batch, &rows)
+}
+
+/// Splits `orders.userId` at the first dot after the collection path
+pub fn split_field(left: &str) -> Result<(&str, &str)> {
+    let start = left.rfind('/').map_or(0, |slash| slash + 1);
+    match left[start..].find('.') {
+        Some(dot) if start + dot + 1 < left.len() => {
+            Ok((&left[..start + dot], &left[start + dot + 1..]))
+        }
+        _ => Err(Error::InvalidInput {
+            message: format!("expected <collection>.<field>, got {}", left),
+        }),
+    }
+}
+
+fn parse_column(column: &str, left: &Side, right: &Side) -> Result<Column> {
+    let column = column.trim();
+    let mut parts = column.splitn(2, '.');
+    let (side, field) = match (parts.next(), parts.next()) {
+        (Some(side), Some(field)) if !field.is_empty() => (side, field),
+        _ => ("", ""),
+    };
+    let right_side = if side == left.id() {
+        false
+    } else if side == right.id() {
+        true
+    } else {
+        return Err(Error::InvalidInput {
+            message: format!(
+                "invalid column {}, expected {}.<field> or {}.<field>",
+                column,
+                left.id(),
+                right.id()
+            ),
+        });
+    };
+    Ok(Column {
+        name: column.to_string(),
+        right: right_side,
+        field: field.to_string(),
+    })
+}
+
+/// The document path `value` refers to, relative to the database root
+fn referenced_path(value: &FirestoreType, right: &Side) -> Option<String> {
+    match value {
+        FirestoreType::Reference(reference) => Some(reference.path.to_string()),
+        FirestoreType::String(id) if id.contains('/') => Some(id.trim_matches('/').to_string()),
+        FirestoreType::String(id) if !id.is_empty() => Some(format!("{}/{}", right.collection, id)),
+        _ => None,
+    }
+}
+
+impl<'a> Join<'a> {
+    // Batch gets the right documents of `batch`, keyed by their path
+    fn lookup(
+        &self,
+        ctx: &DatabaseContext,
+        database_name: &str,
+        batch: &[Document],
+    ) -> Result<HashMap<String, Document>> {
+        let paths = batch
+            .iter()
+            .filter_map(|document| document.fields().get_path(self.key))
+            .filter_map(|value| referenced_path(value, &self.right))
+            .collect::<Vec<String>>();
+        if paths.is_empty() {
+            return Ok(HashMap::new());
+        }
+        Ok(ctx
+            .batch_get_documents(paths, database_name)?
+            .into_iter()
+            .filter_map(|lookup| match lookup {
+                Lookup::Found(document) => Some((relative_path(document.name()), document)),
+                Lookup::Missing(_) => None,
+            })
+            .collect())
+    }
+
+    fn write_rows<W: Write>(
+        &self,
+        out: &mut W,
+        batch: &[Document],
+        rows: &HashMap<String, Document>,
+    ) -> Result<()> {
+        for document in batch {
+            let found = document
+                .fields()
+                .get_path(self.key)
+                .and_then(|value| referenced_path(value, &self.right))
+                .and_then(|path| rows.get(&path));
+            let values = self
+                .columns
+                .iter()
+                .map(|column| {
+                    let source = if column.right { found } else { Some(document) };
+                    source
+                        .and_then(|source| source.fields().get_path(&*column.field))
+                        .map_or(Value::Null, FirestoreType::to_json)
+                })
+                .collect::<Vec<Value>>();
+            match self.format {
+                Format::Ndjson if self.columns.is_empty() => {
+                    let mut row = Map::new();
+                    row.insert(self.left.id().to_string(), side_json(Some(document)));
+                    row.insert(self.right.id().to_string(), side_json(found));
+                    writeln!(out, "{}", Value::Object(row))?;
+                }
+                Format::Ndjson => {
+                    let row = self
+                        .columns
+                        .iter()
+                        .map(|column| column.name.clone())
+                        .zip(values)
+                        .collect::<Map<String, Value>>();
+                    writeln!(out, "{}", Value::Object(row))?;
+                }
+                Format::Csv => {
+                    let row = values.iter().map(|value| match value {
+                        Value::Null => String::new(),
+                        Value::String(text) => csv_field(text),
+                        value => csv_field(&*value.to_string()),
+                    });
+                    writeln!(out, "{}", row.collect::<Vec<String>>().join(","))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A side of an unselected row: its path and plain fields, or null when not found
+fn side_json(document: Option<&Document>) -> Value {
+    match document {
+        Some(document) => {
+            let mut side = Map::new();
+            side.insert(
+                "path".to_string(),
+                Value::String(relative_path(document.name())),
+            );
+            side.insert(
+                "fields".to_string(),
+                Value::Object(document.fields().to_json()),
+            );
+            Value::Object(side)
+        }
+        None => Value::Null,
+    }
+}
+
+/// Quotes a CSV field when it holds a separator, quote or line break
+fn csv_field(text: &str) -> String {
+    if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    text.to_string()
+}
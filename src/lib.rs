@@ -1,8 +1,38 @@
+// `&'static str` constants and explicit `&*` derefs are the house style, as is code
+// written without newer helpers such as `matches!`, `Option::as_deref` or `#[default]`
+#![allow(
+    clippy::redundant_static_lifetimes,
+    clippy::explicit_auto_deref,
+    clippy::borrow_deref_ref,
+    clippy::legacy_numeric_constants,
+    clippy::match_like_matches_macro,
+    clippy::option_as_ref_deref,
+    clippy::unnecessary_map_or,
+    clippy::manual_strip,
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::manual_inspect,
+    clippy::manual_pattern_char_comparison,
+    clippy::iter_kv_map,
+    clippy::mem_replace_with_default,
+    clippy::drain_collect,
+    clippy::derivable_impls
+)]
+
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate snafu_derive;
 
 pub mod api;
+pub mod cancel;
+pub mod canonical;
+pub mod debug;
 pub mod errors;
-pub mod firestore;
+pub(crate) mod firestore;
+pub mod format;
+pub mod path;
+pub mod policy;
+pub mod prelude;
+pub mod report;
+pub mod sink;
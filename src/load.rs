@@ -0,0 +1,279 @@
+use crate::adaptive::AdaptiveLimit;
+use crate::dump::{read_documents, relative_path, Manifest};
+use crate::planner::WritePlanner;
+use crate::progress::Phase;
+use libfiresale::api::{DatabaseContext, Write};
+use libfiresale::errors::{Error, Result};
+use libfiresale::policy::RateLimit;
+use libfiresale::sink;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Firestore accepts at most this many writes per commit
+const COMMIT_SIZE: usize = 500;
+/// Directory inside the snapshot recording how far each dump file was loaded
+const PROGRESS_DIR: &'static str = ".load";
+/// Times a throttled commit is sent again before the load gives up
+const THROTTLED_ATTEMPTS: u32 = 6;
+/// Wait before resending a throttled commit, doubled on every attempt
+const THROTTLED_BACKOFF: Duration = Duration::from_millis(500);
+
+/// One dump file to restore, a whole collection or one of its shards
+struct Job {
+    collection: String,
+    file: String,
+    documents: usize,
+    /// Content hash of the collection in the manifest, identifying the data in `file`
+    content_hash: String,
+}
+
+/// Commits batches for every worker through the same planner and rate limiter,
+/// with no more commits in flight than the adaptive limit allows
+struct BulkWriter {
+    context: DatabaseContext,
+    planner: WritePlanner,
+    /// Keeps all workers together under a number of writes per second
+    limiter: RateLimit,
+    concurrency: AdaptiveLimit,
+    /// Counts the documents committed by every worker
+    phase: Phase,
+}
+
+impl BulkWriter {
+    /// Commits `writes`, sending them again after a pause while Firestore throttles.
+    /// Loads replace whole documents, so a resent commit writes the same data.
+    fn commit(&self, writes: Vec<Write>) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            self.limiter.acquire(writes.len());
+            let result = self.concurrency.run(|| {
+                self.planner
+                    .apply(&self.context, "load", writes.clone(), None)
+            });
+            match result {
+                Err(ref e) if attempt < THROTTLED_ATTEMPTS && e.is_throttled() => {
+                    thread::sleep(THROTTLED_BACKOFF * 2u32.pow(attempt - 1));
+                    attempt += 1;
+                }
+                result => {
+                    result?;
+                    self.phase.advance(writes.len() as u64);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Records how many documents of each dump file have been committed, so an
+/// interrupted load picks up where it stopped. A marker names the content hash of
+/// the data it counted and is ignored once the file holds other data, as when a
+/// newer dump was written into the same directory.
+struct Progress {
+    dir: PathBuf,
+    enabled: bool,
+}
+
+impl Progress {
+    fn marker(&self, file: &str) -> PathBuf {
+        self.dir.join(format!("{}.progress", file))
+    }
+
+    fn loaded(&self, file: &str, content_hash: &str) -> usize {
+        fs::read_to_string(self.marker(file))
+            .ok()
+            .and_then(|marker| parse_marker(&*marker, content_hash))
+            .unwrap_or(0)
+    }
+
+    fn record(&self, file: &str, content_hash: &str, loaded: usize) -> Result<()> {
+        if self.enabled {
+            let marker = self.marker(file);
+            // partitioned dumps keep their files in subdirectories
+            if let Some(parent) = marker.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            sink::write(marker, format!("{} {}", content_hash, loaded))?;
+        }
+        Ok(())
+    }
+}
+
+// The count of a marker written for the data with `content_hash`, markers of other
+// data and those of older versions holding only a count are not
+fn parse_marker(marker: &str, content_hash: &str) -> Option<usize> {
+    let mut parts = marker.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(hash), Some(count), None) if hash == content_hash => count.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Restores the snapshot in `dir` into the database, loading up to `workers`
+/// dump files at a time. Shards of one collection are loaded concurrently. How many
+/// commits are in flight adapts to throttling, up to one per worker.
+pub fn load(
+    context: &DatabaseContext,
+    planner: &WritePlanner,
+    dir: &str,
+    workers: usize,
+    writes_per_second: u32,
+) -> Result<()> {
+    let dir = Path::new(dir);
+    let manifest = Manifest::read(dir)?;
+    let progress = Progress {
+        dir: dir.join(PROGRESS_DIR),
+        enabled: !planner.dry_run,
+    };
+    if progress.enabled {
+        fs::create_dir_all(&progress.dir)?;
+    }
+    let mut jobs = VecDeque::new();
+    for collection in &manifest.collections {
+        match &collection.file {
+            Some(file) => jobs.push_back(Job {
+                collection: collection.label(),
+                file: file.clone(),
+                documents: collection.documents,
+                content_hash: collection.content_hash.clone(),
+            }),
+            None => jobs.extend(collection.shards.iter().map(|shard| Job {
+                collection: collection.label(),
+                file: shard.file.clone(),
+                documents: shard.documents,
+                content_hash: collection.content_hash.clone(),
+            })),
+        }
+    }
+    let total = jobs.iter().map(|job| job.documents as u64).sum();
+    let workers = workers.max(1).min(jobs.len().max(1));
+    let jobs = Arc::new(Mutex::new(jobs));
+    let writer = Arc::new(BulkWriter {
+        context: context.clone(),
+        planner: planner.clone(),
+        limiter: RateLimit::per_second(writes_per_second),
+        concurrency: AdaptiveLimit::new(workers),
+        phase: planner.progress.phase("load", Some(total)),
+    });
+    let progress = Arc::new(progress);
+    let dir = Arc::new(dir.to_path_buf());
+    let handles = (0..workers)
+        .map(|_| {
+            let jobs = jobs.clone();
+            let writer = writer.clone();
+            let progress = progress.clone();
+            let dir = dir.clone();
+            thread::spawn(move || -> Result<()> {
+                loop {
+                    let job = jobs.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+                    match job {
+                        Some(job) => load_file(&*writer, &*progress, &*dir, &job)?,
+                        None => return Ok(()),
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    // wait for every worker before reporting, so no shard is left half written silently
+    let mut first_error = None;
+    for handle in handles {
+        let result = handle.join().map_err(|_| Error::WorkerPanic {
+            task: "loading dump files".to_string(),
+        });
+        if let Err(e) = result.and_then(|result| result) {
+            writer.phase.error();
+            eprintln!("{}", e);
+            first_error.get_or_insert(e);
+        }
+    }
+    writer.phase.finish();
+    if first_error.is_none() && !planner.dry_run {
+        println!(
+            "settled at {} concurrent commit(s) of {} worker(s)",
+            writer.concurrency.limit(),
+            workers
+        );
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// Commits one dump file in batches, skipping what an earlier run already loaded
+fn load_file(writer: &BulkWriter, progress: &Progress, dir: &Path, job: &Job) -> Result<()> {
+    let skip = progress.loaded(&*job.file, &*job.content_hash);
+    // what an earlier run loaded counts towards the total as well
+    writer.phase.advance(skip.min(job.documents) as u64);
+    if skip >= job.documents && job.documents > 0 {
+        println!("skipped {}: already loaded", job.file);
+        return Ok(());
+    }
+    let database_name = &*writer.planner.database_name;
+    let mut loaded = skip;
+    let mut batch = Vec::with_capacity(COMMIT_SIZE);
+    for document in read_documents(&dir.join(&*job.file))?.skip(skip) {
+        let document = document?;
+        batch.push(writer.context.replace_write(
+            database_name,
+            &*relative_path(document.name()),
+            document.fields(),
+        ));
+        if batch.len() == COMMIT_SIZE {
+            loaded += batch.len();
+            writer.commit(std::mem::replace(
+                &mut batch,
+                Vec::with_capacity(COMMIT_SIZE),
+            ))?;
+            progress.record(&*job.file, &*job.content_hash, loaded)?;
+        }
+    }
+    if !batch.is_empty() {
+        loaded += batch.len();
+        writer.commit(batch)?;
+        progress.record(&*job.file, &*job.content_hash, loaded)?;
+    }
+    println!(
+        "loaded {} ({}): {} document(s)",
+        job.file,
+        job.collection,
+        loaded - skip
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_count_only_the_same_data() {
+        assert_eq!(parse_marker("abc 500\n", "abc"), Some(500));
+        assert_eq!(parse_marker("abc 500", "def"), None);
+        // written before markers named their data
+        assert_eq!(parse_marker("500", "abc"), None);
+        assert_eq!(parse_marker("abc many", "abc"), None);
+        assert_eq!(parse_marker("abc 500 1", "abc"), None);
+        assert_eq!(parse_marker("", "abc"), None);
+    }
+
+    #[test]
+    fn progress_follows_the_content_hash() {
+        let dir = std::env::temp_dir().join(format!("firesale-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let progress = Progress {
+            dir: dir.clone(),
+            enabled: true,
+        };
+        assert_eq!(progress.loaded("users.jsonl", "abc"), 0);
+        progress.record("users.jsonl", "abc", 1000).unwrap();
+        assert_eq!(progress.loaded("users.jsonl", "abc"), 1000);
+        assert_eq!(progress.loaded("users.jsonl", "def"), 0);
+        assert_eq!(progress.loaded("orders.jsonl", "abc"), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,82 @@
+// `&'static str` constants and explicit `&*` derefs are the house style, as is code
+// written without newer helpers such as `matches!`, `Option::as_deref` or `#[default]`
+#![allow(
+    clippy::redundant_static_lifetimes,
+    clippy::explicit_auto_deref,
+    clippy::borrow_deref_ref,
+    clippy::legacy_numeric_constants,
+    clippy::match_like_matches_macro,
+    clippy::option_as_ref_deref,
+    clippy::unnecessary_map_or,
+    clippy::manual_strip,
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::manual_inspect,
+    clippy::manual_pattern_char_comparison,
+    clippy::iter_kv_map,
+    clippy::mem_replace_with_default,
+    clippy::drain_collect,
+    clippy::derivable_impls
+)]
+
 extern crate libfiresale;
+use chrono::Utc;
 use clap::ArgMatches;
-use libfiresale::api::{DatabaseContext, Document};
+use libfiresale::api::{AuthOptions, AuthScope, ConnectionOptions, DatabaseContext, HttpVersion};
+use libfiresale::debug::HttpDump;
+use libfiresale::format::{Locale, Truncation};
+use libfiresale::report::{FailOn, Report};
 
+mod access;
+mod adaptive;
+mod audit;
+mod coerce;
+mod completion;
+mod config;
+mod count;
+mod counter;
+mod dump;
 mod entrypoint;
+mod export;
+mod fixtures;
+mod groupby;
+mod hist;
+mod identity;
+mod introspect;
+mod join;
+mod load;
+mod migrate;
+mod mirror;
+mod modify;
+mod notify;
+mod output;
+mod patch;
+mod ping;
+mod pitr;
+mod plan;
+mod planner;
+mod poll;
+mod progress;
+mod prune;
+mod queue;
+mod rollback;
+mod rules;
+mod schema;
+mod shell;
+mod template;
+mod top;
+mod txn;
+mod update;
+mod watch;
 
 // basic 1.0 support
 // read document path
 
 const GOOGLE_APPLICATION_CREDENTIALS_KEY: &'static str = "GOOGLE_APPLICATION_CREDENTIALS";
 const PROJECT_ID_KEY: &'static str = "PROJECT_ID";
+/// Take precedence over `PROJECT_ID` and `GOOGLE_APPLICATION_CREDENTIALS`
+const FIRESALE_PROJECT_KEY: &'static str = "FIRESALE_PROJECT";
+const FIRESALE_CREDENTIALS_KEY: &'static str = "FIRESALE_CREDENTIALS";
 
 #[derive(Debug, Clone)]
 struct Environment {
@@ -16,15 +84,46 @@ struct Environment {
     pub project_id: Option<String>,
 }
 
-// Gathers environment variables before clap parsing to enforce requirements
+// Gathers environment variables before clap parsing to enforce requirements. The other
+// global options read theirs through clap, see `app`.
 fn gather_environment() -> Environment {
     use std::env;
-    let service_account_path = env::var(GOOGLE_APPLICATION_CREDENTIALS_KEY).ok();
-    let project_id = env::var(PROJECT_ID_KEY).ok();
-    return Environment {
+    let service_account_path = env::var(credentials_key()).ok();
+    let project_id = env::var(project_id_key()).ok();
+    Environment {
         service_account_path,
         project_id,
-    };
+    }
+}
+
+/// The variable the project id is read from, the first of those that is set
+pub fn project_id_key() -> &'static str {
+    first_set(&[FIRESALE_PROJECT_KEY, PROJECT_ID_KEY])
+}
+
+/// The variable the credentials location is read from, the first of those that is set
+pub fn credentials_key() -> &'static str {
+    first_set(&[FIRESALE_CREDENTIALS_KEY, GOOGLE_APPLICATION_CREDENTIALS_KEY])
+}
+
+fn first_set(keys: &[&'static str]) -> &'static str {
+    keys.iter()
+        .cloned()
+        .find(|key| std::env::var_os(key).is_some())
+        .unwrap_or(keys[keys.len() - 1])
+}
+
+// A global flag is on when given, or when its variable is set to anything but an empty
+// string, `0` or `false`
+fn flag_or_env(matches: &ArgMatches, name: &str, key: &str) -> bool {
+    matches.is_present(name)
+        || match std::env::var(key) {
+            Ok(value) => {
+                let value = value.trim().to_lowercase();
+                !(value.is_empty() || value == "0" || value == "false")
+            }
+            Err(_) => false,
+        }
 }
 
 /// Used to represent root level applications options
@@ -32,17 +131,49 @@ fn gather_environment() -> Environment {
 struct Options {
     environment: Environment, // cli-defined environment
     database_name: String,
+    dry_run: bool, // print writes instead of sending them
+    auth: AuthOptions,
+    http_version: HttpVersion,          // HTTP version spoken to Firestore
+    debug_http: Option<String>,         // directory receiving request/response dumps
+    confirm_project: Option<String>,    // answers the protected project prompt
+    output: output::OutputFormat,       // how documents, listings and reports are rendered
+    progress: progress::ProgressEvents, // NDJSON progress events on stderr
+    mirror: Option<mirror::MirrorSource>, // read by get, unless live reads are preferred
+    notify: notify::Notifier,           // told when a long operation ends
+    read_time: Option<String>,          // past time documents are read at
 }
 
 /// This represents a query for a certain document
 pub struct DocumentQuery {
     collection_name: String,
     document_name: String,
+    save_bytes: Option<String>, // directory receiving bytes fields on get
+    raw_field: Option<String>,  // field written to stdout as is on get
+    select: Vec<String>,        // field paths printed, all when empty
+    if_changed_since: Option<String>, // RFC 3339 time, or a file holding one
+    poll: Option<String>,       // interval between repeated gets
+    diff: bool,                 // when polling, print only changes
+}
+
+/// This represents a request to write a document's fields
+pub struct SetDocumentQuery {
+    collection_name: String,
+    document_name: String,
+    fields: String,            // JSON object of field values
+    bytes_fields: Vec<String>, // `field=@path` pairs read from disk
 }
 
 /// This represents a query to view an entire collection
 pub struct CollectionQuery {
     collection_name: String,
+    filters: Vec<String>,           // `--where` conditions
+    show_missing: bool,             // also list documents that only hold subcollections
+    near: Option<(String, String)>, // `--near` point and `--radius`
+    geo_field: String,              // GeoPoint field measured from the point
+    geohash_field: Option<String>,  // its geohash, scanned by prefix when given
+    select: Vec<String>,            // field paths printed, all when empty
+    poll: Option<String>,           // interval between repeated listings
+    diff: bool,                     // when polling, print only changes
 }
 
 /// This represents a query to export a collection or collections
@@ -50,6 +181,9 @@ pub struct CollectionQuery {
 pub struct ExportCollectionQuery {
     collections: Vec<String>,
     bucket_name: String,
+    collection: Option<String>, // collection filtered with `--where`
+    filters: Vec<String>,       // `--where` conditions, exported through a local dump
+    all_databases: bool,        // one managed export per database of the project
 }
 
 /// Numerous fronts for the entrypoint of a program after CLI parsing
@@ -57,8 +191,182 @@ enum EntryPoint {
     GetDocument(DocumentQuery),
     ViewCollection(CollectionQuery),
     DeleteDocument(DocumentQuery),
+    SetDocument(SetDocumentQuery),
+    AddDocument {
+        collection: String,
+        fields: String,
+    },
+    PatchDocument {
+        path: String,
+        patch: String,
+        json_patch: bool, // RFC 6902 instead of a merge patch
+    },
+    ModifyDocument {
+        path: String,
+        filter: String, // jq program rewriting the fields
+        attempts: u32,
+    },
+    NewDocument {
+        collection: String,
+        template: String,
+        document: Option<String>, // generated when not given
+    },
+    // parsed, but deleting collections is not implemented yet
+    #[allow(dead_code)]
     DeleteCollection(CollectionQuery),
+    ListCollections(Option<String>), // document whose subcollections are listed, root when not given
     ExportCollection(ExportCollectionQuery),
+    Shell,
+    Plan {
+        desired: String,
+        out: String,
+        fail_if_changes: bool,
+    },
+    Apply(String),
+    Txn(String), // change file applied in one transaction
+    Migrate {
+        dir: String,
+        down: bool,
+    },
+    Dump {
+        dir: String,
+        collections: Vec<String>,
+        compression: dump::Compression,
+        shard_size: Option<u64>,      // megabytes per shard
+        partition_by: Option<String>, // `field:granularity`
+        jobs: usize,                  // collections dumped at the same time
+    },
+    Prune {
+        collection: String,
+        field: String,
+        older_than: String,
+        archive: Option<String>, // dump directory written before deleting
+    },
+    Rollback {
+        manifest: String,   // manifest.json of the dump taken before the bad restore
+        delete_added: bool, // also delete documents the dump does not hold
+    },
+    Load {
+        dir: String,
+        workers: usize,
+        rate: u32, // writes per second across all workers
+    },
+    VerifyBackup {
+        dir: String,
+        deep: bool,
+    },
+    Check {
+        rules: String,
+        collection: Option<String>, // every collection in the rules when not given
+        fail_on: FailOn,
+    },
+    Fixtures {
+        collection: String,
+        sample: usize,
+        out: String,
+        emulator_project: String, // project the fixture documents are named in
+        keep: Vec<String>,        // fields left as they are
+    },
+    GroupBy {
+        collection: String,
+        by: Vec<String>,
+        aggregates: Vec<String>, // `count` or `function:field`
+        filters: Vec<String>,
+    },
+    Histogram {
+        field: String, // `collection.field`
+        buckets: usize,
+        sample: Option<usize>, // documents read, all when not given
+    },
+    SchemaDiff {
+        projects: (String, String),
+        collection: String,
+        sample: Option<usize>, // documents read per project, all when not given
+        fail_on: FailOn,
+    },
+    FixTypes {
+        collection: String,
+        field: String,
+        target: coerce::Target,
+        lossy: bool, // also conversions that lose information, with --coerce
+        fail_on: FailOn,
+    },
+    AnalyzeFields {
+        collection: String,
+        threshold: f64, // percent of documents a field is empty in to be reported
+        sample: Option<usize>, // documents read, all when not given
+        fail_on: FailOn,
+    },
+    Count {
+        collection: String,
+        workers: usize, // ranges read at the same time when falling back to a scan
+    },
+    Top {
+        field: String, // `collection.field`
+        results: usize,
+    },
+    CounterInit {
+        path: String,
+        shards: u32,
+    },
+    CounterIncr {
+        path: String,
+        by: i64,
+    },
+    CounterRead(String),
+    QueuePush {
+        collection: String,
+        fields: String,
+    },
+    QueuePop {
+        collection: String,
+        lease: String,         // interval, e.g. 60s
+        owner: Option<String>, // user and process id when not given
+    },
+    QueueAck {
+        path: String,
+        owner: Option<String>, // checked against the lease when given
+    },
+    QueueNack {
+        path: String,
+        owner: Option<String>,
+        delay: String, // interval before the document can be claimed again
+    },
+    Join {
+        left: String, // `collection.field`
+        right: String,
+        select: Vec<String>,
+        format: join::Format,
+    },
+    Watch {
+        collections: Vec<String>,
+        filters: Vec<String>, // `--where` conditions, applied to every collection
+        triggers: Vec<String>, // `--when` field changes a write has to make
+        interval: String,
+        updated_field: Option<String>, // timestamp field set by every write
+        rules: Option<String>,         // only report writes breaking these
+        webhook: Option<String>,       // also post each report here
+    },
+    Mirror {
+        collections: Vec<String>,
+        dir: String,
+        interval: String,              // interval between polls, e.g. 30s
+        serve: Option<String>,         // address answering queries against the copy
+        updated_field: Option<String>, // timestamp field set by every write
+    },
+    Ping {
+        count: usize, // samples of each kind of request
+    },
+    CanI {
+        action: access::Action,
+        path: String,
+    },
+    AuditShow(Option<usize>),
+    Whoami,
+    Introspect,
+    SelfUpdate {
+        check_only: bool, // only say whether a newer release exists
+    },
     Usage(String),
 }
 
@@ -71,56 +379,1219 @@ const ABOUT_APP: &'static str = "CLI Firestore Interface";
 // Application config
 const CREDENTIALS_LOCATION_ARG: &'static str = "credentials";
 const PROJECT_ID_ARG: &'static str = "project_id";
+const DRY_RUN_ARG: &'static str = "dry-run";
+const READ_ONLY_ARG: &'static str = "read-only";
+const SCOPE_ARG: &'static str = "scope";
+const ADJUST_CLOCK_ARG: &'static str = "adjust-clock";
+const CONFIRM_PROJECT_ARG: &'static str = "confirm-project";
+const DEBUG_HTTP_ARG: &'static str = "debug-http";
+const HTTP_VERSION_ARG: &'static str = "http-version";
+const OUTPUT_ARG: &'static str = "output";
+const LOCALE_ARG: &'static str = "locale";
+const MAX_FIELD_BYTES_ARG: &'static str = "max-field-bytes";
+const MAX_ARRAY_ITEMS_ARG: &'static str = "max-array-items";
+const FULL_ARG: &'static str = "full";
+const PROGRESS_JSON_ARG: &'static str = "progress-json";
+const PLAIN_ARG: &'static str = "plain";
+const MIRROR_ARG: &'static str = "mirror";
+const PREFER_ARG: &'static str = "prefer";
+const NOTIFY_URL_ARG: &'static str = "notify-url";
+const READ_TIME_ARG: &'static str = "read-time";
+const NOTIFY_CMD_ARG: &'static str = "notify-cmd";
+/// Limits of text and table output unless given, other formats are not truncated
+const DEFAULT_MAX_FIELD_BYTES: usize = 1024;
+const DEFAULT_MAX_ARRAY_ITEMS: usize = 20;
 
 // Subcommands
 const GET_SUB_COMMAND: &'static str = "get";
 const DELETE_SUB_COMMAND: &'static str = "delete";
+const LIST_COLLECTIONS_SUB_COMMAND: &'static str = "list-collections";
+const SET_SUB_COMMAND: &'static str = "set";
+const ADD_SUB_COMMAND: &'static str = "add";
+const NEW_SUB_COMMAND: &'static str = "new";
+const PATCH_SUB_COMMAND: &'static str = "patch";
+const MODIFY_SUB_COMMAND: &'static str = "modify";
 const EXPORT_SUB_COMMAND: &'static str = "export";
+const SHELL_SUB_COMMAND: &'static str = "shell";
+const PLAN_SUB_COMMAND: &'static str = "plan";
+const APPLY_SUB_COMMAND: &'static str = "apply";
+const TXN_SUB_COMMAND: &'static str = "txn";
+const TXN_APPLY_SUB_COMMAND: &'static str = "apply";
+const MIGRATE_SUB_COMMAND: &'static str = "migrate";
+const MIGRATE_RUN_SUB_COMMAND: &'static str = "run";
+const DUMP_SUB_COMMAND: &'static str = "dump";
+const WHOAMI_SUB_COMMAND: &'static str = "whoami";
+const INTROSPECT_SUB_COMMAND: &'static str = "introspect";
+const SELF_UPDATE_SUB_COMMAND: &'static str = "self-update";
+const CHECK_ONLY: &'static str = "check-only";
+const LOAD_SUB_COMMAND: &'static str = "load";
+const PRUNE_SUB_COMMAND: &'static str = "prune";
+const ROLLBACK_SUB_COMMAND: &'static str = "rollback";
+const TO_MANIFEST: &'static str = "to-manifest";
+const DELETE_ADDED: &'static str = "delete-added";
+const CHECK_SUB_COMMAND: &'static str = "check";
+const WATCH_SUB_COMMAND: &'static str = "watch";
+const CAN_I_SUB_COMMAND: &'static str = "can-i";
+const PING_SUB_COMMAND: &'static str = "ping";
+const MIRROR_SUB_COMMAND: &'static str = "mirror";
+const FIXTURES_SUB_COMMAND: &'static str = "fixtures";
+const JOIN_SUB_COMMAND: &'static str = "join";
+const GROUP_BY_SUB_COMMAND: &'static str = "groupby";
+const HIST_SUB_COMMAND: &'static str = "hist";
+const TOP_SUB_COMMAND: &'static str = "top";
+const COUNTER_SUB_COMMAND: &'static str = "counter";
+const COUNTER_INIT_SUB_COMMAND: &'static str = "init";
+const COUNTER_INCR_SUB_COMMAND: &'static str = "incr";
+const COUNTER_READ_SUB_COMMAND: &'static str = "read";
+const SHARDS: &'static str = "shards";
+const COUNTER_BY: &'static str = "by";
+const QUEUE_SUB_COMMAND: &'static str = "queue";
+const QUEUE_PUSH_SUB_COMMAND: &'static str = "push";
+const QUEUE_POP_SUB_COMMAND: &'static str = "pop";
+const QUEUE_ACK_SUB_COMMAND: &'static str = "ack";
+const QUEUE_NACK_SUB_COMMAND: &'static str = "nack";
+const LEASE: &'static str = "lease";
+const OWNER: &'static str = "owner";
+const DELAY: &'static str = "delay";
+const SCHEMA_SUB_COMMAND: &'static str = "schema";
+const SCHEMA_DIFF_SUB_COMMAND: &'static str = "diff";
+const ANALYZE_FIELDS_SUB_COMMAND: &'static str = "analyze-fields";
+const EMPTY_THRESHOLD: &'static str = "threshold";
+const COUNT_SUB_COMMAND: &'static str = "count";
+const FIX_TYPES_SUB_COMMAND: &'static str = "fix-types";
+const TARGET_TYPE: &'static str = "to";
+const COERCE: &'static str = "coerce";
+const VERIFY_BACKUP_SUB_COMMAND: &'static str = "verify-backup";
+const AUDIT_SUB_COMMAND: &'static str = "audit";
+const AUDIT_SHOW_SUB_COMMAND: &'static str = "show";
+
+const LIMIT: &'static str = "limit";
+const DESIRED_STATE: &'static str = "desired";
+const PLAN_OUT: &'static str = "out";
+const FAIL_IF_CHANGES: &'static str = "fail-if-changes";
+const FAIL_ON: &'static str = "fail-on";
+const PLAN_FILE: &'static str = "plan";
+const CHANGES_FILE: &'static str = "changes";
+const MIGRATIONS_DIR: &'static str = "dir";
+const MIGRATE_DOWN: &'static str = "down";
+const SNAPSHOT_DIR: &'static str = "dir";
+const DEEP: &'static str = "deep";
+const COMPRESS: &'static str = "compress";
+const SHARD_SIZE: &'static str = "shard-size";
+const PARTITION_BY: &'static str = "partition-by";
+const WORKERS: &'static str = "workers";
+const JOBS: &'static str = "jobs";
+const RATE: &'static str = "rate";
+const OLDER_THAN: &'static str = "older-than";
+const TIMESTAMP_FIELD: &'static str = "field";
+const ARCHIVE: &'static str = "archive";
+const RULES_FILE: &'static str = "rules";
+const WATCH_RULES: &'static str = "check";
+const WEBHOOK: &'static str = "post";
+const WHEN: &'static str = "when";
+const UPDATED_FIELD: &'static str = "updated-field";
+const INTERVAL: &'static str = "interval";
+const SAMPLE: &'static str = "sample";
+const FIXTURES_OUT: &'static str = "out";
+const EMULATOR_PROJECT: &'static str = "emulator-project";
+const KEEP: &'static str = "keep";
+const JOIN_LEFT: &'static str = "left";
+const JOIN_RIGHT: &'static str = "right";
+const SELECT: &'static str = "select";
+const FORMAT: &'static str = "format";
+const GROUP_BY: &'static str = "by";
+const AGGREGATE: &'static str = "agg";
+const HIST_FIELD: &'static str = "field";
+const BUCKETS: &'static str = "buckets";
+const TOP_RESULTS: &'static str = "results";
+const COMPARED_PROJECT: &'static str = "project";
 
 const DATABASE_NAME: &'static str = "database";
 const DEFAULT_DATABASE_NAME: &'static str = "(default)";
 
 const COLLECTIONS: &'static str = "collections";
 const BUCKET_NAME: &'static str = "bucket";
+const ALL_DATABASES: &'static str = "all-databases";
 
 const COLLECTION_NAME: &'static str = "collection";
-const COLLECTION_NAME_SHORT: &'static str = "c";
 
 const DOCUMENT_NAME: &'static str = "document";
-const DOCUMENT_NAME_SHORT: &'static str = "d";
 
-fn setup_arguments(environ: &Environment) -> (Options, EntryPoint) {
+const FIELDS: &'static str = "fields";
+const TEMPLATE: &'static str = "template";
+const DOCUMENT_PATH: &'static str = "path";
+const MERGE_PATCH: &'static str = "merge-patch";
+const JSON_PATCH: &'static str = "json-patch";
+const JQ: &'static str = "jq";
+const ATTEMPTS: &'static str = "attempts";
+const BYTES_FIELD: &'static str = "bytes-field";
+const SAVE_BYTES: &'static str = "save-bytes";
+const RAW_FIELD: &'static str = "raw-field";
+const WHERE: &'static str = "where";
+const SHOW_MISSING: &'static str = "show-missing";
+const NEAR: &'static str = "near";
+const RADIUS: &'static str = "radius";
+const GEO_FIELD: &'static str = "geo-field";
+const GEOHASH_FIELD: &'static str = "geohash-field";
+const IF_CHANGED_SINCE: &'static str = "if-changed-since";
+const POLL: &'static str = "poll";
+const DIFF: &'static str = "diff";
+const ACTION: &'static str = "action";
+const COUNT: &'static str = "count";
+const MIRROR_DIR: &'static str = "dir";
+const SERVE: &'static str = "serve";
+
+/// Exit code of `get --if-changed-since` when the document did not change
+const NOT_MODIFIED_EXIT_CODE: i32 = 3;
+/// Exit code of `queue pop` when no document can be claimed
+const QUEUE_EMPTY_EXIT_CODE: i32 = 5;
+/// Exit code of `can-i` when the credentials are not allowed
+const DENIED_EXIT_CODE: i32 = 6;
+/// Exit code of `check`, `schema diff` and `plan` when their findings reach the
+/// `--fail-on` threshold, or `--fail-if-changes` for a plan
+const VIOLATIONS_EXIT_CODE: i32 = 4;
+
+/// Shown after the usage, clap only lists the variables of options taking a value
+const ENVIRONMENT_HELP: &'static str = "ENVIRONMENT:
+    Every global option can be set with a FIRESALE_ variable named after it, e.g.
+    FIRESALE_OUTPUT=json or FIRESALE_DRY_RUN=1, and the database with FIRESALE_DATABASE.
+    An option on the command line wins over its variable, which wins over the default.
+    A flag is on when its variable is set to anything but an empty string, 0 or false.
+
+    The project id is read from FIRESALE_PROJECT, then PROJECT_ID, and the credentials
+    from FIRESALE_CREDENTIALS, then GOOGLE_APPLICATION_CREDENTIALS. FIRESALE_CONFIG
+    points to the config file, ~/.firesale.toml by default.";
+
+// Shared by the report commands that can fail on their findings
+fn fail_on_arg() -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name(FAIL_ON)
+        .long(FAIL_ON)
+        .takes_value(true)
+        .possible_values(&["never", "errors", "warnings"])
+        .default_value("errors")
+        .help("Exit with status 4 when findings are at least this severe")
+}
+
+// The whole command tree, also walked by `introspect`
+fn app(environ: &Environment) -> clap::App<'static, 'static> {
     use clap::{App, Arg, SubCommand};
-    let matches = App::new(APP_NAME)
+    App::new(APP_NAME)
         .version(APP_VERSION)
         .author(APP_AUTHOR)
         .about(ABOUT_APP)
+        .after_help(ENVIRONMENT_HELP)
         .arg(Arg::with_name(PROJECT_ID_ARG).required(environ.project_id.is_none()))
         .arg(
             Arg::with_name(CREDENTIALS_LOCATION_ARG)
                 .required(environ.service_account_path.is_none()),
         )
+        .arg(
+            Arg::with_name(DRY_RUN_ARG)
+                .long(DRY_RUN_ARG)
+                .global(true)
+                .help("Perform all reads but print writes instead of sending them [env: FIRESALE_DRY_RUN]"),
+        )
+        .arg(
+            Arg::with_name(READ_ONLY_ARG)
+                .long(READ_ONLY_ARG)
+                .global(true)
+                .conflicts_with(SCOPE_ARG)
+                .help("Request a read-only scope and refuse to send any mutation [env: FIRESALE_READ_ONLY]"),
+        )
+        .arg(
+            Arg::with_name(SCOPE_ARG)
+                .long(SCOPE_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_SCOPE")
+                .possible_values(&["datastore", "cloud-platform"])
+                .default_value("datastore")
+                .help("OAuth scope to authenticate with"),
+        )
+        .arg(
+            Arg::with_name(CONFIRM_PROJECT_ARG)
+                .long(CONFIRM_PROJECT_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_CONFIRM_PROJECT")
+                .help("Confirm a destructive command against a protected project without a prompt"),
+        )
+        .arg(
+            Arg::with_name(ADJUST_CLOCK_ARG)
+                .long(ADJUST_CLOCK_ARG)
+                .global(true)
+                .help("Compensate for a wrong local clock when authentication fails because of it [env: FIRESALE_ADJUST_CLOCK]"),
+        )
+        .arg(
+            Arg::with_name(HTTP_VERSION_ARG)
+                .long(HTTP_VERSION_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_HTTP_VERSION")
+                .possible_values(&["negotiate", "2", "1.1"])
+                .default_value("negotiate")
+                .help("HTTP version to speak, negotiate lets the server pick HTTP/2 or HTTP/1.1"),
+        )
+        .arg(
+            Arg::with_name(DEBUG_HTTP_ARG)
+                .long(DEBUG_HTTP_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_DEBUG_HTTP")
+                .help("Write every request and response to numbered files in this directory"),
+        )
+        .arg(
+            Arg::with_name(OUTPUT_ARG)
+                .long(OUTPUT_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_OUTPUT")
+                .possible_values(&["text", "json", "ndjson", "yaml", "table"])
+                .default_value("text")
+                .help("Render documents, listings and reports in this format"),
+        )
+        .arg(
+            Arg::with_name(LOCALE_ARG)
+                .long(LOCALE_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_LOCALE")
+                .validator(|tag| tag.parse::<Locale>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Digit grouping and date format of table output, e.g. en-US or de"),
+        )
+        .arg(
+            Arg::with_name(MAX_FIELD_BYTES_ARG)
+                .long(MAX_FIELD_BYTES_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_MAX_FIELD_BYTES")
+                .validator(|limit| limit.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Cut shown strings and bytes at this size, 1024 for text and table output"),
+        )
+        .arg(
+            Arg::with_name(MAX_ARRAY_ITEMS_ARG)
+                .long(MAX_ARRAY_ITEMS_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_MAX_ARRAY_ITEMS")
+                .validator(|limit| limit.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Show at most this many array items, 20 for text and table output"),
+        )
+        .arg(
+            Arg::with_name(FULL_ARG)
+                .long(FULL_ARG)
+                .global(true)
+                .conflicts_with_all(&[MAX_FIELD_BYTES_ARG, MAX_ARRAY_ITEMS_ARG])
+                .help("Show documents in full, however large [env: FIRESALE_FULL]"),
+        )
+        .arg(
+            Arg::with_name(PROGRESS_JSON_ARG)
+                .long(PROGRESS_JSON_ARG)
+                .global(true)
+                .help("Report the progress of dump, load and prune as NDJSON events on stderr [env: FIRESALE_PROGRESS_JSON]"),
+        )
+        .arg(
+            Arg::with_name(PLAIN_ARG)
+                .long(PLAIN_ARG)
+                .global(true)
+                .help("Print plain ASCII text without color, as when stdout is not a terminal or TERM is dumb. NO_COLOR only turns color off. [env: FIRESALE_PLAIN]"),
+        )
+        .arg(
+            Arg::with_name(MIRROR_ARG)
+                .long(MIRROR_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_MIRROR")
+                .value_name("DIR")
+                .required_ifs(&[(PREFER_ARG, "mirror"), (PREFER_ARG, "mirror-then-live")])
+                .help("Directory kept up to date by the mirror command"),
+        )
+        .arg(
+            Arg::with_name(READ_TIME_ARG)
+                .long(READ_TIME_ARG)
+                .alias("pitr")
+                .global(true)
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .value_name("TIME")
+                .help("Read documents as they were at this RFC 3339 time, or e.g. -30m: within the last hour, or whole minutes up to 7 days back with point-in-time recovery. Writes are refused."),
+        )
+        .arg(
+            Arg::with_name(NOTIFY_URL_ARG)
+                .long(NOTIFY_URL_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_NOTIFY_URL")
+                .value_name("URL")
+                .help("POST a JSON summary here when export, dump, load, prune, migrate, apply, rollback or verify-backup --deep ends"),
+        )
+        .arg(
+            Arg::with_name(NOTIFY_CMD_ARG)
+                .long(NOTIFY_CMD_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_NOTIFY_CMD")
+                .value_name("COMMAND")
+                .help("Run a shell command with the same summary on stdin when a long operation ends"),
+        )
+        .arg(
+            Arg::with_name(PREFER_ARG)
+                .long(PREFER_ARG)
+                .global(true)
+                .takes_value(true)
+                .env("FIRESALE_PREFER")
+                .possible_values(&["live", "mirror", "mirror-then-live"])
+                .default_value("live")
+                .help("Where get reads documents: Firestore, the mirror, or the mirror falling back to Firestore for what it does not hold"),
+        )
         .subcommand(
             SubCommand::with_name(GET_SUB_COMMAND)
                 .arg(Arg::with_name(COLLECTION_NAME).required(true))
-                .arg(Arg::with_name(DOCUMENT_NAME)),
+                .arg(Arg::with_name(DOCUMENT_NAME))
+                .arg(
+                    Arg::with_name(SAVE_BYTES)
+                        .long(SAVE_BYTES)
+                        .takes_value(true)
+                        .help("Write bytes fields to files in this directory"),
+                )
+                .arg(
+                    Arg::with_name(RAW_FIELD)
+                        .long(RAW_FIELD)
+                        .takes_value(true)
+                        .value_name("FIELD")
+                        .requires(DOCUMENT_NAME)
+                        .conflicts_with_all(&[SAVE_BYTES, POLL])
+                        .help("Write only this string or bytes field to stdout, undecorated, e.g. for piping a blob"),
+                )
+                .arg(
+                    Arg::with_name(SELECT)
+                        .long(SELECT)
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .value_name("FIELDS")
+                        .conflicts_with(RAW_FIELD)
+                        .help("Only print these fields, e.g. name,email,address.city"),
+                )
+                .arg(
+                    Arg::with_name(IF_CHANGED_SINCE)
+                        .long(IF_CHANGED_SINCE)
+                        .takes_value(true)
+                        .requires(DOCUMENT_NAME)
+                        .help("Print nothing and exit with code 3 unless the document was updated after this RFC 3339 time, or the time in this file"),
+                )
+                .arg(
+                    Arg::with_name(WHERE)
+                        .long(WHERE)
+                        .alias("filter")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only list documents matching a condition, e.g. \"age >= 21\" or \"createdAt > -7d\". Relative times such as -7d or now are timestamps unless quoted, e.g. 'tag == \"now\"'"),
+                )
+                .arg(
+                    Arg::with_name(SHOW_MISSING)
+                        .long(SHOW_MISSING)
+                        .help("Also list missing documents that still have subcollections"),
+                )
+                .arg(
+                    Arg::with_name(NEAR)
+                        .long(NEAR)
+                        .takes_value(true)
+                        .value_name("LAT,LNG")
+                        .requires(RADIUS)
+                        .conflicts_with_all(&[DOCUMENT_NAME, SHOW_MISSING])
+                        .help("Only list documents within --radius of this point, e.g. 52.5,13.4"),
+                )
+                .arg(
+                    Arg::with_name(RADIUS)
+                        .long(RADIUS)
+                        .takes_value(true)
+                        .requires(NEAR)
+                        .help("Distance from --near, e.g. 500m, 5km or 3mi"),
+                )
+                .arg(
+                    Arg::with_name(GEO_FIELD)
+                        .long(GEO_FIELD)
+                        .takes_value(true)
+                        .default_value("location")
+                        .help("GeoPoint field measured from --near"),
+                )
+                .arg(
+                    Arg::with_name(GEOHASH_FIELD)
+                        .long(GEOHASH_FIELD)
+                        .takes_value(true)
+                        .requires(NEAR)
+                        .help("Field holding the geohash of the GeoPoint, scanned by prefix rather than by latitude"),
+                )
+                .arg(
+                    Arg::with_name(POLL)
+                        .long(POLL)
+                        .takes_value(true)
+                        .value_name("INTERVAL")
+                        .conflicts_with_all(&[SHOW_MISSING, IF_CHANGED_SINCE])
+                        .help("Repeat the request every interval, e.g. 10s or 1m, until interrupted"),
+                )
+                .arg(
+                    Arg::with_name(DIFF)
+                        .long(DIFF)
+                        .requires(POLL)
+                        .help("When polling, print only what changed since the previous request"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(LIST_COLLECTIONS_SUB_COMMAND)
+                .about("List the root collections, or the subcollections of a document")
+                .arg(
+                    Arg::with_name(DOCUMENT_PATH)
+                        .help("Document whose subcollections are listed, e.g. users/alice"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SET_SUB_COMMAND)
+                .about("Create or replace a document")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(Arg::with_name(DOCUMENT_NAME).required(true))
+                .arg(Arg::with_name(FIELDS).default_value("{}"))
+                .arg(
+                    Arg::with_name(BYTES_FIELD)
+                        .long(BYTES_FIELD)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Set a bytes field from a file, e.g. avatar=@photo.png"),
+                ),
         )
         .subcommand(
             SubCommand::with_name(DELETE_SUB_COMMAND)
                 .arg(Arg::with_name(COLLECTION_NAME).required(true))
                 .arg(Arg::with_name(DOCUMENT_NAME)),
         )
+        .subcommand(
+            SubCommand::with_name(ADD_SUB_COMMAND)
+                .about("Create a document with a generated id")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(Arg::with_name(FIELDS).default_value("{}")),
+        )
+        .subcommand(
+            SubCommand::with_name(PATCH_SUB_COMMAND)
+                .about("Update part of a document with a JSON Merge Patch or a JSON Patch")
+                .arg(Arg::with_name(DOCUMENT_PATH).required(true))
+                .arg(
+                    Arg::with_name(MERGE_PATCH)
+                        .long(MERGE_PATCH)
+                        .takes_value(true)
+                        .help("RFC 7396 merge patch, inline or @file"),
+                )
+                .arg(
+                    Arg::with_name(JSON_PATCH)
+                        .long(JSON_PATCH)
+                        .takes_value(true)
+                        .help("RFC 6902 list of operations, inline or @file"),
+                )
+                .group(
+                    clap::ArgGroup::with_name("patch")
+                        .args(&[MERGE_PATCH, JSON_PATCH])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(MODIFY_SUB_COMMAND)
+                .about("Rewrite a document with a jq filter, retrying if it changes concurrently")
+                .arg(Arg::with_name(DOCUMENT_PATH).required(true))
+                .arg(
+                    Arg::with_name(JQ)
+                        .long(JQ)
+                        .takes_value(true)
+                        .required(true)
+                        .help("jq filter turning the fields, as a JSON object, into the new ones, e.g. '.count += 1'"),
+                )
+                .arg(
+                    Arg::with_name(ATTEMPTS)
+                        .long(ATTEMPTS)
+                        .takes_value(true)
+                        .default_value("5")
+                        .validator(|attempts| {
+                            attempts.parse::<u32>().map(|_| ()).map_err(|e| e.to_string())
+                        })
+                        .help("Read-modify-write cycles tried before giving up on conflicts"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(NEW_SUB_COMMAND)
+                .about("Create a document from a template, asking for each placeholder")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(Arg::with_name(DOCUMENT_NAME))
+                .arg(
+                    Arg::with_name(TEMPLATE)
+                        .long(TEMPLATE)
+                        .takes_value(true)
+                        .required(true)
+                        .help("Name of a JSON template in the templates directory"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name(EXPORT_SUB_COMMAND)
                 .arg(Arg::with_name(BUCKET_NAME).required(true))
-                .arg(Arg::with_name(COLLECTIONS).multiple(true)),
+                .arg(Arg::with_name(COLLECTIONS).multiple(true))
+                .arg(
+                    Arg::with_name(COLLECTION_NAME)
+                        .long(COLLECTION_NAME)
+                        .takes_value(true)
+                        .conflicts_with(COLLECTIONS)
+                        .requires(WHERE)
+                        .help("Collection to export selected documents from"),
+                )
+                .arg(
+                    Arg::with_name(WHERE)
+                        .long(WHERE)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires(COLLECTION_NAME)
+                        .help("Only export documents matching a condition. Unlike the managed export, this uploads a firesale dump, to be restored with load"),
+                )
+                .arg(
+                    Arg::with_name(ALL_DATABASES)
+                        .long(ALL_DATABASES)
+                        .conflicts_with(COLLECTION_NAME)
+                        .help("Export every database of the project, each under <bucket>/<database id>, and follow them to the end"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SHELL_SUB_COMMAND)
+                .about("Interactive session supporting begin, commit and rollback"),
+        )
+        .subcommand(
+            SubCommand::with_name(PLAN_SUB_COMMAND)
+                .about("Compute the changes turning live data into a desired state file")
+                .arg(Arg::with_name(DESIRED_STATE).required(true))
+                .arg(
+                    Arg::with_name(PLAN_OUT)
+                        .long(PLAN_OUT)
+                        .takes_value(true)
+                        .default_value("plan.json"),
+                )
+                .arg(
+                    Arg::with_name(FAIL_IF_CHANGES)
+                        .long(FAIL_IF_CHANGES)
+                        .help("Exit with status 4 when live data differs from the desired state"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(APPLY_SUB_COMMAND)
+                .about("Execute a plan, refusing if live data changed since planning")
+                .arg(Arg::with_name(PLAN_FILE).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name(TXN_SUB_COMMAND)
+                .about("Read, check and write several documents in one transaction")
+                .subcommand(
+                    SubCommand::with_name(TXN_APPLY_SUB_COMMAND)
+                        .about("Commit a change file's writes if all its reads pass their checks, retrying on contention")
+                        .arg(Arg::with_name(CHANGES_FILE).required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(MIGRATE_SUB_COMMAND)
+                .about("Run ordered data migrations tracked in the database")
+                .subcommand(
+                    SubCommand::with_name(MIGRATE_RUN_SUB_COMMAND)
+                        .arg(Arg::with_name(MIGRATIONS_DIR).required(true))
+                        .arg(
+                            Arg::with_name(MIGRATE_DOWN)
+                                .long(MIGRATE_DOWN)
+                                .help("Revert the most recently applied migration"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(DUMP_SUB_COMMAND)
+                .about("Write collections to a local snapshot with a manifest")
+                .arg(Arg::with_name(SNAPSHOT_DIR).required(true))
+                .arg(Arg::with_name(COLLECTIONS).required(true).multiple(true))
+                .arg(
+                    Arg::with_name(COMPRESS)
+                        .long(COMPRESS)
+                        .takes_value(true)
+                        .possible_values(&["gzip", "zstd"])
+                        .help("Compress dump files as .ndjson.gz or .ndjson.zst"),
+                )
+                .arg(
+                    Arg::with_name(SHARD_SIZE)
+                        .long(SHARD_SIZE)
+                        .takes_value(true)
+                        .value_name("MB")
+                        .help("Split each collection into files of at most this many megabytes"),
+                )
+                .arg(
+                    Arg::with_name(PARTITION_BY)
+                        .long(PARTITION_BY)
+                        .takes_value(true)
+                        .value_name("FIELD:GRANULARITY")
+                        .help("Write documents into directories by a timestamp field, e.g. createdAt:month"),
+                )
+                .arg(
+                    Arg::with_name(JOBS)
+                        .long(JOBS)
+                        .takes_value(true)
+                        .default_value("4")
+                        .help("Number of collections dumped at the same time"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(LOAD_SUB_COMMAND)
+                .about("Restore a snapshot made by dump, resuming an interrupted load")
+                .arg(Arg::with_name(SNAPSHOT_DIR).required(true))
+                .arg(
+                    Arg::with_name(WORKERS)
+                        .long(WORKERS)
+                        .takes_value(true)
+                        .default_value("8")
+                        .help("Most dump files loaded at the same time, commits in flight adapt to throttling"),
+                )
+                .arg(
+                    Arg::with_name(RATE)
+                        .long(RATE)
+                        .takes_value(true)
+                        .default_value("500")
+                        .help("Maximum writes per second across all workers"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(PRUNE_SUB_COMMAND)
+                .about("Delete documents older than a threshold, optionally dumping them first")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(
+                    Arg::with_name(OLDER_THAN)
+                        .long(OLDER_THAN)
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("AGE")
+                        .help("Delete documents older than this, e.g. 90d or 12h"),
+                )
+                .arg(
+                    Arg::with_name(TIMESTAMP_FIELD)
+                        .long(TIMESTAMP_FIELD)
+                        .takes_value(true)
+                        .required(true)
+                        .help("Timestamp field holding each document's age, e.g. createdAt"),
+                )
+                .arg(
+                    Arg::with_name(ARCHIVE)
+                        .long(ARCHIVE)
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Dump the documents to this directory before deleting them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(ROLLBACK_SUB_COMMAND)
+                .about("Undo a load or import by putting back the documents of an earlier dump")
+                .arg(
+                    Arg::with_name(TO_MANIFEST)
+                        .long(TO_MANIFEST)
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("MANIFEST")
+                        .help("manifest.json of the dump taken before, or its directory"),
+                )
+                .arg(
+                    Arg::with_name(DELETE_ADDED)
+                        .long(DELETE_ADDED)
+                        .help("Also delete documents missing from the dump. Only for dumps of whole collections"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(VERIFY_BACKUP_SUB_COMMAND)
+                .about("Check a snapshot made by dump against its manifest")
+                .arg(Arg::with_name(SNAPSHOT_DIR).required(true))
+                .arg(
+                    Arg::with_name(DEEP)
+                        .long(DEEP)
+                        .help("Also compare a sample of documents with the live database"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(CHECK_SUB_COMMAND)
+                .about("Report documents breaking the rules file, failing if there are any")
+                .arg(Arg::with_name(COLLECTION_NAME))
+                .arg(
+                    Arg::with_name(RULES_FILE)
+                        .long(RULES_FILE)
+                        .takes_value(true)
+                        .default_value(rules::DEFAULT_RULES_FILE)
+                        .help("Rules file describing the expected documents"),
+                )
+                .arg(fail_on_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name(FIXTURES_SUB_COMMAND)
+                .about("Write anonymized sample documents for seeding the emulator")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(
+                    Arg::with_name(SAMPLE)
+                        .long(SAMPLE)
+                        .takes_value(true)
+                        .default_value("50")
+                        .help("Number of documents picked at random"),
+                )
+                .arg(
+                    Arg::with_name(FIXTURES_OUT)
+                        .long(FIXTURES_OUT)
+                        .takes_value(true)
+                        .default_value("fixtures")
+                        .help("Directory the fixture files are written to"),
+                )
+                .arg(
+                    Arg::with_name(EMULATOR_PROJECT)
+                        .long(EMULATOR_PROJECT)
+                        .takes_value(true)
+                        .default_value("demo-firesale")
+                        .help("Project id the emulator is started with"),
+                )
+                .arg(
+                    Arg::with_name(KEEP)
+                        .long(KEEP)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("FIELD")
+                        .help("Field path copied without anonymizing, e.g. status"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(GROUP_BY_SUB_COMMAND)
+                .about("Print aggregates of a collection grouped by field values")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(
+                    Arg::with_name(GROUP_BY)
+                        .long(GROUP_BY)
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .required(true)
+                        .help("Fields whose values form the groups, e.g. status"),
+                )
+                .arg(
+                    Arg::with_name(AGGREGATE)
+                        .long(AGGREGATE)
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("count")
+                        .help("Aggregates per group: count, sum:<field>, avg:<field>, min:<field> or max:<field>"),
+                )
+                .arg(
+                    Arg::with_name(WHERE)
+                        .long(WHERE)
+                        .alias("filter")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only aggregate documents matching a condition, e.g. \"total > 0\""),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(HIST_SUB_COMMAND)
+                .about("Print the distribution of a numeric field")
+                .arg(
+                    Arg::with_name(HIST_FIELD)
+                        .required(true)
+                        .value_name("COLLECTION.FIELD")
+                        .help("Field to profile, e.g. users.age"),
+                )
+                .arg(
+                    Arg::with_name(BUCKETS)
+                        .long(BUCKETS)
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Number of histogram bars"),
+                )
+                .arg(
+                    Arg::with_name(SAMPLE)
+                        .long(SAMPLE)
+                        .takes_value(true)
+                        .help("Read only this many documents instead of the whole collection"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SCHEMA_SUB_COMMAND)
+                .about("Compare the fields documents hold")
+                .subcommand(
+                    SubCommand::with_name(SCHEMA_DIFF_SUB_COMMAND)
+                        .about("Report fields missing from a project or holding other types")
+                        .arg(
+                            Arg::with_name(COMPARED_PROJECT)
+                                .long(COMPARED_PROJECT)
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .required(true)
+                                .help("Project to compare, given twice, e.g. --project staging --project prod"),
+                        )
+                        .arg(
+                            Arg::with_name(COLLECTION_NAME)
+                                .long(COLLECTION_NAME)
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name(SAMPLE)
+                                .long(SAMPLE)
+                                .takes_value(true)
+                                .help("Read only this many documents in each project"),
+                        )
+                        .arg(fail_on_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(ANALYZE_FIELDS_SUB_COMMAND)
+                .about("Report fields that are mostly empty, constant or of mixed types")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(
+                    Arg::with_name(EMPTY_THRESHOLD)
+                        .long(EMPTY_THRESHOLD)
+                        .takes_value(true)
+                        .default_value("90")
+                        .value_name("PERCENT")
+                        .validator(|percent| {
+                            percent.parse::<f64>().map(|_| ()).map_err(|e| e.to_string())
+                        })
+                        .help("Report fields null or missing in at least this percentage of documents"),
+                )
+                .arg(
+                    Arg::with_name(SAMPLE)
+                        .long(SAMPLE)
+                        .takes_value(true)
+                        .help("Read only this many documents"),
+                )
+                .arg(fail_on_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name(FIX_TYPES_SUB_COMMAND)
+                .about("Convert a field to one type, e.g. numbers stored as strings")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(
+                    Arg::with_name(HIST_FIELD)
+                        .long(HIST_FIELD)
+                        .takes_value(true)
+                        .required(true)
+                        .help("Top level field to convert, e.g. age"),
+                )
+                .arg(
+                    Arg::with_name(TARGET_TYPE)
+                        .long(TARGET_TYPE)
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["integer", "double", "string", "boolean", "timestamp"]),
+                )
+                .arg(
+                    Arg::with_name(COERCE)
+                        .long(COERCE)
+                        .help("Also make lossy conversions: round doubles, read 0/1 and yes/no as booleans, integers as epoch seconds"),
+                )
+                .arg(fail_on_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name(COUNT_SUB_COMMAND)
+                .about("Count the documents of a collection, by aggregation or else a keys-only scan")
+                .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                .arg(
+                    Arg::with_name(WORKERS)
+                        .long(WORKERS)
+                        .takes_value(true)
+                        .default_value("8")
+                        .help("Ranges scanned at the same time when COUNT aggregation is unavailable"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(TOP_SUB_COMMAND)
+                .about("Print the most frequent values of a field")
+                .arg(
+                    Arg::with_name(HIST_FIELD)
+                        .required(true)
+                        .value_name("COLLECTION.FIELD")
+                        .help("Field to count, e.g. users.country"),
+                )
+                .arg(
+                    Arg::with_name(TOP_RESULTS)
+                        .short("n")
+                        .long(TOP_RESULTS)
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Number of values printed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(COUNTER_SUB_COMMAND)
+                .about("Sharded counters, for counts updated more than once a second")
+                .subcommand(
+                    SubCommand::with_name(COUNTER_INIT_SUB_COMMAND)
+                        .about("Create a counter document and its shards, all at zero")
+                        .arg(Arg::with_name(DOCUMENT_PATH).required(true))
+                        .arg(
+                            Arg::with_name(SHARDS)
+                                .long(SHARDS)
+                                .takes_value(true)
+                                .default_value("10")
+                                .help("Shards sharing the writes, each taking about one per second"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(COUNTER_INCR_SUB_COMMAND)
+                        .about("Add to a random shard of a counter")
+                        .arg(Arg::with_name(DOCUMENT_PATH).required(true))
+                        .arg(
+                            Arg::with_name(COUNTER_BY)
+                                .long(COUNTER_BY)
+                                .takes_value(true)
+                                .allow_hyphen_values(true)
+                                .default_value("1")
+                                .help("Amount added, negative to decrement"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(COUNTER_READ_SUB_COMMAND)
+                        .about("Print a counter's value, the sum of its shards")
+                        .arg(Arg::with_name(DOCUMENT_PATH).required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(QUEUE_SUB_COMMAND)
+                .about("Use a collection as a work queue of leased documents")
+                .subcommand(
+                    SubCommand::with_name(QUEUE_PUSH_SUB_COMMAND)
+                        .about("Add a document, claimable right away")
+                        .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                        .arg(Arg::with_name(FIELDS).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name(QUEUE_POP_SUB_COMMAND)
+                        .about("Claim and print the oldest document whose lease is over, exit with code 5 if there is none")
+                        .arg(Arg::with_name(COLLECTION_NAME).required(true))
+                        .arg(
+                            Arg::with_name(LEASE)
+                                .long(LEASE)
+                                .takes_value(true)
+                                .default_value("60s")
+                                .help("How long the document is claimed, e.g. 60s or 5m"),
+                        )
+                        .arg(
+                            Arg::with_name(OWNER)
+                                .long(OWNER)
+                                .takes_value(true)
+                                .help("Name recorded as the lease holder, the user and process id by default"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(QUEUE_ACK_SUB_COMMAND)
+                        .about("Delete a processed document")
+                        .arg(Arg::with_name(DOCUMENT_PATH).required(true))
+                        .arg(
+                            Arg::with_name(OWNER)
+                                .long(OWNER)
+                                .takes_value(true)
+                                .help("Refuse unless the lease is held by this name"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(QUEUE_NACK_SUB_COMMAND)
+                        .about("Release a document back to the queue")
+                        .arg(Arg::with_name(DOCUMENT_PATH).required(true))
+                        .arg(
+                            Arg::with_name(OWNER)
+                                .long(OWNER)
+                                .takes_value(true)
+                                .help("Refuse unless the lease is held by this name"),
+                        )
+                        .arg(
+                            Arg::with_name(DELAY)
+                                .long(DELAY)
+                                .takes_value(true)
+                                .default_value("0s")
+                                .help("Time before the document can be claimed again"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(JOIN_SUB_COMMAND)
+                .about("Report documents joined with the documents they refer to")
+                .arg(
+                    Arg::with_name(JOIN_LEFT)
+                        .required(true)
+                        .value_name("COLLECTION.FIELD")
+                        .help("Collection streamed and its field referring to the other, e.g. orders.userId"),
+                )
+                .arg(
+                    Arg::with_name(JOIN_RIGHT)
+                        .required(true)
+                        .value_name("COLLECTION")
+                        .help("Collection the field refers to, e.g. users"),
+                )
+                .arg(
+                    Arg::with_name(SELECT)
+                        .long(SELECT)
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("Columns to output, e.g. users.email,orders.total"),
+                )
+                .arg(
+                    Arg::with_name(FORMAT)
+                        .long(FORMAT)
+                        .takes_value(true)
+                        .possible_values(&["ndjson", "csv"])
+                        .default_value("ndjson"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(MIRROR_SUB_COMMAND)
+                .about("Keep a local copy of collections up to date, optionally serving queries against it")
+                .arg(Arg::with_name(COLLECTIONS).required(true).multiple(true))
+                .arg(
+                    Arg::with_name(MIRROR_DIR)
+                        .long(MIRROR_DIR)
+                        .takes_value(true)
+                        .default_value("mirror")
+                        .help("Directory holding the copy, one .ndjson file per collection"),
+                )
+                .arg(
+                    Arg::with_name(INTERVAL)
+                        .long(INTERVAL)
+                        .takes_value(true)
+                        .default_value("30s")
+                        .help("Time between polls of the collections, e.g. 10s or 5m"),
+                )
+                .arg(
+                    Arg::with_name(SERVE)
+                        .long(SERVE)
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .help("Answer read-only HTTP queries against the copy on this address, e.g. 127.0.0.1:9000"),
+                )
+                .arg(
+                    Arg::with_name(UPDATED_FIELD)
+                        .long(UPDATED_FIELD)
+                        .takes_value(true)
+                        .value_name("FIELD")
+                        .help("Timestamp field every write sets, e.g. to the server time; polls then only read documents written since the previous one"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(PING_SUB_COMMAND)
+                .about("Measure the latency of authentication and reads, and of the emulator if FIRESTORE_EMULATOR_HOST is set")
+                .arg(
+                    Arg::with_name(COUNT)
+                        .long(COUNT)
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("Requests timed of each kind"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(CAN_I_SUB_COMMAND)
+                .about("Check whether the credentials may read, write or delete a document, changing nothing")
+                .arg(
+                    Arg::with_name(ACTION)
+                        .required(true)
+                        .possible_values(&["read", "write", "delete"]),
+                )
+                .arg(Arg::with_name(DOCUMENT_PATH).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name(WATCH_SUB_COMMAND)
+                .about("Report documents as they are written or removed, or only writes breaking the rules")
+                .arg(Arg::with_name(COLLECTIONS).required(true).multiple(true))
+                .arg(
+                    Arg::with_name(WHERE)
+                        .long(WHERE)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only watch documents matching a condition, in every collection"),
+                )
+                .arg(
+                    Arg::with_name(WHEN)
+                        .long(WHEN)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("TRIGGER")
+                        .help("Only report writes changing a field, e.g. 'status changed to \"shipped\"' or 'stock changed from 1 to 0'"),
+                )
+                .arg(
+                    Arg::with_name(WATCH_RULES)
+                        .long(WATCH_RULES)
+                        .takes_value(true)
+                        .value_name("RULES")
+                        .help("Only report writes breaking this rules file"),
+                )
+                .arg(
+                    Arg::with_name(WEBHOOK)
+                        .long(WEBHOOK)
+                        .takes_value(true)
+                        .value_name("URL")
+                        .help("POST each report as JSON to this URL"),
+                )
+                .arg(
+                    Arg::with_name(INTERVAL)
+                        .long(INTERVAL)
+                        .takes_value(true)
+                        .default_value("30s")
+                        .help("Time between scans of the collections, e.g. 10s or 5m"),
+                )
+                .arg(
+                    Arg::with_name(UPDATED_FIELD)
+                        .long(UPDATED_FIELD)
+                        .takes_value(true)
+                        .value_name("FIELD")
+                        .help("Timestamp field every write sets, e.g. to the server time; scans then only read documents written since the previous one"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(AUDIT_SUB_COMMAND)
+                .about("Inspect the local log of destructive operations")
+                .subcommand(
+                    SubCommand::with_name(AUDIT_SHOW_SUB_COMMAND)
+                        .arg(
+                            Arg::with_name(LIMIT)
+                                .long(LIMIT)
+                                .takes_value(true)
+                                .validator(|limit| {
+                                    limit.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())
+                                })
+                                .help("Show only this many of the most recent entries"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(WHOAMI_SUB_COMMAND)
+                .about("Print the project and service account commands would use"),
+        )
+        .subcommand(
+            SubCommand::with_name(INTROSPECT_SUB_COMMAND)
+                .about("Print the commands, flags, output formats and features as JSON"),
+        )
+        .subcommand(
+            SubCommand::with_name(SELF_UPDATE_SUB_COMMAND)
+                .about("Replace this executable with the latest release, after checking its checksum")
+                .arg(
+                    Arg::with_name(CHECK_ONLY)
+                        .long(CHECK_ONLY)
+                        .help("Only print whether a newer release is available"),
+                ),
         )
         .arg(
             Arg::with_name(DATABASE_NAME)
                 .required(true)
+                .env("FIRESALE_DATABASE")
                 .default_value(DEFAULT_DATABASE_NAME),
         )
-        .get_matches();
+}
+
+fn setup_arguments(environ: &Environment) -> (Options, EntryPoint) {
+    let matches = app(environ).get_matches();
     let environment = {
         // TODO(hazebooth): investigate
         let service_account_path = matches.value_of(CREDENTIALS_LOCATION_ARG).map(String::from);
@@ -130,10 +1601,80 @@ fn setup_arguments(environ: &Environment) -> (Options, EntryPoint) {
             project_id,
         }
     };
-    let database_name = matches.value_of(DATABASE_NAME).unwrap();
+    let database_name = matches.value_of(DATABASE_NAME).unwrap().to_string();
+    let dry_run = flag_or_env(&matches, DRY_RUN_ARG, "FIRESALE_DRY_RUN");
+    let scope = if flag_or_env(&matches, READ_ONLY_ARG, "FIRESALE_READ_ONLY") {
+        AuthScope::ReadOnly
+    } else {
+        // clap already restricted the value to a known scope
+        matches.value_of(SCOPE_ARG).unwrap().parse().unwrap()
+    };
+    let auth = AuthOptions {
+        scope,
+        adjust_clock: flag_or_env(&matches, ADJUST_CLOCK_ARG, "FIRESALE_ADJUST_CLOCK"),
+    };
+    // clap already restricted the value to a known version
+    let http_version = matches.value_of(HTTP_VERSION_ARG).unwrap().parse().unwrap();
+    let debug_http = matches.value_of(DEBUG_HTTP_ARG).map(String::from);
+    let confirm_project = matches.value_of(CONFIRM_PROJECT_ARG).map(String::from);
+    // clap already validated the locale and restricted the value to a known format
+    let locale = matches
+        .value_of(LOCALE_ARG)
+        .map_or(Locale::default(), |tag| tag.parse().unwrap());
+    let output =
+        output::OutputFormat::parse(matches.value_of(OUTPUT_ARG).unwrap(), locale).unwrap();
+    let limit = |arg, default| {
+        matches
+            .value_of(arg)
+            // clap already validated the limit
+            .map(|limit: &str| limit.parse().unwrap())
+            .or(if output.is_human() {
+                Some(default)
+            } else {
+                None
+            })
+    };
+    let truncation = if flag_or_env(&matches, FULL_ARG, "FIRESALE_FULL") {
+        Truncation::default()
+    } else {
+        Truncation {
+            max_field_bytes: limit(MAX_FIELD_BYTES_ARG, DEFAULT_MAX_FIELD_BYTES),
+            max_array_items: limit(MAX_ARRAY_ITEMS_ARG, DEFAULT_MAX_ARRAY_ITEMS),
+        }
+    };
+    let terminal = output::Terminal::detect(flag_or_env(&matches, PLAIN_ARG, "FIRESALE_PLAIN"));
+    let output = output.with_truncation(truncation).with_terminal(terminal);
+    // clap already restricted the value to a known preference
+    let prefer = mirror::Prefer::parse(matches.value_of(PREFER_ARG).unwrap()).unwrap();
+    let mirror = match (prefer, matches.value_of(MIRROR_ARG)) {
+        (mirror::Prefer::Live, _) | (_, None) => None,
+        (prefer, Some(dir)) => Some(mirror::MirrorSource {
+            dir: dir.into(),
+            prefer,
+        }),
+    };
+    let progress = progress::ProgressEvents::new(flag_or_env(
+        &matches,
+        PROGRESS_JSON_ARG,
+        "FIRESALE_PROGRESS_JSON",
+    ));
+    let notify = notify::Notifier {
+        url: matches.value_of(NOTIFY_URL_ARG).map(String::from),
+        command: matches.value_of(NOTIFY_CMD_ARG).map(String::from),
+    };
     let options = Options {
         environment,
         database_name,
+        dry_run,
+        auth,
+        http_version,
+        debug_http,
+        confirm_project,
+        output,
+        progress,
+        mirror,
+        notify,
+        read_time: matches.value_of(READ_TIME_ARG).map(String::from),
     };
     if let Some(get_command) = &matches.subcommand_matches(GET_SUB_COMMAND) {
         if get_command.is_present(DOCUMENT_NAME) {
@@ -143,6 +1684,9 @@ fn setup_arguments(environ: &Environment) -> (Options, EntryPoint) {
             let query = CollectionQuery::from_sub_matches(get_command);
             return (options, EntryPoint::ViewCollection(query));
         }
+    } else if let Some(list_command) = &matches.subcommand_matches(LIST_COLLECTIONS_SUB_COMMAND) {
+        let path = list_command.value_of(DOCUMENT_PATH).map(String::from);
+        return (options, EntryPoint::ListCollections(path));
     } else if let Some(delete_command) = &matches.subcommand_matches(DELETE_SUB_COMMAND) {
         if delete_command.is_present(DOCUMENT_NAME) {
             let query = DocumentQuery::from_sub_matches(delete_command);
@@ -151,22 +1695,448 @@ fn setup_arguments(environ: &Environment) -> (Options, EntryPoint) {
             let query = CollectionQuery::from_sub_matches(delete_command);
             return (options, EntryPoint::DeleteCollection(query));
         }
-        let query = DocumentQuery::from_sub_matches(delete_command);
-        return (options, EntryPoint::DeleteDocument(query));
+    } else if let Some(set_command) = &matches.subcommand_matches(SET_SUB_COMMAND) {
+        let query = SetDocumentQuery::from_sub_matches(set_command);
+        return (options, EntryPoint::SetDocument(query));
+    } else if let Some(add_command) = &matches.subcommand_matches(ADD_SUB_COMMAND) {
+        let collection = add_command.value_of(COLLECTION_NAME).unwrap().to_string();
+        let fields = add_command.value_of(FIELDS).unwrap().to_string();
+        return (options, EntryPoint::AddDocument { collection, fields });
+    } else if let Some(patch_command) = &matches.subcommand_matches(PATCH_SUB_COMMAND) {
+        let path = patch_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+        let json_patch = patch_command.is_present(JSON_PATCH);
+        // clap guarantees exactly one of the two is present
+        let patch = patch_command
+            .value_of(JSON_PATCH)
+            .or_else(|| patch_command.value_of(MERGE_PATCH))
+            .unwrap()
+            .to_string();
+        return (
+            options,
+            EntryPoint::PatchDocument {
+                path,
+                patch,
+                json_patch,
+            },
+        );
+    } else if let Some(modify_command) = &matches.subcommand_matches(MODIFY_SUB_COMMAND) {
+        let path = modify_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+        let filter = modify_command.value_of(JQ).unwrap().to_string();
+        // clap already validated the attempts, which default to 5
+        let attempts = modify_command.value_of(ATTEMPTS).unwrap().parse().unwrap();
+        return (
+            options,
+            EntryPoint::ModifyDocument {
+                path,
+                filter,
+                attempts,
+            },
+        );
+    } else if let Some(new_command) = &matches.subcommand_matches(NEW_SUB_COMMAND) {
+        let collection = new_command.value_of(COLLECTION_NAME).unwrap().to_string();
+        let template = new_command.value_of(TEMPLATE).unwrap().to_string();
+        let document = new_command.value_of(DOCUMENT_NAME).map(String::from);
+        return (
+            options,
+            EntryPoint::NewDocument {
+                collection,
+                template,
+                document,
+            },
+        );
     } else if let Some(export_command) = &matches.subcommand_matches(EXPORT_SUB_COMMAND) {
         let query = ExportCollectionQuery::from_sub_matches(export_command);
         return (options, EntryPoint::ExportCollection(query));
+    } else if matches.subcommand_matches(SHELL_SUB_COMMAND).is_some() {
+        return (options, EntryPoint::Shell);
+    } else if let Some(plan_command) = &matches.subcommand_matches(PLAN_SUB_COMMAND) {
+        let desired = plan_command.value_of(DESIRED_STATE).unwrap().to_string();
+        let out = plan_command.value_of(PLAN_OUT).unwrap().to_string();
+        let fail_if_changes = plan_command.is_present(FAIL_IF_CHANGES);
+        return (
+            options,
+            EntryPoint::Plan {
+                desired,
+                out,
+                fail_if_changes,
+            },
+        );
+    } else if let Some(apply_command) = &matches.subcommand_matches(APPLY_SUB_COMMAND) {
+        let plan = apply_command.value_of(PLAN_FILE).unwrap().to_string();
+        return (options, EntryPoint::Apply(plan));
+    } else if let Some(txn_command) = &matches.subcommand_matches(TXN_SUB_COMMAND) {
+        if let Some(apply_command) = txn_command.subcommand_matches(TXN_APPLY_SUB_COMMAND) {
+            let changes = apply_command.value_of(CHANGES_FILE).unwrap().to_string();
+            return (options, EntryPoint::Txn(changes));
+        }
+    } else if let Some(migrate_command) = &matches.subcommand_matches(MIGRATE_SUB_COMMAND) {
+        if let Some(run_command) = migrate_command.subcommand_matches(MIGRATE_RUN_SUB_COMMAND) {
+            let dir = run_command.value_of(MIGRATIONS_DIR).unwrap().to_string();
+            let down = run_command.is_present(MIGRATE_DOWN);
+            return (options, EntryPoint::Migrate { dir, down });
+        }
+    } else if let Some(dump_command) = &matches.subcommand_matches(DUMP_SUB_COMMAND) {
+        let dir = dump_command.value_of(SNAPSHOT_DIR).unwrap().to_string();
+        let collections = dump_command.values_of_lossy(COLLECTIONS).unwrap();
+        // clap already restricted the value to a known compression
+        let compression = dump_command
+            .value_of(COMPRESS)
+            .map_or(dump::Compression::None, |name| {
+                dump::Compression::parse(name).unwrap()
+            });
+        let shard_size = dump_command
+            .value_of(SHARD_SIZE)
+            .and_then(|size| size.parse::<u64>().ok())
+            .filter(|size| *size > 0);
+        let partition_by = dump_command.value_of(PARTITION_BY).map(String::from);
+        let jobs = dump_command
+            .value_of(JOBS)
+            .and_then(|jobs| jobs.parse().ok())
+            .unwrap_or(4);
+        return (
+            options,
+            EntryPoint::Dump {
+                dir,
+                collections,
+                compression,
+                shard_size,
+                partition_by,
+                jobs,
+            },
+        );
+    } else if let Some(load_command) = &matches.subcommand_matches(LOAD_SUB_COMMAND) {
+        let dir = load_command.value_of(SNAPSHOT_DIR).unwrap().to_string();
+        let workers = load_command
+            .value_of(WORKERS)
+            .and_then(|workers| workers.parse().ok())
+            .unwrap_or(8);
+        let rate = load_command
+            .value_of(RATE)
+            .and_then(|rate| rate.parse().ok())
+            .unwrap_or(500);
+        return (options, EntryPoint::Load { dir, workers, rate });
+    } else if let Some(prune_command) = &matches.subcommand_matches(PRUNE_SUB_COMMAND) {
+        let collection = prune_command.value_of(COLLECTION_NAME).unwrap().to_string();
+        let field = prune_command.value_of(TIMESTAMP_FIELD).unwrap().to_string();
+        let older_than = prune_command.value_of(OLDER_THAN).unwrap().to_string();
+        let archive = prune_command.value_of(ARCHIVE).map(String::from);
+        return (
+            options,
+            EntryPoint::Prune {
+                collection,
+                field,
+                older_than,
+                archive,
+            },
+        );
+    } else if let Some(rollback_command) = &matches.subcommand_matches(ROLLBACK_SUB_COMMAND) {
+        let manifest = rollback_command.value_of(TO_MANIFEST).unwrap().to_string();
+        let delete_added = rollback_command.is_present(DELETE_ADDED);
+        return (
+            options,
+            EntryPoint::Rollback {
+                manifest,
+                delete_added,
+            },
+        );
+    } else if let Some(verify_command) = &matches.subcommand_matches(VERIFY_BACKUP_SUB_COMMAND) {
+        let dir = verify_command.value_of(SNAPSHOT_DIR).unwrap().to_string();
+        let deep = verify_command.is_present(DEEP);
+        return (options, EntryPoint::VerifyBackup { dir, deep });
+    } else if let Some(check_command) = &matches.subcommand_matches(CHECK_SUB_COMMAND) {
+        let rules = check_command.value_of(RULES_FILE).unwrap().to_string();
+        let collection = check_command.value_of(COLLECTION_NAME).map(String::from);
+        // clap already restricted the value to a known threshold
+        let fail_on = check_command.value_of(FAIL_ON).unwrap().parse().unwrap();
+        return (
+            options,
+            EntryPoint::Check {
+                rules,
+                collection,
+                fail_on,
+            },
+        );
+    } else if let Some(fixtures_command) = &matches.subcommand_matches(FIXTURES_SUB_COMMAND) {
+        let collection = fixtures_command
+            .value_of(COLLECTION_NAME)
+            .unwrap()
+            .to_string();
+        let sample = fixtures_command
+            .value_of(SAMPLE)
+            .and_then(|sample| sample.parse().ok())
+            .unwrap_or(50);
+        let out = fixtures_command.value_of(FIXTURES_OUT).unwrap().to_string();
+        let emulator_project = fixtures_command
+            .value_of(EMULATOR_PROJECT)
+            .unwrap()
+            .to_string();
+        let keep = fixtures_command.values_of_lossy(KEEP).unwrap_or_default();
+        return (
+            options,
+            EntryPoint::Fixtures {
+                collection,
+                sample,
+                out,
+                emulator_project,
+                keep,
+            },
+        );
+    } else if let Some(group_command) = &matches.subcommand_matches(GROUP_BY_SUB_COMMAND) {
+        let collection = group_command.value_of(COLLECTION_NAME).unwrap().to_string();
+        let by = group_command.values_of_lossy(GROUP_BY).unwrap();
+        let aggregates = group_command.values_of_lossy(AGGREGATE).unwrap();
+        let filters = group_command.values_of_lossy(WHERE).unwrap_or_default();
+        return (
+            options,
+            EntryPoint::GroupBy {
+                collection,
+                by,
+                aggregates,
+                filters,
+            },
+        );
+    } else if let Some(hist_command) = &matches.subcommand_matches(HIST_SUB_COMMAND) {
+        let field = hist_command.value_of(HIST_FIELD).unwrap().to_string();
+        let buckets = hist_command
+            .value_of(BUCKETS)
+            .and_then(|buckets| buckets.parse().ok())
+            .unwrap_or(10);
+        let sample = hist_command
+            .value_of(SAMPLE)
+            .and_then(|sample| sample.parse().ok());
+        return (
+            options,
+            EntryPoint::Histogram {
+                field,
+                buckets,
+                sample,
+            },
+        );
+    } else if let Some(schema_command) = &matches.subcommand_matches(SCHEMA_SUB_COMMAND) {
+        if let Some(diff_command) = schema_command.subcommand_matches(SCHEMA_DIFF_SUB_COMMAND) {
+            let projects = diff_command.values_of_lossy(COMPARED_PROJECT).unwrap();
+            if projects.len() != 2 {
+                let usage = "schema diff compares two projects, give --project twice";
+                return (options, EntryPoint::Usage(usage.to_string()));
+            }
+            let (first, second) = (projects[0].clone(), projects[1].clone());
+            let collection = diff_command.value_of(COLLECTION_NAME).unwrap().to_string();
+            let sample = diff_command
+                .value_of(SAMPLE)
+                .and_then(|sample| sample.parse().ok());
+            // clap already restricted the value to a known threshold
+            let fail_on = diff_command.value_of(FAIL_ON).unwrap().parse().unwrap();
+            return (
+                options,
+                EntryPoint::SchemaDiff {
+                    projects: (first, second),
+                    collection,
+                    sample,
+                    fail_on,
+                },
+            );
+        }
+    } else if let Some(fix_command) = &matches.subcommand_matches(FIX_TYPES_SUB_COMMAND) {
+        let collection = fix_command.value_of(COLLECTION_NAME).unwrap().to_string();
+        let field = fix_command.value_of(HIST_FIELD).unwrap().to_string();
+        // clap already restricted the type and fail-on to known values
+        let target = coerce::Target::parse(fix_command.value_of(TARGET_TYPE).unwrap()).unwrap();
+        let lossy = fix_command.is_present(COERCE);
+        let fail_on = fix_command.value_of(FAIL_ON).unwrap().parse().unwrap();
+        return (
+            options,
+            EntryPoint::FixTypes {
+                collection,
+                field,
+                target,
+                lossy,
+                fail_on,
+            },
+        );
+    } else if let Some(analyze_command) = &matches.subcommand_matches(ANALYZE_FIELDS_SUB_COMMAND) {
+        let collection = analyze_command
+            .value_of(COLLECTION_NAME)
+            .unwrap()
+            .to_string();
+        // clap already validated the threshold and restricted fail-on
+        let threshold = analyze_command
+            .value_of(EMPTY_THRESHOLD)
+            .unwrap()
+            .parse()
+            .unwrap();
+        let sample = analyze_command
+            .value_of(SAMPLE)
+            .and_then(|sample| sample.parse().ok());
+        let fail_on = analyze_command.value_of(FAIL_ON).unwrap().parse().unwrap();
+        return (
+            options,
+            EntryPoint::AnalyzeFields {
+                collection,
+                threshold,
+                sample,
+                fail_on,
+            },
+        );
+    } else if let Some(count_command) = &matches.subcommand_matches(COUNT_SUB_COMMAND) {
+        let collection = count_command.value_of(COLLECTION_NAME).unwrap().to_string();
+        let workers = count_command
+            .value_of(WORKERS)
+            .and_then(|workers| workers.parse().ok())
+            .unwrap_or(8);
+        return (
+            options,
+            EntryPoint::Count {
+                collection,
+                workers,
+            },
+        );
+    } else if let Some(top_command) = &matches.subcommand_matches(TOP_SUB_COMMAND) {
+        let field = top_command.value_of(HIST_FIELD).unwrap().to_string();
+        let results = top_command
+            .value_of(TOP_RESULTS)
+            .and_then(|results| results.parse().ok())
+            .unwrap_or(10);
+        return (options, EntryPoint::Top { field, results });
+    } else if let Some(counter_command) = &matches.subcommand_matches(COUNTER_SUB_COMMAND) {
+        if let Some(init_command) = counter_command.subcommand_matches(COUNTER_INIT_SUB_COMMAND) {
+            let path = init_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+            let shards = init_command
+                .value_of(SHARDS)
+                .and_then(|shards| shards.parse().ok())
+                .unwrap_or(10);
+            return (options, EntryPoint::CounterInit { path, shards });
+        } else if let Some(incr_command) =
+            counter_command.subcommand_matches(COUNTER_INCR_SUB_COMMAND)
+        {
+            let path = incr_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+            let by = incr_command
+                .value_of(COUNTER_BY)
+                .and_then(|by| by.parse().ok())
+                .unwrap_or(1);
+            return (options, EntryPoint::CounterIncr { path, by });
+        } else if let Some(read_command) =
+            counter_command.subcommand_matches(COUNTER_READ_SUB_COMMAND)
+        {
+            let path = read_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+            return (options, EntryPoint::CounterRead(path));
+        }
+    } else if let Some(queue_command) = &matches.subcommand_matches(QUEUE_SUB_COMMAND) {
+        if let Some(push_command) = queue_command.subcommand_matches(QUEUE_PUSH_SUB_COMMAND) {
+            let collection = push_command.value_of(COLLECTION_NAME).unwrap().to_string();
+            let fields = push_command.value_of(FIELDS).unwrap().to_string();
+            return (options, EntryPoint::QueuePush { collection, fields });
+        } else if let Some(pop_command) = queue_command.subcommand_matches(QUEUE_POP_SUB_COMMAND) {
+            let collection = pop_command.value_of(COLLECTION_NAME).unwrap().to_string();
+            let lease = pop_command.value_of(LEASE).unwrap().to_string();
+            let owner = pop_command.value_of(OWNER).map(String::from);
+            return (
+                options,
+                EntryPoint::QueuePop {
+                    collection,
+                    lease,
+                    owner,
+                },
+            );
+        } else if let Some(ack_command) = queue_command.subcommand_matches(QUEUE_ACK_SUB_COMMAND) {
+            let path = ack_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+            let owner = ack_command.value_of(OWNER).map(String::from);
+            return (options, EntryPoint::QueueAck { path, owner });
+        } else if let Some(nack_command) = queue_command.subcommand_matches(QUEUE_NACK_SUB_COMMAND)
+        {
+            let path = nack_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+            let owner = nack_command.value_of(OWNER).map(String::from);
+            let delay = nack_command.value_of(DELAY).unwrap().to_string();
+            return (options, EntryPoint::QueueNack { path, owner, delay });
+        }
+    } else if let Some(join_command) = &matches.subcommand_matches(JOIN_SUB_COMMAND) {
+        let left = join_command.value_of(JOIN_LEFT).unwrap().to_string();
+        let right = join_command.value_of(JOIN_RIGHT).unwrap().to_string();
+        let select = join_command.values_of_lossy(SELECT).unwrap_or_default();
+        // clap already restricted the value to a known format
+        let format = join::Format::parse(join_command.value_of(FORMAT).unwrap()).unwrap();
+        return (
+            options,
+            EntryPoint::Join {
+                left,
+                right,
+                select,
+                format,
+            },
+        );
+    } else if let Some(watch_command) = &matches.subcommand_matches(WATCH_SUB_COMMAND) {
+        let collections = watch_command.values_of_lossy(COLLECTIONS).unwrap();
+        let filters = watch_command.values_of_lossy(WHERE).unwrap_or_default();
+        let triggers = watch_command.values_of_lossy(WHEN).unwrap_or_default();
+        let interval = watch_command.value_of(INTERVAL).unwrap().to_string();
+        let updated_field = watch_command.value_of(UPDATED_FIELD).map(String::from);
+        let rules = watch_command.value_of(WATCH_RULES).map(String::from);
+        let webhook = watch_command.value_of(WEBHOOK).map(String::from);
+        return (
+            options,
+            EntryPoint::Watch {
+                collections,
+                filters,
+                triggers,
+                interval,
+                updated_field,
+                rules,
+                webhook,
+            },
+        );
+    } else if let Some(mirror_command) = &matches.subcommand_matches(MIRROR_SUB_COMMAND) {
+        let collections = mirror_command.values_of_lossy(COLLECTIONS).unwrap();
+        let dir = mirror_command.value_of(MIRROR_DIR).unwrap().to_string();
+        let interval = mirror_command.value_of(INTERVAL).unwrap().to_string();
+        let serve = mirror_command.value_of(SERVE).map(String::from);
+        let updated_field = mirror_command.value_of(UPDATED_FIELD).map(String::from);
+        return (
+            options,
+            EntryPoint::Mirror {
+                collections,
+                dir,
+                interval,
+                serve,
+                updated_field,
+            },
+        );
+    } else if let Some(ping_command) = &matches.subcommand_matches(PING_SUB_COMMAND) {
+        let count = ping_command
+            .value_of(COUNT)
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(10);
+        return (options, EntryPoint::Ping { count });
+    } else if let Some(can_i_command) = &matches.subcommand_matches(CAN_I_SUB_COMMAND) {
+        // clap already restricted the value to a known action
+        let action = access::Action::parse(can_i_command.value_of(ACTION).unwrap()).unwrap();
+        let path = can_i_command.value_of(DOCUMENT_PATH).unwrap().to_string();
+        return (options, EntryPoint::CanI { action, path });
+    } else if let Some(audit_command) = &matches.subcommand_matches(AUDIT_SUB_COMMAND) {
+        if let Some(show_command) = audit_command.subcommand_matches(AUDIT_SHOW_SUB_COMMAND) {
+            // clap already validated the limit
+            let limit = show_command
+                .value_of(LIMIT)
+                .map(|limit| limit.parse().unwrap());
+            return (options, EntryPoint::AuditShow(limit));
+        }
+    } else if matches.subcommand_matches(WHOAMI_SUB_COMMAND).is_some() {
+        return (options, EntryPoint::Whoami);
+    } else if matches.subcommand_matches(INTROSPECT_SUB_COMMAND).is_some() {
+        return (options, EntryPoint::Introspect);
+    } else if let Some(update_command) = matches.subcommand_matches(SELF_UPDATE_SUB_COMMAND) {
+        let check_only = update_command.is_present(CHECK_ONLY);
+        return (options, EntryPoint::SelfUpdate { check_only });
     }
-    return (options, EntryPoint::Usage(matches.usage().to_string()));
+    (options, EntryPoint::Usage(matches.usage().to_string()))
 }
 
 impl ExportCollectionQuery {
     fn from_sub_matches(matches: &&ArgMatches) -> ExportCollectionQuery {
         ExportCollectionQuery {
-            collections: matches
-                .values_of_lossy(COLLECTIONS)
-                .unwrap_or_else(|| Vec::new()),
+            collections: matches.values_of_lossy(COLLECTIONS).unwrap_or_default(),
             bucket_name: matches.value_of(BUCKET_NAME).unwrap().to_string(),
+            collection: matches.value_of(COLLECTION_NAME).map(String::from),
+            filters: matches.values_of_lossy(WHERE).unwrap_or_default(),
+            all_databases: matches.is_present(ALL_DATABASES),
         }
     }
 }
@@ -176,6 +2146,23 @@ impl DocumentQuery {
         DocumentQuery {
             collection_name: matches.value_of(COLLECTION_NAME).unwrap().to_string(),
             document_name: matches.value_of(DOCUMENT_NAME).unwrap().to_string(),
+            save_bytes: matches.value_of(SAVE_BYTES).map(String::from),
+            raw_field: matches.value_of(RAW_FIELD).map(String::from),
+            select: matches.values_of_lossy(SELECT).unwrap_or_default(),
+            if_changed_since: matches.value_of(IF_CHANGED_SINCE).map(String::from),
+            poll: matches.value_of(POLL).map(String::from),
+            diff: matches.is_present(DIFF),
+        }
+    }
+}
+
+impl SetDocumentQuery {
+    fn from_sub_matches(matches: &&ArgMatches) -> SetDocumentQuery {
+        SetDocumentQuery {
+            collection_name: matches.value_of(COLLECTION_NAME).unwrap().to_string(),
+            document_name: matches.value_of(DOCUMENT_NAME).unwrap().to_string(),
+            fields: matches.value_of(FIELDS).unwrap().to_string(),
+            bytes_fields: matches.values_of_lossy(BYTES_FIELD).unwrap_or_default(),
         }
     }
 }
@@ -184,39 +2171,620 @@ impl CollectionQuery {
     fn from_sub_matches(matches: &&ArgMatches) -> CollectionQuery {
         CollectionQuery {
             collection_name: matches.value_of(COLLECTION_NAME).unwrap().to_string(),
+            filters: matches.values_of_lossy(WHERE).unwrap_or_default(),
+            show_missing: matches.is_present(SHOW_MISSING),
+            near: match (matches.value_of(NEAR), matches.value_of(RADIUS)) {
+                (Some(point), Some(radius)) => Some((point.to_string(), radius.to_string())),
+                _ => None,
+            },
+            geo_field: matches
+                .value_of(GEO_FIELD)
+                .unwrap_or("location")
+                .to_string(),
+            geohash_field: matches.value_of(GEOHASH_FIELD).map(String::from),
+            select: matches.values_of_lossy(SELECT).unwrap_or_default(),
+            poll: matches.value_of(POLL).map(String::from),
+            diff: matches.is_present(DIFF),
+        }
+    }
+}
+
+impl EntryPoint {
+    /// Name of the operation unless it leaves the database as it is. Every variant is
+    /// listed, so a new command has to be declared read-only to skip the confirmation.
+    fn destructive_operation(&self) -> Option<&'static str> {
+        match self {
+            EntryPoint::DeleteDocument(_) => Some("delete"),
+            EntryPoint::DeleteCollection(_) => Some("delete"),
+            EntryPoint::SetDocument(_) => Some("set"),
+            EntryPoint::AddDocument { .. } => Some("add"),
+            EntryPoint::PatchDocument { .. } => Some("patch"),
+            EntryPoint::ModifyDocument { .. } => Some("modify"),
+            EntryPoint::NewDocument { .. } => Some("new"),
+            // its delete and commit write, and it cannot ask before each of them
+            EntryPoint::Shell => Some("shell"),
+            EntryPoint::Apply(_) => Some("apply"),
+            EntryPoint::Txn(_) => Some("txn"),
+            EntryPoint::Migrate { .. } => Some("migrate"),
+            EntryPoint::Prune { .. } => Some("prune"),
+            EntryPoint::Rollback { .. } => Some("rollback"),
+            EntryPoint::Load { .. } => Some("load"),
+            EntryPoint::FixTypes { .. } => Some("fix-types"),
+            EntryPoint::CounterInit { .. } => Some("counter init"),
+            EntryPoint::CounterIncr { .. } => Some("counter incr"),
+            EntryPoint::QueuePush { .. } => Some("queue push"),
+            EntryPoint::QueuePop { .. } => Some("queue pop"),
+            EntryPoint::QueueAck { .. } => Some("queue ack"),
+            EntryPoint::QueueNack { .. } => Some("queue nack"),
+            EntryPoint::GetDocument(_)
+            | EntryPoint::ViewCollection(_)
+            | EntryPoint::ListCollections(_)
+            // writes to the bucket, only reads the database
+            | EntryPoint::ExportCollection(_)
+            | EntryPoint::Plan { .. }
+            | EntryPoint::Dump { .. }
+            | EntryPoint::VerifyBackup { .. }
+            | EntryPoint::Check { .. }
+            | EntryPoint::Fixtures { .. }
+            | EntryPoint::GroupBy { .. }
+            | EntryPoint::Histogram { .. }
+            | EntryPoint::SchemaDiff { .. }
+            | EntryPoint::AnalyzeFields { .. }
+            | EntryPoint::Count { .. }
+            | EntryPoint::Top { .. }
+            | EntryPoint::CounterRead(_)
+            | EntryPoint::Join { .. }
+            | EntryPoint::Watch { .. }
+            | EntryPoint::Mirror { .. }
+            | EntryPoint::Ping { .. }
+            // its probe is built to be refused
+            | EntryPoint::CanI { .. }
+            | EntryPoint::AuditShow(_)
+            | EntryPoint::Whoami
+            | EntryPoint::Introspect
+            | EntryPoint::SelfUpdate { .. }
+            | EntryPoint::Usage(_) => None,
+        }
+    }
+
+    // Operations that may run long enough for someone to want to hear when they end
+    fn long_operation(&self) -> Option<&'static str> {
+        match self {
+            EntryPoint::ExportCollection(_) => Some("export"),
+            EntryPoint::Dump { .. } => Some("dump"),
+            EntryPoint::Load { .. } => Some("load"),
+            EntryPoint::Prune { .. } => Some("prune"),
+            EntryPoint::Migrate { .. } => Some("migrate"),
+            EntryPoint::Apply(_) => Some("apply"),
+            EntryPoint::Rollback { .. } => Some("rollback"),
+            EntryPoint::VerifyBackup { deep: true, .. } => Some("verify-backup"),
+            _ => None,
         }
     }
 }
 
+// Exits with `VIOLATIONS_EXIT_CODE` when the report has findings past the threshold
+fn gate(
+    report: libfiresale::errors::Result<Report>,
+    fail_on: FailOn,
+) -> libfiresale::errors::Result<()> {
+    match report {
+        Ok(ref report) => match exit_code(report, fail_on) {
+            Some(code) => std::process::exit(code),
+            None => Ok(()),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// The code to exit with for `report`, if its findings fail the command
+fn exit_code(report: &Report, fail_on: FailOn) -> Option<i32> {
+    if report.fails(fail_on) {
+        Some(VIOLATIONS_EXIT_CODE)
+    } else {
+        None
+    }
+}
+
 fn main() -> Result<(), String> {
     let environment = gather_environment();
     let (options, entrypoint) = setup_arguments(&environment);
-    // if the entrypoint is set, use that
-    // if the entrypoint is not set, default to env
-    let context = {
-        if let (Some(service_account_path), Some(project_id)) = (
-            options.environment.service_account_path,
-            options.environment.project_id,
-        ) {
-            DatabaseContext::new(project_id, service_account_path)
-        } else if let (Some(service_account_path), Some(project_id)) =
-            (environment.service_account_path, environment.project_id)
-        {
-            DatabaseContext::new(project_id, service_account_path)
-        } else {
-            Err(String::from("Failed to create database context, not provided in environment variables or cli args"))
-        }
-    }?;
-    match entrypoint {
-        EntryPoint::GetDocument(query) => entrypoint::handle_document_get(query, context),
-        EntryPoint::ViewCollection(query) => entrypoint::handle_document_view(query, context),
-        EntryPoint::DeleteDocument(query) => entrypoint::handle_document_delete(query, context),
-        EntryPoint::DeleteCollection(query) => entrypoint::handle_collection_delete(query, context),
-        EntryPoint::ExportCollection(query) => entrypoint::handle_database_export(query, context),
-        EntryPoint::Usage(usage_str) => Ok(println!("{}", usage_str)),
+    // the audit log is local, no need for credentials
+    if let EntryPoint::AuditShow(limit) = entrypoint {
+        return audit::show(limit).map_err(|e| e.to_string());
+    }
+    // so is the command tree
+    if let EntryPoint::Introspect = entrypoint {
+        let app = app(&environment);
+        return introspect::introspect(&app, APP_VERSION).map_err(|e| e.to_string());
+    }
+    // and updating the executable
+    if let EntryPoint::SelfUpdate { check_only } = entrypoint {
+        return update::self_update(APP_VERSION, check_only).map_err(|e| e.to_string());
+    }
+    // so is a shallow backup check
+    if let EntryPoint::VerifyBackup { dir, deep: false } = &entrypoint {
+        return dump::verify(&*dir, None, &options.output).map_err(|e| e.to_string());
+    }
+    // cli args take precedence over the environment, unless the user picks otherwise
+    let identity = identity::select(&options.environment, &environment)?;
+    if let EntryPoint::Whoami = entrypoint {
+        identity::whoami(&identity, options.auth.scope);
+        return Ok(());
+    }
+    if let Some(operation) = entrypoint.destructive_operation() {
+        // a dry run writes nothing, so there is nothing to protect against
+        if !options.dry_run {
+            let confirmed = options.confirm_project.as_ref().map(|id| &**id);
+            config::Config::load()?.confirm(&*identity.project_id, operation, confirmed)?;
+        }
+    }
+    let context = DatabaseContext::with_auth_options(
+        identity.project_id,
+        identity.service_account_path,
+        options.auth,
+    )?
+    .with_connection_options(&ConnectionOptions {
+        http_version: options.http_version,
+        ..ConnectionOptions::default()
+    })
+    .map_err(|e| e.to_string())?;
+    let context = match &options.debug_http {
+        Some(dir) => context.with_http_hook(HttpDump::new(&**dir).map_err(|e| e.to_string())?),
+        None => context,
+    };
+    let read_time = match &options.read_time {
+        Some(read_time) => {
+            Some(pitr::parse_read_time(&*read_time, Utc::now()).map_err(|e| e.to_string())?)
+        }
+        None => None,
+    };
+    let context = match read_time {
+        Some(read_time) => context.at_read_time(read_time),
+        None => context,
+    };
+    // a mirror only has the present, reading the past has to go to the server
+    let mirror = options.mirror.as_ref().filter(|_| read_time.is_none());
+    // kept to look up the retention of the database should the read time be refused
+    let retention_context = context.clone();
+    let database_name = &*options.database_name;
+    let planner = planner::WritePlanner {
+        database_name: options.database_name.clone(),
+        dry_run: options.dry_run,
+        progress: options.progress.clone(),
+    };
+    let project_id = context.project_id.clone();
+    let long_operation = entrypoint.long_operation();
+    let started = Utc::now();
+    let result = match entrypoint {
+        EntryPoint::GetDocument(query) => {
+            match entrypoint::handle_document_get(
+                query,
+                context,
+                database_name,
+                &options.output,
+                mirror,
+            ) {
+                Ok(false) => std::process::exit(NOT_MODIFIED_EXIT_CODE),
+                result => result.map(|_| ()),
+            }
+        }
+        EntryPoint::ViewCollection(query) => entrypoint::handle_collection_list(
+            query,
+            context,
+            database_name,
+            &options.output,
+            mirror,
+        ),
+        EntryPoint::ListCollections(path) => {
+            entrypoint::handle_collection_ids(&context, database_name, path.as_ref().map(|p| &**p))
+        }
+        EntryPoint::DeleteDocument(query) => {
+            entrypoint::handle_document_delete(query, context, &planner)
+        }
+        EntryPoint::SetDocument(query) => entrypoint::handle_document_set(query, context, &planner),
+        EntryPoint::AddDocument { collection, fields } => {
+            entrypoint::handle_document_add(&*collection, &*fields, context, &planner)
+        }
+        EntryPoint::PatchDocument {
+            path,
+            patch,
+            json_patch: false,
+        } => patch::merge_patch(&context, &planner, &*path, &*patch),
+        EntryPoint::PatchDocument {
+            path,
+            patch,
+            json_patch: true,
+        } => patch::json_patch(&context, &planner, &*path, &*patch),
+        EntryPoint::ModifyDocument {
+            path,
+            filter,
+            attempts,
+        } => modify::modify(&context, &planner, &*path, &*filter, attempts),
+        EntryPoint::NewDocument {
+            collection,
+            template,
+            document,
+        } => template::new_document(
+            &context,
+            &planner,
+            &*collection,
+            &*template,
+            document.as_ref().map(|document| &**document),
+        ),
+        EntryPoint::ExportCollection(query) => {
+            entrypoint::handle_database_export(query, context, database_name)
+        }
+        EntryPoint::Shell => shell::run(context, &planner),
+        EntryPoint::Plan {
+            desired,
+            out,
+            fail_if_changes,
+        } => {
+            let fail_on = if fail_if_changes {
+                FailOn::Errors
+            } else {
+                FailOn::Never
+            };
+            let report = plan::plan(&context, database_name, &*desired, &*out, &options.output);
+            gate(report, fail_on)
+        }
+        EntryPoint::Apply(plan) => plan::apply(&context, &planner, &*plan),
+        EntryPoint::Txn(changes) => txn::apply(&context, &planner, &*changes),
+        EntryPoint::Migrate { dir, down } => migrate::run(&context, &planner, &*dir, down),
+        EntryPoint::Dump {
+            dir,
+            collections,
+            compression,
+            shard_size,
+            partition_by,
+            jobs,
+        } => partition_by
+            .map(|spec| dump::Partition::parse(&*spec))
+            .transpose()
+            .and_then(|partition| {
+                let options = dump::DumpOptions {
+                    compression,
+                    max_shard_bytes: shard_size.map(|megabytes| megabytes * 1024 * 1024),
+                    partition,
+                    jobs,
+                    progress: options.progress.clone(),
+                    ..dump::DumpOptions::default()
+                };
+                dump::dump(&context, database_name, &*dir, &*collections, &options)
+            }),
+        EntryPoint::Load { dir, workers, rate } => {
+            load::load(&context, &planner, &*dir, workers, rate)
+        }
+        EntryPoint::Prune {
+            collection,
+            field,
+            older_than,
+            archive,
+        } => prune::prune(
+            &context,
+            &planner,
+            &*collection,
+            &*field,
+            &*older_than,
+            archive.as_ref().map(|dir| &**dir),
+        ),
+        EntryPoint::Rollback {
+            manifest,
+            delete_added,
+        } => rollback::rollback(&context, &planner, &*manifest, delete_added),
+        EntryPoint::VerifyBackup { dir, .. } => {
+            dump::verify(&*dir, Some(&context), &options.output)
+        }
+        EntryPoint::Fixtures {
+            collection,
+            sample,
+            out,
+            emulator_project,
+            keep,
+        } => fixtures::fixtures(
+            &context,
+            database_name,
+            &*collection,
+            sample,
+            &*out,
+            &*emulator_project,
+            &*keep,
+        ),
+        EntryPoint::GroupBy {
+            collection,
+            by,
+            aggregates,
+            filters,
+        } => groupby::group_by(
+            &context,
+            database_name,
+            &*collection,
+            &*by,
+            &*aggregates,
+            &*filters,
+        ),
+        EntryPoint::Histogram {
+            field,
+            buckets,
+            sample,
+        } => hist::histogram(
+            &context,
+            database_name,
+            &*field,
+            buckets,
+            sample,
+            &options.output,
+        ),
+        EntryPoint::SchemaDiff {
+            projects,
+            collection,
+            sample,
+            fail_on,
+        } => {
+            let projects = (&*projects.0, &*projects.1);
+            let report = schema::diff(
+                &context,
+                database_name,
+                projects,
+                &*collection,
+                sample,
+                &options.output,
+            );
+            gate(report, fail_on)
+        }
+        EntryPoint::FixTypes {
+            collection,
+            field,
+            target,
+            lossy,
+            fail_on,
+        } => {
+            let report = coerce::fix_types(
+                &context,
+                &planner,
+                &*collection,
+                &*field,
+                target,
+                lossy,
+                &options.output,
+            );
+            gate(report, fail_on)
+        }
+        EntryPoint::AnalyzeFields {
+            collection,
+            threshold,
+            sample,
+            fail_on,
+        } => {
+            let report = schema::analyze(
+                &context,
+                database_name,
+                &*collection,
+                threshold,
+                sample,
+                &options.output,
+            );
+            gate(report, fail_on)
+        }
+        EntryPoint::Count {
+            collection,
+            workers,
+        } => count::count(&context, database_name, &*collection, workers),
+        EntryPoint::Top { field, results } => top::top(&context, database_name, &*field, results),
+        EntryPoint::CounterInit { path, shards } => {
+            counter::init(&context, &planner, &*path, shards)
+        }
+        EntryPoint::CounterIncr { path, by } => counter::incr(&context, &planner, &*path, by),
+        EntryPoint::CounterRead(path) => counter::read(&context, database_name, &*path),
+        EntryPoint::QueuePush { collection, fields } => {
+            queue::push(&context, &planner, &*collection, &*fields)
+        }
+        EntryPoint::QueuePop {
+            collection,
+            lease,
+            owner,
+        } => {
+            let owner = owner
+                .unwrap_or_else(|| format!("{}-{}", audit::current_user(), std::process::id()));
+            let popped = poll::parse_interval(&*lease).and_then(|lease| {
+                queue::pop(
+                    &context,
+                    &planner,
+                    &*collection,
+                    lease,
+                    &*owner,
+                    &options.output,
+                )
+            });
+            match popped {
+                Ok(false) => std::process::exit(QUEUE_EMPTY_EXIT_CODE),
+                result => result.map(|_| ()),
+            }
+        }
+        EntryPoint::QueueAck { path, owner } => {
+            queue::ack(&context, &planner, &*path, owner.as_ref().map(|o| &**o))
+        }
+        EntryPoint::QueueNack { path, owner, delay } => {
+            poll::parse_interval(&*delay).and_then(|delay| {
+                queue::nack(
+                    &context,
+                    &planner,
+                    &*path,
+                    owner.as_ref().map(|o| &**o),
+                    delay,
+                )
+            })
+        }
+        EntryPoint::Join {
+            left,
+            right,
+            select,
+            format,
+        } => join::join(&context, database_name, &*left, &*right, &*select, format),
+        EntryPoint::Mirror {
+            collections,
+            dir,
+            interval,
+            serve,
+            updated_field,
+        } => poll::parse_interval(&*interval).and_then(|interval| {
+            mirror::mirror(
+                &context,
+                database_name,
+                &*collections,
+                &*dir,
+                interval,
+                serve.as_ref().map(|serve| &**serve),
+                updated_field.as_ref().map(|field| &**field),
+            )
+        }),
+        EntryPoint::Ping { count } => ping::ping(&context, database_name, count),
+        EntryPoint::CanI { action, path } => {
+            match access::can_i(&context, &planner, action, &*path) {
+                Ok(Some(false)) => std::process::exit(DENIED_EXIT_CODE),
+                result => result.map(|_| ()),
+            }
+        }
+        EntryPoint::Watch {
+            collections,
+            filters,
+            triggers,
+            interval,
+            updated_field,
+            rules,
+            webhook,
+        } => poll::parse_interval(&*interval).and_then(|interval| {
+            let options = watch::WatchOptions {
+                filters,
+                triggers,
+                interval,
+                updated_field,
+                rules,
+                webhook,
+            };
+            watch::watch(&context, database_name, &collections, &options)
+        }),
+        EntryPoint::Check {
+            rules,
+            collection,
+            fail_on,
+        } => {
+            let collection = collection.as_ref().map(|collection| &**collection);
+            let report = rules::check(
+                &context,
+                database_name,
+                &*rules,
+                collection,
+                &options.output,
+            );
+            gate(report, fail_on)
+        }
+        EntryPoint::Usage(usage_str) => {
+            println!("{}", usage_str);
+            Ok(())
+        }
         _ => {
             println!("entrypoint not implemented");
             Ok(())
         }
+    };
+    let result = match (result, read_time) {
+        (Err(e), Some(read_time)) => Err(pitr::explain(
+            &retention_context,
+            database_name,
+            read_time,
+            e,
+        )),
+        (result, _) => result,
+    };
+    if let Some(operation) = long_operation.filter(|_| options.notify.is_enabled()) {
+        let finished = Utc::now();
+        options.notify.notify(&notify::Summary {
+            operation: operation.to_string(),
+            project_id,
+            database: database_name.to_string(),
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            started,
+            finished,
+            elapsed_seconds: finished.signed_duration_since(started).num_seconds(),
+        });
+    }
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libfiresale::report::{Finding, Severity};
+
+    fn parse(args: &[&str]) -> clap::Result<ArgMatches<'static>> {
+        let environ = Environment {
+            service_account_path: None,
+            project_id: None,
+        };
+        let mut argv = vec!["firesale", "project", "credentials.json"];
+        argv.extend_from_slice(args);
+        app(&environ).get_matches_from_safe(argv)
+    }
+
+    #[test]
+    fn limits_must_be_numbers() {
+        for arg in &["--max-field-bytes", "--max-array-items"] {
+            let error = parse(&[arg, "lots", LIST_COLLECTIONS_SUB_COMMAND]).unwrap_err();
+            assert_eq!(error.kind, clap::ErrorKind::ValueValidation);
+            assert!(parse(&[arg, "-1", LIST_COLLECTIONS_SUB_COMMAND]).is_err());
+            assert!(parse(&[arg, "64", LIST_COLLECTIONS_SUB_COMMAND]).is_ok());
+        }
+    }
+
+    #[test]
+    fn attempts_must_be_a_number() {
+        let modify = |attempts: &str| {
+            parse(&[
+                MODIFY_SUB_COMMAND,
+                "users/ada",
+                "--jq",
+                ".",
+                "--attempts",
+                attempts,
+            ])
+        };
+        let error = modify("lots").unwrap_err();
+        assert_eq!(error.kind, clap::ErrorKind::ValueValidation);
+        assert!(modify("-1").is_err());
+        assert!(modify("3").is_ok());
+    }
+
+    #[test]
+    fn violations_exit_with_their_own_code() {
+        let mut report = Report::new("check");
+        assert_eq!(exit_code(&report, FailOn::Warnings), None);
+        report.add(
+            Finding::new("violation", "users/ada", "missing").with_severity(Severity::Warning),
+        );
+        assert_eq!(exit_code(&report, FailOn::Errors), None);
+        assert_eq!(
+            exit_code(&report, FailOn::Warnings),
+            Some(VIOLATIONS_EXIT_CODE)
+        );
+        report.add(Finding::new("violation", "users/bob", "missing"));
+        assert_eq!(
+            exit_code(&report, FailOn::Errors),
+            Some(VIOLATIONS_EXIT_CODE)
+        );
+        assert_eq!(exit_code(&report, FailOn::Never), None);
+        assert_ne!(VIOLATIONS_EXIT_CODE, 0);
+    }
+
+    #[test]
+    fn the_audit_limit_must_be_a_number() {
+        let show =
+            |limit: &str| parse(&[AUDIT_SUB_COMMAND, AUDIT_SHOW_SUB_COMMAND, "--limit", limit]);
+        let error = show("ten").unwrap_err();
+        assert_eq!(error.kind, clap::ErrorKind::ValueValidation);
+        assert!(show("-1").is_err());
+        assert!(show("10").is_ok());
     }
 }
@@ -0,0 +1,150 @@
+use crate::planner::WritePlanner;
+use chrono::Utc;
+use libfiresale::api::{batch_get::Lookup, DatabaseContext, Write};
+use libfiresale::errors::{Error, Result};
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::PathBuf;
+
+/// Collection recording which migrations have been applied
+const MIGRATIONS_COLLECTION: &'static str = "_firesale_migrations";
+const MIGRATION_EXTENSION: &'static str = "json";
+
+/// A built-in operation performed by a migration
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Set {
+        path: String,
+        fields: Map<String, Value>,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+/// Contents of a migration file, applied in order on `up` and reverted by `down`
+#[derive(Debug, Deserialize)]
+struct MigrationFile {
+    #[serde(default)]
+    up: Vec<Operation>,
+    #[serde(default)]
+    down: Vec<Operation>,
+}
+
+struct Migration {
+    /// File stem, e.g. `0001_add_defaults`, used as the tracking document id
+    id: String,
+    file: MigrationFile,
+}
+
+impl Operation {
+    fn into_write(self, ctx: &DatabaseContext, database_name: &str) -> Write {
+        match self {
+            Operation::Set { path, fields } => ctx.update_write(database_name, &*path, &fields),
+            Operation::Delete { path } => ctx.delete_write(database_name, &*path),
+        }
+    }
+}
+
+/// Loads every migration in `dir`, ordered by file name
+fn load_migrations(dir: &str) -> Result<Vec<Migration>> {
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |ext| ext == MIGRATION_EXTENSION)
+        })
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| Error::InvalidInput {
+                    message: format!("invalid migration file name {}", path.display()),
+                })?
+                .to_string();
+            let file = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+            Ok(Migration { id, file })
+        })
+        .collect()
+}
+
+fn record_path(id: &str) -> String {
+    format!("{}/{}", MIGRATIONS_COLLECTION, id)
+}
+
+/// Applies every pending migration in `dir`, or with `down` reverts the most recently
+/// applied one. Each migration and its tracking record are committed atomically.
+pub fn run(ctx: &DatabaseContext, planner: &WritePlanner, dir: &str, down: bool) -> Result<()> {
+    let database_name = &*planner.database_name;
+    let migrations = load_migrations(dir)?;
+    let records = migrations
+        .iter()
+        .map(|migration| record_path(&*migration.id))
+        .collect::<Vec<String>>();
+    let lookups = ctx.batch_get_documents(records, database_name)?;
+    let applied = lookups
+        .iter()
+        .map(|lookup| match lookup {
+            Lookup::Found(_) => true,
+            Lookup::Missing(_) => false,
+        })
+        .collect::<Vec<bool>>();
+    if down {
+        let last = migrations
+            .into_iter()
+            .zip(applied)
+            .filter(|(_, applied)| *applied)
+            .map(|(migration, _)| migration)
+            .next_back();
+        let migration = match last {
+            Some(migration) => migration,
+            None => {
+                println!("no applied migrations to revert");
+                return Ok(());
+            }
+        };
+        let mut writes = migration
+            .file
+            .down
+            .into_iter()
+            .map(|operation| operation.into_write(ctx, database_name))
+            .collect::<Vec<Write>>();
+        writes.push(ctx.delete_write(database_name, &*record_path(&*migration.id)));
+        planner.apply(ctx, "migrate down", writes, None)?;
+        println!("reverted {}", migration.id);
+        return Ok(());
+    }
+    let pending = migrations
+        .into_iter()
+        .zip(applied)
+        .filter(|(_, applied)| !*applied)
+        .map(|(migration, _)| migration)
+        .collect::<Vec<Migration>>();
+    if pending.is_empty() {
+        println!("no pending migrations");
+    }
+    for migration in pending {
+        let mut writes = migration
+            .file
+            .up
+            .into_iter()
+            .map(|operation| operation.into_write(ctx, database_name))
+            .collect::<Vec<Write>>();
+        let mut record = Map::new();
+        record.insert(
+            "applied_at".to_string(),
+            Value::from(Utc::now().to_rfc3339()),
+        );
+        writes.push(ctx.update_write(database_name, &*record_path(&*migration.id), &record));
+        planner.apply(ctx, "migrate up", writes, None)?;
+        println!("applied {}", migration.id);
+    }
+    Ok(())
+}
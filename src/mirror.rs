@@ -0,0 +1,366 @@
+use crate::dump::{read_documents, relative_path};
+use chrono::{DateTime, Utc};
+use libfiresale::api::{DatabaseContext, Document};
+use libfiresale::errors::{Error, Result};
+use libfiresale::format::{Formatter, Json};
+use libfiresale::prelude::{Condition, DocumentChange, FilterPlan, WatchTarget};
+use libfiresale::sink::{self, AtomicFile};
+use reqwest::Url;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Extension of the files holding each mirrored collection, one document per line
+const MIRROR_EXTENSION: &'static str = "ndjson";
+/// When each collection was last polled, changed or not
+const SYNCED_FILE: &'static str = "synced.json";
+/// Requests answered at the same time; more connections wait to be accepted
+const SERVER_WORKERS: usize = 8;
+/// Longest a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Documents of a collection by their path relative to the database
+type Collection = BTreeMap<String, Document>;
+
+/// Every mirrored collection, shared between the refresh loop and the server
+type Mirror = Arc<RwLock<BTreeMap<String, Collection>>>;
+
+/// Keeps a local copy of `collections` in `dir`, polling them on one change stream
+/// every `interval` and rewriting the file of each collection that changed. Copies
+/// left by an earlier run are loaded first, so they are served right away, and seed
+/// the stream, so only what changed since is read again. With `updated_field`, polls
+/// only read the documents written since the previous one.
+///
+/// With `serve`, read-only queries are answered from the copy over HTTP on that
+/// address: `GET /` lists the collections, `GET /<collection>` its documents as a JSON
+/// array, with `where` conditions as in `get --where` and a `limit`, and
+/// `GET /<collection>/<id>` a single document. A few requests are answered at a time,
+/// and further connections wait to be accepted.
+pub fn mirror(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collections: &[String],
+    dir: &str,
+    interval: Duration,
+    serve: Option<&str>,
+    updated_field: Option<&str>,
+) -> Result<()> {
+    let dir = Path::new(dir);
+    std::fs::create_dir_all(dir)?;
+    let collections = collections
+        .iter()
+        .map(|collection| collection.trim_matches('/').to_string())
+        .collect::<Vec<String>>();
+    let mut loaded = BTreeMap::new();
+    for collection in &collections {
+        loaded.insert(collection.clone(), load(&*file(dir, collection))?);
+    }
+    // the copy holds every document already, the stream need not cache them too
+    let mut stream = ctx.changes(database_name).cache_size(0);
+    for collection in &collections {
+        let mut target = WatchTarget::collection(collection, FilterPlan::client_only(Vec::new()))?;
+        if let Some(field) = updated_field {
+            target = target.with_updated_field(field);
+        }
+        stream = stream.target(target);
+    }
+    for (collection, documents) in &loaded {
+        stream = stream.seed(collection, documents.values().cloned());
+    }
+    let mut synced = read_synced(dir)?;
+    let mirror: Mirror = Arc::new(RwLock::new(loaded));
+    if let Some(address) = serve {
+        let listener = TcpListener::bind(address)?;
+        println!("serving {} on http://{}", dir.display(), address);
+        serve_on(listener, mirror.clone());
+    }
+    loop {
+        let changes = stream.poll()?;
+        let now = Utc::now();
+        for collection in &collections {
+            synced.insert(collection.clone(), now);
+        }
+        let mut changed = BTreeMap::new();
+        {
+            let mut mirror = mirror.write().unwrap_or_else(|e| e.into_inner());
+            for (collection, change) in changes {
+                let documents = mirror.entry(collection.clone()).or_default();
+                let path = relative_path(change.name());
+                match change {
+                    DocumentChange::Added(after) | DocumentChange::Modified { after, .. } => {
+                        documents.insert(path, after);
+                    }
+                    DocumentChange::Removed { .. } => {
+                        documents.remove(&path);
+                    }
+                }
+                *changed.entry(collection).or_insert(0) += 1;
+            }
+        }
+        let mirror = mirror.read().unwrap_or_else(|e| e.into_inner());
+        for (collection, count) in changed {
+            let documents = &mirror[&collection];
+            save(&*file(dir, &*collection), documents)?;
+            println!(
+                "{}: {} document(s), {} changed",
+                collection,
+                documents.len(),
+                count
+            );
+        }
+        drop(mirror);
+        sink::write_json(dir.join(SYNCED_FILE), &synced)?;
+        thread::sleep(interval);
+    }
+}
+
+// Answers requests on `listener` from a fixed number of threads. Connections past
+// those queued for them wait in the listen backlog rather than get a thread each.
+fn serve_on(listener: TcpListener, mirror: Mirror) {
+    let (send, receive) = mpsc::sync_channel::<TcpStream>(SERVER_WORKERS);
+    let receive = Arc::new(Mutex::new(receive));
+    for _ in 0..SERVER_WORKERS {
+        let receive = receive.clone();
+        let mirror = mirror.clone();
+        thread::spawn(move || answer_queued(&receive, &mirror));
+    }
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+            if send.send(stream).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn answer_queued(receive: &Mutex<Receiver<TcpStream>>, mirror: &Mirror) {
+    loop {
+        let next = receive.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let stream = match next {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        // a client hanging up or too slow to ask is its own problem
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT)).ok();
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT)).ok();
+        respond(stream, mirror).ok();
+    }
+}
+
+// `users` is kept in `users.ndjson`, `users/alice/orders` in `users.alice.orders.ndjson`
+fn file(dir: &Path, collection: &str) -> PathBuf {
+    dir.join(format!(
+        "{}.{}",
+        collection.replace('/', "."),
+        MIRROR_EXTENSION
+    ))
+}
+
+fn read_synced(dir: &Path) -> Result<BTreeMap<String, DateTime<Utc>>> {
+    match std::fs::read_to_string(dir.join(SYNCED_FILE)) {
+        Ok(contents) => Ok(serde_json::from_str(&*contents)?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn load(path: &Path) -> Result<Collection> {
+    if !path.exists() {
+        return Ok(Collection::new());
+    }
+    read_documents(path)?
+        .map(|document| document.map(|document| (relative_path(document.name()), document)))
+        .collect()
+}
+
+fn save(path: &Path, collection: &Collection) -> Result<()> {
+    let mut out = BufWriter::new(AtomicFile::create(path)?);
+    for document in collection.values() {
+        serde_json::to_writer(&mut out, document)?;
+        out.write_all(b"\n")?;
+    }
+    out.into_inner().map_err(std::io::Error::from)?.commit()?;
+    Ok(())
+}
+
+// Answers a single request, closing the connection after the response
+fn respond(stream: TcpStream, mirror: &Mirror) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are of no use, but the client expects them read
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+    let parts = request_line.split_whitespace().collect::<Vec<&str>>();
+    let (status, body) = match &parts[..] {
+        ["GET", target, _] | ["GET", target] => match answer(target, mirror) {
+            Ok(Some(body)) => ("200 OK", body),
+            Ok(None) => ("404 Not Found", error_body("not mirrored")),
+            Err(e) => ("400 Bad Request", error_body(&*e.to_string())),
+        },
+        _ => (
+            "405 Method Not Allowed",
+            error_body("the mirror only answers GET requests"),
+        ),
+    };
+    let mut out = BufWriter::new(stream);
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    out.write_all(&body)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn error_body(message: &str) -> Vec<u8> {
+    json!({ "error": message }).to_string().into_bytes()
+}
+
+// The JSON body for `target`, `None` when there is nothing there
+fn answer(target: &str, mirror: &Mirror) -> Result<Option<Vec<u8>>> {
+    let url =
+        Url::parse(&*format!("http://mirror{}", target)).map_err(|e| Error::InvalidInput {
+            message: format!("invalid request target {}: {}", target, e),
+        })?;
+    let path = url.path().trim_matches('/').to_string();
+    let mirror = mirror.read().unwrap_or_else(|e| e.into_inner());
+    let mut body = Vec::new();
+    if path.is_empty() {
+        let counts = mirror
+            .iter()
+            .map(|(collection, documents)| (collection.clone(), json!(documents.len())))
+            .collect::<serde_json::Map<String, serde_json::Value>>();
+        serde_json::to_writer_pretty(&mut body, &counts)?;
+        return Ok(Some(body));
+    }
+    if let Some(documents) = mirror.get(&path) {
+        let mut conditions = Vec::new();
+        let mut limit = None;
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "where" => conditions.push(value.parse::<Condition>()?),
+                "limit" => {
+                    limit = Some(value.parse::<usize>().map_err(|_| Error::InvalidInput {
+                        message: format!("invalid limit {}", value),
+                    })?)
+                }
+                _ => {}
+            }
+        }
+        let plan = FilterPlan::client_only(conditions);
+        let page = documents
+            .values()
+            .filter(|document| plan.matches(document))
+            .take(limit.unwrap_or(usize::max_value()))
+            .cloned()
+            .collect::<Vec<Document>>();
+        Json.page(&mut body, &page)?;
+        return Ok(Some(body));
+    }
+    // otherwise a document of a mirrored collection
+    let parent = &path[..path.rfind('/').unwrap_or(0)];
+    match mirror
+        .get(parent)
+        .and_then(|documents| documents.get(&path))
+    {
+        Some(document) => {
+            Json.document(&mut body, document)?;
+            Ok(Some(body))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Which copy read commands consult, picked with `--prefer`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prefer {
+    /// Always Firestore
+    Live,
+    /// Only the mirror, failing for collections it does not hold
+    Mirror,
+    /// The mirror, and Firestore for what it does not hold
+    MirrorThenLive,
+}
+
+impl Prefer {
+    pub fn parse(prefer: &str) -> Result<Prefer> {
+        match prefer {
+            "live" => Ok(Prefer::Live),
+            "mirror" => Ok(Prefer::Mirror),
+            "mirror-then-live" => Ok(Prefer::MirrorThenLive),
+            _ => Err(Error::InvalidInput {
+                message: format!(
+                    "unknown preference {:?}, expected live, mirror or mirror-then-live",
+                    prefer
+                ),
+            }),
+        }
+    }
+}
+
+/// A directory kept by `mirror`, read by `get` instead of or before Firestore
+#[derive(Debug, Clone)]
+pub struct MirrorSource {
+    pub dir: PathBuf,
+    pub prefer: Prefer,
+}
+
+impl MirrorSource {
+    /// The documents of `collection`, `None` when the mirror does not hold it and
+    /// Firestore may be asked instead. Says on stderr how old the copy is.
+    pub fn collection(&self, collection: &str) -> Result<Option<Vec<Document>>> {
+        let collection = collection.trim_matches('/');
+        let path = file(&self.dir, collection);
+        let synced = read_synced(&self.dir)?.get(collection).cloned();
+        let synced = match synced {
+            Some(synced) if path.exists() => synced,
+            _ if self.prefer == Prefer::Mirror => {
+                return Err(Error::InvalidInput {
+                    message: format!("{} is not mirrored in {}", collection, self.dir.display()),
+                })
+            }
+            _ => return Ok(None),
+        };
+        let documents = load(&*path)?.into_iter().map(|(_, document)| document);
+        let age = Utc::now().signed_duration_since(synced);
+        eprintln!(
+            "from mirror {}, synced {} ({}s ago)",
+            self.dir.display(),
+            synced.to_rfc3339(),
+            age.num_seconds().max(0)
+        );
+        Ok(Some(documents.collect()))
+    }
+
+    /// The document at `path`, `None` when Firestore may be asked instead
+    pub fn document(&self, path: &str) -> Result<Option<Document>> {
+        let path = path.trim_matches('/');
+        let collection = &path[..path.rfind('/').unwrap_or(0)];
+        let documents = match self.collection(collection)? {
+            Some(documents) => documents,
+            None => return Ok(None),
+        };
+        let document = documents
+            .into_iter()
+            .find(|document| relative_path(document.name()) == path);
+        match document {
+            Some(document) => Ok(Some(document)),
+            // the copy may predate the document
+            None if self.prefer == Prefer::MirrorThenLive => Ok(None),
+            None => Err(Error::InvalidInput {
+                message: format!("{} is not in the mirror", path),
+            }),
+        }
+    }
+}
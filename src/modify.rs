@@ -0,0 +1,54 @@
+use crate::planner::WritePlanner;
+use libfiresale::api::DatabaseContext;
+use libfiresale::errors::{Error, Result};
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Rewrites the fields of the document at `path` with a jq filter, retrying from a
+/// fresh read when the document is updated concurrently. Needs `jq` on the PATH.
+pub fn modify(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    path: &str,
+    filter: &str,
+    attempts: u32,
+) -> Result<()> {
+    if planner.modify(ctx, path, attempts, |fields| jq(filter, fields))? {
+        if !planner.dry_run {
+            println!("modified {}", path);
+        }
+    } else {
+        println!("{} unchanged", path);
+    }
+    Ok(())
+}
+
+// Runs `jq` on the fields as a JSON object, which it has to turn into another object
+fn jq(filter: &str, fields: Map<String, Value>) -> Result<Map<String, Value>> {
+    let mut child = Command::new("jq")
+        .arg("--compact-output")
+        .arg(filter)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| Error::InvalidInput {
+            message: format!("cannot run jq: {}", e),
+        })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(Value::Object(fields).to_string().as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::InvalidInput {
+            message: format!("jq filter {} failed with {}", filter, output.status),
+        });
+    }
+    match serde_json::from_slice(&*output.stdout)? {
+        Value::Object(fields) => Ok(fields),
+        other => Err(Error::InvalidInput {
+            message: format!("jq filter has to produce an object, got {}", other),
+        }),
+    }
+}
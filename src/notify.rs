@@ -0,0 +1,74 @@
+use chrono::{DateTime, Utc};
+use libfiresale::errors::Result;
+use serde_derive::Serialize;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// What is sent when a long operation ends, successful or not
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub operation: String,
+    pub project_id: String,
+    pub database: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub elapsed_seconds: i64,
+}
+
+/// Where the end of a long operation is reported, from `--notify-url` and `--notify-cmd`
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    /// Receives the summary as a JSON POST
+    pub url: Option<String>,
+    /// Run by the shell with the summary as JSON on stdin
+    pub command: Option<String>,
+}
+
+impl Notifier {
+    pub fn is_enabled(&self) -> bool {
+        self.url.is_some() || self.command.is_some()
+    }
+
+    /// Sends `summary` everywhere asked. The operation is over, so failing to tell
+    /// anyone about it is only a warning.
+    pub fn notify(&self, summary: &Summary) {
+        if let Some(url) = &self.url {
+            let sent = reqwest::Client::new().post(&**url).json(summary).send();
+            if let Err(e) = sent.and_then(|response| response.error_for_status()) {
+                eprintln!("warning: failed to notify {}: {}", url, e);
+            }
+        }
+        if let Some(command) = &self.command {
+            if let Err(e) = run(command, summary) {
+                eprintln!("warning: failed to run {}: {}", command, e);
+            }
+        }
+    }
+}
+
+// The outcome is also in the environment, for commands that do not read stdin
+fn run(command: &str, summary: &Summary) -> Result<()> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("FIRESALE_OPERATION", &*summary.operation)
+        .env("FIRESALE_OK", if summary.ok { "true" } else { "false" })
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a command ignoring its input may exit before reading it
+        stdin.write_all(&serde_json::to_vec(summary)?).ok();
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        eprintln!("warning: {} exited with {}", command, status);
+    }
+    Ok(())
+}
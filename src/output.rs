@@ -0,0 +1,197 @@
+use libfiresale::api::Document;
+use libfiresale::errors::Result;
+use libfiresale::format::{Formatter, Formatters, Locale, Table, Truncation};
+use libfiresale::report::{Finding, Report, Severity};
+use std::fmt::{self, Display};
+use std::io;
+use std::sync::Arc;
+
+const TEXT: &'static str = "text";
+const TABLE: &'static str = "table";
+/// Set to anything but an empty string, turns color off, see https://no-color.org
+const NO_COLOR_KEY: &'static str = "NO_COLOR";
+
+/// What the terminal receiving stdout can show. Output decorates itself only through
+/// this, so redirected output, `TERM=dumb`, `NO_COLOR` and `--plain` all get plain text.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Terminal {
+    /// ANSI colors
+    pub color: bool,
+    /// Characters beyond ASCII, such as block elements for bars
+    pub unicode: bool,
+}
+
+/// How a piece of text is highlighted, when the terminal allows it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Added,
+    Removed,
+    Changed,
+    Error,
+    Warning,
+}
+
+impl Terminal {
+    /// Plain text only
+    pub fn plain() -> Terminal {
+        Terminal::default()
+    }
+
+    /// The capabilities of stdout, none at all when `plain`
+    pub fn detect(plain: bool) -> Terminal {
+        use std::env;
+        let dumb = env::var("TERM").map_or(false, |term| term == "dumb");
+        if plain || dumb || !atty::is(atty::Stream::Stdout) {
+            return Terminal::plain();
+        }
+        let no_color = env::var_os(NO_COLOR_KEY).map_or(false, |value| !value.is_empty());
+        Terminal {
+            color: !no_color,
+            unicode: true,
+        }
+    }
+
+    /// `text` in the color of `style`, or as is
+    pub fn paint<D: Display>(&self, style: Style, text: D) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        let code = match style {
+            Style::Added => "32",
+            Style::Removed | Style::Error => "31",
+            Style::Changed | Style::Warning => "33",
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+
+    /// A bar `length` cells long
+    pub fn bar(&self, length: usize) -> String {
+        let cell = if self.unicode { "\u{2588}" } else { "#" };
+        cell.repeat(length)
+    }
+}
+
+/// The formatter picked with `--output`
+#[derive(Clone)]
+pub struct OutputFormat {
+    name: String,
+    formatter: Arc<dyn Formatter>,
+    truncation: Truncation,
+    terminal: Terminal,
+}
+
+impl fmt::Debug for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("OutputFormat").field(&self.name).finish()
+    }
+}
+
+impl OutputFormat {
+    /// The formatter called `name`, the table rendering numbers and times for `locale`
+    pub fn parse(name: &str, locale: Locale) -> Result<OutputFormat> {
+        let mut formatters = Formatters::new();
+        formatters.register(TABLE, Table::new(locale));
+        Ok(OutputFormat {
+            name: name.to_string(),
+            formatter: formatters.get(name)?,
+            truncation: Truncation::default(),
+            terminal: Terminal::plain(),
+        })
+    }
+
+    /// Cuts large values of rendered documents short, reports are left whole
+    pub fn with_truncation(mut self, truncation: Truncation) -> OutputFormat {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Decorates text output as far as `terminal` allows, other formats stay plain
+    pub fn with_terminal(mut self, terminal: Terminal) -> OutputFormat {
+        self.terminal = terminal;
+        self
+    }
+
+    /// What text output may use, always plain for formats read by programs
+    pub fn terminal(&self) -> Terminal {
+        if self.is_human() {
+            self.terminal
+        } else {
+            Terminal::plain()
+        }
+    }
+
+    /// Whether this format is meant to be read by people rather than programs
+    pub fn is_human(&self) -> bool {
+        self.name == TEXT || self.name == TABLE
+    }
+
+    /// Text keeps each command's own wording rather than rendering its report
+    pub fn is_text(&self) -> bool {
+        self.name == TEXT
+    }
+
+    pub fn document(&self, document: &Document) -> Result<()> {
+        let stdout = io::stdout();
+        let document = self.truncation.document(document);
+        Ok(self.formatter.document(&mut stdout.lock(), &document)?)
+    }
+
+    pub fn page(&self, documents: &[Document]) -> Result<()> {
+        let stdout = io::stdout();
+        let documents = documents
+            .iter()
+            .map(|document| self.truncation.document(document))
+            .collect::<Vec<Document>>();
+        Ok(self.formatter.page(&mut stdout.lock(), &*documents)?)
+    }
+
+    pub fn report(&self, report: &Report) -> Result<()> {
+        let stdout = io::stdout();
+        Ok(self.formatter.report(&mut stdout.lock(), report)?)
+    }
+}
+
+/// Builds the `Report` of a command while printing its text output as it goes,
+/// unless the report itself is to be rendered in another format
+pub struct Reporter {
+    format: OutputFormat,
+    report: Report,
+}
+
+impl Reporter {
+    pub fn new(command: &str, format: &OutputFormat) -> Reporter {
+        Reporter {
+            format: format.clone(),
+            report: Report::new(command),
+        }
+    }
+
+    /// Prints a line of text output that has no place in the report
+    pub fn say<D: Display>(&self, line: D) {
+        if self.format.is_text() {
+            println!("{}", line);
+        }
+    }
+
+    /// Records `finding`, shown as `line` in text output
+    pub fn found<D: Display>(&mut self, finding: Finding, line: D) {
+        let style = match finding.severity {
+            Severity::Error => Style::Error,
+            Severity::Warning => Style::Warning,
+        };
+        self.say(self.format.terminal().paint(style, line));
+        self.report.add(finding);
+    }
+
+    pub fn count(&mut self, key: &str, count: u64) {
+        self.report.count(key, count);
+    }
+
+    /// Renders the report unless it was already printed as text, and returns it
+    pub fn finish(self) -> Result<Report> {
+        if !self.format.is_text() {
+            self.format.report(&self.report)?;
+        }
+        Ok(self.report)
+    }
+}
@@ -0,0 +1,624 @@
+use crate::planner::WritePlanner;
+use chrono::{DateTime, Utc};
+use libfiresale::api::{
+    field_path, json_to_wire, DatabaseContext, FirestoreFields, FirestoreType, Precondition,
+};
+use libfiresale::errors::{Error, Result};
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fs;
+
+/// One RFC 6902 operation
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// Either a literal JSON argument or `@file`, like `set --bytes-field`
+fn read_argument(argument: &str) -> Result<String> {
+    if argument.starts_with('@') {
+        return Ok(fs::read_to_string(&argument[1..])?);
+    }
+    Ok(argument.to_string())
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidInput { message }
+}
+
+/// Applies an RFC 7396 merge patch as a masked update: every leaf of the patch is
+/// written, `null` deletes the field and fields not in the patch are left alone.
+/// Nothing is read first, so a nested empty object cannot be merged into what is
+/// stored and sets the field to an empty map instead.
+pub fn merge_patch(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    document: &str,
+    patch: &str,
+) -> Result<()> {
+    let patch = match serde_json::from_str(&*read_argument(patch)?)? {
+        Value::Object(patch) => patch,
+        _ => return Err(invalid("a merge patch must be a JSON object".to_string())),
+    };
+    let mut fields = Map::new();
+    let mut mask = Vec::new();
+    collect_merge(&patch, &mut Vec::new(), &mut fields, &mut mask);
+    if mask.is_empty() {
+        return Err(invalid("the merge patch changes nothing".to_string()));
+    }
+    let write = ctx
+        .update_write(&*planner.database_name, document, &fields)
+        .with_update_mask(mask);
+    planner.apply(ctx, "patch", vec![write], None)?;
+    Ok(())
+}
+
+// Collects the plain values and field paths a merge patch writes
+fn collect_merge(
+    patch: &Map<String, Value>,
+    prefix: &mut Vec<String>,
+    fields: &mut Map<String, Value>,
+    mask: &mut Vec<String>,
+) {
+    for (key, value) in patch {
+        prefix.push(key.clone());
+        match value {
+            Value::Object(nested) if !nested.is_empty() => {
+                collect_merge(nested, prefix, fields, mask)
+            }
+            Value::Null => mask.push(mask_path(prefix)),
+            value => {
+                insert_plain(fields, prefix, value.clone());
+                mask.push(mask_path(prefix));
+            }
+        }
+        prefix.pop();
+    }
+}
+
+fn insert_plain(fields: &mut Map<String, Value>, path: &[String], value: Value) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if rest.is_empty() {
+        fields.insert(first.clone(), value);
+        return;
+    }
+    let child = fields
+        .entry(first.clone())
+        .or_insert_with(|| Value::Object(Map::new()));
+    if let Value::Object(child) = child {
+        insert_plain(child, rest, value);
+    }
+}
+
+fn mask_path(segments: &[String]) -> String {
+    field_path(&*segments.iter().map(|s| &**s).collect::<Vec<&str>>())
+}
+
+/// Applies an RFC 6902 patch. The document is read first so `test`, `move`, `copy`
+/// and array elements work on the stored values, then only the touched fields are
+/// written, guarded by the document's update time. Array elements cannot be masked,
+/// so a change inside an array rewrites the whole array.
+pub fn json_patch(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    document: &str,
+    patch: &str,
+) -> Result<()> {
+    let operations: Vec<Operation> = serde_json::from_str(&*read_argument(patch)?)?;
+    let database_name = &*planner.database_name;
+    let (fields, precondition) = match ctx.get_document(database_name, document, None) {
+        Ok(current) => (
+            serde_json::to_value(current.fields())?,
+            Precondition::UpdateTime(current.update_time()),
+        ),
+        Err(ref e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            (json!({}), Precondition::Exists(false))
+        }
+        Err(e) => return Err(e),
+    };
+    // the document behaves like a map value, so every path starts the same way
+    let mut root = json!({ "mapValue": { "fields": fields } });
+    let mut touched = Vec::new();
+    for operation in operations {
+        apply(&mut root, operation, &mut touched)?;
+    }
+    touched.sort();
+    touched.dedup();
+    // a field whose parent is also written is covered by the parent
+    let touched = touched
+        .iter()
+        .filter(|path| {
+            !touched
+                .iter()
+                .any(|other| other.len() < path.len() && path.starts_with(other))
+        })
+        .cloned()
+        .collect::<Vec<Vec<String>>>();
+    if touched.is_empty() {
+        return Err(invalid("the patch changes nothing".to_string()));
+    }
+    let mut written = json!({ "mapValue": { "fields": {} } });
+    for path in &touched {
+        if let Some(value) = get(&root, path) {
+            let value = value.clone();
+            insert_wire(&mut written, path, value);
+        }
+    }
+    let fields: FirestoreFields = serde_json::from_value(written["mapValue"]["fields"].take())?;
+    let write = ctx
+        .replace_write(database_name, document, &fields)
+        .with_update_mask(touched.iter().map(|path| mask_path(path)).collect())
+        .with_precondition(precondition);
+    planner.apply(ctx, "patch", vec![write], None)?;
+    Ok(())
+}
+
+// Decodes a JSON pointer into its reference tokens
+fn pointer(path: &str) -> Result<Vec<String>> {
+    if path.is_empty() {
+        return Err(invalid(
+            "patching the whole document is not supported, use set".to_string(),
+        ));
+    }
+    if !path.starts_with('/') {
+        return Err(invalid(format!("invalid JSON pointer {:?}", path)));
+    }
+    let tokens = path[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect::<Vec<String>>();
+    // valid in a JSON pointer, but Firestore has no empty field names
+    if tokens.iter().any(|token| token.is_empty()) {
+        return Err(invalid(format!(
+            "invalid JSON pointer {:?}, field names cannot be empty",
+            path
+        )));
+    }
+    Ok(tokens)
+}
+
+fn apply(root: &mut Value, operation: Operation, touched: &mut Vec<Vec<String>>) -> Result<()> {
+    match operation {
+        Operation::Add { path, value } => {
+            let path = pointer(&*path)?;
+            touched.push(written_path(root, &path));
+            add(root, &path, json_to_wire(&value))
+        }
+        Operation::Remove { path } => {
+            let path = pointer(&*path)?;
+            touched.push(written_path(root, &path));
+            remove(root, &path).map(|_| ())
+        }
+        Operation::Replace { path, value } => {
+            let path = pointer(&*path)?;
+            touched.push(written_path(root, &path));
+            remove(root, &path)?;
+            add(root, &path, json_to_wire(&value))
+        }
+        Operation::Move { from, path } => {
+            let (from, path) = (pointer(&*from)?, pointer(&*path)?);
+            touched.push(written_path(root, &from));
+            let value = remove(root, &from)?;
+            touched.push(written_path(root, &path));
+            add(root, &path, value)
+        }
+        Operation::Copy { from, path } => {
+            let (from, path) = (pointer(&*from)?, pointer(&*path)?);
+            let value = get(root, &from)
+                .cloned()
+                .ok_or_else(|| invalid(format!("nothing to copy at /{}", from.join("/"))))?;
+            touched.push(written_path(root, &path));
+            add(root, &path, value)
+        }
+        Operation::Test { path, value } => {
+            let path = pointer(&*path)?;
+            let stored = get(root, &path)
+                .and_then(|stored| serde_json::from_value::<FirestoreType>(stored.clone()).ok());
+            match stored {
+                Some(stored) if same_value(&stored, &value) => Ok(()),
+                _ => Err(Error::Conflict {
+                    message: format!("test failed at /{}", path.join("/")),
+                }),
+            }
+        }
+    }
+}
+
+/// Whether a stored value equals the plain JSON of a `test`. Typed values compare
+/// with the text `get` prints for them, timestamps as instants and numbers by value,
+/// as RFC 6902 asks.
+fn same_value(stored: &FirestoreType, expected: &Value) -> bool {
+    match (stored, expected) {
+        (FirestoreType::Integer(stored), Value::Number(expected)) => match expected.as_i64() {
+            Some(expected) => *stored == expected,
+            None => expected.as_f64() == Some(*stored as f64),
+        },
+        (FirestoreType::Double(stored), Value::Number(expected)) => {
+            expected.as_f64() == Some(stored.value())
+        }
+        (FirestoreType::Timestamp(stored), Value::String(expected)) => {
+            match DateTime::parse_from_rfc3339(&*expected) {
+                Ok(expected) => expected.with_timezone(&Utc) == stored.time(),
+                Err(_) => false,
+            }
+        }
+        (FirestoreType::GeoLocation(stored), Value::Object(expected)) => {
+            expected.len() == 2
+                && expected.get("latitude").and_then(Value::as_f64) == Some(stored.latitude)
+                && expected.get("longitude").and_then(Value::as_f64) == Some(stored.longitude)
+        }
+        (FirestoreType::Array(stored), Value::Array(expected)) => {
+            stored.values().len() == expected.len()
+                && stored
+                    .values()
+                    .iter()
+                    .zip(expected)
+                    .all(|(stored, expected)| same_value(stored, expected))
+        }
+        (FirestoreType::Map(stored), Value::Object(expected)) => {
+            stored.fields().len() == expected.len()
+                && stored.fields().iter().all(|(key, stored)| {
+                    expected
+                        .get(&**key)
+                        .map_or(false, |expected| same_value(stored, expected))
+                })
+        }
+        (stored, expected) => stored.to_json() == *expected,
+    }
+}
+
+/// The part of `path` that can go in an update mask: map keys up to the first array
+fn written_path(root: &Value, path: &[String]) -> Vec<String> {
+    let mut written = Vec::new();
+    let mut value = Some(root);
+    for token in path {
+        match value.and_then(|value| value.get("arrayValue")) {
+            Some(_) => break,
+            None => written.push(token.clone()),
+        }
+        value = value.and_then(|value| value.get("mapValue")?.get("fields")?.get(&**token));
+    }
+    written
+}
+
+fn get<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let (token, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Some(value),
+    };
+    let child = if let Some(map) = value.get("mapValue") {
+        map.get("fields")?.get(&**token)?
+    } else {
+        let values = value.get("arrayValue")?.get("values")?.as_array()?;
+        values.get(token.parse::<usize>().ok()?)?
+    };
+    get(child, rest)
+}
+
+// The map fields or array values directly holding the last token of a path
+enum Parent<'a> {
+    Map(&'a mut Map<String, Value>),
+    Array(&'a mut Vec<Value>),
+}
+
+fn parent<'a>(value: &'a mut Value, path: &[String]) -> Result<Parent<'a>> {
+    let missing = || invalid(format!("no value at /{}", path.join("/")));
+    let container = match value.as_object_mut() {
+        Some(object) if object.contains_key("mapValue") => {
+            let map = object
+                .get_mut("mapValue")
+                .and_then(Value::as_object_mut)
+                .ok_or_else(missing)?;
+            let fields = map.entry("fields").or_insert_with(|| json!({}));
+            Parent::Map(fields.as_object_mut().ok_or_else(missing)?)
+        }
+        Some(object) if object.contains_key("arrayValue") => {
+            let array = object
+                .get_mut("arrayValue")
+                .and_then(Value::as_object_mut)
+                .ok_or_else(missing)?;
+            let values = array.entry("values").or_insert_with(|| json!([]));
+            Parent::Array(values.as_array_mut().ok_or_else(missing)?)
+        }
+        _ => return Err(missing()),
+    };
+    if path.len() == 1 {
+        return Ok(container);
+    }
+    let child = match container {
+        Parent::Map(fields) => fields.get_mut(&*path[0]),
+        Parent::Array(values) => path[0]
+            .parse::<usize>()
+            .ok()
+            .and_then(move |index| values.get_mut(index)),
+    };
+    match child {
+        Some(child) => parent(child, &path[1..]),
+        None => Err(missing()),
+    }
+}
+
+fn add(root: &mut Value, path: &[String], value: Value) -> Result<()> {
+    let token = &*path[path.len() - 1];
+    match parent(root, path)? {
+        Parent::Map(fields) => {
+            fields.insert(token.to_string(), value);
+        }
+        Parent::Array(values) if token == "-" => values.push(value),
+        Parent::Array(values) => match token.parse::<usize>() {
+            Ok(index) if index <= values.len() => values.insert(index, value),
+            _ => return Err(invalid(format!("invalid array index {}", token))),
+        },
+    }
+    Ok(())
+}
+
+fn remove(root: &mut Value, path: &[String]) -> Result<Value> {
+    let token = &*path[path.len() - 1];
+    let removed = match parent(root, path)? {
+        Parent::Map(fields) => fields.remove(token),
+        Parent::Array(values) => match token.parse::<usize>() {
+            Ok(index) if index < values.len() => Some(values.remove(index)),
+            _ => None,
+        },
+    };
+    removed.ok_or_else(|| invalid(format!("no value at /{}", path.join("/"))))
+}
+
+// Places a wire value at a map path, creating the enclosing maps
+fn insert_wire(value: &mut Value, path: &[String], new: Value) {
+    let fields = &mut value["mapValue"]["fields"];
+    if !fields.is_object() {
+        *fields = json!({});
+    }
+    if path.len() == 1 {
+        fields[&*path[0]] = new;
+        return;
+    }
+    let child = &mut fields[&*path[0]];
+    if child.get("mapValue").is_none() {
+        *child = json!({ "mapValue": { "fields": {} } });
+    }
+    insert_wire(child, &path[1..], new);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A stored document with a value of every kind the tests touch
+    fn document() -> Value {
+        json!({ "mapValue": { "fields": {
+            "name": { "stringValue": "Ada" },
+            "age": { "integerValue": "36" },
+            "score": { "doubleValue": 1.0 },
+            "born": { "timestampValue": "1815-12-10T00:00:00Z" },
+            "father": { "referenceValue": "projects/p/databases/(default)/documents/users/byron" },
+            "photo": { "bytesValue": "AAEC" },
+            "home": { "geoPointValue": { "latitude": 51.5, "longitude": -0.12 } },
+            "settings": { "mapValue": {} },
+            "address": { "mapValue": { "fields": { "city": { "stringValue": "London" } } } },
+            "tags": { "arrayValue": { "values": [
+                { "stringValue": "math" },
+                { "stringValue": "poetry" }
+            ] } }
+        } } })
+    }
+
+    fn run(root: &mut Value, operations: Value) -> Result<Vec<Vec<String>>> {
+        let operations: Vec<Operation> = serde_json::from_value(operations).unwrap();
+        let mut touched = Vec::new();
+        for operation in operations {
+            apply(root, operation, &mut touched)?;
+        }
+        Ok(touched)
+    }
+
+    fn at(root: &Value, path: &str) -> Option<Value> {
+        get(root, &*pointer(path).unwrap()).cloned()
+    }
+
+    fn paths(paths: &[&str]) -> Vec<Vec<String>> {
+        paths
+            .iter()
+            .map(|path| path.split('.').map(String::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn add_sets_fields_and_inserts_into_arrays() {
+        let mut root = document();
+        let touched = run(
+            &mut root,
+            json!([
+                { "op": "add", "path": "/email", "value": "ada@example.com" },
+                { "op": "add", "path": "/address/zip", "value": "W1" },
+                { "op": "add", "path": "/tags/1", "value": "engines" },
+                { "op": "add", "path": "/tags/-", "value": "music" }
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            at(&root, "/email"),
+            Some(json!({ "stringValue": "ada@example.com" }))
+        );
+        assert_eq!(
+            at(&root, "/address/zip"),
+            Some(json!({ "stringValue": "W1" }))
+        );
+        let tags = (0..4)
+            .map(|index| at(&root, &*format!("/tags/{}", index)).unwrap()["stringValue"].clone())
+            .collect::<Vec<Value>>();
+        assert_eq!(tags, vec!["math", "engines", "poetry", "music"]);
+        // array elements cannot be masked, so the whole array is written
+        assert_eq!(touched, paths(&["email", "address.zip", "tags", "tags"]));
+    }
+
+    #[test]
+    fn add_rejects_indexes_past_the_end() {
+        let mut root = document();
+        let patch = json!([{ "op": "add", "path": "/tags/3", "value": "x" }]);
+        assert!(run(&mut root, patch).is_err());
+        let patch = json!([{ "op": "add", "path": "/tags/x", "value": "x" }]);
+        assert!(run(&mut root, patch).is_err());
+    }
+
+    #[test]
+    fn remove_deletes_fields_and_array_elements() {
+        let mut root = document();
+        let touched = run(
+            &mut root,
+            json!([
+                { "op": "remove", "path": "/age" },
+                { "op": "remove", "path": "/tags/0" }
+            ]),
+        )
+        .unwrap();
+        assert_eq!(at(&root, "/age"), None);
+        assert_eq!(
+            at(&root, "/tags/0"),
+            Some(json!({ "stringValue": "poetry" }))
+        );
+        assert_eq!(at(&root, "/tags/1"), None);
+        assert_eq!(touched, paths(&["age", "tags"]));
+        let patch = json!([{ "op": "remove", "path": "/nothing" }]);
+        assert!(run(&mut root, patch).is_err());
+    }
+
+    #[test]
+    fn replace_needs_an_existing_value() {
+        let mut root = document();
+        run(
+            &mut root,
+            json!([{ "op": "replace", "path": "/address/city", "value": "Paris" }]),
+        )
+        .unwrap();
+        assert_eq!(
+            at(&root, "/address/city"),
+            Some(json!({ "stringValue": "Paris" }))
+        );
+        let patch = json!([{ "op": "replace", "path": "/nothing", "value": 1 }]);
+        assert!(run(&mut root, patch).is_err());
+    }
+
+    #[test]
+    fn move_and_copy_keep_typed_values() {
+        let mut root = document();
+        let touched = run(
+            &mut root,
+            json!([
+                { "op": "move", "from": "/born", "path": "/birth" },
+                { "op": "copy", "from": "/home", "path": "/address/location" }
+            ]),
+        )
+        .unwrap();
+        assert_eq!(at(&root, "/born"), None);
+        assert_eq!(
+            at(&root, "/birth"),
+            Some(json!({ "timestampValue": "1815-12-10T00:00:00Z" }))
+        );
+        assert_eq!(at(&root, "/address/location"), at(&root, "/home"));
+        assert_eq!(touched, paths(&["born", "birth", "address.location"]));
+        let patch = json!([{ "op": "copy", "from": "/nothing", "path": "/x" }]);
+        assert!(run(&mut root, patch).is_err());
+    }
+
+    #[test]
+    fn test_compares_typed_values_with_plain_json() {
+        let mut root = document();
+        run(
+            &mut root,
+            json!([
+                { "op": "test", "path": "/name", "value": "Ada" },
+                { "op": "test", "path": "/age", "value": 36 },
+                { "op": "test", "path": "/age", "value": 36.0 },
+                { "op": "test", "path": "/score", "value": 1 },
+                { "op": "test", "path": "/born", "value": "1815-12-10T00:00:00.000Z" },
+                { "op": "test", "path": "/born", "value": "1815-12-10T01:00:00+01:00" },
+                {
+                    "op": "test",
+                    "path": "/father",
+                    "value": "projects/p/databases/(default)/documents/users/byron"
+                },
+                { "op": "test", "path": "/photo", "value": "AAEC" },
+                { "op": "test", "path": "/home", "value": { "latitude": 51.5, "longitude": -0.12 } },
+                { "op": "test", "path": "/settings", "value": {} },
+                { "op": "test", "path": "/address", "value": { "city": "London" } },
+                { "op": "test", "path": "/tags", "value": ["math", "poetry"] },
+                { "op": "test", "path": "/tags/1", "value": "poetry" }
+            ]),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn failed_tests_conflict() {
+        for (path, value) in &[
+            ("/name", json!("Byron")),
+            ("/age", json!("36")),
+            ("/born", json!("1815-12-11T00:00:00Z")),
+            ("/settings", json!({ "theme": "dark" })),
+            ("/address", json!({})),
+            ("/tags", json!(["math"])),
+            ("/nothing", json!(null)),
+        ] {
+            let mut root = document();
+            let patch = json!([{ "op": "test", "path": path, "value": value }]);
+            match run(&mut root, patch) {
+                Err(Error::Conflict { .. }) => {}
+                other => panic!("test of {} against {} gave {:?}", path, value, other),
+            }
+        }
+    }
+
+    #[test]
+    fn pointers() {
+        assert_eq!(
+            pointer("/a~1b/c~0d").unwrap(),
+            vec!["a/b".to_string(), "c~d".to_string()]
+        );
+        for invalid in &["", "a", "/", "/a//b", "/a/"] {
+            assert!(pointer(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn merge_patches_write_leaves_and_delete_nulls() {
+        let patch = json!({
+            "name": "Ada",
+            "address": { "city": "Paris", "zip": null },
+            "settings": {},
+            "a.b": 1
+        });
+        let (mut fields, mut mask) = (Map::new(), Vec::new());
+        collect_merge(
+            patch.as_object().unwrap(),
+            &mut Vec::new(),
+            &mut fields,
+            &mut mask,
+        );
+        assert_eq!(
+            Value::Object(fields),
+            json!({
+                "name": "Ada",
+                "address": { "city": "Paris" },
+                "settings": {},
+                "a.b": 1
+            })
+        );
+        mask.sort();
+        assert_eq!(
+            mask,
+            vec!["`a.b`", "address.city", "address.zip", "name", "settings"]
+        );
+    }
+}
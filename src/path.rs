@@ -0,0 +1,595 @@
+//! Paths to documents, relative to a database root, fully qualified
+//! references to documents which may live in another project or database,
+//! and the resource names the REST API addresses.
+
+use crate::errors::{Error, Result};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A document's location within a database, e.g. `cars/abc` or `cars/abc/parts/wheel`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DocumentPath {
+    segments: Vec<String>,
+}
+
+/// Length of the ids Firestore generates for new documents
+const DOCUMENT_ID_LENGTH: usize = 20;
+
+/// Generates a random document id in the same style as Firestore's own,
+/// letting callers know a document's name before it is created
+pub fn generate_document_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(DOCUMENT_ID_LENGTH)
+        .collect()
+}
+
+impl DocumentPath {
+    /// Parses a slash separated path, which must alternate collection and document ids
+    pub fn parse(path: &str) -> Result<DocumentPath> {
+        let segments = path
+            .trim_matches('/')
+            .split('/')
+            .map(String::from)
+            .collect::<Vec<String>>();
+        if segments.iter().any(|segment| segment.is_empty()) || segments.len() % 2 != 0 {
+            return Err(Error::InvalidInput {
+                message: format!("{} is not a document path", path),
+            });
+        }
+        Ok(DocumentPath { segments })
+    }
+
+    /// Id of the collection directly containing this document
+    pub fn collection_id(&self) -> &str {
+        &*self.segments[self.segments.len() - 2]
+    }
+
+    pub fn document_id(&self) -> &str {
+        &*self.segments[self.segments.len() - 1]
+    }
+
+    /// The document owning this document's collection, if it is a subcollection
+    pub fn parent(&self) -> Option<DocumentPath> {
+        if self.segments.len() <= 2 {
+            return None;
+        }
+        Some(DocumentPath {
+            segments: self.segments[..self.segments.len() - 2].to_vec(),
+        })
+    }
+
+    /// The path of document `document_id` in the collection `collection_id` beneath this one
+    pub fn child(&self, collection_id: &str, document_id: &str) -> DocumentPath {
+        let mut segments = self.segments.clone();
+        segments.push(collection_id.to_string());
+        segments.push(document_id.to_string());
+        DocumentPath { segments }
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &*self.segments
+    }
+}
+
+impl fmt::Display for DocumentPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.segments.join("/"))
+    }
+}
+
+impl FromStr for DocumentPath {
+    type Err = Error;
+
+    fn from_str(path: &str) -> Result<DocumentPath> {
+        DocumentPath::parse(path)
+    }
+}
+
+/// A collection's location within a database, e.g. `cars` or `cars/abc/parts`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CollectionPath {
+    segments: Vec<String>,
+}
+
+impl CollectionPath {
+    /// Parses a slash separated path, which must alternate collection and document ids
+    /// and end with a collection id. Slashes around it are ignored.
+    pub fn parse(path: &str) -> Result<CollectionPath> {
+        let segments = path
+            .trim_matches('/')
+            .split('/')
+            .map(String::from)
+            .collect::<Vec<String>>();
+        if segments.iter().any(|segment| segment.is_empty()) || segments.len() % 2 != 1 {
+            return Err(Error::InvalidInput {
+                message: format!("{} is not a collection path", path),
+            });
+        }
+        Ok(CollectionPath { segments })
+    }
+
+    pub fn collection_id(&self) -> &str {
+        &*self.segments[self.segments.len() - 1]
+    }
+
+    /// The document owning this collection, if it is a subcollection
+    pub fn parent(&self) -> Option<DocumentPath> {
+        if self.segments.len() == 1 {
+            return None;
+        }
+        Some(DocumentPath {
+            segments: self.segments[..self.segments.len() - 1].to_vec(),
+        })
+    }
+
+    /// The path of document `document_id` in this collection
+    pub fn document(&self, document_id: &str) -> DocumentPath {
+        let mut segments = self.segments.clone();
+        segments.push(document_id.to_string());
+        DocumentPath { segments }
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &*self.segments
+    }
+}
+
+impl fmt::Display for CollectionPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.segments.join("/"))
+    }
+}
+
+impl FromStr for CollectionPath {
+    type Err = Error;
+
+    fn from_str(path: &str) -> Result<CollectionPath> {
+        CollectionPath::parse(path)
+    }
+}
+
+/// A `referenceValue`: a document in any project and database,
+/// formatted as projects/{project_id}/databases/{database_id}/documents/{path}
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DocumentReference {
+    pub project_id: String,
+    pub database_id: String,
+    pub path: DocumentPath,
+}
+
+impl DocumentReference {
+    pub fn new<S: Into<String>>(project_id: S, database_id: S, path: DocumentPath) -> Self {
+        DocumentReference {
+            project_id: project_id.into(),
+            database_id: database_id.into(),
+            path,
+        }
+    }
+
+    /// Parses a full resource name
+    pub fn parse(name: &str) -> Result<DocumentReference> {
+        let invalid = || Error::InvalidInput {
+            message: format!("{} is not a document resource name", name),
+        };
+        let mut parts = name.splitn(6, '/');
+        match (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) {
+            (
+                Some("projects"),
+                Some(project_id),
+                Some("databases"),
+                Some(database_id),
+                Some("documents"),
+                Some(path),
+            ) if !project_id.is_empty() && !database_id.is_empty() => Ok(DocumentReference::new(
+                project_id,
+                database_id,
+                DocumentPath::parse(path).map_err(|_| invalid())?,
+            )),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Whether this reference points into the given project and database
+    pub fn is_in(&self, project_id: &str, database_id: &str) -> bool {
+        self.project_id == project_id && self.database_id == database_id
+    }
+}
+
+impl fmt::Display for DocumentReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "projects/{}/databases/{}/documents/{}",
+            self.project_id, self.database_id, self.path
+        )
+    }
+}
+
+impl FromStr for DocumentReference {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<DocumentReference> {
+        DocumentReference::parse(name)
+    }
+}
+
+impl From<DocumentReference> for DocumentPath {
+    fn from(reference: DocumentReference) -> DocumentPath {
+        reference.path
+    }
+}
+
+impl serde::Serialize for DocumentReference {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&*self.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DocumentReference {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<DocumentReference, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let name = String::deserialize(deserializer)?;
+        DocumentReference::parse(&*name).map_err(D::Error::custom)
+    }
+}
+
+/// A fully qualified name of the Firestore resources the REST API addresses
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ResourceName {
+    /// projects/{project_id}/databases/{database_id}
+    Database {
+        project_id: String,
+        database_id: String,
+    },
+    /// projects/{project_id}/databases/{database_id}/documents, the parent of root
+    /// collections
+    Documents {
+        project_id: String,
+        database_id: String,
+    },
+    /// projects/{project_id}/databases/{database_id}/documents/{path}
+    Document(DocumentReference),
+}
+
+impl ResourceName {
+    pub fn database<S: Into<String>>(project_id: S, database_id: S) -> Result<ResourceName> {
+        let (project_id, database_id) = validate_ids(project_id.into(), database_id.into())?;
+        Ok(ResourceName::Database {
+            project_id,
+            database_id,
+        })
+    }
+
+    pub fn documents<S: Into<String>>(project_id: S, database_id: S) -> Result<ResourceName> {
+        let (project_id, database_id) = validate_ids(project_id.into(), database_id.into())?;
+        Ok(ResourceName::Documents {
+            project_id,
+            database_id,
+        })
+    }
+
+    pub fn document<S: Into<String>>(
+        project_id: S,
+        database_id: S,
+        path: DocumentPath,
+    ) -> Result<ResourceName> {
+        let (project_id, database_id) = validate_ids(project_id.into(), database_id.into())?;
+        Ok(ResourceName::Document(DocumentReference::new(
+            project_id,
+            database_id,
+            path,
+        )))
+    }
+
+    /// The parent whose collections a list or query reads: the document at `path`, or
+    /// the root of the database when there is none
+    pub fn parent<S: Into<String>>(
+        project_id: S,
+        database_id: S,
+        path: Option<&DocumentPath>,
+    ) -> Result<ResourceName> {
+        match path {
+            Some(path) => ResourceName::document(project_id, database_id, path.clone()),
+            None => ResourceName::documents(project_id, database_id),
+        }
+    }
+
+    /// Parses any of the three forms
+    pub fn parse(name: &str) -> Result<ResourceName> {
+        let invalid = || Error::InvalidInput {
+            message: format!("{} is not a Firestore resource name", name),
+        };
+        let parts = name.splitn(6, '/').collect::<Vec<&str>>();
+        let parsed = match &*parts {
+            ["projects", project_id, "databases", database_id] => {
+                ResourceName::database(*project_id, *database_id)
+            }
+            ["projects", project_id, "databases", database_id, "documents"] => {
+                ResourceName::documents(*project_id, *database_id)
+            }
+            ["projects", _, "databases", _, "documents", _] => {
+                DocumentReference::parse(name).map(ResourceName::Document)
+            }
+            _ => Err(invalid()),
+        };
+        parsed.map_err(|_| invalid())
+    }
+
+    pub fn project_id(&self) -> &str {
+        match self {
+            ResourceName::Database { project_id, .. }
+            | ResourceName::Documents { project_id, .. } => &*project_id,
+            ResourceName::Document(reference) => &*reference.project_id,
+        }
+    }
+
+    pub fn database_id(&self) -> &str {
+        match self {
+            ResourceName::Database { database_id, .. }
+            | ResourceName::Documents { database_id, .. } => &*database_id,
+            ResourceName::Document(reference) => &*reference.database_id,
+        }
+    }
+
+    /// The document path, for a document
+    pub fn path(&self) -> Option<&DocumentPath> {
+        match self {
+            ResourceName::Document(reference) => Some(&reference.path),
+            _ => None,
+        }
+    }
+}
+
+// Project and database ids are single, non empty segments of a resource name
+fn validate_ids(project_id: String, database_id: String) -> Result<(String, String)> {
+    for (kind, id) in &[("project", &project_id), ("database", &database_id)] {
+        if id.is_empty() || id.contains('/') {
+            return Err(Error::InvalidInput {
+                message: format!("{:?} is not a valid {} id", id, kind),
+            });
+        }
+    }
+    Ok((project_id, database_id))
+}
+
+impl fmt::Display for ResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceName::Database {
+                project_id,
+                database_id,
+            } => write!(f, "projects/{}/databases/{}", project_id, database_id),
+            ResourceName::Documents {
+                project_id,
+                database_id,
+            } => write!(
+                f,
+                "projects/{}/databases/{}/documents",
+                project_id, database_id
+            ),
+            ResourceName::Document(reference) => write!(f, "{}", reference),
+        }
+    }
+}
+
+impl FromStr for ResourceName {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<ResourceName> {
+        ResourceName::parse(name)
+    }
+}
+
+impl TryFrom<&str> for ResourceName {
+    type Error = Error;
+
+    fn try_from(name: &str) -> Result<ResourceName> {
+        ResourceName::parse(name)
+    }
+}
+
+impl From<DocumentReference> for ResourceName {
+    fn from(reference: DocumentReference) -> ResourceName {
+        ResourceName::Document(reference)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_paths() {
+        let root = CollectionPath::parse("users").unwrap();
+        assert_eq!(root.collection_id(), "users");
+        assert_eq!(root.parent(), None);
+        assert_eq!(root.document("ada").to_string(), "users/ada");
+        let nested = CollectionPath::parse("users/ada/orders").unwrap();
+        assert_eq!(nested.collection_id(), "orders");
+        assert_eq!(
+            nested.parent(),
+            Some(DocumentPath::parse("users/ada").unwrap())
+        );
+        assert_eq!(nested.to_string(), "users/ada/orders");
+    }
+
+    #[test]
+    fn collection_paths_ignore_surrounding_slashes() {
+        for path in &["users/", "/users", "/users/"] {
+            assert_eq!(CollectionPath::parse(path).unwrap().to_string(), "users");
+        }
+    }
+
+    #[test]
+    fn document_paths_are_not_collection_paths() {
+        for path in &["", "/", "users/ada", "users//orders", "users/ada/orders/1"] {
+            let error = CollectionPath::parse(path).unwrap_err().to_string();
+            assert!(
+                error.contains(&*format!("{} is not a collection path", path)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn document_references_round_trip() {
+        let name = "projects/p/databases/d/documents/a/b";
+        let reference = DocumentReference::parse(name).unwrap();
+        assert_eq!(reference.project_id, "p");
+        assert_eq!(reference.database_id, "d");
+        assert_eq!(reference.path, DocumentPath::parse("a/b").unwrap());
+        assert_eq!(reference.to_string(), name);
+        assert_eq!(name.parse::<DocumentReference>().unwrap(), reference);
+    }
+
+    #[test]
+    fn document_references_in_the_default_database() {
+        let name = "projects/p/databases/(default)/documents/a/b/c/d";
+        let reference = DocumentReference::parse(name).unwrap();
+        assert_eq!(reference.database_id, "(default)");
+        assert!(reference.is_in("p", "(default)"));
+        assert_eq!(
+            reference.path.parent(),
+            Some(DocumentPath::parse("a/b").unwrap())
+        );
+        assert_eq!(reference.to_string(), name);
+    }
+
+    #[test]
+    fn document_references_ignore_a_trailing_slash() {
+        assert_eq!(
+            DocumentReference::parse("projects/p/databases/d/documents/a/b/").unwrap(),
+            DocumentReference::parse("projects/p/databases/d/documents/a/b").unwrap()
+        );
+    }
+
+    #[test]
+    fn document_references_need_a_document_path() {
+        for name in &[
+            "projects/p/databases/d/documents/a",
+            "projects/p/databases/d/documents/a/b/c",
+            "projects/p/databases/d/documents/",
+            "projects/p/databases/d/documents",
+            "projects//databases/d/documents/a/b",
+            "projects/p/databases//documents/a/b",
+            "projects/p/documents/a/b",
+            "a/b",
+        ] {
+            let error = DocumentReference::parse(name).unwrap_err().to_string();
+            assert!(
+                error.contains(&*format!("{} is not a document resource name", name)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn document_references_know_their_database() {
+        let reference = DocumentReference::parse("projects/p/databases/d/documents/a/b").unwrap();
+        assert!(reference.is_in("p", "d"));
+        assert!(!reference.is_in("other", "d"));
+        assert!(!reference.is_in("p", "(default)"));
+    }
+
+    #[test]
+    fn document_references_compare_every_part() {
+        let path = DocumentPath::parse("a/b").unwrap();
+        let reference = DocumentReference::new("p", "d", path.clone());
+        assert_eq!(reference, DocumentReference::new("p", "d", path.clone()));
+        assert_ne!(reference, DocumentReference::new("q", "d", path.clone()));
+        assert_ne!(reference, DocumentReference::new("p", "e", path.clone()));
+        assert_ne!(
+            reference,
+            DocumentReference::new("p", "d", DocumentPath::parse("a/c").unwrap())
+        );
+        assert_eq!(DocumentPath::from(reference), path);
+    }
+
+    #[test]
+    fn resource_names_round_trip() {
+        for name in &[
+            "projects/p/databases/(default)",
+            "projects/p/databases/d/documents",
+            "projects/p/databases/d/documents/a/b",
+            "projects/p/databases/d/documents/a/b/c/d",
+        ] {
+            let parsed = ResourceName::try_from(*name).unwrap();
+            assert_eq!(parsed.to_string(), *name);
+            assert_eq!(parsed.project_id(), "p");
+            assert_eq!(name.parse::<ResourceName>().unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn resource_names_by_kind() {
+        assert_eq!(
+            ResourceName::try_from("projects/p/databases/d").unwrap(),
+            ResourceName::database("p", "d").unwrap()
+        );
+        assert_eq!(
+            ResourceName::try_from("projects/p/databases/d/documents").unwrap(),
+            ResourceName::parent("p", "d", None).unwrap()
+        );
+        let path = DocumentPath::parse("a/b").unwrap();
+        let document = ResourceName::try_from("projects/p/databases/d/documents/a/b").unwrap();
+        assert_eq!(document.path(), Some(&path));
+        assert_eq!(document.database_id(), "d");
+        assert_eq!(
+            document,
+            ResourceName::from(DocumentReference::new("p", "d", path.clone()))
+        );
+        assert_eq!(
+            document,
+            ResourceName::parent("p", "d", Some(&path)).unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_resource_names_are_rejected() {
+        for name in &[
+            "projects/p",
+            "projects/p/d/documents/a/b",
+            "projects/p/documents/a/b",
+            "projects//databases/d",
+            "projects//databases/d/documents/a/b",
+            "projects/p/databases/",
+            "projects/p/databases/d/docs",
+            "projects/p/databases/d/documents/a",
+            "databases/d/documents/a/b",
+            "",
+        ] {
+            let error = ResourceName::try_from(*name).unwrap_err().to_string();
+            assert!(
+                error.contains(&*format!("{} is not a Firestore resource name", name)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn resource_names_need_ids() {
+        assert!(ResourceName::database("", "d").is_err());
+        assert!(ResourceName::documents("p", "").is_err());
+        assert!(ResourceName::document("p/q", "d", DocumentPath::parse("a/b").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use crate::hist::nearest_rank;
+use libfiresale::api::DatabaseContext;
+use libfiresale::errors::{Error, Result};
+use std::env;
+use std::time::{Duration, Instant};
+
+/// Set by the Firebase tools to the emulator's host and port, e.g. `localhost:8080`
+const EMULATOR_HOST_KEY: &'static str = "FIRESTORE_EMULATOR_HOST";
+/// A document no one writes, read only to time the round trip
+const PING_DOCUMENT: &'static str = "firesale-ping/ping";
+const PERCENTILES: &[f64] = &[50.0, 90.0, 99.0];
+
+/// Times `count` token exchanges and `count` single document reads against Firestore,
+/// and as many requests to the emulator when `FIRESTORE_EMULATOR_HOST` is set, then
+/// prints the percentiles of each. The reads go to a document that does not exist, so
+/// the time is the round trip rather than the size of a document.
+pub fn ping(ctx: &DatabaseContext, database_name: &str, count: usize) -> Result<()> {
+    if count == 0 {
+        return Err(Error::InvalidInput {
+            message: "--count must be at least 1".to_string(),
+        });
+    }
+    print_latencies("auth", &*sample(count, || ctx.renew_token())?);
+    let read = || ctx.find_document(database_name, PING_DOCUMENT).map(|_| ());
+    // the first read also opens the connection, which later ones reuse
+    read()?;
+    print_latencies("read", &*sample(count, read)?);
+    if let Ok(host) = env::var(EMULATOR_HOST_KEY) {
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/", host);
+        let emulator = || -> Result<()> {
+            client.get(&*url).send()?;
+            Ok(())
+        };
+        print_latencies("emulator", &*sample(count, emulator)?);
+    }
+    Ok(())
+}
+
+// Runs `call` `count` times, returning the milliseconds each took in ascending order
+fn sample<F: FnMut() -> Result<()>>(count: usize, mut call: F) -> Result<Vec<f64>> {
+    let mut latencies = Vec::with_capacity(count);
+    for _ in 0..count {
+        let started = Instant::now();
+        call()?;
+        latencies.push(millis(started.elapsed()));
+    }
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(latencies)
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_micros()) / 1000.0
+}
+
+fn print_latencies(name: &str, sorted: &[f64]) {
+    let percentiles = PERCENTILES
+        .iter()
+        .map(|percentile| format!("p{} {:.1}ms", percentile, nearest_rank(sorted, *percentile)))
+        .collect::<Vec<String>>();
+    println!(
+        "{:<9}{} sample(s)  min {:.1}ms  {}  max {:.1}ms",
+        name,
+        sorted.len(),
+        sorted[0],
+        percentiles.join("  "),
+        sorted[sorted.len() - 1]
+    );
+}
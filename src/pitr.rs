@@ -0,0 +1,96 @@
+use chrono::{DateTime, Duration, Timelike, Utc};
+use libfiresale::api::filter::relative_time;
+use libfiresale::api::DatabaseContext;
+use libfiresale::errors::{Error, Result};
+use reqwest::StatusCode;
+
+/// How far back any time can be read
+const VERSION_RETENTION_MINUTES: i64 = 60;
+/// How far back whole minutes can be read with point-in-time recovery
+const PITR_RETENTION_DAYS: i64 = 7;
+
+/// Reads `--read-time`, an RFC 3339 time or one relative to now such as `-30m`, and
+/// checks that Firestore could serve it: not in the future, within the last hour, or
+/// a whole minute within the last seven days for databases with point-in-time recovery
+pub fn parse_read_time(read_time: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let time = match relative_time(read_time.trim(), now) {
+        Some(time) => time,
+        None => DateTime::parse_from_rfc3339(read_time.trim())
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|_| Error::InvalidInput {
+                message: format!(
+                    "invalid read time {:?}, expected an RFC 3339 time or e.g. -30m",
+                    read_time
+                ),
+            })?,
+    };
+    if time > now {
+        return Err(Error::InvalidInput {
+            message: format!("read time {} is in the future", time.to_rfc3339()),
+        });
+    }
+    if time < now - Duration::days(PITR_RETENTION_DAYS) {
+        return Err(Error::InvalidInput {
+            message: format!(
+                "read time {} is more than {} days ago, older versions are not kept",
+                time.to_rfc3339(),
+                PITR_RETENTION_DAYS
+            ),
+        });
+    }
+    let whole_minute = time.second() == 0 && time.nanosecond() == 0;
+    if time < now - Duration::minutes(VERSION_RETENTION_MINUTES) && !whole_minute {
+        let minute = time.with_nanosecond(0).and_then(|time| time.with_second(0));
+        return Err(Error::InvalidInput {
+            message: format!(
+                "read time {} is more than an hour ago, where only whole minutes can be read{}",
+                time.to_rfc3339(),
+                minute.map_or(String::new(), |minute| format!(
+                    ", e.g. {}",
+                    minute.to_rfc3339()
+                ))
+            ),
+        });
+    }
+    Ok(time)
+}
+
+/// Adds to `error`, from a read at `read_time`, how far back `database_name` can be
+/// read, when the server rejected the time rather than something else
+pub fn explain(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    read_time: DateTime<Utc>,
+    error: Error,
+) -> Error {
+    if error.status() != Some(StatusCode::BAD_REQUEST) {
+        return error;
+    }
+    // the database settings need more access than reading documents, so this is best effort
+    let database = match ctx.database_info(database_name) {
+        Ok(database) => database,
+        Err(_) => return error,
+    };
+    let earliest = match database.earliest_version_time {
+        Some(earliest) if read_time < earliest => earliest,
+        _ => return error,
+    };
+    let pitr = database
+        .point_in_time_recovery_enablement
+        .as_ref()
+        .map(|p| &**p)
+        == Some("POINT_IN_TIME_RECOVERY_ENABLED");
+    Error::InvalidInput {
+        message: format!(
+            "{}\nthe earliest time {} can be read at is {}{}",
+            error,
+            database_name,
+            earliest.to_rfc3339(),
+            if pitr {
+                ""
+            } else {
+                ", enable point-in-time recovery to keep seven days"
+            }
+        ),
+    }
+}
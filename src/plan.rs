@@ -0,0 +1,293 @@
+use crate::output::{OutputFormat, Reporter};
+use crate::planner::WritePlanner;
+use chrono::{DateTime, Utc};
+use libfiresale::api::{batch_get::Lookup, DatabaseContext, FirestoreFields, Precondition, Write};
+use libfiresale::canonical::{canonical_fields, canonical_json, checksum};
+use libfiresale::errors::{Error, Result};
+use libfiresale::report::{Finding, Report};
+use libfiresale::sink;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::BufReader;
+
+/// A change-set computed against live data, to be applied later with `apply`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub project_id: String,
+    pub database: String,
+    pub created_at: DateTime<Utc>,
+    pub changes: Vec<Change>,
+}
+
+/// One planned mutation. Updates and deletes remember the `updateTime` and content
+/// checksum they were planned against so `apply` can refuse to overwrite newer data.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Change {
+    Create {
+        path: String,
+        fields: Map<String, Value>,
+    },
+    Update {
+        path: String,
+        fields: Map<String, Value>,
+        diff: Vec<FieldDiff>,
+        update_time: DateTime<Utc>,
+        #[serde(default)]
+        checksum: String,
+    },
+    Delete {
+        path: String,
+        update_time: DateTime<Utc>,
+        #[serde(default)]
+        checksum: String,
+    },
+}
+
+/// A top level field that differs between live and desired state
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FieldDiff {
+    pub field: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<Value>,
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Create { path, .. } => path,
+            Change::Update { path, .. } => path,
+            Change::Delete { path, .. } => path,
+        }
+    }
+
+    /// Whether `fields`, last updated at `update_time`, still matches what was planned
+    fn matches(&self, update_time: DateTime<Utc>, fields: &FirestoreFields) -> bool {
+        match self {
+            Change::Create { .. } => false,
+            Change::Update {
+                update_time: planned,
+                checksum: planned_checksum,
+                ..
+            }
+            | Change::Delete {
+                update_time: planned,
+                checksum: planned_checksum,
+                ..
+            } => {
+                *planned == update_time || *planned_checksum == checksum(&canonical_fields(fields))
+            }
+        }
+    }
+
+    /// Moves the expected `updateTime` forward after a content-preserving touch
+    fn rebase(&mut self, live_update_time: DateTime<Utc>) {
+        match self {
+            Change::Create { .. } => {}
+            Change::Update { update_time, .. } | Change::Delete { update_time, .. } => {
+                *update_time = live_update_time
+            }
+        }
+    }
+
+    /// The write performing this change, guarded by a precondition so the
+    /// server rejects it if the document changed after planning
+    fn into_write(self, ctx: &DatabaseContext, database_name: &str) -> Write {
+        match self {
+            Change::Create { path, fields } => ctx
+                .update_write(database_name, &*path, &fields)
+                .with_precondition(Precondition::Exists(false)),
+            Change::Update {
+                path,
+                fields,
+                update_time,
+                ..
+            } => ctx
+                .update_write(database_name, &*path, &fields)
+                .with_precondition(Precondition::UpdateTime(update_time)),
+            Change::Delete {
+                path, update_time, ..
+            } => ctx
+                .delete_write(database_name, &*path)
+                .with_precondition(Precondition::UpdateTime(update_time)),
+        }
+    }
+}
+
+/// Compares fields in canonical form so formatting differences are not reported
+fn diff_fields(live: &FirestoreFields, desired: &Map<String, Value>) -> Vec<FieldDiff> {
+    let before = live.to_json();
+    let canonical_before = canonical_fields(live);
+    let canonical_after = canonical_json(desired);
+    let mut fields = before
+        .keys()
+        .chain(desired.keys())
+        .collect::<Vec<&String>>();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter(|field| canonical_before.get(*field) != canonical_after.get(*field))
+        .map(|field| FieldDiff {
+            field: field.clone(),
+            before: before.get(field).cloned(),
+            after: desired.get(field).cloned(),
+        })
+        .collect()
+}
+
+/// A document path and the fields it should have, `None` when it should not exist
+type Desired = (String, Option<Map<String, Value>>);
+
+/// Reads the desired state: an object mapping document paths to their fields,
+/// or to `null` for documents that should not exist
+fn read_desired(path: &str) -> Result<Vec<Desired>> {
+    let desired: Map<String, Value> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    desired
+        .into_iter()
+        .map(|(path, value)| match value {
+            Value::Object(fields) => Ok((path, Some(fields))),
+            Value::Null => Ok((path, None)),
+            _ => Err(Error::InvalidInput {
+                message: format!("{} must map to an object or null", path),
+            }),
+        })
+        .collect()
+}
+
+/// Computes the changes needed to turn live data into `desired_path` and writes them to
+/// `out_path`. Each change is also reported as a `create`, `update` or `delete` finding,
+/// updates with the changed fields as details.
+pub fn plan(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    desired_path: &str,
+    out_path: &str,
+    format: &OutputFormat,
+) -> Result<Report> {
+    let desired = read_desired(desired_path)?;
+    let paths = desired
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<String>>();
+    let lookups = ctx.batch_get_documents(paths, database_name)?;
+    let mut changes = Vec::new();
+    for ((path, fields), lookup) in desired.into_iter().zip(lookups) {
+        match (lookup, fields) {
+            (Lookup::Missing(_), Some(fields)) => changes.push(Change::Create { path, fields }),
+            (Lookup::Missing(_), None) => {}
+            (Lookup::Found(document), Some(fields)) => {
+                let diff = diff_fields(document.fields(), &fields);
+                if !diff.is_empty() {
+                    changes.push(Change::Update {
+                        path,
+                        fields,
+                        diff,
+                        update_time: document.update_time(),
+                        checksum: checksum(&canonical_fields(document.fields())),
+                    });
+                }
+            }
+            (Lookup::Found(document), None) => changes.push(Change::Delete {
+                path,
+                update_time: document.update_time(),
+                checksum: checksum(&canonical_fields(document.fields())),
+            }),
+        }
+    }
+    let plan = Plan {
+        project_id: ctx.project_id.clone(),
+        database: database_name.to_string(),
+        created_at: Utc::now(),
+        changes,
+    };
+    let mut reporter = Reporter::new("plan", format);
+    for change in &plan.changes {
+        match change {
+            Change::Create { path, .. } => {
+                let finding = Finding::new("create", &**path, "document will be created");
+                reporter.found(finding, format!("+ {}", path));
+                reporter.count("creates", 1);
+            }
+            Change::Update { path, diff, .. } => {
+                let mut line = format!("~ {}", path);
+                for field in diff {
+                    line.push_str(&*format!(
+                        "\n    {}: {:?} -> {:?}",
+                        field.field, field.before, field.after
+                    ));
+                }
+                let message = format!("{} field(s) will change", diff.len());
+                let finding = Finding::new("update", &**path, message)
+                    .with_details(serde_json::to_value(diff)?);
+                reporter.found(finding, line);
+                reporter.count("updates", 1);
+            }
+            Change::Delete { path, .. } => {
+                let finding = Finding::new("delete", &**path, "document will be deleted");
+                reporter.found(finding, format!("- {}", path));
+                reporter.count("deletes", 1);
+            }
+        }
+    }
+    sink::write_json(out_path, &plan)?;
+    reporter.say(format!(
+        "{} change(s) written to {}",
+        plan.changes.len(),
+        out_path
+    ));
+    reporter.finish()
+}
+
+/// Executes a plan produced by `plan`, refusing to run if any document drifted since
+pub fn apply(ctx: &DatabaseContext, planner: &WritePlanner, plan_path: &str) -> Result<()> {
+    let plan: Plan = serde_json::from_reader(BufReader::new(File::open(plan_path)?))?;
+    let database_name = &*planner.database_name;
+    if plan.project_id != ctx.project_id || plan.database != database_name {
+        return Err(Error::Conflict {
+            message: format!(
+                "plan targets {}/{} but running against {}/{}",
+                plan.project_id, plan.database, ctx.project_id, database_name
+            ),
+        });
+    }
+    let paths = plan
+        .changes
+        .iter()
+        .map(|change| change.path().to_string())
+        .collect::<Vec<String>>();
+    let lookups = ctx.batch_get_documents(paths, database_name)?;
+    let mut drifted = Vec::new();
+    let mut changes = plan.changes;
+    for (change, lookup) in changes.iter_mut().zip(lookups) {
+        match (&*change, lookup) {
+            (Change::Create { .. }, Lookup::Missing(_)) => {}
+            (_, Lookup::Found(ref document))
+                if change.matches(document.update_time(), document.fields()) =>
+            {
+                change.rebase(document.update_time())
+            }
+            _ => drifted.push(change.path().to_string()),
+        }
+    }
+    if !drifted.is_empty() {
+        return Err(Error::Conflict {
+            message: format!(
+                "live data changed since the plan was made: {}",
+                drifted.join(", ")
+            ),
+        });
+    }
+    let writes = changes
+        .into_iter()
+        .map(|change| change.into_write(ctx, database_name))
+        .collect::<Vec<Write>>();
+    let count = writes.len();
+    if planner.apply(ctx, "apply", writes, None)?.is_some() {
+        println!("applied {} change(s)", count);
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use crate::audit;
+use crate::progress::ProgressEvents;
+use libfiresale::api::{commit, DatabaseContext, Write};
+use libfiresale::errors::Result;
+use serde_json::{Map, Value};
+
+/// Every mutation made by the CLI is routed through here so that `--dry-run`
+/// and the audit log always agree on what would be, or was, written.
+#[derive(Clone)]
+pub struct WritePlanner {
+    pub database_name: String,
+    pub dry_run: bool,
+    /// Where long operations report how far they got
+    pub progress: ProgressEvents,
+}
+
+impl WritePlanner {
+    /// Commits `writes`, or only prints them when running with `--dry-run`.
+    /// A dry run rolls back `transaction` so no locks are left behind.
+    pub fn apply(
+        &self,
+        context: &DatabaseContext,
+        operation: &str,
+        writes: Vec<Write>,
+        transaction: Option<String>,
+    ) -> Result<Option<commit::Response>> {
+        let database_name = &*self.database_name;
+        if self.dry_run {
+            for write in &writes {
+                println!("[dry-run] {}", write);
+            }
+            if let Some(transaction) = transaction {
+                context.rollback(database_name, transaction)?;
+            }
+            return Ok(None);
+        }
+        let paths = writes.iter().map(Write::path).collect::<Vec<String>>();
+        let response = context.commit(database_name, writes, transaction)?;
+        if !paths.is_empty() {
+            audit::record(&audit::Entry::new(
+                &*context.project_id,
+                database_name,
+                operation,
+                paths,
+            ));
+        }
+        Ok(Some(response))
+    }
+
+    /// Commits `write` to learn whether the credentials may make it, without making
+    /// it: the write has to carry a precondition no document meets, so the commit is
+    /// refused past the permission check. Nothing is audited, as nothing is written.
+    /// A dry run only prints the probe and returns `false`; otherwise the refusal is
+    /// the error returned.
+    pub fn probe(&self, context: &DatabaseContext, write: Write) -> Result<bool> {
+        if self.dry_run {
+            println!("[dry-run] probe {}", write);
+            return Ok(false);
+        }
+        context.commit(&*self.database_name, vec![write], None)?;
+        Ok(true)
+    }
+
+    /// Read-modify-write of `document` with retries on conflict, see
+    /// `DatabaseContext::modify`. A dry run reads and modifies once and prints the
+    /// write instead. Returns whether anything changed.
+    pub fn modify<F>(
+        &self,
+        context: &DatabaseContext,
+        document: &str,
+        attempts: u32,
+        mut modify: F,
+    ) -> Result<bool>
+    where
+        F: FnMut(Map<String, Value>) -> Result<Map<String, Value>>,
+    {
+        let database_name = &*self.database_name;
+        if self.dry_run {
+            let base = context.find_document(database_name, document)?;
+            let fields = base
+                .as_ref()
+                .map_or_else(Map::new, |base| base.fields().to_json());
+            let modified = modify(fields)?;
+            return Ok(
+                match context.modify_write(database_name, document, base.as_ref(), &modified) {
+                    Some(write) => {
+                        println!("[dry-run] {}", write);
+                        true
+                    }
+                    None => false,
+                },
+            );
+        }
+        if context
+            .modify(database_name, document, attempts, modify)?
+            .is_none()
+        {
+            return Ok(false);
+        }
+        audit::record(&audit::Entry::new(
+            &*context.project_id,
+            database_name,
+            "modify",
+            vec![document.to_string()],
+        ));
+        Ok(true)
+    }
+
+    /// Creates a document with a generated id in `collection`, safe to retry.
+    /// Returns the new document's path, or `None` on a dry run.
+    pub fn add(
+        &self,
+        context: &DatabaseContext,
+        collection: &str,
+        fields: &Map<String, Value>,
+    ) -> Result<Option<String>> {
+        let database_name = &*self.database_name;
+        if self.dry_run {
+            println!(
+                "[dry-run] create {}/<generated id> {}",
+                collection,
+                Value::Object(fields.clone())
+            );
+            return Ok(None);
+        }
+        let path = context.add_document(database_name, collection, fields)?;
+        audit::record(&audit::Entry::new(
+            &*context.project_id,
+            database_name,
+            "add",
+            vec![path.clone()],
+        ));
+        Ok(Some(path))
+    }
+}
@@ -0,0 +1,105 @@
+use crate::errors::Result;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a request is sent again after a retryable failure, see
+/// `Error::is_retryable`. Set it on a context with `DatabaseContext::with_retry_policy`.
+///
+/// A commit whose response was lost is retried too, which is only safe for writes
+/// that are idempotent or carry a precondition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, 1 sends every request once
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every further one
+    pub initial_backoff: Duration,
+    /// The wait never grows past this
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::none()
+    }
+}
+
+impl RetryPolicy {
+    /// Every request is sent once and its failure returned as is
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+
+    /// Up to `max_attempts` attempts, waiting 100ms, 200ms, 400ms... in between
+    pub fn attempts(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..RetryPolicy::none()
+        }
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Wait after failed attempt number `attempt`, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Runs `call` until it succeeds, fails with an error that is not retryable, or
+    /// runs out of attempts
+    pub fn run<T, F: FnMut() -> Result<T>>(&self, mut call: F) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match call() {
+                Err(ref e) if attempt < self.max_attempts && e.is_retryable() => {
+                    thread::sleep(self.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Spaces out requests, or any other unit of work, so that everyone sharing the
+/// limit stays under a rate. Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    per_permit: Duration,
+    next: Arc<Mutex<Instant>>,
+}
+
+impl RateLimit {
+    /// At most `permits` per second, at least one
+    pub fn per_second(permits: u32) -> RateLimit {
+        RateLimit {
+            per_permit: Duration::from_secs(1) / permits.max(1),
+            next: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Blocks until `permits` more can be used
+    pub fn acquire(&self, permits: usize) {
+        let start = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next).max(Instant::now());
+            *next = start + self.per_permit * permits as u32;
+            start
+        };
+        let now = Instant::now();
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+}
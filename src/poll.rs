@@ -0,0 +1,106 @@
+use crate::output::{OutputFormat, Style, Terminal};
+use libfiresale::api::Document;
+use libfiresale::canonical::canonical_fields;
+use libfiresale::errors::{Error, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+
+/// Parses an interval such as `500ms`, `10s`, `5m`, `1h` or `90d`, seconds when unitless
+pub fn parse_interval(interval: &str) -> Result<Duration> {
+    let interval = interval.trim();
+    let split = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(interval.len());
+    let (amount, unit) = interval.split_at(split);
+    let invalid = || Error::InvalidInput {
+        message: format!("invalid interval {:?}, expected e.g. 10s or 5m", interval),
+    };
+    let amount = amount.parse::<u64>().map_err(|_| invalid())?;
+    let interval = match unit {
+        "ms" => Duration::from_millis(amount),
+        "" | "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount * 60),
+        "h" => Duration::from_secs(amount * 60 * 60),
+        "d" => Duration::from_secs(amount * 60 * 60 * 24),
+        _ => return Err(invalid()),
+    };
+    if interval == Duration::from_secs(0) {
+        return Err(invalid());
+    }
+    Ok(interval)
+}
+
+/// Runs `fetch` every `interval` until it fails, rendering every result or, with
+/// `diff`, only the documents added, removed or changed since the previous round
+pub fn poll<F>(interval: Duration, diff: bool, output: &OutputFormat, mut fetch: F) -> Result<()>
+where
+    F: FnMut() -> Result<Vec<Document>>,
+{
+    let mut previous: Option<BTreeMap<String, Document>> = None;
+    loop {
+        let current = fetch()?
+            .into_iter()
+            .map(|document| (document.name().to_string(), document))
+            .collect::<BTreeMap<String, Document>>();
+        match (&previous, diff) {
+            (Some(previous), true) => print_changes(previous, &current, output.terminal()),
+            _ => output.page(&*current.values().cloned().collect::<Vec<Document>>())?,
+        }
+        previous = Some(current);
+        thread::sleep(interval);
+    }
+}
+
+// One line per added or removed document, and one per changed top level field
+fn print_changes(
+    previous: &BTreeMap<String, Document>,
+    current: &BTreeMap<String, Document>,
+    terminal: Terminal,
+) {
+    for (name, document) in current {
+        match previous.get(name) {
+            None => {
+                let line = format!("+ {} {}", name, Value::Object(document.fields().to_json()));
+                println!("{}", terminal.paint(Style::Added, line))
+            }
+            Some(before) if before.update_time() != document.update_time() => {
+                print_field_changes(name, before, document, terminal)
+            }
+            Some(_) => {}
+        }
+    }
+    for name in previous.keys().filter(|name| !current.contains_key(*name)) {
+        println!("{}", terminal.paint(Style::Removed, format!("- {}", name)));
+    }
+}
+
+// Fields are compared in canonical form so a touch without changes prints nothing
+fn print_field_changes(name: &str, before: &Document, after: &Document, terminal: Terminal) {
+    let (canonical_before, canonical_after) = (
+        canonical_fields(before.fields()),
+        canonical_fields(after.fields()),
+    );
+    let (plain_before, plain_after) = (before.fields().to_json(), after.fields().to_json());
+    let mut fields = plain_before
+        .keys()
+        .chain(plain_after.keys())
+        .collect::<Vec<&String>>();
+    fields.sort();
+    fields.dedup();
+    for field in fields {
+        if canonical_before.get(field) == canonical_after.get(field) {
+            continue;
+        }
+        let show = |value: Option<&Value>| value.map_or("<absent>".to_string(), Value::to_string);
+        let line = format!(
+            "~ {} {}: {} -> {}",
+            name,
+            field,
+            show(plain_before.get(field)),
+            show(plain_after.get(field))
+        );
+        println!("{}", terminal.paint(Style::Changed, line));
+    }
+}
@@ -0,0 +1,32 @@
+//! The stable surface of `libfiresale`, bring it into scope with
+//! `use libfiresale::prelude::*;`
+
+pub use crate::api::batch_get::Lookup;
+pub use crate::api::batch_write::Response as BatchWriteResponse;
+pub use crate::api::commit::Response as CommitResponse;
+pub use crate::api::filter::{Condition, FilterPlan, Operator};
+pub use crate::api::geo::GeoFilter;
+pub use crate::api::list_collection_ids::CollectionIds;
+pub use crate::api::query::{
+    Aggregation, Cursor, Direction, FieldOperator, Projection, QueryBuilder, QueryStream,
+    StructuredQuery, UnaryFilter, UnaryOperator,
+};
+pub use crate::api::watch::{ChangeStream, DocumentChange, WatchTarget};
+pub use crate::api::{field_path, json_to_wire, resolve_credentials_path};
+pub use crate::api::{
+    ArrayValue, AuthOptions, AuthScope, ConnectionOptions, ConsistencySelector, DatabaseContext,
+    Document, DocumentMask, Double, FieldTransform, FirestoreFields, FirestoreType, GeoPoint,
+    HttpVersion, MapValue, NonFinitePolicy, Precondition, Timestamp, Transaction, Write,
+    WriteOperation,
+};
+pub use crate::cancel::CancellationToken;
+pub use crate::canonical::{canonical_fields, canonical_json, checksum};
+pub use crate::debug::{
+    Exchange, HttpDump, HttpHook, IncomingResponse, Interceptor, OutgoingRequest,
+};
+pub use crate::errors::{Error, Result};
+pub use crate::firestore::databases::{ExportDocumentQuery, ImportDocumentQuery};
+pub use crate::format::{Formatter, Formatters, Locale, Truncation};
+pub use crate::path::{CollectionPath, DocumentPath, DocumentReference, ResourceName};
+pub use crate::policy::{RateLimit, RetryPolicy};
+pub use crate::report::{FailOn, Finding, Report, Severity};
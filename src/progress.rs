@@ -0,0 +1,103 @@
+use serde_json::json;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Progress of long operations as NDJSON events on stderr, with `--progress-json`.
+/// Every event has the same keys so consumers need no knowledge of the operation:
+///
+/// ```json
+/// {"event":"progress","phase":"load","processed":500,"total":1200,"rate":250.0,"errors":0}
+/// ```
+///
+/// `event` is `start`, `progress` or `done`, `total` is null when unknown up front and
+/// `rate` is items per second since the phase started.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressEvents {
+    enabled: bool,
+}
+
+impl ProgressEvents {
+    pub fn new(enabled: bool) -> ProgressEvents {
+        ProgressEvents { enabled }
+    }
+
+    /// Starts a phase of `total` items, if known
+    pub fn phase(&self, phase: &str, total: Option<u64>) -> Phase {
+        let phase = Phase {
+            enabled: self.enabled,
+            name: phase.to_string(),
+            total,
+            started: Instant::now(),
+            counts: Mutex::new(Counts::default()),
+        };
+        phase.emit("start", &Counts::default());
+        phase
+    }
+}
+
+/// One phase of an operation, shared by the workers advancing it
+#[derive(Debug)]
+pub struct Phase {
+    enabled: bool,
+    name: String,
+    total: Option<u64>,
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    processed: u64,
+    errors: u64,
+}
+
+impl Phase {
+    /// Counts `items` more as processed
+    pub fn advance(&self, items: u64) {
+        let counts = {
+            let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+            counts.processed += items;
+            *counts
+        };
+        self.emit("progress", &counts);
+    }
+
+    pub fn error(&self) {
+        let counts = {
+            let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+            counts.errors += 1;
+            *counts
+        };
+        self.emit("progress", &counts);
+    }
+
+    pub fn finish(&self) {
+        let counts = *self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        self.emit("done", &counts);
+    }
+
+    fn emit(&self, event: &str, counts: &Counts) {
+        if !self.enabled {
+            return;
+        }
+        let elapsed = self.started.elapsed();
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        let rate = if seconds > 0.0 {
+            counts.processed as f64 / seconds
+        } else {
+            0.0
+        };
+        let line = json!({
+            "event": event,
+            "phase": self.name,
+            "processed": counts.processed,
+            "total": self.total,
+            "rate": (rate * 10.0).round() / 10.0,
+            "errors": counts.errors,
+        });
+        // one write per line, so events from several workers never interleave
+        let stderr = io::stderr();
+        writeln!(stderr.lock(), "{}", line).ok();
+    }
+}
@@ -0,0 +1,102 @@
+use crate::dump::{self, DumpOptions};
+use crate::planner::WritePlanner;
+use crate::poll::parse_interval;
+use chrono::Utc;
+use libfiresale::api::query::FieldOperator;
+use libfiresale::api::{DatabaseContext, FirestoreType, Timestamp, Write};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::{Condition, FilterPlan, Operator, StructuredQuery};
+
+/// Deletes per commit, the most Firestore accepts in one
+const BATCH_SIZE: usize = 500;
+
+/// Deletes every document of `collection` whose timestamp `field` is older than
+/// `older_than`, e.g. `90d`. With `archive` the documents are first dumped to that
+/// directory, and nothing is deleted if the dump fails. Documents without the field,
+/// or where it is not a timestamp, are kept.
+pub fn prune(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    collection: &str,
+    field: &str,
+    older_than: &str,
+    archive: Option<&str>,
+) -> Result<()> {
+    let age = chrono::Duration::from_std(parse_interval(older_than)?).map_err(|_| {
+        Error::InvalidInput {
+            message: format!("--older-than {} is too long", older_than),
+        }
+    })?;
+    let cutoff = Utc::now() - age;
+    let plan = FilterPlan::new(vec![Condition {
+        field: field.to_string(),
+        op: Operator::Field(FieldOperator::LessThan),
+        value: FirestoreType::Timestamp(Timestamp::new(cutoff)),
+    }]);
+    let collection = collection.trim_matches('/');
+    println!(
+        "pruning {} where {} < {}",
+        collection,
+        field,
+        cutoff.to_rfc3339()
+    );
+    match archive {
+        // an archive is only worth writing when the documents are really deleted
+        Some(dir) if planner.dry_run => println!("[dry-run] archive to {}", dir),
+        Some(dir) => dump::dump(
+            ctx,
+            &*planner.database_name,
+            dir,
+            &[collection.to_string()],
+            &DumpOptions {
+                filter: Some(plan.clone()),
+                progress: planner.progress.clone(),
+                ..DumpOptions::default()
+            },
+        )?,
+        None => {}
+    }
+    let path = CollectionPath::parse(collection)?;
+    let mut query = StructuredQuery::collection(path.collection_id());
+    plan.apply(&mut query);
+    // the scan is pinned to its first read time, so deleting as it goes is safe
+    let stream = ctx.query_stream(&*planner.database_name, path.parent(), query);
+    let phase = planner.progress.phase("prune", None);
+    let mut batch = Vec::new();
+    let mut deleted = 0;
+    for document in stream {
+        let document = document?;
+        batch.push(ctx.delete_write(&*planner.database_name, document.name()));
+        if batch.len() == BATCH_SIZE {
+            let committed = commit(ctx, planner, &mut batch)?;
+            deleted += committed;
+            phase.advance(committed as u64);
+            eprintln!("{}: {} document(s) so far", collection, deleted);
+        }
+    }
+    let committed = commit(ctx, planner, &mut batch)?;
+    deleted += committed;
+    phase.advance(committed as u64);
+    phase.finish();
+    if planner.dry_run {
+        println!(
+            "[dry-run] {}: {} document(s) would be deleted",
+            collection, deleted
+        );
+    } else {
+        println!("{}: {} document(s) deleted", collection, deleted);
+    }
+    Ok(())
+}
+
+// Sends one batch of deletes, returning how many there were
+fn commit(ctx: &DatabaseContext, planner: &WritePlanner, batch: &mut Vec<Write>) -> Result<usize> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let writes = batch.drain(..).collect::<Vec<Write>>();
+    let count = writes.len();
+    planner.apply(ctx, "prune", writes, None)?;
+    Ok(count)
+}
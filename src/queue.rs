@@ -0,0 +1,233 @@
+use crate::output::OutputFormat;
+use crate::planner::WritePlanner;
+use chrono::{DateTime, Utc};
+use libfiresale::api::query::{
+    Direction, FieldFilter, FieldOperator, FieldReference, Filter, Order, StructuredQuery,
+};
+use libfiresale::api::{
+    ConsistencySelector, DatabaseContext, Document, FieldTransform, FirestoreType, Precondition,
+    Timestamp, Write,
+};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::{generate_document_id, CollectionPath};
+use serde_json::{json, Map, Value};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Time until which a document is claimed. Documents without it are not queued.
+const LEASE_FIELD: &'static str = "leaseUntil";
+/// Who holds the current lease
+const OWNER_FIELD: &'static str = "owner";
+/// Times the document was handed out, counting the current lease
+const DELIVERIES_FIELD: &'static str = "deliveries";
+/// Claimable documents read by a pop, the oldest of which is claimed
+const CANDIDATES: i32 = 20;
+/// Times a pop is attempted when other workers claim the same document
+const ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for every further one
+const BACKOFF: Duration = Duration::from_millis(100);
+
+/// Adds a document with a generated id to the queue in `collection`, claimable
+/// right away
+pub fn push(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    collection: &str,
+    fields: &str,
+) -> Result<()> {
+    let fields: Map<String, Value> = serde_json::from_str(fields)?;
+    let path = format!(
+        "{}/{}",
+        collection.trim_matches('/'),
+        generate_document_id()
+    );
+    let write = ctx
+        .update_write(&*planner.database_name, &*path, &fields)
+        .with_field(LEASE_FIELD, &timestamp(DateTime::<Utc>::from(UNIX_EPOCH)))
+        .with_precondition(Precondition::Exists(false));
+    if planner
+        .apply(ctx, "queue push", vec![write], None)?
+        .is_some()
+    {
+        println!("{}", path);
+    }
+    Ok(())
+}
+
+/// Claims the oldest document of `collection` whose lease is over, in a transaction so
+/// no two workers get the same one, and prints it. Returns `false` when there is none.
+pub fn pop(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    collection: &str,
+    lease: Duration,
+    owner: &str,
+    output: &OutputFormat,
+) -> Result<bool> {
+    let path = CollectionPath::parse(collection)?;
+    let lease = chrono::Duration::from_std(lease).map_err(|_| Error::InvalidInput {
+        message: "lease is too long".to_string(),
+    })?;
+    let database_name = &*planner.database_name;
+    let mut aborted: Option<String> = None;
+    let mut attempt = 1;
+    loop {
+        let transaction = match aborted.take() {
+            Some(previous) => ctx.retry_transaction(database_name, previous)?,
+            None => ctx.begin_transaction(database_name, false)?,
+        };
+        match claim(ctx, planner, &path, lease, owner, transaction.clone()) {
+            Ok(Some(document)) => {
+                output.document(&document)?;
+                return Ok(true);
+            }
+            Ok(None) => {
+                ctx.rollback(database_name, transaction)?;
+                return Ok(false);
+            }
+            // another worker claimed the same document first
+            Err(ref e)
+                if attempt < ATTEMPTS && e.status() == Some(reqwest::StatusCode::CONFLICT) =>
+            {
+                thread::sleep(BACKOFF * 2u32.pow(attempt - 1));
+                aborted = Some(transaction);
+                attempt += 1;
+            }
+            Err(e) => {
+                // best effort, the transaction expires on its own anyway
+                ctx.rollback(database_name, transaction).ok();
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Leases the oldest of the first claimable documents, ordered by lease expiry
+fn claim(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    path: &CollectionPath,
+    lease: chrono::Duration,
+    owner: &str,
+    transaction: String,
+) -> Result<Option<Document>> {
+    let now = Utc::now();
+    let lease_field = || FieldReference {
+        field_path: LEASE_FIELD.to_string(),
+    };
+    let mut query = StructuredQuery::collection(path.collection_id());
+    query.filter = Some(Filter::Field(FieldFilter {
+        field: lease_field(),
+        op: FieldOperator::LessThan,
+        value: timestamp(now),
+    }));
+    query.order_by = vec![Order {
+        field: lease_field(),
+        direction: Direction::Ascending,
+    }];
+    query.limit = Some(CANDIDATES);
+    let consistency = ConsistencySelector::Transaction(transaction.clone());
+    let database_name = &*planner.database_name;
+    let oldest = ctx
+        .run_query(
+            database_name,
+            path.parent().as_ref(),
+            &query,
+            Some(&consistency),
+        )?
+        .into_iter()
+        .filter_map(|response| response.document)
+        .min_by_key(Document::create_time);
+    let document = match oldest {
+        Some(document) => document,
+        None => return Ok(None),
+    };
+    let mut fields = Map::new();
+    fields.insert(OWNER_FIELD.to_string(), json!(owner));
+    let write = Write::update(document.name().to_string(), &fields)
+        .with_field(LEASE_FIELD, &timestamp(now + lease))
+        .with_update_mask(vec![LEASE_FIELD.to_string(), OWNER_FIELD.to_string()])
+        .with_transform(FieldTransform::increment(DELIVERIES_FIELD, 1));
+    planner.apply(ctx, "queue pop", vec![write], Some(transaction))?;
+    Ok(Some(document))
+}
+
+/// Removes a processed document from the queue, unless it was leased again since
+pub fn ack(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    path: &str,
+    owner: Option<&str>,
+) -> Result<()> {
+    let document = leased(ctx, &*planner.database_name, path, owner)?;
+    let write = ctx
+        .delete_write(&*planner.database_name, path)
+        .with_precondition(Precondition::UpdateTime(document.update_time()));
+    if planner
+        .apply(ctx, "queue ack", vec![write], None)?
+        .is_some()
+    {
+        println!("acked {}", path);
+    }
+    Ok(())
+}
+
+/// Gives a document back to the queue, claimable again after `delay`
+pub fn nack(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    path: &str,
+    owner: Option<&str>,
+    delay: Duration,
+) -> Result<()> {
+    let delay = chrono::Duration::from_std(delay).map_err(|_| Error::InvalidInput {
+        message: "delay is too long".to_string(),
+    })?;
+    let document = leased(ctx, &*planner.database_name, path, owner)?;
+    // the owner is in the mask but not the fields, which deletes it
+    let write = Write::update(document.name().to_string(), &Map::new())
+        .with_field(LEASE_FIELD, &timestamp(Utc::now() + delay))
+        .with_update_mask(vec![LEASE_FIELD.to_string(), OWNER_FIELD.to_string()])
+        .with_precondition(Precondition::UpdateTime(document.update_time()));
+    if planner
+        .apply(ctx, "queue nack", vec![write], None)?
+        .is_some()
+    {
+        println!("released {}", path);
+    }
+    Ok(())
+}
+
+// The queued document at `path`, checking it is leased by `owner` when one is given
+fn leased(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    path: &str,
+    owner: Option<&str>,
+) -> Result<Document> {
+    let document = ctx
+        .find_document(database_name, path)?
+        .ok_or_else(|| Error::InvalidInput {
+            message: format!("{} is not in the queue", path),
+        })?;
+    if let Some(owner) = owner {
+        match document.fields().get(OWNER_FIELD) {
+            Some(FirestoreType::String(current)) if current == owner => {}
+            Some(FirestoreType::String(current)) => {
+                return Err(Error::Conflict {
+                    message: format!("{} is leased by {}", path, current),
+                })
+            }
+            _ => {
+                return Err(Error::Conflict {
+                    message: format!("{} is not leased", path),
+                })
+            }
+        }
+    }
+    Ok(document)
+}
+
+fn timestamp(time: DateTime<Utc>) -> FirestoreType {
+    FirestoreType::Timestamp(Timestamp::new(time))
+}
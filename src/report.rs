@@ -0,0 +1,170 @@
+//! The machine readable result of the commands that compare or validate data, such as
+//! `plan`, `check`, `schema diff` and `verify-backup`, printed with `--output json` so
+//! CI pipelines can gate on it.
+//!
+//! The structure is stable within a `version`: fields may be added, but removing one
+//! or changing its meaning bumps the version.
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "command": "check",
+//!   "ok": false,
+//!   "summary": { "documents": 120, "violations": 1 },
+//!   "findings": [
+//!     {
+//!       "kind": "violation",
+//!       "severity": "error",
+//!       "subject": "users/alice",
+//!       "field": "email",
+//!       "message": "missing"
+//!     }
+//!   ]
+//! }
+//! ```
+
+use crate::errors::{Error, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+pub const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    /// `REPORT_VERSION` when the report was written
+    pub version: u32,
+    /// The command that produced the report, e.g. `schema diff`
+    pub command: String,
+    /// Whether there are no findings, i.e. nothing to fix, drift or pending change
+    pub ok: bool,
+    /// Counts describing what was examined, keys depend on the command
+    #[serde(default)]
+    pub summary: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub findings: Vec<Finding>,
+}
+
+/// One thing a command found, like a rule violation or a planned change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    /// What was found, one of a small set of values documented by each command
+    pub kind: String,
+    #[serde(default)]
+    pub severity: Severity,
+    /// What the finding is about: a document path, collection or file
+    pub subject: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Human readable explanation
+    pub message: String,
+    /// Structured data specific to `kind`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// How serious a finding is, findings without one being errors
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Default for Severity {
+    fn default() -> Severity {
+        Severity::Error
+    }
+}
+
+/// The least severe finding that makes a command fail, `never` only reporting them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailOn {
+    Never,
+    Errors,
+    Warnings,
+}
+
+impl std::str::FromStr for FailOn {
+    type Err = Error;
+
+    fn from_str(fail_on: &str) -> Result<FailOn> {
+        match fail_on {
+            "never" => Ok(FailOn::Never),
+            "errors" => Ok(FailOn::Errors),
+            "warnings" => Ok(FailOn::Warnings),
+            _ => Err(Error::InvalidInput {
+                message: format!(
+                    "unknown threshold {}, expected never, errors or warnings",
+                    fail_on
+                ),
+            }),
+        }
+    }
+}
+
+impl Report {
+    pub fn new<S: Into<String>>(command: S) -> Report {
+        Report {
+            version: REPORT_VERSION,
+            command: command.into(),
+            ok: true,
+            summary: BTreeMap::new(),
+            findings: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, finding: Finding) {
+        self.ok = false;
+        self.findings.push(finding);
+    }
+
+    /// Adds `count` to the summary entry `key`
+    pub fn count<S: Into<String>>(&mut self, key: S, count: u64) {
+        *self.summary.entry(key.into()).or_insert(0) += count;
+    }
+
+    /// Whether any finding is at least as severe as `fail_on` allows
+    pub fn fails(&self, fail_on: FailOn) -> bool {
+        let threshold = match fail_on {
+            FailOn::Never => return false,
+            FailOn::Errors => Severity::Error,
+            FailOn::Warnings => Severity::Warning,
+        };
+        self.findings
+            .iter()
+            .any(|finding| finding.severity >= threshold)
+    }
+}
+
+impl Finding {
+    pub fn new<S, T, U>(kind: S, subject: T, message: U) -> Finding
+    where
+        S: Into<String>,
+        T: Into<String>,
+        U: Into<String>,
+    {
+        Finding {
+            kind: kind.into(),
+            severity: Severity::Error,
+            subject: subject.into(),
+            field: None,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_field<S: Into<String>>(mut self, field: S) -> Finding {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn with_severity(mut self, severity: Severity) -> Finding {
+        self.severity = severity;
+        self
+    }
+
+    pub fn with_details(mut self, details: Value) -> Finding {
+        self.details = Some(details);
+        self
+    }
+}
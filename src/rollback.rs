@@ -0,0 +1,112 @@
+use crate::dump::{read_documents, relative_path, Manifest};
+use crate::planner::WritePlanner;
+use libfiresale::api::{DatabaseContext, Document, Write};
+use libfiresale::canonical::{canonical_fields, checksum};
+use libfiresale::errors::Result;
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Writes per commit, the most Firestore accepts in one
+const BATCH_SIZE: usize = 500;
+
+/// Puts back the collections of the dump described by `manifest`, a `manifest.json` or
+/// the directory holding it, as taken before a `load` or import went wrong. Documents
+/// changed since are replaced with their dumped version and deleted ones are written
+/// again; identical ones are left alone. With `delete_added`, documents missing from
+/// the dump are deleted as well, which is only right when it holds whole collections
+/// rather than, say, a `prune --archive`.
+pub fn rollback(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    manifest: &str,
+    delete_added: bool,
+) -> Result<()> {
+    let manifest = Path::new(manifest);
+    let dir = if manifest.is_dir() {
+        manifest
+    } else {
+        manifest.parent().unwrap_or_else(|| Path::new("."))
+    };
+    let snapshot = Manifest::read(dir)?;
+    // partitions of a collection have an entry each
+    let mut collections = BTreeMap::new();
+    for collection in &snapshot.collections {
+        let files = collections
+            .entry(collection.collection.clone())
+            .or_insert_with(Vec::new);
+        files.extend(collection.files().into_iter().map(|file| dir.join(file)));
+    }
+    for (collection, files) in collections {
+        let mut dumped = BTreeMap::new();
+        for file in files {
+            for document in read_documents(&*file)? {
+                let document = document?;
+                dumped.insert(relative_path(document.name()), document);
+            }
+        }
+        restore(ctx, planner, &*collection, dumped, delete_added)?;
+    }
+    Ok(())
+}
+
+// Brings one collection back to `dumped`, by relative path
+fn restore(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    collection: &str,
+    mut dumped: BTreeMap<String, Document>,
+    delete_added: bool,
+) -> Result<()> {
+    let database_name = &*planner.database_name;
+    let path = CollectionPath::parse(collection)?;
+    let query = StructuredQuery::collection(path.collection_id());
+    let mut batch = Vec::new();
+    let (mut changed, mut added) = (0, 0);
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        let document = document?;
+        let path = relative_path(document.name());
+        match dumped.remove(&path) {
+            Some(before) => {
+                let current = checksum(&canonical_fields(document.fields()));
+                if checksum(&canonical_fields(before.fields())) != current {
+                    batch.push(ctx.replace_write(database_name, &*path, before.fields()));
+                    changed += 1;
+                }
+            }
+            None if delete_added => {
+                batch.push(ctx.delete_write(database_name, &*path));
+                added += 1;
+            }
+            None => {}
+        }
+        if batch.len() >= BATCH_SIZE {
+            commit(ctx, planner, &mut batch)?;
+        }
+    }
+    // whatever the scan did not meet was deleted since the dump
+    let deleted = dumped.len();
+    for (path, before) in dumped {
+        batch.push(ctx.replace_write(database_name, &*path, before.fields()));
+        if batch.len() >= BATCH_SIZE {
+            commit(ctx, planner, &mut batch)?;
+        }
+    }
+    commit(ctx, planner, &mut batch)?;
+    let prefix = if planner.dry_run { "[dry-run] " } else { "" };
+    println!(
+        "{}{}: {} changed document(s) restored, {} deleted document(s) recreated, {} added document(s) deleted",
+        prefix, collection, changed, deleted, added
+    );
+    Ok(())
+}
+
+fn commit(ctx: &DatabaseContext, planner: &WritePlanner, batch: &mut Vec<Write>) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let writes = batch.drain(..).collect::<Vec<Write>>();
+    planner.apply(ctx, "rollback", writes, None)?;
+    Ok(())
+}
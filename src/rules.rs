@@ -0,0 +1,484 @@
+use crate::dump::relative_path;
+use crate::output::{OutputFormat, Reporter};
+use libfiresale::api::{DatabaseContext, Document, FirestoreType};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use libfiresale::report::{Finding, Report, Severity};
+use regex::Regex;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+pub const DEFAULT_RULES_FILE: &'static str = "firesale.rules.toml";
+
+/// Expectations for the documents of each collection, e.g.
+///
+/// ```toml
+/// [collections.users]
+/// required = ["email", "createdAt"]
+///
+/// [collections.users.fields]
+/// email = { type = "string", pattern = "^[^@]+@[^@]+$" }
+/// "address.city" = { type = "string" }
+/// team = { type = "reference", target = "teams" }
+/// nickname = { type = "string", severity = "warning" }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Rules {
+    #[serde(default)]
+    collections: BTreeMap<String, CollectionRules>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectionRules {
+    /// Field paths every document must have
+    #[serde(default)]
+    required: Vec<String>,
+    /// Constraints on field paths, checked when the field is present
+    #[serde(default)]
+    fields: BTreeMap<String, FieldRule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldRule {
+    /// One of the names given by `type_name`, `number` accepting integers and doubles
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// A regular expression string values must match
+    pattern: Option<String>,
+    /// The collection a reference must point into, e.g. `teams` or `teams/abc/members`
+    target: Option<String>,
+    /// Breaking the rule is an error unless it is marked as a warning
+    #[serde(default)]
+    severity: Severity,
+}
+
+const TYPE_NAMES: &[&str] = &[
+    "integer",
+    "double",
+    "number",
+    "boolean",
+    "string",
+    "geopoint",
+    "array",
+    "map",
+    "timestamp",
+    "bytes",
+    "reference",
+    "null",
+];
+
+fn type_name(value: &FirestoreType) -> &'static str {
+    match value {
+        FirestoreType::Integer(_) => "integer",
+        FirestoreType::Double(_) => "double",
+        FirestoreType::Boolean(_) => "boolean",
+        FirestoreType::String(_) => "string",
+        FirestoreType::GeoLocation(_) => "geopoint",
+        FirestoreType::Array(_) => "array",
+        FirestoreType::Map(_) => "map",
+        FirestoreType::Timestamp(_) => "timestamp",
+        FirestoreType::Bytes(_) => "bytes",
+        FirestoreType::Reference(_) => "reference",
+        FirestoreType::Null => "null",
+    }
+}
+
+/// A field rule with its pattern compiled
+struct CompiledRule<'a> {
+    field: &'a str,
+    rule: &'a FieldRule,
+    pattern: Option<Regex>,
+}
+
+impl Rules {
+    pub fn load(path: &str) -> Result<Rules> {
+        let contents = fs::read_to_string(path).map_err(|e| Error::InvalidInput {
+            message: format!("cannot read rules {}: {}", path, e),
+        })?;
+        Rules::parse(path, &*contents)
+    }
+
+    /// Reads the rules in `contents`, from the file at `path`
+    fn parse(path: &str, contents: &str) -> Result<Rules> {
+        let rules: Rules = toml::from_str(contents).map_err(|e| Error::InvalidInput {
+            message: format!("invalid rules {}: {}", path, e),
+        })?;
+        for (collection, field, rule) in rules.field_rules() {
+            if let Some(kind) = &rule.kind {
+                if !TYPE_NAMES.contains(&&**kind) {
+                    return Err(Error::InvalidInput {
+                        message: format!(
+                            "{}.{}: unknown type {:?}, expected one of {}",
+                            collection,
+                            field,
+                            kind,
+                            TYPE_NAMES.join(", ")
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// The checker for `collection`, an error if the rules do not mention it
+    pub fn checker(&self, collection: &str) -> Result<Checker<'_>> {
+        let collection = collection.trim_matches('/');
+        match self.collections.get(collection) {
+            Some(rules) => Checker::new(collection, rules),
+            None => Err(Error::InvalidInput {
+                message: format!("{} has no rules", collection),
+            }),
+        }
+    }
+
+    fn field_rules(&self) -> impl Iterator<Item = (&str, &str, &FieldRule)> {
+        self.collections.iter().flat_map(|(collection, rules)| {
+            rules
+                .fields
+                .iter()
+                .map(move |(field, rule)| (&**collection, &**field, rule))
+        })
+    }
+}
+
+impl FieldRule {
+    fn check(&self, value: &FirestoreType, pattern: Option<&Regex>) -> Option<String> {
+        let actual = type_name(value);
+        if let Some(kind) = &self.kind {
+            let matches = match (&**kind, value) {
+                ("number", FirestoreType::Integer(_)) | ("number", FirestoreType::Double(_)) => {
+                    true
+                }
+                (kind, _) => kind == actual,
+            };
+            if !matches {
+                return Some(format!("expected {}, found {}", kind, actual));
+            }
+        }
+        if let (Some(pattern), FirestoreType::String(text)) = (pattern, value) {
+            if !pattern.is_match(text) {
+                return Some(format!("{:?} does not match /{}/", text, pattern));
+            }
+        }
+        if let (Some(target), FirestoreType::Reference(reference)) = (&self.target, value) {
+            let segments = reference.path.segments();
+            let collection = segments[..segments.len() - 1].join("/");
+            if collection != target.trim_matches('/') {
+                return Some(format!("{} is not in {}", reference.path, target));
+            }
+        }
+        None
+    }
+}
+
+/// A rule broken by a document, shown as `field: problem`
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub field: String,
+    pub problem: String,
+    pub severity: Severity,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.problem)?;
+        if self.severity == Severity::Warning {
+            write!(f, " (warning)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The compiled rules of one collection
+pub struct Checker<'a> {
+    rules: &'a CollectionRules,
+    compiled: Vec<CompiledRule<'a>>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(collection: &str, rules: &'a CollectionRules) -> Result<Checker<'a>> {
+        let compiled = rules
+            .fields
+            .iter()
+            .map(|(field, rule)| {
+                let pattern = match &rule.pattern {
+                    Some(pattern) => {
+                        Some(Regex::new(pattern).map_err(|e| Error::InvalidInput {
+                            message: format!("{}.{}: invalid pattern: {}", collection, field, e),
+                        })?)
+                    }
+                    None => None,
+                };
+                Ok(CompiledRule {
+                    field: &**field,
+                    rule,
+                    pattern,
+                })
+            })
+            .collect::<Result<Vec<CompiledRule>>>()?;
+        Ok(Checker { rules, compiled })
+    }
+
+    /// Every rule `document` breaks
+    pub fn violations(&self, document: &Document) -> Vec<Violation> {
+        let fields = document.fields();
+        let mut problems = self
+            .rules
+            .required
+            .iter()
+            .filter(|field| fields.get_path(field).is_none())
+            .map(|field| Violation {
+                field: field.clone(),
+                problem: "missing".to_string(),
+                severity: Severity::Error,
+            })
+            .collect::<Vec<Violation>>();
+        for compiled in &self.compiled {
+            if let Some(value) = fields.get_path(compiled.field) {
+                if let Some(problem) = compiled.rule.check(value, compiled.pattern.as_ref()) {
+                    problems.push(Violation {
+                        field: compiled.field.to_string(),
+                        problem,
+                        severity: compiled.rule.severity,
+                    });
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// Checks every document of `collection`, or of every collection in the rules, reporting
+/// each violation as a `violation` finding with the document path as its subject
+pub fn check(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    rules_path: &str,
+    collection: Option<&str>,
+    format: &OutputFormat,
+) -> Result<Report> {
+    let rules = Rules::load(rules_path)?;
+    let collections = match collection {
+        Some(collection) => vec![collection.trim_matches('/')],
+        None => rules
+            .collections
+            .keys()
+            .map(|collection| &**collection)
+            .collect(),
+    };
+    let mut reporter = Reporter::new("check", format);
+    for collection in collections {
+        let checker = rules.checker(collection)?;
+        let path = CollectionPath::parse(collection)?;
+        let query = StructuredQuery::collection(path.collection_id());
+        let documents = ctx.query_stream(database_name, path.parent(), query);
+        check_documents(&mut reporter, collection, &checker, documents)?;
+    }
+    reporter.finish()
+}
+
+// Reports the violations of every document of `collection`, with a summary line
+fn check_documents<I>(
+    reporter: &mut Reporter,
+    collection: &str,
+    checker: &Checker,
+    documents: I,
+) -> Result<()>
+where
+    I: IntoIterator<Item = Result<Document>>,
+{
+    let (mut read, mut found) = (0, 0);
+    for document in documents {
+        let document = document?;
+        read += 1;
+        let document_path = relative_path(document.name());
+        for violation in checker.violations(&document) {
+            let line = format!("{}: {}", document_path, violation);
+            let finding = Finding::new("violation", &*document_path, violation.problem)
+                .with_field(violation.field)
+                .with_severity(violation.severity);
+            reporter.found(finding, line);
+            found += 1;
+        }
+    }
+    reporter.say(format!(
+        "{}: {} document(s), {} violation(s)",
+        collection, read, found
+    ));
+    reporter.count("documents", read);
+    reporter.count("violations", found);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libfiresale::format::Locale;
+    use libfiresale::report::FailOn;
+    use serde_json::{json, Value};
+
+    const RULES: &'static str = r#"
+        [collections.users]
+        required = ["email", "address.city"]
+
+        [collections.users.fields]
+        email = { type = "string", pattern = "^[^@]+@[^@]+$" }
+        age = { type = "number" }
+        team = { type = "reference", target = "teams" }
+        nickname = { type = "string", severity = "warning" }
+    "#;
+
+    fn user(id: &str, fields: Value) -> Document {
+        serde_json::from_value(json!({
+            "name": format!("projects/p/databases/(default)/documents/users/{}", id),
+            "fields": fields,
+            "createTime": "2019-06-01T00:00:00Z",
+            "updateTime": "2019-06-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    // A user breaking no rule, with `fields` added or replaced
+    fn valid_user(fields: Value) -> Document {
+        let mut valid = json!({
+            "email": { "stringValue": "ada@example.com" },
+            "address": { "mapValue": { "fields": { "city": { "stringValue": "London" } } } },
+        });
+        for (name, value) in fields.as_object().unwrap() {
+            valid[name] = value.clone();
+        }
+        user("ada", valid)
+    }
+
+    fn problems(document: &Document) -> Vec<String> {
+        let rules = Rules::parse("rules.toml", RULES).unwrap();
+        let checker = rules.checker("users").unwrap();
+        checker
+            .violations(document)
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn valid_documents_break_no_rule() {
+        assert!(problems(&valid_user(json!({}))).is_empty());
+        assert!(problems(&valid_user(json!({
+            "age": { "doubleValue": 36.5 },
+            "team": { "referenceValue": "projects/p/databases/(default)/documents/teams/t1" },
+            "nickname": { "stringValue": "ada" },
+        })))
+        .is_empty());
+    }
+
+    #[test]
+    fn required_fields_must_be_present() {
+        assert_eq!(
+            problems(&user("ada", json!({}))),
+            vec!["email: missing", "address.city: missing"]
+        );
+        assert_eq!(
+            problems(&user(
+                "ada",
+                json!({
+                    "email": { "stringValue": "ada@example.com" },
+                    "address": { "mapValue": { "fields": {} } },
+                })
+            )),
+            vec!["address.city: missing"]
+        );
+    }
+
+    #[test]
+    fn fields_must_have_their_type() {
+        assert_eq!(
+            problems(&valid_user(json!({ "email": { "integerValue": "1" } }))),
+            vec!["email: expected string, found integer"]
+        );
+        assert!(problems(&valid_user(json!({ "age": { "integerValue": "36" } }))).is_empty());
+        assert_eq!(
+            problems(&valid_user(json!({ "age": { "stringValue": "36" } }))),
+            vec!["age: expected number, found string"]
+        );
+        assert_eq!(
+            problems(&valid_user(json!({ "nickname": { "nullValue": null } }))),
+            vec!["nickname: expected string, found null (warning)"]
+        );
+    }
+
+    #[test]
+    fn strings_must_match_their_pattern() {
+        assert_eq!(
+            problems(&valid_user(json!({ "email": { "stringValue": "ada" } }))),
+            vec![r#"email: "ada" does not match /^[^@]+@[^@]+$/"#]
+        );
+    }
+
+    #[test]
+    fn references_must_point_into_their_target() {
+        let team = |name: &str| valid_user(json!({ "team": { "referenceValue": name } }));
+        assert_eq!(
+            problems(&team("projects/p/databases/(default)/documents/people/t1")),
+            vec!["team: people/t1 is not in teams"]
+        );
+        assert_eq!(
+            problems(&team(
+                "projects/p/databases/(default)/documents/orgs/o/teams/t1"
+            )),
+            vec!["team: orgs/o/teams/t1 is not in teams"]
+        );
+    }
+
+    #[test]
+    fn rules_name_known_types_and_valid_patterns() {
+        let unknown = "[collections.users.fields]\nemail = { type = \"text\" }";
+        let error = Rules::parse("rules.toml", unknown).unwrap_err().to_string();
+        assert!(
+            error.contains("users.email: unknown type \"text\""),
+            "{}",
+            error
+        );
+        let invalid = "[collections.users.fields]\nemail = { pattern = \"(\" }";
+        let rules = Rules::parse("rules.toml", invalid).unwrap();
+        let error = rules.checker("users").err().unwrap().to_string();
+        assert!(error.contains("users.email: invalid pattern"), "{}", error);
+        assert!(rules.checker("teams").is_err());
+    }
+
+    #[test]
+    fn violations_fail_the_check() {
+        let rules = Rules::parse("rules.toml", RULES).unwrap();
+        let checker = rules.checker("users").unwrap();
+        let format = OutputFormat::parse("text", Locale::default()).unwrap();
+        let report = |documents: Vec<Document>| {
+            let mut reporter = Reporter::new("check", &format);
+            check_documents(
+                &mut reporter,
+                "users",
+                &checker,
+                documents.into_iter().map(Ok),
+            )
+            .unwrap();
+            reporter.finish().unwrap()
+        };
+        let clean = report(vec![valid_user(json!({}))]);
+        assert!(clean.ok);
+        assert!(!clean.fails(FailOn::Warnings));
+        let warned = report(vec![valid_user(
+            json!({ "nickname": { "nullValue": null } }),
+        )]);
+        assert!(!warned.fails(FailOn::Errors));
+        assert!(warned.fails(FailOn::Warnings));
+        let broken = report(vec![valid_user(json!({})), user("bob", json!({}))]);
+        assert!(broken.fails(FailOn::Errors));
+        assert!(!broken.fails(FailOn::Never));
+        assert_eq!(broken.findings.len(), 2);
+        assert_eq!(broken.findings[0].subject, "users/bob");
+        assert_eq!(broken.summary.get("documents"), Some(&2));
+        assert_eq!(broken.summary.get("violations"), Some(&2));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::dump::collect_schema;
+use crate::groupby::display;
+use crate::output::{OutputFormat, Reporter};
+use libfiresale::api::{DatabaseContext, FirestoreType};
+use libfiresale::canonical::canonical_fields;
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use libfiresale::report::{Finding, Report, Severity};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+type Schema = BTreeMap<String, BTreeSet<String>>;
+
+/// Every field path of `collection` with the value types it holds, from every
+/// document or the first `sample` ones by id
+fn infer(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    sample: Option<usize>,
+) -> Result<(Schema, usize)> {
+    let path = CollectionPath::parse(collection)?;
+    let mut query = StructuredQuery::collection(path.collection_id());
+    query.limit = sample.map(|sample| sample as i32);
+    let mut schema = Schema::new();
+    let mut documents = 0;
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        let document = document?;
+        documents += 1;
+        collect_schema(&canonical_fields(document.fields()), "", &mut schema);
+    }
+    Ok((schema, documents))
+}
+
+// `stringValue` reads better as `string`
+fn type_names(types: &BTreeSet<String>) -> String {
+    types
+        .iter()
+        .map(|kind| kind.trim_end_matches("Value"))
+        .collect::<Vec<&str>>()
+        .join("|")
+}
+
+/// Infers the schema of `collection` in two projects and reports the fields found in
+/// only one of them as `missing` findings, and those holding different types as
+/// `type_mismatch` ones. Their details list the types on the `left` and `right` side,
+/// the first and second project.
+pub fn diff(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    projects: (&str, &str),
+    collection: &str,
+    sample: Option<usize>,
+    format: &OutputFormat,
+) -> Result<Report> {
+    let collection = collection.trim_matches('/');
+    let (left, right) = projects;
+    let (left_schema, left_documents) =
+        infer(&ctx.for_project(left), database_name, collection, sample)?;
+    let (right_schema, right_documents) =
+        infer(&ctx.for_project(right), database_name, collection, sample)?;
+    let mut reporter = Reporter::new("schema diff", format);
+    reporter.say(format!(
+        "{}: {} document(s) in {}, {} in {}",
+        collection, left_documents, left, right_documents, right
+    ));
+    reporter.count("left_documents", left_documents as u64);
+    reporter.count("right_documents", right_documents as u64);
+    let fields = left_schema
+        .keys()
+        .chain(right_schema.keys())
+        .collect::<BTreeSet<&String>>();
+    let mut drifted = false;
+    for field in fields {
+        let (left_types, right_types) = (left_schema.get(field), right_schema.get(field));
+        let (kind, message) = match (left_types, right_types) {
+            (Some(types), None) => (
+                "missing",
+                format!("only in {}: {} ({})", left, field, type_names(types)),
+            ),
+            (None, Some(types)) => (
+                "missing",
+                format!("only in {}: {} ({})", right, field, type_names(types)),
+            ),
+            (Some(left_types), Some(right_types)) if left_types != right_types => (
+                "type_mismatch",
+                format!(
+                    "types differ: {} is {} in {}, {} in {}",
+                    field,
+                    type_names(left_types),
+                    left,
+                    type_names(right_types),
+                    right
+                ),
+            ),
+            _ => continue,
+        };
+        let names = |types: Option<&BTreeSet<String>>| {
+            types.map_or(Vec::new(), |types| {
+                types
+                    .iter()
+                    .map(|kind| kind.trim_end_matches("Value").to_string())
+                    .collect()
+            })
+        };
+        let details = json!({ "left": names(left_types), "right": names(right_types) });
+        let finding = Finding::new(kind, collection, &*message)
+            .with_field(&**field)
+            .with_details(details);
+        reporter.found(finding, message);
+        drifted = true;
+    }
+    if !drifted {
+        reporter.say("no drift");
+    }
+    reporter.finish()
+}
+
+/// What `analyze` learns about one field path
+#[derive(Debug, Default)]
+struct FieldStats {
+    /// Documents holding the field, null or not
+    present: usize,
+    nulls: usize,
+    /// Types of the non-null values
+    types: BTreeSet<String>,
+    /// The first non-null value, kept while every other one equals it
+    constant: Option<Value>,
+    varies: bool,
+}
+
+impl FieldStats {
+    fn add(&mut self, kind: &str, value: &Value) {
+        self.present += 1;
+        if kind == "nullValue" {
+            self.nulls += 1;
+            return;
+        }
+        self.types.insert(kind.to_string());
+        if self.varies {
+            return;
+        }
+        match &self.constant {
+            None => self.constant = Some(value.clone()),
+            Some(first) if first != value => {
+                self.varies = true;
+                self.constant = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+// Like `collect_schema`, counting values rather than only recording their types
+fn collect_stats(fields: &Value, prefix: &str, stats: &mut BTreeMap<String, FieldStats>) {
+    let fields = match fields.as_object() {
+        Some(fields) => fields,
+        None => return,
+    };
+    for (field, value) in fields {
+        let path = format!("{}{}", prefix, field);
+        if let Some((kind, inner)) = value.as_object().and_then(|value| value.iter().next()) {
+            stats.entry(path.clone()).or_default().add(kind, value);
+            if kind == "mapValue" {
+                if let Some(nested) = inner.get("fields") {
+                    collect_stats(nested, &*format!("{}.", path), stats);
+                }
+            }
+        }
+    }
+}
+
+/// Reports the fields of `collection` that are probably dead schema, read from every
+/// document or the first `sample` ones by id: `mostly_empty` when null or missing in at
+/// least `threshold` percent of the documents, `constant` when every document holding
+/// it has the same value, and `mixed_types` when its values have more than one type.
+/// Their details hold the counts behind them. All are warnings, to be judged by someone
+/// who knows which fields are still read.
+pub fn analyze(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collection: &str,
+    threshold: f64,
+    sample: Option<usize>,
+    format: &OutputFormat,
+) -> Result<Report> {
+    if !(0.0..=100.0).contains(&threshold) {
+        return Err(Error::InvalidInput {
+            message: format!("--threshold {} is not a percentage", threshold),
+        });
+    }
+    let collection = collection.trim_matches('/');
+    let path = CollectionPath::parse(collection)?;
+    let mut query = StructuredQuery::collection(path.collection_id());
+    query.limit = sample.map(|sample| sample as i32);
+    let mut stats = BTreeMap::new();
+    let mut documents = 0;
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        let document = document?;
+        documents += 1;
+        collect_stats(&canonical_fields(document.fields()), "", &mut stats);
+    }
+    let mut reporter = Reporter::new("analyze-fields", format);
+    reporter.say(format!(
+        "{}: {} document(s), {} field(s)",
+        collection,
+        documents,
+        stats.len()
+    ));
+    reporter.count("documents", documents as u64);
+    reporter.count("fields", stats.len() as u64);
+    let mut suspicious = false;
+    for (field, field_stats) in &stats {
+        let valued = field_stats.present - field_stats.nulls;
+        let empty = documents - valued;
+        let details = json!({
+            "documents": documents,
+            "present": field_stats.present,
+            "nulls": field_stats.nulls,
+            "types": field_stats
+                .types
+                .iter()
+                .map(|kind| kind.trim_end_matches("Value"))
+                .collect::<Vec<&str>>(),
+        });
+        let mut findings = Vec::new();
+        let empty_percent = 100.0 * empty as f64 / documents.max(1) as f64;
+        if empty_percent >= threshold {
+            findings.push((
+                "mostly_empty",
+                format!(
+                    "{} is null or missing in {:.1}% of documents ({} of {})",
+                    field, empty_percent, empty, documents
+                ),
+            ));
+        }
+        // the fields of a constant map are reported one by one
+        let is_map = field_stats.types.contains("mapValue");
+        if let (Some(value), false) = (&field_stats.constant, is_map) {
+            if valued > 1 {
+                let value = serde_json::from_value::<FirestoreType>(value.clone())
+                    .map(|value| display(&value))
+                    .unwrap_or_else(|_| value.to_string());
+                findings.push((
+                    "constant",
+                    format!(
+                        "{} is {} in all {} document(s) holding it",
+                        field, value, valued
+                    ),
+                ));
+            }
+        }
+        if field_stats.types.len() > 1 {
+            findings.push((
+                "mixed_types",
+                format!("{} holds {}", field, type_names(&field_stats.types)),
+            ));
+        }
+        for (kind, message) in findings {
+            let finding = Finding::new(kind, collection, &*message)
+                .with_field(&**field)
+                .with_severity(Severity::Warning)
+                .with_details(details.clone());
+            reporter.found(finding, message);
+            suspicious = true;
+        }
+    }
+    if !suspicious {
+        reporter.say("every field looks in use");
+    }
+    reporter.finish()
+}
@@ -0,0 +1,343 @@
+use crate::completion::CompletionCache;
+use crate::dump::relative_path;
+use crate::planner::WritePlanner;
+use chrono::{DateTime, Utc};
+use libfiresale::api::{ConsistencySelector, DatabaseContext, Write};
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::{Condition, FilterPlan, StructuredQuery};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Context, Editor, Helper};
+use serde_json::Value;
+
+const PROMPT: &'static str = "firesale> ";
+/// Documents printed by `query` before it stops
+const QUERY_LIMIT: usize = 20;
+
+const HELP: &'static str = "\
+get <path>              print a document
+delete <path>           delete a document (buffered until commit inside a transaction)
+query <collection> [where <condition> [and <condition>]...]
+                        print the first matching documents, e.g. where age >= 21,
+                        read at the pinned time but outside any transaction
+begin [read-only]       start a transaction, reads see a consistent snapshot
+begin at <rfc3339>      pin every read to a point in time
+commit                  apply buffered writes and end the session pin
+rollback                discard buffered writes and end the session pin
+help                    show this message
+exit                    leave the shell
+
+Tab completes commands, collection ids and the fields of a queried collection.";
+
+/// What the session's reads (and writes) are currently pinned to
+enum Pin {
+    Transaction {
+        id: String,
+        read_only: bool,
+        writes: Vec<Write>,
+    },
+    ReadTime(DateTime<Utc>),
+}
+
+/// Hands the writes of a pinned transaction to `commit` and unpins it once they went
+/// through, so a failed commit leaves it pinned to be committed again or rolled back.
+/// Returns the number of writes and what `commit` returned, `None` when no
+/// transaction is pinned.
+fn commit_pinned<T, F>(pin: &mut Option<Pin>, commit: F) -> Result<Option<(usize, T)>>
+where
+    F: FnOnce(String, Vec<Write>) -> Result<T>,
+{
+    let (id, writes) = match pin {
+        Some(Pin::Transaction { id, writes, .. }) => (id.clone(), writes.clone()),
+        _ => return Ok(None),
+    };
+    let count = writes.len();
+    let committed = commit(id, writes)?;
+    *pin = None;
+    Ok(Some((count, committed)))
+}
+
+/// State kept between commands of one interactive shell
+struct Session<'a> {
+    context: DatabaseContext,
+    database_name: &'a str,
+    planner: &'a WritePlanner,
+    pin: Option<Pin>,
+}
+
+/// Completes input lines from the session's completion cache
+struct ShellHelper {
+    completion: CompletionCache,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.completion.complete(&line[..pos]))
+    }
+}
+
+impl Hinter for ShellHelper {}
+
+impl Highlighter for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+impl<'a> Session<'a> {
+    fn consistency(&self) -> Option<ConsistencySelector> {
+        match &self.pin {
+            Some(Pin::Transaction { id, .. }) => Some(ConsistencySelector::Transaction(id.clone())),
+            Some(Pin::ReadTime(time)) => Some(ConsistencySelector::ReadTime(*time)),
+            None => None,
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<()> {
+        let consistency = self.consistency();
+        let document = self
+            .context
+            .get_document(self.database_name, path, consistency.as_ref())?;
+        println!("{:#?}", document);
+        Ok(())
+    }
+
+    fn query(&self, collection: &str, args: &[&str]) -> Result<()> {
+        let conditions = match args {
+            [] => Vec::new(),
+            ["where", conditions @ ..] if !conditions.is_empty() => conditions
+                .join(" ")
+                .split(" and ")
+                .map(|condition| condition.parse())
+                .collect::<Result<Vec<Condition>>>()?,
+            _ => {
+                return Err(Error::InvalidInput {
+                    message: "usage: query <collection> [where <condition> [and <condition>]...]"
+                        .to_string(),
+                })
+            }
+        };
+        let plan = FilterPlan::new(conditions);
+        let path = CollectionPath::parse(collection)?;
+        let mut query = StructuredQuery::collection(path.collection_id());
+        plan.apply(&mut query);
+        let mut stream = self
+            .context
+            .query_stream(self.database_name, path.parent(), query);
+        if let Some(Pin::ReadTime(time)) = &self.pin {
+            stream = stream.read_at(*time);
+        }
+        let mut printed = 0;
+        for document in stream {
+            let document = document?;
+            if !plan.matches(&document) {
+                continue;
+            }
+            if printed == QUERY_LIMIT {
+                println!("... more, narrow the conditions to see them");
+                break;
+            }
+            println!(
+                "{} {}",
+                relative_path(document.name()),
+                Value::Object(document.fields().to_json())
+            );
+            printed += 1;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, path: &str) -> Result<()> {
+        match &mut self.pin {
+            Some(Pin::Transaction {
+                read_only: false,
+                writes,
+                ..
+            }) => {
+                writes.push(self.context.delete_write(self.database_name, path));
+                println!("queued delete of {} ({} pending)", path, writes.len());
+            }
+            Some(_) => println!("session is read-only, commit or rollback first"),
+            None => {
+                let write = self.context.delete_write(self.database_name, path);
+                self.planner
+                    .apply(&self.context, "delete", vec![write], None)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, args: &[&str]) -> Result<()> {
+        if self.pin.is_some() {
+            println!("session already pinned, commit or rollback first");
+            return Ok(());
+        }
+        match args {
+            ["at", time] => match DateTime::parse_from_rfc3339(time) {
+                Ok(time) => {
+                    let time = time.with_timezone(&Utc);
+                    self.pin = Some(Pin::ReadTime(time));
+                    println!("reads pinned to {}", time.to_rfc3339());
+                }
+                Err(e) => println!("invalid read time {}: {}", time, e),
+            },
+            [] | ["read-only"] => {
+                let read_only = !args.is_empty();
+                let id = self
+                    .context
+                    .begin_transaction(self.database_name, read_only)?;
+                self.pin = Some(Pin::Transaction {
+                    id,
+                    read_only,
+                    writes: Vec::new(),
+                });
+                println!("transaction started");
+            }
+            _ => println!("usage: begin [read-only | at <rfc3339>]"),
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if let Some(Pin::ReadTime(_)) = self.pin {
+            self.pin = None;
+            println!("read time pin released");
+            return Ok(());
+        }
+        let (planner, context) = (self.planner, &self.context);
+        let committed = commit_pinned(&mut self.pin, |id, writes| {
+            planner.apply(context, "commit", writes, Some(id))
+        })?;
+        match committed {
+            Some((count, Some(response))) => println!(
+                "committed {} write(s) at {}",
+                count,
+                response.commit_time.to_rfc3339()
+            ),
+            // a dry run printed the writes instead
+            Some((_, None)) => {}
+            None => println!("nothing to commit"),
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        match self.pin.take() {
+            Some(Pin::Transaction { id, writes, .. }) => {
+                self.context.rollback(self.database_name, id)?;
+                println!("discarded {} write(s)", writes.len());
+            }
+            Some(Pin::ReadTime(_)) => println!("read time pin released"),
+            None => println!("nothing to roll back"),
+        }
+        Ok(())
+    }
+
+    /// Runs a single line of input, returning false once the session should end
+    fn execute(&mut self, line: &str) -> Result<bool> {
+        let words = line.split_whitespace().collect::<Vec<&str>>();
+        match &*words {
+            [] => {}
+            ["get", path] => self.get(path)?,
+            ["delete", path] => self.delete(path)?,
+            ["query", collection, args @ ..] => self.query(collection, args)?,
+            ["begin", args @ ..] => self.begin(args)?,
+            ["commit"] => self.commit()?,
+            ["rollback"] => self.rollback()?,
+            ["help"] => println!("{}", HELP),
+            ["exit"] | ["quit"] => return Ok(false),
+            _ => println!("unknown command, try `help`"),
+        }
+        Ok(true)
+    }
+}
+
+/// Reads commands from stdin until `exit` or end of input. An open transaction
+/// is rolled back on the way out so nothing is committed implicitly. Collection ids
+/// and field names for completion come from a cache refreshed in the background.
+pub fn run(context: DatabaseContext, planner: &WritePlanner) -> Result<()> {
+    let completion = CompletionCache::load(&*context.project_id, &*planner.database_name);
+    completion.refresh_in_background(context.clone(), planner.database_name.clone());
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper { completion }));
+    let mut session = Session {
+        context,
+        database_name: &*planner.database_name,
+        planner,
+        pin: None,
+    };
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            // ctrl-c abandons the line being typed, not the session
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        };
+        editor.add_history_entry(&*line);
+        match session.execute(&*line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+    if let Some(Pin::Transaction { .. }) = session.pin {
+        session.rollback()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned() -> Option<Pin> {
+        Some(Pin::Transaction {
+            id: "tx".to_string(),
+            read_only: false,
+            writes: vec![Write::delete("users/alice".to_string())],
+        })
+    }
+
+    #[test]
+    fn a_failed_commit_keeps_the_transaction() {
+        let mut pin = pinned();
+        let result = commit_pinned(&mut pin, |_, _| -> Result<()> {
+            Err(Error::Conflict {
+                message: "aborted".to_string(),
+            })
+        });
+        assert!(result.is_err());
+        match pin {
+            Some(Pin::Transaction { id, writes, .. }) => {
+                assert_eq!(id, "tx");
+                assert_eq!(writes.len(), 1);
+            }
+            _ => panic!("the transaction was unpinned"),
+        }
+    }
+
+    #[test]
+    fn a_commit_unpins_the_transaction() {
+        let mut pin = pinned();
+        let committed = commit_pinned(&mut pin, |id, writes| Ok((id, writes.len()))).unwrap();
+        assert_eq!(committed, Some((1, ("tx".to_string(), 1))));
+        assert!(pin.is_none());
+    }
+
+    #[test]
+    fn nothing_is_committed_without_a_transaction() {
+        let mut pin = None;
+        let committed = commit_pinned(&mut pin, |_, _| -> Result<()> {
+            panic!("nothing to commit")
+        });
+        assert_eq!(committed.unwrap(), None);
+    }
+}
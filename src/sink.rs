@@ -0,0 +1,83 @@
+//! Local files replaced at once, never seen half written
+
+use crate::errors::Result;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A local file written under a temporary name next to `path` and renamed over it once
+/// complete, so readers see either the previous file or the whole new one. Dropped
+/// before `commit`, as when a job fails half way, the temporary file is removed. A
+/// process killed outright may leave it behind, hidden and ending in `.tmp`.
+#[derive(Debug)]
+pub struct AtomicFile {
+    path: PathBuf,
+    temp: PathBuf,
+    file: File,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Starts writing `path`, whose directory must exist
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<AtomicFile> {
+        let path = path.as_ref().to_path_buf();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+        let file = File::create(&temp)?;
+        Ok(AtomicFile {
+            path,
+            temp,
+            file,
+            committed: false,
+        })
+    }
+
+    /// Sets the permissions the file is moved into place with
+    pub fn set_permissions(&self, permissions: fs::Permissions) -> io::Result<()> {
+        self.file.set_permissions(permissions)
+    }
+
+    /// Flushes the file to disk and moves it into place, replacing any previous one
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        fs::rename(&self.temp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            fs::remove_file(&self.temp).ok();
+        }
+    }
+}
+
+/// Replaces `path` with `contents` at once
+pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(contents.as_ref())?;
+    file.commit()
+}
+
+/// Replaces `path` with `value` as pretty printed JSON at once
+pub fn write_json<P: AsRef<Path>, T: Serialize>(path: P, value: &T) -> Result<()> {
+    let mut out = BufWriter::new(AtomicFile::create(path)?);
+    serde_json::to_writer_pretty(&mut out, value)?;
+    out.into_inner().map_err(io::Error::from)?.commit()?;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use crate::config::Config;
+use crate::planner::WritePlanner;
+use libfiresale::api::DatabaseContext;
+use libfiresale::errors::{Error, Result};
+use serde_json::{Map, Value};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const TEMPLATE_EXTENSION: &'static str = "json";
+
+/// A placeholder found in a template, written `"{{ question }}"` or
+/// `"{{ question | default }}"` in place of a string value
+struct Placeholder<'a> {
+    question: &'a str,
+    default: Option<&'a str>,
+}
+
+impl<'a> Placeholder<'a> {
+    fn parse(value: &'a str) -> Option<Placeholder<'a>> {
+        let inner = value.trim();
+        if !inner.starts_with("{{") || !inner.ends_with("}}") || inner.len() < 4 {
+            return None;
+        }
+        let mut parts = inner[2..inner.len() - 2].splitn(2, '|');
+        let question = parts.next()?.trim();
+        let default = parts.next().map(str::trim);
+        Some(Placeholder { question, default })
+    }
+}
+
+/// Reads `<template dir>/<name>.json`, a JSON object of document fields
+fn read_template(config: &Config, name: &str) -> Result<Map<String, Value>> {
+    let path = config
+        .template_dir()
+        .join(format!("{}.{}", name, TEMPLATE_EXTENSION));
+    let contents = fs::read_to_string(&path).map_err(|e| Error::InvalidInput {
+        message: format!("cannot read template {}: {}", path.display(), e),
+    })?;
+    match serde_json::from_str(&*contents)? {
+        Value::Object(fields) => Ok(fields),
+        _ => Err(Error::InvalidInput {
+            message: format!("template {} is not a JSON object", path.display()),
+        }),
+    }
+}
+
+// Replaces every placeholder in `value`, prompting in document order
+fn fill(value: Value, field: &str) -> Result<Value> {
+    match value {
+        Value::String(text) => match Placeholder::parse(&*text) {
+            Some(placeholder) => ask(field, &placeholder),
+            None => Ok(Value::String(text)),
+        },
+        Value::Object(fields) => Ok(Value::Object(fill_fields(fields, field)?)),
+        Value::Array(values) => values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| fill(value, &*format!("{}[{}]", field, index)))
+            .collect::<Result<Vec<Value>>>()
+            .map(Value::Array),
+        value => Ok(value),
+    }
+}
+
+fn fill_fields(fields: Map<String, Value>, prefix: &str) -> Result<Map<String, Value>> {
+    fields
+        .into_iter()
+        .map(|(key, value)| {
+            let field = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            Ok((key, fill(value, &*field)?))
+        })
+        .collect()
+}
+
+/// Prompts on stderr and reads one line. Answers are read as JSON when possible, so
+/// `42` and `true` keep their types, and as a plain string otherwise.
+fn ask(field: &str, placeholder: &Placeholder) -> Result<Value> {
+    match placeholder.default {
+        Some(default) => eprint!("{} ({}) [{}]: ", placeholder.question, field, default),
+        None => eprint!("{} ({}): ", placeholder.question, field),
+    }
+    io::stderr().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(Error::InvalidInput {
+            message: format!("no value given for {}", field),
+        });
+    }
+    let answer = match (line.trim(), placeholder.default) {
+        ("", Some(default)) => default,
+        (answer, _) => answer,
+    };
+    Ok(serde_json::from_str(answer).unwrap_or_else(|_| Value::String(answer.to_string())))
+}
+
+/// Creates a document in `collection` from the template `name`, asking for every
+/// placeholder. Without `document` the id is generated.
+pub fn new_document(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    collection: &str,
+    name: &str,
+    document: Option<&str>,
+) -> Result<()> {
+    let config = Config::load().map_err(|message| Error::InvalidInput { message })?;
+    let fields = fill_fields(read_template(&config, name)?, "")?;
+    match document {
+        Some(document) => {
+            let path = format!("{}/{}", collection, document);
+            let write = ctx.update_write(&*planner.database_name, &*path, &fields);
+            planner.apply(ctx, "new", vec![write], None)?;
+        }
+        None => {
+            if let Some(path) = planner.add(ctx, collection, &fields)? {
+                println!("{}", path);
+            }
+        }
+    }
+    Ok(())
+}
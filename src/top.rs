@@ -0,0 +1,186 @@
+use crate::groupby::display;
+use crate::join::split_field;
+use libfiresale::api::DatabaseContext;
+use libfiresale::errors::{Error, Result};
+use libfiresale::path::CollectionPath;
+use libfiresale::prelude::StructuredQuery;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Distinct values counted exactly before switching to a count-min sketch
+const EXACT_LIMIT: usize = 100_000;
+const SKETCH_WIDTH: usize = 1 << 16;
+const SKETCH_DEPTH: usize = 4;
+/// Candidates tracked per requested value once sketching, so the top ones survive
+const CANDIDATES_PER_RESULT: usize = 10;
+
+/// Approximate counts in fixed memory. Estimates never undercount, and overcount
+/// by a small fraction of the total.
+struct CountMinSketch {
+    rows: Vec<Vec<u64>>,
+}
+
+impl CountMinSketch {
+    fn new() -> CountMinSketch {
+        CountMinSketch {
+            rows: vec![vec![0; SKETCH_WIDTH]; SKETCH_DEPTH],
+        }
+    }
+
+    fn column(row: usize, value: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (row, value).hash(&mut hasher);
+        (hasher.finish() % SKETCH_WIDTH as u64) as usize
+    }
+
+    /// Adds `count` occurrences of `value`, returning its new estimate
+    fn add(&mut self, value: &str, count: u64) -> u64 {
+        let mut estimate = u64::max_value();
+        for (row, counters) in self.rows.iter_mut().enumerate() {
+            let counter = &mut counters[CountMinSketch::column(row, value)];
+            *counter += count;
+            estimate = estimate.min(*counter);
+        }
+        estimate
+    }
+}
+
+/// Value counts, exact until there are too many distinct values to keep
+enum Counter {
+    Exact(HashMap<String, u64>),
+    Sketch {
+        sketch: CountMinSketch,
+        /// The values most likely to be the most frequent, with their estimates
+        candidates: HashMap<String, u64>,
+        capacity: usize,
+    },
+}
+
+impl Counter {
+    fn add(&mut self, value: String, results: usize) {
+        let switch = match self {
+            Counter::Exact(counts) => {
+                *counts.entry(value).or_insert(0) += 1;
+                counts.len() > EXACT_LIMIT
+            }
+            Counter::Sketch {
+                sketch,
+                candidates,
+                capacity,
+            } => {
+                let estimate = sketch.add(&*value, 1);
+                track(candidates, *capacity, value, estimate);
+                false
+            }
+        };
+        if switch {
+            self.start_sketching(results);
+        }
+    }
+
+    // Moves the exact counts into a sketch, keeping the largest as candidates
+    fn start_sketching(&mut self, results: usize) {
+        let counts = match self {
+            Counter::Exact(counts) => std::mem::replace(counts, HashMap::new()),
+            Counter::Sketch { .. } => return,
+        };
+        let capacity = results * CANDIDATES_PER_RESULT;
+        let mut sketch = CountMinSketch::new();
+        let mut candidates = HashMap::new();
+        for (value, count) in counts {
+            let estimate = sketch.add(&*value, count);
+            track(&mut candidates, capacity, value, estimate);
+        }
+        *self = Counter::Sketch {
+            sketch,
+            candidates,
+            capacity,
+        };
+    }
+
+    fn is_exact(&self) -> bool {
+        match self {
+            Counter::Exact(_) => true,
+            Counter::Sketch { .. } => false,
+        }
+    }
+
+    /// The `results` most frequent values, most frequent first
+    fn top(self, results: usize) -> Vec<(String, u64)> {
+        let counts = match self {
+            Counter::Exact(counts) => counts,
+            Counter::Sketch { candidates, .. } => candidates,
+        };
+        let mut counts = counts.into_iter().collect::<Vec<(String, u64)>>();
+        counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        counts.truncate(results);
+        counts
+    }
+}
+
+// Records an estimate, evicting the smallest candidate when full and outgrown
+fn track(candidates: &mut HashMap<String, u64>, capacity: usize, value: String, estimate: u64) {
+    if candidates.contains_key(&value) || candidates.len() < capacity {
+        candidates.insert(value, estimate);
+        return;
+    }
+    let smallest = candidates
+        .iter()
+        .min_by_key(|(_, count)| **count)
+        .map(|(value, count)| (value.clone(), *count));
+    if let Some((smallest, count)) = smallest {
+        if estimate > count {
+            candidates.remove(&smallest);
+            candidates.insert(value, estimate);
+        }
+    }
+}
+
+/// Prints the `results` most frequent values of a field, written `users.country`, with
+/// their counts and share of the documents. Counts are exact unless the field has more
+/// than `EXACT_LIMIT` distinct values, when they become estimates marked with `~`.
+pub fn top(ctx: &DatabaseContext, database_name: &str, target: &str, results: usize) -> Result<()> {
+    if results == 0 {
+        return Err(Error::InvalidInput {
+            message: "-n must be at least 1".to_string(),
+        });
+    }
+    let (collection, field) = split_field(target)?;
+    let path = CollectionPath::parse(collection)?;
+    let query = StructuredQuery::collection(path.collection_id());
+    let mut counter = Counter::Exact(HashMap::new());
+    let (mut documents, mut missing) = (0u64, 0u64);
+    for document in ctx.query_stream(database_name, path.parent(), query) {
+        let document = document?;
+        documents += 1;
+        match document.fields().get_path(field) {
+            Some(value) => counter.add(display(value), results),
+            None => missing += 1,
+        }
+    }
+    println!(
+        "{}: {} document(s), {} without {}",
+        collection, documents, missing, field
+    );
+    let marker = if counter.is_exact() { "" } else { "~" };
+    let rows = counter.top(results);
+    let width = rows
+        .iter()
+        .map(|(value, _)| value.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (rank, (value, count)) in rows.iter().enumerate() {
+        let share = *count as f64 * 100.0 / documents.max(1) as f64;
+        println!(
+            "{:>3}  {:<width$}  {}{}  {:.1}%",
+            rank + 1,
+            value,
+            marker,
+            count,
+            share,
+            width = width
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use crate::planner::WritePlanner;
+use libfiresale::api::filter::Condition;
+use libfiresale::api::{ConsistencySelector, DatabaseContext, Write};
+use libfiresale::errors::{Error, Result};
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::thread;
+use std::time::Duration;
+
+/// Times a transaction is attempted when others contend for its documents
+const ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for every further one
+const BACKOFF: Duration = Duration::from_millis(200);
+
+/// A change file: documents read and checked, then writes committed only if every
+/// check passed, all within one transaction
+///
+/// ```json
+/// {
+///   "reads": [
+///     { "path": "accounts/alice", "when": ["balance >= 100"] },
+///     { "path": "accounts/bob", "exists": true }
+///   ],
+///   "writes": [
+///     { "op": "patch", "path": "accounts/alice", "fields": { "balance": 0 } },
+///     { "op": "set", "path": "transfers/t1", "fields": { "amount": 100 } },
+///     { "op": "delete", "path": "holds/alice" }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+struct ChangeFile {
+    #[serde(default)]
+    reads: Vec<Read>,
+    #[serde(default)]
+    writes: Vec<Operation>,
+}
+
+/// A document read in the transaction, locking it until the commit
+#[derive(Debug, Deserialize)]
+struct Read {
+    path: String,
+    /// Whether the document has to exist, or not exist
+    #[serde(default)]
+    exists: Option<bool>,
+    /// `--where` style conditions on its fields, a missing document having none
+    #[serde(default)]
+    when: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    /// Replaces the document, creating it if needed
+    Set {
+        path: String,
+        fields: Map<String, Value>,
+    },
+    /// Updates only the given top level fields
+    Patch {
+        path: String,
+        fields: Map<String, Value>,
+    },
+    Delete {
+        path: String,
+    },
+}
+
+impl Operation {
+    fn to_write(&self, ctx: &DatabaseContext, database_name: &str) -> Write {
+        match self {
+            Operation::Set { path, fields } => ctx.update_write(database_name, &*path, fields),
+            Operation::Patch { path, fields } => ctx
+                .update_write(database_name, &*path, fields)
+                .with_update_mask(fields.keys().cloned().collect()),
+            Operation::Delete { path } => ctx.delete_write(database_name, &*path),
+        }
+    }
+}
+
+/// How an attempt ended short of an error
+enum Outcome {
+    Committed,
+    /// A check failed, so nothing was written
+    Aborted(String),
+}
+
+/// Runs the reads and writes of `file` in a single transaction, retrying it when
+/// contention aborts it. A failed check rolls everything back and is reported as
+/// a conflict.
+pub fn apply(ctx: &DatabaseContext, planner: &WritePlanner, file: &str) -> Result<()> {
+    let changes: ChangeFile = serde_json::from_reader(BufReader::new(File::open(file)?))?;
+    let checks = changes
+        .reads
+        .iter()
+        .map(|read| {
+            let conditions = read
+                .when
+                .iter()
+                .map(|condition| condition.parse())
+                .collect::<Result<Vec<Condition>>>()?;
+            Ok((read, conditions))
+        })
+        .collect::<Result<Vec<(&Read, Vec<Condition>)>>>()?;
+    let database_name = &*planner.database_name;
+    let mut aborted: Option<String> = None;
+    let mut attempt = 1;
+    loop {
+        let transaction = match aborted.take() {
+            Some(previous) => ctx.retry_transaction(database_name, previous)?,
+            None => ctx.begin_transaction(database_name, false)?,
+        };
+        match attempt_once(ctx, planner, &changes, &checks, transaction.clone()) {
+            Ok(Outcome::Committed) => {
+                if !planner.dry_run {
+                    println!(
+                        "committed {} write(s) after {} read(s), attempt {}",
+                        changes.writes.len(),
+                        changes.reads.len(),
+                        attempt
+                    );
+                }
+                return Ok(());
+            }
+            Ok(Outcome::Aborted(reason)) => {
+                ctx.rollback(database_name, transaction)?;
+                return Err(Error::Conflict {
+                    message: format!("transaction aborted, {}", reason),
+                });
+            }
+            // Firestore aborts a transaction that lost a race for its documents
+            Err(ref e)
+                if attempt < ATTEMPTS && e.status() == Some(reqwest::StatusCode::CONFLICT) =>
+            {
+                eprintln!("contention on attempt {}, retrying", attempt);
+                thread::sleep(BACKOFF * 2u32.pow(attempt - 1));
+                aborted = Some(transaction);
+                attempt += 1;
+            }
+            Err(e) => {
+                // best effort, the transaction expires on its own anyway
+                ctx.rollback(database_name, transaction).ok();
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn attempt_once(
+    ctx: &DatabaseContext,
+    planner: &WritePlanner,
+    changes: &ChangeFile,
+    checks: &[(&Read, Vec<Condition>)],
+    transaction: String,
+) -> Result<Outcome> {
+    let database_name = &*planner.database_name;
+    let consistency = ConsistencySelector::Transaction(transaction.clone());
+    for (read, conditions) in checks {
+        let fields = match ctx.get_document(database_name, &*read.path, Some(&consistency)) {
+            Ok(document) => Some(document.fields().clone()),
+            Err(ref e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => None,
+            Err(e) => return Err(e),
+        };
+        match read.exists {
+            Some(true) if fields.is_none() => {
+                return Ok(Outcome::Aborted(format!("{} does not exist", read.path)))
+            }
+            Some(false) if fields.is_some() => {
+                return Ok(Outcome::Aborted(format!("{} exists", read.path)))
+            }
+            _ => {}
+        }
+        let fields = fields.unwrap_or_default();
+        if let Some(failed) = conditions.iter().find(|c| !c.matches(&fields)) {
+            let reason = format!("{} does not satisfy {}", read.path, failed);
+            return Ok(Outcome::Aborted(reason));
+        }
+    }
+    let writes = changes
+        .writes
+        .iter()
+        .map(|operation| operation.to_write(ctx, database_name))
+        .collect::<Vec<Write>>();
+    planner.apply(ctx, "txn", writes, Some(transaction))?;
+    Ok(Outcome::Committed)
+}
@@ -0,0 +1,226 @@
+use libfiresale::errors::{Error, Result};
+use libfiresale::sink::AtomicFile;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Overrides the release feed in debug builds, e.g. to test against a local mirror
+const FEED_KEY: &'static str = "FIRESALE_UPDATE_FEED";
+/// The latest release, in the format of the GitHub releases API
+const DEFAULT_FEED: &'static str = "https://api.github.com/repos/haze/firesale/releases/latest";
+/// Every binary is published next to a file holding its SHA-256 checksum in hex
+const CHECKSUM_SUFFIX: &'static str = ".sha256";
+/// and one holding its base64 Ed25519 signature made with the release key
+const SIGNATURE_SUFFIX: &'static str = ".sig";
+/// The public half of the release key, base64. Release builds pin it at compile
+/// time, builds without it cannot update themselves.
+const RELEASE_KEY: Option<&'static str> = option_env!("FIRESALE_RELEASE_KEY");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Replaces the running executable with the latest release when it is newer than
+/// `current`, or with `check_only` only says whether there is one. The binary for
+/// this platform is checked against its published checksum and signature and
+/// replaces the executable as an `AtomicFile`, so an interrupted update leaves the
+/// old one.
+pub fn self_update(current: &str, check_only: bool) -> Result<()> {
+    let feed = match env::var(FEED_KEY) {
+        Ok(feed) if cfg!(debug_assertions) => feed,
+        Ok(_) => {
+            eprintln!("{} is only read by debug builds, ignoring it", FEED_KEY);
+            DEFAULT_FEED.to_string()
+        }
+        Err(_) => DEFAULT_FEED.to_string(),
+    };
+    let client = reqwest::Client::new();
+    let release: Release = client.get(&*feed).send()?.error_for_status()?.json()?;
+    let latest = release.tag_name.trim_start_matches('v');
+    if !is_newer(latest, current) {
+        println!("firesale {} is up to date", current);
+        return Ok(());
+    }
+    if check_only {
+        println!("firesale {} is available, running {}", latest, current);
+        return Ok(());
+    }
+    let key = RELEASE_KEY.ok_or_else(|| Error::InvalidInput {
+        message: "this build has no release key to verify updates with, not updating".to_string(),
+    })?;
+    let name = asset_name();
+    let find = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| Error::InvalidInput {
+                message: format!("release {} has no {}", release.tag_name, name),
+            })
+    };
+    let binary = find(&*name)?;
+    let checksum = find(&*format!("{}{}", name, CHECKSUM_SUFFIX))?;
+    let signature = find(&*format!("{}{}", name, SIGNATURE_SUFFIX))?;
+    let expected = download(&client, &*checksum.browser_download_url)?;
+    let expected = String::from_utf8_lossy(&expected)
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    let bytes = download(&client, &*binary.browser_download_url)?;
+    let actual = Sha256::digest(&bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    if actual != expected {
+        return Err(Error::InvalidInput {
+            message: format!(
+                "checksum of {} is {}, expected {}, not updating",
+                name, actual, expected
+            ),
+        });
+    }
+    let signature = download(&client, &*signature.browser_download_url)?;
+    verify(key, &bytes, &signature).map_err(|message| Error::InvalidInput {
+        message: format!("{} of {}, not updating", message, name),
+    })?;
+    let executable = env::current_exe()?;
+    replace(&executable, &bytes)?;
+    println!(
+        "updated {} from {} to {}",
+        executable.display(),
+        current,
+        latest
+    );
+    Ok(())
+}
+
+// The release asset built for this platform, e.g. firesale-x86_64-linux
+fn asset_name() -> String {
+    let extension = if cfg!(windows) { ".exe" } else { "" };
+    format!(
+        "firesale-{}-{}{}",
+        env::consts::ARCH,
+        env::consts::OS,
+        extension
+    )
+}
+
+// Checks the base64 `signature` of `bytes` against the base64 public `key`
+fn verify(key: &str, bytes: &[u8], signature: &[u8]) -> std::result::Result<(), String> {
+    let key = base64::decode(key.trim()).map_err(|e| format!("invalid release key ({})", e))?;
+    let signature = String::from_utf8_lossy(signature);
+    let signature =
+        base64::decode(signature.trim()).map_err(|e| format!("unreadable signature ({})", e))?;
+    UnparsedPublicKey::new(&ED25519, &key)
+        .verify(bytes, &signature)
+        .map_err(|_| "invalid signature".to_string())
+}
+
+fn download(client: &reqwest::Client, url: &str) -> Result<Vec<u8>> {
+    let mut response = client.get(url).send()?.error_for_status()?;
+    let mut bytes = Vec::new();
+    response.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Dotted versions compared number by number, missing numbers being 0
+fn is_newer(candidate: &str, current: &str) -> bool {
+    let numbers = |version: &str| {
+        version
+            .split(|c| c == '.' || c == '-' || c == '+')
+            .take(3)
+            .map(|part| part.parse::<u64>().unwrap_or(0))
+            .chain(std::iter::repeat(0))
+            .take(3)
+            .collect::<Vec<u64>>()
+    };
+    numbers(candidate) > numbers(current)
+}
+
+fn replace(executable: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = AtomicFile::create(executable)?;
+    file.write_all(bytes)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o755))?;
+    }
+    if !cfg!(windows) {
+        return file.commit();
+    }
+    // a running executable cannot be replaced on windows, only moved aside, and
+    // put back when the new one cannot take its place
+    let mut old = executable.file_name().unwrap_or_default().to_os_string();
+    old.push(".old");
+    let old = executable.with_file_name(old);
+    fs::remove_file(&old).ok();
+    fs::rename(executable, &old)?;
+    file.commit().map_err(|e| {
+        if let Err(restore) = fs::rename(&old, executable) {
+            eprintln!(
+                "could not restore {} from {}: {}",
+                executable.display(),
+                old.display(),
+                restore
+            );
+        }
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn signed_binaries_are_accepted() {
+        let pair = key_pair();
+        let key = base64::encode(pair.public_key().as_ref());
+        let signature = base64::encode(pair.sign(b"binary").as_ref());
+        assert_eq!(verify(&*key, b"binary", signature.as_bytes()), Ok(()));
+    }
+
+    #[test]
+    fn tampered_binaries_are_rejected() {
+        let pair = key_pair();
+        let key = base64::encode(pair.public_key().as_ref());
+        let signature = base64::encode(pair.sign(b"binary").as_ref());
+        assert!(verify(&*key, b"binarY", signature.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn signatures_by_another_key_are_rejected() {
+        let key = base64::encode(key_pair().public_key().as_ref());
+        let signature = base64::encode(key_pair().sign(b"binary").as_ref());
+        assert!(verify(&*key, b"binary", signature.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn newer_versions() {
+        assert!(is_newer("0.2.0", "0.1.9"));
+        assert!(is_newer("1.0", "0.9.9"));
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("0.1.0-beta", "0.1.0"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::dump::relative_path;
+use crate::rules::{Checker, Rules};
+use libfiresale::api::{DatabaseContext, Document, FirestoreType};
+use libfiresale::errors::{Error, Result};
+use libfiresale::prelude::{Condition, DocumentChange, FilterPlan, WatchTarget};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What `watch` looks at, and what it reports where
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// `--where` conditions, applied to every collection
+    pub filters: Vec<String>,
+    /// `--when` triggers, every one of which a write has to fire to be reported
+    pub triggers: Vec<String>,
+    pub interval: Duration,
+    /// Timestamp field every write sets, polled for what was written since
+    pub updated_field: Option<String>,
+    /// Only report writes breaking this rules file
+    pub rules: Option<String>,
+    /// Also post each report here
+    pub webhook: Option<String>,
+}
+
+/// A `--when` trigger such as `status changed`, `status changed to "shipped"` or
+/// `stock changed from 1 to 0`, firing on writes that changed the value of a field.
+/// Values are read as JSON when possible and as a plain string otherwise, and a
+/// missing field matches none of them. A new document changed every field it has,
+/// and a removed one every field it had.
+#[derive(Debug, Clone)]
+struct Trigger {
+    field: String,
+    from: Option<Value>,
+    to: Option<Value>,
+}
+
+impl FromStr for Trigger {
+    type Err = Error;
+
+    fn from_str(trigger: &str) -> Result<Trigger> {
+        let invalid = || Error::InvalidInput {
+            message: format!(
+                "invalid trigger {:?}, expected `field changed [from value] [to value]`",
+                trigger
+            ),
+        };
+        let mut parts = trigger.trim().splitn(3, char::is_whitespace);
+        let field = match (parts.next(), parts.next()) {
+            (Some(field), Some("changed")) if !field.is_empty() => field.to_string(),
+            _ => return Err(invalid()),
+        };
+        let mut rest = parts.next().unwrap_or("").trim_start();
+        let (mut from, mut to) = (None, None);
+        while !rest.is_empty() {
+            let mut words = rest.splitn(2, char::is_whitespace);
+            let slot = match words.next() {
+                Some("from") if from.is_none() && to.is_none() => &mut from,
+                Some("to") if to.is_none() => &mut to,
+                _ => return Err(invalid()),
+            };
+            let (value, remainder) = read_value(words.next().unwrap_or("")).ok_or_else(invalid)?;
+            *slot = Some(value);
+            rest = remainder.trim_start();
+        }
+        Ok(Trigger { field, from, to })
+    }
+}
+
+/// The value at the start of `text`, a quoted JSON string or a single word, and what
+/// follows it
+fn read_value(text: &str) -> Option<(Value, &str)> {
+    let text = text.trim_start();
+    if text.starts_with('"') {
+        let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+        let value = values.next()?.ok()?;
+        return Some((value, &text[values.byte_offset()..]));
+    }
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    if end == 0 {
+        return None;
+    }
+    let (word, rest) = text.split_at(end);
+    let value = serde_json::from_str(word).unwrap_or_else(|_| Value::String(word.to_string()));
+    Some((value, rest))
+}
+
+impl Trigger {
+    fn fires(&self, old: Option<&Document>, new: Option<&Document>) -> bool {
+        let before = old.and_then(|old| old.fields().get_path(&*self.field));
+        let after = new.and_then(|new| new.fields().get_path(&*self.field));
+        let (before, after) = (
+            before.map(FirestoreType::to_json),
+            after.map(FirestoreType::to_json),
+        );
+        before != after
+            && self
+                .from
+                .as_ref()
+                .map_or(true, |from| before.as_ref() == Some(from))
+            && self
+                .to
+                .as_ref()
+                .map_or(true, |to| after.as_ref() == Some(to))
+    }
+}
+
+/// Reports each change to the documents of `collections`, polling them every interval
+/// on one change stream; the first poll only takes stock. Each report names the
+/// collection it came from as `target` and the change as `added`, `modified` or
+/// `removed`. With `filters` only documents matching them are watched, with
+/// `triggers` only the writes firing them are reported, and with `rules` only those
+/// breaking them. Triggers compare the states kept by the change stream, and a write
+/// whose earlier state it no longer holds is skipped with a warning. With an updated
+/// field, polls only read what was written since the previous one. Reports are printed and, with `webhook`, posted as JSON; a failed
+/// post is printed and does not stop the watch.
+pub fn watch(
+    ctx: &DatabaseContext,
+    database_name: &str,
+    collections: &[String],
+    options: &WatchOptions,
+) -> Result<()> {
+    let rules = match &options.rules {
+        Some(path) => Some(Rules::load(&**path)?),
+        None => None,
+    };
+    let conditions = options
+        .filters
+        .iter()
+        .map(|condition| condition.parse())
+        .collect::<Result<Vec<Condition>>>()?;
+    let plan = FilterPlan::new(conditions);
+    let triggers = options
+        .triggers
+        .iter()
+        .map(|trigger| trigger.parse())
+        .collect::<Result<Vec<Trigger>>>()?;
+    let mut checkers = HashMap::new();
+    let mut stream = ctx
+        .changes(database_name)
+        .interval(options.interval)
+        .report_existing(false);
+    for collection in collections {
+        let mut target = WatchTarget::collection(collection, plan.clone())?;
+        if let Some(field) = &options.updated_field {
+            target = target.with_updated_field(&**field);
+        }
+        if let Some(rules) = &rules {
+            checkers.insert(target.label.clone(), rules.checker(&*target.label)?);
+        }
+        stream = stream.target(target);
+    }
+    for change in stream {
+        let (target, change) = change?;
+        let kind = match &change {
+            DocumentChange::Added(_) => "added",
+            DocumentChange::Modified { .. } => "modified",
+            DocumentChange::Removed { .. } => "removed",
+        };
+        if !triggers.is_empty() {
+            let known = match &change {
+                DocumentChange::Added(_) => true,
+                _ => change.before().is_some(),
+            };
+            if !known {
+                eprintln!(
+                    "warning: {}: earlier state no longer cached, --when not evaluated",
+                    relative_path(change.name())
+                );
+                continue;
+            }
+            let fired = triggers
+                .iter()
+                .all(|trigger| trigger.fires(change.before(), change.after()));
+            if !fired {
+                continue;
+            }
+        }
+        let report = match change.after() {
+            Some(document) => report(&*target, kind, document, checkers.get(&target)),
+            // a removal breaks no rules
+            None if rules.is_some() => None,
+            None => Some(removal(&*target, change.name())),
+        };
+        if let Some(report) = report {
+            println!("{}", report);
+            if let Some(webhook) = &options.webhook {
+                if let Err(e) = ctx.post_json(&**webhook, &report) {
+                    eprintln!("warning: failed to post to {}: {}", webhook, e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// What to report about a written document, nothing when it passes the rules
+fn report(
+    target: &str,
+    kind: &str,
+    document: &Document,
+    checker: Option<&Checker>,
+) -> Option<Value> {
+    let violations = match checker {
+        Some(checker) => match checker.violations(document) {
+            ref violations if violations.is_empty() => return None,
+            violations => violations,
+        },
+        None => Vec::new(),
+    };
+    Some(json!({
+        "target": target,
+        "change": kind,
+        "document": relative_path(document.name()),
+        "updateTime": document.update_time().to_rfc3339(),
+        "violations": violations.iter().map(ToString::to_string).collect::<Vec<String>>(),
+    }))
+}
+
+fn removal(target: &str, name: &str) -> Value {
+    json!({
+        "target": target,
+        "change": "removed",
+        "document": relative_path(name),
+    })
+}